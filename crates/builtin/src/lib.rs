@@ -1,11 +1,27 @@
+//! Catalogue of Nix builtins.
+//!
+//! Builtins are loaded from versioned snapshots of `nix __dump-language` checked in under
+//! `snapshots`, or from the output of the user's Nix at runtime. See `dev/dump-builtins.sh` for
+//! how to generate a new snapshot.
+//!
+//! Only the snapshot of Nix 2.18 is checked in for now. Without a runtime Nix, older versions
+//! get its builtins, and names only known by other versions are undefined rather than
+//! unavailable.
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashSet};
+use std::sync::{Arc, Mutex, OnceLock};
+
+/// Checked-in snapshots, sorted by Nix versions.
+const SNAPSHOTS: &[(&str, &str)] = &[("2.18", include_str!("../snapshots/nix-2.18.json"))];
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Builtin {
     pub kind: BuiltinKind,
     pub is_global: bool,
-    pub summary: &'static str,
-    pub doc: Option<&'static str>,
+    pub summary: String,
+    pub doc: Option<String>,
     pub impure_only: bool,
-    pub experimental_feature: Option<&'static str>,
+    pub experimental_feature: Option<String>,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
@@ -15,9 +31,254 @@ pub enum BuiltinKind {
     Attrset,
 }
 
-#[allow(clippy::all)]
-pub static ALL_BUILTINS: phf::Map<&'static str, Builtin> =
-    include!(concat!(env!("OUT_DIR"), "/generated.expr"));
+/// All builtins of a specific Nix version.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Builtins {
+    nix_version: String,
+    entries: BTreeMap<&'static str, Builtin>,
+}
+
+/// The format of checked-in snapshots.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Snapshot {
+    /// The output of `builtins.nixVersion`.
+    pub nix_version: String,
+    /// The output of `builtins.attrNames builtins`.
+    pub attr_names: Vec<String>,
+    /// Builtins which are also accessible without the `builtins.` prefix.
+    pub globals: Vec<String>,
+    /// The output of `nix __dump-language`.
+    pub language: DumpLanguage,
+}
+
+/// The output of `nix __dump-language`, which is available since Nix 2.17.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct DumpLanguage {
+    pub builtins: BTreeMap<String, DumpBuiltin>,
+    pub constants: BTreeMap<String, DumpConstant>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct DumpBuiltin {
+    pub args: Vec<String>,
+    pub doc: String,
+    #[serde(default)]
+    pub experimental_feature: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct DumpConstant {
+    pub doc: String,
+    pub impure_only: bool,
+    #[serde(rename = "type")]
+    pub type_: String,
+}
+
+impl Builtins {
+    /// Get the builtins of the latest checked-in snapshot.
+    pub fn latest() -> Arc<Self> {
+        snapshots().last().expect("No snapshot").clone()
+    }
+
+    /// Get the builtins of the newest checked-in snapshot not newer than `nix_version`,
+    /// or the oldest one if all snapshots are newer.
+    pub fn snapshot_for_version(nix_version: &str) -> Arc<Self> {
+        let version = parse_version(nix_version);
+        let snapshots = snapshots();
+        SNAPSHOTS
+            .iter()
+            .zip(snapshots)
+            .rev()
+            .find(|((snapshot_version, _), _)| parse_version(snapshot_version) <= version)
+            .map_or(&snapshots[0], |(_, builtins)| builtins)
+            .clone()
+    }
+
+    /// Get the builtins of the closest checked-in snapshot for `nix_version`, as if they come
+    /// from that version. This is used when Nix is unavailable but its version is known.
+    pub fn for_version(nix_version: &str) -> Self {
+        Self {
+            nix_version: nix_version.into(),
+            ..Self::clone(&Self::snapshot_for_version(nix_version))
+        }
+    }
+
+    /// Use the builtins as if they come from `nix_version`. This is for forks like Lix, whose
+    /// versions do not correspond to snapshots.
+    pub fn with_nix_version(self, nix_version: &str) -> Self {
+        Self {
+            nix_version: nix_version.into(),
+            ..self
+        }
+    }
+
+    /// Check if `name` is a builtin in any known Nix version.
+    pub fn is_known(name: &str) -> bool {
+        snapshots().iter().any(|b| b.contains_key(name))
+    }
+
+    /// Build the catalogue from the information of some Nix.
+    ///
+    /// Information missing from `language`, eg. for Nix older than 2.17 which doesn't support
+    /// `nix __dump-language`, is taken from the closest checked-in snapshot.
+    pub fn from_nix(nix_version: String, attr_names: &[String], language: &DumpLanguage) -> Self {
+        let base = Self::snapshot_for_version(&nix_version);
+        let entries = attr_names
+            .iter()
+            .map(|name| {
+                let mut b = builtin_from_dump(name, language)
+                    .or_else(|| base.get(name).cloned())
+                    .unwrap_or_else(|| undocumented_builtin(name));
+                b.is_global = base.get(name).is_some_and(|b| b.is_global);
+                (intern(name), b)
+            })
+            .collect();
+        Self {
+            nix_version,
+            entries,
+        }
+    }
+
+    fn from_snapshot(snapshot: &Snapshot) -> Self {
+        let globals = snapshot.globals.iter().collect::<HashSet<_>>();
+        let entries = snapshot
+            .attr_names
+            .iter()
+            .map(|name| {
+                let mut b = builtin_from_dump(name, &snapshot.language)
+                    .unwrap_or_else(|| undocumented_builtin(name));
+                b.is_global = globals.contains(name);
+                (intern(name), b)
+            })
+            .collect();
+        Self {
+            nix_version: snapshot.nix_version.clone(),
+            entries,
+        }
+    }
+
+    /// The Nix version where these builtins come from.
+    pub fn nix_version(&self) -> &str {
+        &self.nix_version
+    }
+
+    /// Check if the Nix version is at least `version`, by comparing leading numeric components.
+    pub fn nix_version_at_least(&self, version: &str) -> bool {
+        parse_version(&self.nix_version) >= parse_version(version)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Builtin> {
+        self.entries.get(name)
+    }
+
+    pub fn get_entry(&self, name: &str) -> Option<(&'static str, &Builtin)> {
+        self.entries.get_key_value(name).map(|(&k, v)| (k, v))
+    }
+
+    pub fn contains_key(&self, name: &str) -> bool {
+        self.entries.contains_key(name)
+    }
+
+    /// Iterate all builtins, sorted by names.
+    pub fn entries(&self) -> impl Iterator<Item = (&'static str, &Builtin)> + '_ {
+        self.entries.iter().map(|(&k, v)| (k, v))
+    }
+}
+
+fn snapshots() -> &'static [Arc<Builtins>] {
+    static CACHE: OnceLock<Vec<Arc<Builtins>>> = OnceLock::new();
+    CACHE.get_or_init(|| {
+        SNAPSHOTS
+            .iter()
+            .map(|(version, json)| {
+                let snapshot = serde_json::from_str::<Snapshot>(json)
+                    .unwrap_or_else(|err| panic!("Invalid builtins snapshot {version}: {err}"));
+                Arc::new(Builtins::from_snapshot(&snapshot))
+            })
+            .collect()
+    })
+}
+
+fn builtin_from_dump(name: &str, language: &DumpLanguage) -> Option<Builtin> {
+    if let Some(b) = language.builtins.get(name) {
+        return Some(Builtin {
+            kind: BuiltinKind::Function,
+            is_global: false,
+            summary: summary(name, &b.args),
+            doc: Some(b.doc.clone()),
+            impure_only: false,
+            experimental_feature: b.experimental_feature.clone(),
+        });
+    }
+    let c = language.constants.get(name)?;
+    let kind = if c.type_.eq_ignore_ascii_case("set") {
+        BuiltinKind::Attrset
+    } else {
+        BuiltinKind::Const
+    };
+    Some(Builtin {
+        kind,
+        is_global: false,
+        summary: summary(name, &[]),
+        doc: Some(c.doc.clone()),
+        impure_only: c.impure_only,
+        experimental_feature: None,
+    })
+}
+
+fn undocumented_builtin(name: &str) -> Builtin {
+    let kind = match name {
+        "builtins" => BuiltinKind::Attrset,
+        "true" | "false" | "null" => BuiltinKind::Const,
+        _ => BuiltinKind::Function,
+    };
+    Builtin {
+        kind,
+        is_global: false,
+        summary: summary(name, &[]),
+        doc: None,
+        impure_only: false,
+        experimental_feature: None,
+    }
+}
+
+fn summary(name: &str, args: &[String]) -> String {
+    ["`builtins.", name]
+        .into_iter()
+        .chain(args.iter().flat_map(|arg| [" ", arg]))
+        .chain(Some("`"))
+        .collect()
+}
+
+/// Intern builtin names, so that they can be referenced as `&'static str` everywhere.
+/// There are only a few hundreds of them across all Nix versions.
+fn intern(name: &str) -> &'static str {
+    static NAMES: OnceLock<Mutex<HashSet<&'static str>>> = OnceLock::new();
+    let mut names = NAMES.get_or_init(Mutex::default).lock().unwrap();
+    match names.get(name) {
+        Some(name) => name,
+        None => {
+            let name = &*Box::leak(name.to_owned().into_boxed_str());
+            names.insert(name);
+            name
+        }
+    }
+}
+
+/// Parse the leading numeric components of a Nix version, eg. `2.18.0pre20230808_abcdef` gives
+/// `[2, 18, 0]`.
+fn parse_version(version: &str) -> Vec<u32> {
+    version
+        .split('.')
+        .map_while(|part| {
+            let digits = part.split(|c: char| !c.is_ascii_digit()).next()?;
+            digits.parse().ok()
+        })
+        .collect()
+}
 
 #[cfg(test)]
 mod tests {
@@ -25,34 +286,98 @@ mod tests {
 
     #[test]
     fn sanity() {
+        let builtins = Builtins::latest();
+        assert_eq!(builtins.nix_version(), "2.18.1");
         assert!(matches!(
-            ALL_BUILTINS["true"],
+            builtins.get("true").unwrap(),
             Builtin {
                 kind: BuiltinKind::Const,
                 is_global: true,
-                summary: "`builtins.true`",
+                summary,
                 doc: _,
                 impure_only: false,
                 experimental_feature: None,
-            }
+            } if summary == "`builtins.true`"
         ));
 
         assert_eq!(
-            ALL_BUILTINS["attrNames"],
-            Builtin {
+            builtins.get("attrNames").unwrap(),
+            &Builtin {
                 kind: BuiltinKind::Function,
                 is_global: false,
-                summary: "`builtins.attrNames set`",
+                summary: "`builtins.attrNames set`".into(),
                 doc: Some(
                     "\
 Return the names of the attributes in the set *set* in an
 alphabetically sorted list. For instance, `builtins.attrNames { y
-= 1; x = \"foo\"; }` evaluates to `[ \"x\" \"y\" ]`.\
-                "
+= 1; x = \"foo\"; }` evaluates to `[ \"x\" \"y\" ]`."
+                        .into()
                 ),
                 impure_only: false,
                 experimental_feature: None,
             }
         );
+
+        // Undocumented.
+        assert_eq!(builtins.get("addErrorContext").unwrap().doc, None);
+    }
+
+    #[test]
+    fn snapshot_for_version() {
+        let snapshots = snapshots();
+        assert!(SNAPSHOTS
+            .windows(2)
+            .all(|w| parse_version(w[0].0) < parse_version(w[1].0)));
+        for ((version, _), builtins) in SNAPSHOTS.iter().zip(snapshots) {
+            assert!(builtins.nix_version().starts_with(&format!("{version}.")));
+            for v in [version.to_string(), format!("{version}.99")] {
+                let got = Builtins::snapshot_for_version(&v);
+                assert!(
+                    Arc::ptr_eq(&got, builtins),
+                    "{v} selects {}",
+                    got.nix_version()
+                );
+            }
+        }
+        // Versions older than all snapshots get the oldest one.
+        assert!(Arc::ptr_eq(
+            &Builtins::snapshot_for_version("2.3"),
+            &snapshots[0],
+        ));
+    }
+
+    #[test]
+    fn from_nix() {
+        let attr_names = ["map", "head", "newBuiltin"].map(String::from);
+        let builtins = Builtins::from_nix("2.3.16".into(), &attr_names, &DumpLanguage::default());
+        assert_eq!(builtins.nix_version(), "2.3.16");
+        assert_eq!(
+            builtins.entries().map(|(name, _)| name).collect::<Vec<_>>(),
+            ["head", "map", "newBuiltin"],
+        );
+        assert!(builtins.get("map").unwrap().is_global);
+        assert!(builtins.get("head").unwrap().doc.is_some());
+        assert_eq!(builtins.get("newBuiltin").unwrap().doc, None);
+        assert!(!builtins.contains_key("fetchTree"));
+        assert!(Builtins::is_known("fetchTree"));
+        assert!(!Builtins::is_known("newBuiltin"));
+    }
+
+    #[test]
+    fn version() {
+        assert_eq!(parse_version("2.18.1"), [2, 18, 1]);
+        assert_eq!(parse_version("2.18.0pre20230808_abcdef"), [2, 18, 0]);
+        assert_eq!(parse_version("2.90.0-rc1"), [2, 90, 0]);
+        assert!(parse_version("2.3.16") < parse_version("2.18"));
+
+        let builtins = Builtins::for_version("2.24.0");
+        assert_eq!(builtins.nix_version(), "2.24.0");
+        assert!(builtins.nix_version_at_least("2.24"));
+        assert!(!builtins.nix_version_at_least("2.25"));
+        assert!(builtins.contains_key("map"));
+
+        let builtins = Builtins::for_version("2.18").with_nix_version("2.91.0");
+        assert_eq!(builtins.nix_version(), "2.91.0");
+        assert!(builtins.contains_key("map"));
     }
 }
@@ -1,11 +1,15 @@
+use builtin::Builtins;
+use nix_interop::flake_lock::FlakeLock;
 use nix_interop::flake_output::FlakeOutput;
-use nix_interop::nixos_options::NixosOptions;
+use nix_interop::info::NixDialect;
+use nix_interop::nixos_options::{NixosOptions, OptionSource};
 use salsa::Durability;
-use std::collections::HashMap;
+use smol_str::SmolStr;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use syntax::{TextRange, TextSize};
+use syntax::{Parse, TextRange, TextSize};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct FileId(pub u32);
@@ -128,7 +132,7 @@ impl FileSet {
         &self.paths[&file]
     }
 
-    pub fn iter(&self) -> impl Iterator<Item = (FileId, &'_ VfsPath)> + ExactSizeIterator + '_ {
+    pub fn iter(&self) -> impl ExactSizeIterator<Item = (FileId, &'_ VfsPath)> + '_ {
         self.paths.iter().map(|(&file, path)| (file, path))
     }
 }
@@ -159,7 +163,7 @@ impl SourceRoot {
         self.file_set.path_for_file(file)
     }
 
-    pub fn files(&self) -> impl Iterator<Item = (FileId, &'_ VfsPath)> + ExactSizeIterator + '_ {
+    pub fn files(&self) -> impl ExactSizeIterator<Item = (FileId, &'_ VfsPath)> + '_ {
         self.file_set.iter()
     }
 
@@ -179,6 +183,10 @@ pub struct FlakeInfo {
     pub flake_file: FileId,
     pub input_store_paths: HashMap<String, VfsPath>,
     pub input_flake_outputs: HashMap<String, FlakeOutput>,
+    /// Evaluated outputs of the flake itself, for `self`.
+    pub self_flake_output: Option<FlakeOutput>,
+    /// The lock graph from `flake.lock`, or `None` if it is absent.
+    pub lock: Option<FlakeLock>,
 }
 
 impl fmt::Debug for FlakeInfo {
@@ -187,6 +195,7 @@ impl fmt::Debug for FlakeInfo {
             .field("flake_file", &self.flake_file)
             .field("input_store_paths", &self.input_store_paths)
             .field("input_flake_outputs", &self.input_flake_outputs.keys())
+            .field("self_flake_output", &self.self_flake_output.is_some())
             .finish_non_exhaustive()
     }
 }
@@ -238,11 +247,22 @@ impl FileRange {
     }
 }
 
+/// The content and the parse result of a previous revision of a file,
+/// which incremental reparsing starts from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseBase {
+    pub content: Arc<str>,
+    pub parse: Parse,
+}
+
 #[salsa::query_group(SourceDatabaseStorage)]
 pub trait SourceDatabase {
     #[salsa::input]
     fn file_content(&self, file_id: FileId) -> Arc<str>;
 
+    #[salsa::input]
+    fn file_parse_base(&self, file_id: FileId) -> Option<Arc<ParseBase>>;
+
     #[salsa::input]
     fn source_root(&self, sid: SourceRootId) -> Arc<SourceRoot>;
 
@@ -254,8 +274,19 @@ pub trait SourceDatabase {
     #[salsa::input]
     fn flake_graph(&self) -> Arc<FlakeGraph>;
 
+    /// Evaluated options of each option source.
+    #[salsa::input]
+    fn module_options(&self, source: OptionSource) -> Arc<NixosOptions>;
+
     #[salsa::input]
-    fn nixos_options(&self) -> Arc<NixosOptions>;
+    fn builtins(&self) -> Arc<Builtins>;
+
+    #[salsa::input]
+    fn nix_dialect(&self) -> NixDialect;
+
+    /// The system to evaluate for, eg. `x86_64-linux`.
+    #[salsa::input]
+    fn nix_system(&self) -> SmolStr;
 }
 
 fn source_root_flake_info(db: &dyn SourceDatabase, sid: SourceRootId) -> Option<Arc<FlakeInfo>> {
@@ -267,7 +298,12 @@ pub struct Change {
     pub flake_graph: Option<FlakeGraph>,
     pub roots: Option<Vec<SourceRoot>>,
     pub file_changes: Vec<(FileId, Arc<str>)>,
-    pub nixos_options: Option<NixosOptions>,
+    /// Files in `file_changes` whose previous contents are already in the database.
+    pub edited_files: HashSet<FileId>,
+    pub module_options: HashMap<OptionSource, NixosOptions>,
+    pub builtins: Option<Arc<Builtins>>,
+    pub nix_dialect: Option<NixDialect>,
+    pub nix_system: Option<SmolStr>,
 }
 
 impl Change {
@@ -279,8 +315,20 @@ impl Change {
         self.flake_graph = Some(graph);
     }
 
-    pub fn set_nixos_options(&mut self, opts: NixosOptions) {
-        self.nixos_options = Some(opts);
+    pub fn set_module_options(&mut self, source: OptionSource, opts: NixosOptions) {
+        self.module_options.insert(source, opts);
+    }
+
+    pub fn set_builtins(&mut self, builtins: Arc<Builtins>) {
+        self.builtins = Some(builtins);
+    }
+
+    pub fn set_nix_dialect(&mut self, dialect: NixDialect) {
+        self.nix_dialect = Some(dialect);
+    }
+
+    pub fn set_nix_system(&mut self, system: SmolStr) {
+        self.nix_system = Some(system);
     }
 
     pub fn set_roots(&mut self, roots: Vec<SourceRoot>) {
@@ -288,6 +336,17 @@ impl Change {
     }
 
     pub fn change_file(&mut self, file_id: FileId, content: Arc<str>) {
+        self.edited_files.remove(&file_id);
+        self.file_changes.push((file_id, content));
+    }
+
+    /// Same as `change_file`, but the file is already loaded and its content is being edited,
+    /// so that its previous parse result can be reused.
+    pub fn edit_file(&mut self, file_id: FileId, content: Arc<str>) {
+        // The file may be just loaded in this change.
+        if !self.file_changes.iter().any(|(file, _)| *file == file_id) {
+            self.edited_files.insert(file_id);
+        }
         self.file_changes.push((file_id, content));
     }
 
@@ -295,8 +354,17 @@ impl Change {
         if let Some(flake_graph) = self.flake_graph {
             db.set_flake_graph_with_durability(Arc::new(flake_graph), Durability::MEDIUM);
         }
-        if let Some(opts) = self.nixos_options {
-            db.set_nixos_options_with_durability(Arc::new(opts), Durability::MEDIUM);
+        for (source, opts) in self.module_options {
+            db.set_module_options_with_durability(source, Arc::new(opts), Durability::MEDIUM);
+        }
+        if let Some(builtins) = self.builtins {
+            db.set_builtins_with_durability(builtins, Durability::HIGH);
+        }
+        if let Some(dialect) = self.nix_dialect {
+            db.set_nix_dialect_with_durability(dialect, Durability::HIGH);
+        }
+        if let Some(system) = self.nix_system {
+            db.set_nix_system_with_durability(system, Durability::HIGH);
         }
         if let Some(roots) = self.roots {
             u32::try_from(roots.len()).expect("Length overflow");
@@ -309,6 +377,7 @@ impl Change {
         }
         for (file_id, content) in self.file_changes {
             db.set_file_content_with_durability(file_id, content, Durability::LOW);
+            db.set_file_parse_base_with_durability(file_id, None, Durability::LOW);
         }
     }
 }
@@ -0,0 +1,197 @@
+//! Consistency check of flake inputs between `inputs`, the parameter of `outputs` and
+//! `flake.lock`.
+//!
+//! We now identifies,
+//! - Inputs declared in `inputs` but neither used by `outputs` nor followed by other inputs.
+//! - Parameters of `outputs` with no declared nor locked input.
+//! - Declared inputs missing from `flake.lock`.
+//! - `follows` targets which don't exist.
+use super::{BindingValue, Bindings, DefDatabase, Expr, ExprId, Literal, Module, NameId};
+use crate::{Diagnostic, DiagnosticKind, FileId, ModuleKind};
+use smol_str::SmolStr;
+use std::collections::HashSet;
+use std::sync::Arc;
+
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct FlakeInputCheckResult {
+    unused_inputs: Box<[NameId]>,
+    undefined_params: Box<[NameId]>,
+    unlocked_inputs: Box<[NameId]>,
+    undefined_follows: Box<[ExprId]>,
+}
+
+impl FlakeInputCheckResult {
+    pub fn is_unused_input(&self, name: NameId) -> bool {
+        self.unused_inputs.contains(&name)
+    }
+
+    pub fn is_undefined_param(&self, name: NameId) -> bool {
+        self.undefined_params.contains(&name)
+    }
+
+    pub fn to_diagnostics(&self, db: &dyn DefDatabase, file: FileId) -> Vec<Diagnostic> {
+        let source_map = db.source_map(file);
+        let mut diags = Vec::new();
+        for (names, kind) in [
+            (&self.unused_inputs, DiagnosticKind::UnusedFlakeInput),
+            (&self.undefined_params, DiagnosticKind::UndefinedFlakeInput),
+            (&self.unlocked_inputs, DiagnosticKind::UnlockedFlakeInput),
+        ] {
+            diags.extend(
+                names
+                    .iter()
+                    .flat_map(|&name| source_map.nodes_for_name(name))
+                    .map(|ptr| Diagnostic::new(ptr.text_range(), kind)),
+            );
+        }
+        diags.extend(
+            self.undefined_follows
+                .iter()
+                .filter_map(|&expr| source_map.node_for_expr(expr))
+                .map(|ptr| Diagnostic::new(ptr.text_range(), DiagnosticKind::UndefinedFollows)),
+        );
+        diags.sort_by_key(|diag| (diag.range.start(), diag.range.end()));
+        diags
+    }
+}
+
+pub(crate) fn flake_input_check_query(
+    db: &dyn DefDatabase,
+    file: FileId,
+) -> Arc<FlakeInputCheckResult> {
+    let ModuleKind::FlakeNix {
+        explicit_inputs,
+        param_inputs,
+        outputs_expr,
+    } = &*db.module_kind(file)
+    else {
+        return Arc::default();
+    };
+    let module = db.module(file);
+    let flake_info = db.source_root_flake_info(db.file_source_root(file));
+    let lock = flake_info.as_deref().and_then(|info| info.lock.as_ref());
+    let locked_inputs = lock.map(|lock| lock.root_inputs().collect::<HashSet<_>>());
+
+    let mut follows = Vec::new();
+    if let Some(inputs) = inputs_bindings(&module) {
+        for &(_, value) in inputs.statics.iter() {
+            if let BindingValue::Expr(e) = value {
+                collect_follows(&module, e, &mut follows);
+            }
+        }
+    }
+    let followed = follows
+        .iter()
+        .filter_map(|(_, path)| path.first())
+        .collect::<HashSet<_>>();
+
+    // Inputs can also be used via the `@` binding of `outputs`, which we cannot track.
+    let unused_inputs = match outputs_expr.map(|e| &module[e]) {
+        Some(Expr::Lambda(None, Some(_), _)) => explicit_inputs
+            .iter()
+            .filter(|(name, _)| !param_inputs.contains_key(*name) && !followed.contains(name))
+            .map(|(_, &name)| name)
+            .collect(),
+        _ => Vec::new(),
+    };
+
+    let (undefined_params, unlocked_inputs) = match &locked_inputs {
+        Some(locked) => (
+            param_inputs
+                .iter()
+                .filter(|(name, _)| {
+                    !explicit_inputs.contains_key(*name) && !locked.contains(&***name)
+                })
+                .map(|(_, &name)| name)
+                .collect(),
+            explicit_inputs
+                .iter()
+                .filter(|(name, _)| !locked.contains(&***name))
+                .map(|(_, &name)| name)
+                .collect(),
+        ),
+        None => (Vec::new(), Vec::new()),
+    };
+
+    let undefined_follows = follows
+        .iter()
+        .filter(|(_, path)| {
+            let Some(first) = path.first() else {
+                // `follows = ""` refers to the current flake itself.
+                return false;
+            };
+            if !explicit_inputs.contains_key(first) && !param_inputs.contains_key(first) {
+                return true;
+            }
+            // Deep paths can only be checked with an up-to-date lock.
+            match (lock, &locked_inputs) {
+                (Some(lock), Some(locked)) if locked.contains(&**first) => {
+                    !lock.has_input_path(path)
+                }
+                _ => false,
+            }
+        })
+        .map(|&(expr, _)| expr)
+        .collect::<Vec<_>>();
+
+    let mut ret = FlakeInputCheckResult {
+        unused_inputs: unused_inputs.into(),
+        undefined_params: undefined_params.into(),
+        unlocked_inputs: unlocked_inputs.into(),
+        undefined_follows: undefined_follows.into(),
+    };
+    // Make the result stable.
+    ret.unused_inputs.sort_unstable();
+    ret.undefined_params.sort_unstable();
+    ret.unlocked_inputs.sort_unstable();
+    ret.undefined_follows.sort_unstable();
+    Arc::new(ret)
+}
+
+/// Get bindings of the top-level `inputs` of a flake.
+fn inputs_bindings(module: &Module) -> Option<&Bindings> {
+    let (Expr::Attrset(flake_set) | Expr::RecAttrset(flake_set)) = &module[module.entry_expr()]
+    else {
+        return None;
+    };
+    flake_set
+        .statics
+        .iter()
+        .find_map(|&(name, value)| match value {
+            BindingValue::Expr(e) if module[name].text == "inputs" => match &module[e] {
+                Expr::Attrset(inputs) | Expr::RecAttrset(inputs) => Some(inputs),
+                _ => None,
+            },
+            _ => None,
+        })
+}
+
+/// Collect `follows` of an input and its nested `inputs`, with their paths split by `/`.
+fn collect_follows(module: &Module, input: ExprId, out: &mut Vec<(ExprId, Vec<SmolStr>)>) {
+    let (Expr::Attrset(input) | Expr::RecAttrset(input)) = &module[input] else {
+        return;
+    };
+    for &(name, value) in input.statics.iter() {
+        let BindingValue::Expr(value) = value else {
+            continue;
+        };
+        match (&*module[name].text, &module[value]) {
+            ("follows", Expr::Literal(Literal::String(path))) => {
+                let path = path
+                    .split('/')
+                    .filter(|s| !s.is_empty())
+                    .map(SmolStr::from)
+                    .collect();
+                out.push((value, path));
+            }
+            ("inputs", Expr::Attrset(inputs) | Expr::RecAttrset(inputs)) => {
+                for &(_, value) in inputs.statics.iter() {
+                    if let BindingValue::Expr(e) = value {
+                        collect_follows(module, e, out);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
@@ -1,12 +1,14 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::sync::Arc;
 
 use if_chain::if_chain;
+use nix_interop::nixos_options::OptionSource;
 use smol_str::SmolStr;
+use syntax::SyntaxKind;
 
-use crate::{DefDatabase, FileId, Module};
+use crate::{DefDatabase, FileId, Module, SourceRootId};
 
-use super::{BindingValue, Expr, ExprId, NameId};
+use super::{resolve_module_path, BindingValue, Expr, ExprId, Literal, NameId};
 
 /// Guessed kind of a nix file.
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -142,6 +144,146 @@ fn guess(module: &Module) -> ModuleKind {
     ModuleKind::Unknown
 }
 
+/// Flake outputs whose referenced files are modules or configurations of an option source.
+const FLAKE_OUTPUT_OPTION_SOURCES: &[(&str, OptionSource)] = &[
+    ("darwinConfigurations", OptionSource::NixDarwin),
+    ("darwinModules", OptionSource::NixDarwin),
+    ("homeConfigurations", OptionSource::HomeManager),
+    ("homeManagerModules", OptionSource::HomeManager),
+    ("homeModules", OptionSource::HomeManager),
+    ("nixosConfigurations", OptionSource::Nixos),
+    ("nixosModules", OptionSource::Nixos),
+];
+
+/// The option of NixOS and nix-darwin containing Home Manager configurations,
+/// eg. `home-manager.users.<name>`.
+const HOME_MANAGER_OPTION: &str = "home-manager";
+
+/// The prefix of the hint comment in the file header to choose the option source,
+/// eg. `# nil: options=home-manager`.
+const HINT_COMMENT_PREFIX: &str = "nil:";
+
+pub(crate) fn module_option_source_query(db: &dyn DefDatabase, file_id: FileId) -> OptionSource {
+    if let Some(source) = parse_option_source_hint(db, file_id) {
+        return source;
+    }
+    let sid = db.file_source_root(file_id);
+    db.source_root_option_sources(sid)
+        .get(&file_id)
+        .copied()
+        .unwrap_or(OptionSource::Nixos)
+}
+
+fn parse_option_source_hint(db: &dyn DefDatabase, file_id: FileId) -> Option<OptionSource> {
+    db.parse(file_id)
+        .syntax_node()
+        .children_with_tokens()
+        .map_while(|elem| elem.into_token().filter(|tok| tok.kind().is_trivia()))
+        .filter(|tok| tok.kind() == SyntaxKind::COMMENT)
+        .find_map(|tok| {
+            let text = tok.text();
+            let text = text
+                .strip_prefix('#')
+                .or_else(|| text.strip_prefix("/*")?.strip_suffix("*/"))?;
+            text.trim()
+                .strip_prefix(HINT_COMMENT_PREFIX)?
+                .split_whitespace()
+                .find_map(|arg| arg.strip_prefix("options=")?.parse().ok())
+        })
+}
+
+/// Guess option sources of files referenced, directly or transitively, by flake outputs like
+/// `nixosConfigurations` and `homeConfigurations`.
+/// Files referenced under `home-manager` of NixOS or nix-darwin configurations are considered
+/// as Home Manager modules. If a file is referenced in multiple ways, the nearest one to
+/// `flake.nix` wins.
+pub(crate) fn source_root_option_sources_query(
+    db: &dyn DefDatabase,
+    sid: SourceRootId,
+) -> Arc<HashMap<FileId, OptionSource>> {
+    let Some(flake_info) = db.source_root_flake_info(sid) else {
+        return Arc::default();
+    };
+    let flake_file = flake_info.flake_file;
+    let ModuleKind::FlakeNix {
+        outputs_expr: Some(outputs_expr),
+        ..
+    } = *db.module_kind(flake_file)
+    else {
+        return Arc::default();
+    };
+
+    let mut ret = HashMap::new();
+    let mut queue = VecDeque::new();
+    collect_option_source_refs(db, flake_file, outputs_expr, None, &mut queue);
+    while let Some((file, source)) = queue.pop_front() {
+        if file == flake_file || ret.contains_key(&file) {
+            continue;
+        }
+        ret.insert(file, source);
+        let entry_expr = db.module(file).entry_expr();
+        collect_option_source_refs(db, file, entry_expr, Some(source), &mut queue);
+    }
+    ret.shrink_to_fit();
+    Arc::new(ret)
+}
+
+fn collect_option_source_refs(
+    db: &dyn DefDatabase,
+    file_id: FileId,
+    expr: ExprId,
+    source: Option<OptionSource>,
+    queue: &mut VecDeque<(FileId, OptionSource)>,
+) {
+    let module = db.module(file_id);
+    let source_root = db.source_root(db.file_source_root(file_id));
+    let mut stack = vec![(expr, source)];
+    while let Some((expr, source)) = stack.pop() {
+        match &module[expr] {
+            &Expr::Literal(Literal::Path(path)) => {
+                if let Some(source) = source {
+                    if let Some(file) = resolve_module_path(db, &source_root, path) {
+                        queue.push_back((file, source));
+                    }
+                }
+            }
+            Expr::Attrset(bindings)
+            | Expr::RecAttrset(bindings)
+            | Expr::LetAttrset(bindings)
+            | Expr::LetIn(bindings, _) => {
+                if let Expr::LetIn(_, body) = &module[expr] {
+                    stack.push((*body, source));
+                }
+                for &(name, value) in bindings.statics.iter() {
+                    let name = &*module[name].text;
+                    let source = match source {
+                        None => FLAKE_OUTPUT_OPTION_SOURCES
+                            .iter()
+                            .find_map(|&(output, src)| (output == name).then_some(src)),
+                        Some(OptionSource::Nixos | OptionSource::NixDarwin)
+                            if name == HOME_MANAGER_OPTION =>
+                        {
+                            Some(OptionSource::HomeManager)
+                        }
+                        Some(_) => source,
+                    };
+                    if let BindingValue::Expr(e) | BindingValue::Inherit(e) = value {
+                        stack.push((e, source));
+                    }
+                }
+                for &e in bindings.inherit_froms.iter() {
+                    stack.push((e, source));
+                }
+                for &(k, v) in bindings.dynamics.iter() {
+                    stack.push((k, source));
+                    stack.push((v, source));
+                }
+            }
+            e => e.walk_child_exprs(|e| stack.push((e, source))),
+        }
+    }
+}
+
 /// Peel all environment-like wrapper expression like `With`, `Assert` and `LetIn`.
 fn peel_expr(module: &Module, expr: ExprId) -> ExprId {
     std::iter::successors(Some(expr), |&e| match &module[e] {
@@ -269,4 +411,74 @@ with lib;
             expect!["Config: { lib, pkgs, ... }:"],
         );
     }
+
+    #[test]
+    fn option_source() {
+        let (db, f) = TestDB::from_fixture(
+            r#"
+#- /flake.nix
+{
+    outputs = { self, nixpkgs, home-manager, darwin }: {
+        nixosConfigurations.host = nixpkgs.lib.nixosSystem {
+            modules = [
+                ./host.nix
+                home-manager.nixosModules.home-manager
+                { home-manager.users.me = import ./home.nix; }
+            ];
+        };
+        homeConfigurations.me = home-manager.lib.homeManagerConfiguration {
+            modules = [ ./home-standalone.nix ];
+        };
+        darwinConfigurations.mac = darwin.lib.darwinSystem {
+            modules = [ ./darwin.nix ];
+        };
+        lib = import ./lib.nix;
+    };
+}
+#- /host.nix
+{ ... }: { imports = [ ./common.nix ]; }
+#- /common.nix
+{ ... }: { }
+#- /home.nix
+{ ... }: { }
+#- /home-standalone.nix
+{ ... }: { imports = [ ./home.nix ]; }
+#- /darwin.nix
+{ ... }: { home-manager.users.me = ./home-darwin.nix; }
+#- /home-darwin.nix
+{ ... }: { }
+#- /lib.nix
+{ ... }: { }
+#- /hinted.nix
+# Some description.
+# nil: options=home-manager
+{ ... }: { }
+            "#,
+        )
+        .unwrap();
+        let got = [
+            "/host.nix",
+            "/common.nix",
+            "/home.nix",
+            "/home-standalone.nix",
+            "/darwin.nix",
+            "/home-darwin.nix",
+            "/lib.nix",
+            "/hinted.nix",
+        ]
+        .iter()
+        .map(|path| format!("{path}: {}\n", db.module_option_source(f[*path])))
+        .collect::<String>();
+        expect![[r#"
+            /host.nix: nixos
+            /common.nix: nixos
+            /home.nix: home-manager
+            /home-standalone.nix: home-manager
+            /darwin.nix: nix-darwin
+            /home-darwin.nix: home-manager
+            /lib.nix: nixos
+            /hinted.nix: home-manager
+        "#]]
+        .assert_eq(&got);
+    }
 }
@@ -33,6 +33,18 @@ pub struct LivenessCheckResult {
 }
 
 impl LivenessCheckResult {
+    pub fn is_unused_name(&self, name: NameId) -> bool {
+        self.names.contains(&name)
+    }
+
+    pub fn is_unused_with(&self, expr: ExprId) -> bool {
+        self.withs.contains(&expr)
+    }
+
+    pub fn is_unused_rec(&self, expr: ExprId) -> bool {
+        self.rec_attrsets.contains(&expr)
+    }
+
     pub fn to_diagnostics<'a>(
         &'a self,
         db: &dyn DefDatabase,
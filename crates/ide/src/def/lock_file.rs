@@ -0,0 +1,230 @@
+//! Parsing of `flake.lock`.
+//!
+//! The lock graph is deserialized into [`FlakeLock`] for its semantics, and also into a spanned
+//! JSON tree to map lock nodes and inputs back to text ranges.
+use super::DefDatabase;
+use crate::FileId;
+use json_spanned_value::spanned;
+use nix_interop::flake_lock::FlakeLock;
+use std::ops::Range;
+use std::sync::Arc;
+use syntax::{TextRange, TextSize};
+
+/// A parsed `flake.lock`.
+#[derive(Debug)]
+pub struct LockFile {
+    src: Arc<str>,
+    lock: FlakeLock,
+    json: spanned::Value,
+}
+
+// Ranges are fully determined by the source, and `spanned::Value` ignores them in comparison.
+impl PartialEq for LockFile {
+    fn eq(&self, other: &Self) -> bool {
+        self.src == other.src
+    }
+}
+
+impl Eq for LockFile {}
+
+/// The error of an invalid lock file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidLockFile {
+    pub range: TextRange,
+    pub message: String,
+}
+
+/// An element in the lock file which refers to a lock node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockElement<'a> {
+    /// The key of a node in `nodes`.
+    Node { id: &'a str, range: TextRange },
+    /// The key of an input of a node.
+    Input {
+        owner: &'a str,
+        name: &'a str,
+        target: Option<&'a str>,
+        range: TextRange,
+    },
+    /// The node ID or `follows` path of an input.
+    Reference {
+        target: Option<&'a str>,
+        range: TextRange,
+    },
+}
+
+pub(crate) fn lock_file_query(
+    db: &dyn DefDatabase,
+    file_id: FileId,
+) -> Result<Arc<LockFile>, InvalidLockFile> {
+    let src = db.file_content(file_id);
+    let json = json_spanned_value::from_str::<spanned::Value>(&src)
+        .map_err(|err| InvalidLockFile::new(&src, None, &err))?;
+    let lock = json_spanned_value::from_str::<FlakeLock>(&src)
+        .map_err(|err| InvalidLockFile::new(&src, Some(&json), &err))?;
+    Ok(Arc::new(LockFile { src, lock, json }))
+}
+
+impl InvalidLockFile {
+    fn new(src: &str, json: Option<&spanned::Value>, err: &serde_json::Error) -> Self {
+        let (line, column) = (err.line(), err.column());
+        let message = err.to_string();
+        let message = message
+            .strip_suffix(&format!(" at line {line} column {column}"))
+            .unwrap_or(&message)
+            .to_owned();
+
+        // The error position is the last consumed byte, with 1-based line and column.
+        let line_start = src
+            .split_inclusive('\n')
+            .take(line.saturating_sub(1))
+            .map(str::len)
+            .sum::<usize>();
+        let mut pos = (line_start + column.saturating_sub(1)).min(src.len());
+        while !src.is_char_boundary(pos) {
+            pos += 1;
+        }
+
+        // Invalid values are reported before them. Report on the whole value if any.
+        if let Some(json) = json {
+            let start = pos + (src.len() - pos - src[pos..].trim_start().len());
+            if let Some(range) = value_starting_at(json, start) {
+                return Self {
+                    range: to_text_range(range),
+                    message,
+                };
+            }
+        }
+
+        let len = src[pos..].chars().next().map_or(0, char::len_utf8);
+        Self {
+            range: to_text_range(pos..pos + len),
+            message,
+        }
+    }
+}
+
+fn value_starting_at(json: &spanned::Value, start: usize) -> Option<Range<usize>> {
+    if json.start() == start {
+        return Some(json.range());
+    }
+    if !json.range().contains(&start) {
+        return None;
+    }
+    if let Some(elems) = json.as_array() {
+        return elems.iter().find_map(|elem| value_starting_at(elem, start));
+    }
+    entries(json).find_map(|(key, value)| {
+        if key.start() == start {
+            return Some(key.range());
+        }
+        value_starting_at(value, start)
+    })
+}
+
+fn to_text_range(range: Range<usize>) -> TextRange {
+    let offset = |pos: usize| TextSize::try_from(pos).expect("File too large");
+    TextRange::new(offset(range.start), offset(range.end))
+}
+
+fn entries(
+    json: &spanned::Value,
+) -> impl Iterator<Item = (&spanned::String, &spanned::Value)> + '_ {
+    json.as_object().into_iter().flat_map(|obj| obj.iter())
+}
+
+fn field<'a>(
+    json: &'a spanned::Value,
+    key: &str,
+) -> Option<(&'a spanned::String, &'a spanned::Value)> {
+    json.as_object()?.get_key_value(key)
+}
+
+impl LockFile {
+    /// The lock graph.
+    pub fn lock(&self) -> &FlakeLock {
+        &self.lock
+    }
+
+    fn nodes(&self) -> impl Iterator<Item = (&spanned::String, &spanned::Value)> + '_ {
+        field(&self.json, "nodes")
+            .into_iter()
+            .flat_map(|(_, nodes)| entries(nodes))
+    }
+
+    fn node_json(&self, id: &str) -> Option<(&spanned::String, &spanned::Value)> {
+        field(&self.json, "nodes").and_then(|(_, nodes)| field(nodes, id))
+    }
+
+    /// The range of the key of a node, and the range covering both its key and value.
+    pub fn node_range(&self, id: &str) -> Option<(TextRange, TextRange)> {
+        let (key, node) = self.node_json(id)?;
+        Some((
+            to_text_range(key.range()),
+            to_text_range(key.start()..node.end()),
+        ))
+    }
+
+    /// The range of a key of a node, like `inputs` or `locked`.
+    pub fn node_field_range(&self, id: &str, field_name: &str) -> Option<TextRange> {
+        let (_, node) = self.node_json(id)?;
+        let (key, _) = field(node, field_name)?;
+        Some(to_text_range(key.range()))
+    }
+
+    /// IDs of all nodes.
+    pub fn node_ids(&self) -> impl Iterator<Item = &str> + '_ {
+        self.nodes().map(|(key, _)| &***key)
+    }
+
+    /// Inputs of a node, with ranges of their names.
+    pub fn inputs(&self, id: &str) -> Vec<(&str, TextRange)> {
+        let Some((_, node)) = self.node_json(id) else {
+            return Vec::new();
+        };
+        let Some((_, inputs)) = field(node, "inputs") else {
+            return Vec::new();
+        };
+        entries(inputs)
+            .map(|(name, _)| (&***name, to_text_range(name.range())))
+            .collect()
+    }
+
+    pub fn element_at(&self, pos: TextSize) -> Option<LockElement<'_>> {
+        let pos = usize::from(pos);
+        let contains = |range: Range<usize>| range.start <= pos && pos <= range.end;
+        for (key, node) in self.nodes() {
+            let id = &***key;
+            if contains(key.range()) {
+                return Some(LockElement::Node {
+                    id,
+                    range: to_text_range(key.range()),
+                });
+            }
+            if !contains(node.range()) {
+                continue;
+            }
+            let Some((_, inputs)) = field(node, "inputs") else {
+                continue;
+            };
+            for (name, input) in entries(inputs) {
+                let target = self.lock.resolve_input(id, name);
+                if contains(name.range()) {
+                    return Some(LockElement::Input {
+                        owner: id,
+                        name,
+                        target,
+                        range: to_text_range(name.range()),
+                    });
+                }
+                if contains(input.range()) {
+                    return Some(LockElement::Reference {
+                        target,
+                        range: to_text_range(input.range()),
+                    });
+                }
+            }
+        }
+        None
+    }
+}
@@ -1,5 +1,7 @@
+mod flake_inputs;
 mod kind;
 mod liveness;
+mod lock_file;
 mod lower;
 mod nameres;
 mod path;
@@ -8,8 +10,9 @@ mod path;
 mod tests;
 
 use crate::base::SourceDatabase;
-use crate::{Diagnostic, FileId, SourceRootId, VfsPath};
+use crate::{Diagnostic, FileId, SourceRoot, SourceRootId, VfsPath};
 use la_arena::{Arena, ArenaMap, Idx};
+use nix_interop::nixos_options::OptionSource;
 use nix_interop::DEFAULT_IMPORT_FILE;
 use ordered_float::OrderedFloat;
 use smallvec::SmallVec;
@@ -17,13 +20,13 @@ use smol_str::SmolStr;
 use std::collections::{HashMap, HashSet};
 use std::ops;
 use std::sync::Arc;
-use syntax::Parse;
+use syntax::{Parse, TextRange, TextSize};
 
+pub use self::flake_inputs::FlakeInputCheckResult;
 pub use self::kind::ModuleKind;
 pub use self::liveness::LivenessCheckResult;
-pub use self::nameres::{
-    ModuleScopes, NameReference, NameResolution, ResolveResult, ScopeData, ScopeId,
-};
+pub use self::lock_file::{InvalidLockFile, LockElement, LockFile};
+pub use self::nameres::{ModuleScopes, NameReference, NameResolution, ResolveResult};
 pub use self::path::{Path, PathAnchor, PathData};
 pub use syntax::ast::{BinaryOpKind as BinaryOp, UnaryOpKind as UnaryOp};
 
@@ -43,6 +46,13 @@ pub trait DefDatabase: SourceDatabase {
     #[salsa::invoke(ModuleKind::module_kind_query)]
     fn module_kind(&self, file_id: FileId) -> Arc<ModuleKind>;
 
+    #[salsa::invoke(kind::source_root_option_sources_query)]
+    fn source_root_option_sources(&self, sid: SourceRootId) -> Arc<HashMap<FileId, OptionSource>>;
+
+    /// Which option tree applies to a config or module file.
+    #[salsa::invoke(kind::module_option_source_query)]
+    fn module_option_source(&self, file_id: FileId) -> OptionSource;
+
     #[salsa::invoke(Module::module_references_query)]
     fn module_references(&self, file_id: FileId) -> Arc<HashSet<FileId>>;
 
@@ -71,11 +81,43 @@ pub trait DefDatabase: SourceDatabase {
 
     #[salsa::invoke(liveness::liveness_check_query)]
     fn liveness_check(&self, file_id: FileId) -> Arc<LivenessCheckResult>;
+
+    #[salsa::invoke(flake_inputs::flake_input_check_query)]
+    fn flake_input_check(&self, file_id: FileId) -> Arc<FlakeInputCheckResult>;
+
+    /// Parse a `flake.lock` file.
+    #[salsa::invoke(lock_file::lock_file_query)]
+    fn lock_file(&self, file_id: FileId) -> Result<Arc<LockFile>, InvalidLockFile>;
 }
 
 fn parse(db: &dyn DefDatabase, file_id: FileId) -> Parse {
     let content = db.file_content(file_id);
-    syntax::parse_file(&content)
+    let Some(base) = db.file_parse_base(file_id) else {
+        return syntax::parse_file(&content);
+    };
+
+    // Find the changed range by trimming the common prefix and suffix.
+    let (old, new) = (base.content.as_bytes(), content.as_bytes());
+    let mut prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    while !content.is_char_boundary(prefix) {
+        prefix -= 1;
+    }
+    let max_suffix = old.len().min(new.len()) - prefix;
+    let mut suffix = old
+        .iter()
+        .rev()
+        .zip(new.iter().rev())
+        .take(max_suffix)
+        .take_while(|(a, b)| a == b)
+        .count();
+    while !content.is_char_boundary(new.len() - suffix) {
+        suffix -= 1;
+    }
+    let delete = TextRange::new(
+        TextSize::try_from(prefix).expect("Length overflow"),
+        TextSize::try_from(old.len() - suffix).expect("Length overflow"),
+    );
+    base.parse.reparse(delete, &content)
 }
 
 fn module_with_source_map(
@@ -183,11 +225,11 @@ impl Module {
         self.entry_expr
     }
 
-    pub fn exprs(&self) -> impl Iterator<Item = (ExprId, &'_ Expr)> + ExactSizeIterator + '_ {
+    pub fn exprs(&self) -> impl ExactSizeIterator<Item = (ExprId, &'_ Expr)> + '_ {
         self.exprs.iter()
     }
 
-    pub fn names(&self) -> impl Iterator<Item = (NameId, &'_ Name)> + ExactSizeIterator + '_ {
+    pub fn names(&self) -> impl ExactSizeIterator<Item = (NameId, &'_ Name)> + '_ {
         self.names.iter()
     }
 
@@ -203,11 +245,7 @@ impl Module {
                 let &Expr::Literal(Literal::Path(path)) = kind else {
                     return None;
                 };
-                let mut vpath = path.resolve(db)?;
-                source_root.file_for_path(&vpath).or_else(|| {
-                    vpath.push(DEFAULT_IMPORT_FILE)?;
-                    source_root.file_for_path(&vpath)
-                })
+                resolve_module_path(db, &source_root, path)
             })
             .collect::<HashSet<_>>();
         refs.shrink_to_fit();
@@ -215,6 +253,19 @@ impl Module {
     }
 }
 
+/// Resolve a path literal to the file it references, or its `default.nix` for directories.
+fn resolve_module_path(
+    db: &dyn DefDatabase,
+    source_root: &SourceRoot,
+    path: Path,
+) -> Option<FileId> {
+    let mut vpath = path.resolve(db)?;
+    source_root.file_for_path(&vpath).or_else(|| {
+        vpath.push(DEFAULT_IMPORT_FILE)?;
+        source_root.file_for_path(&vpath)
+    })
+}
+
 pub type AstPtr = syntax::SyntaxNodePtr;
 
 #[derive(Default, Debug, Clone, PartialEq, Eq)]
@@ -1,6 +1,6 @@
-use super::{BindingValue, Bindings, DefDatabase, Expr, ExprId, Module, NameId};
+use super::{BindingValue, Bindings, DefDatabase, Expr, ExprId, Literal, Module, NameId};
 use crate::{Diagnostic, DiagnosticKind, FileId};
-use builtin::ALL_BUILTINS;
+use builtin::Builtins;
 use if_chain::if_chain;
 use la_arena::{Arena, ArenaMap, Idx};
 use smol_str::SmolStr;
@@ -53,8 +53,17 @@ impl ModuleScopes {
     }
 
     /// Resolve a name in the scope of an Expr.
-    fn resolve_name(&self, expr_id: ExprId, name: &SmolStr) -> Option<ResolveResult> {
+    pub(crate) fn resolve_name(
+        &self,
+        expr_id: ExprId,
+        name: &SmolStr,
+        builtins: &Builtins,
+    ) -> Option<ResolveResult> {
         let scope = self.scope_for_expr(expr_id)?;
+        // 0. `__curPos` is a keyword in Nix lexer, thus cannot be shadowed.
+        if name == "__curPos" {
+            return Some(ResolveResult::Builtin("__curPos"));
+        }
         // 1. Local defs.
         if let Some(name) = self
             .ancestors(scope)
@@ -63,7 +72,7 @@ impl ModuleScopes {
             return Some(ResolveResult::Definition(*name));
         }
         // 2. Global builtin names.
-        if let Some((name, b)) = ALL_BUILTINS.get_entry(name) {
+        if let Some((name, b)) = builtins.get_entry(name) {
             if b.is_global {
                 return Some(ResolveResult::Builtin(name));
             }
@@ -228,18 +237,21 @@ pub struct NameResolution {
     // All names from the common pattern `inherit (builtins) ...`.
     // This is used for tracking builtins names even through alising.
     inherited_builtins: HashSet<NameId>,
+    // References to builtins from `with builtins;`, if it's the innermost `with`.
+    with_builtins: HashMap<ExprId, &'static str>,
 }
 
 impl NameResolution {
     pub(crate) fn name_resolution_query(db: &dyn DefDatabase, file_id: FileId) -> Arc<Self> {
         let module = db.module(file_id);
         let scopes = db.scopes(file_id);
+        let builtins = db.builtins();
         let mut resolve_map = module
             .exprs()
             .filter_map(|(e, kind)| {
                 match kind {
                     // Inherited attrs are also translated into Expr::References.
-                    Expr::Reference(name) => Some((e, scopes.resolve_name(e, name))),
+                    Expr::Reference(name) => Some((e, scopes.resolve_name(e, name, &builtins))),
                     _ => None,
                 }
             })
@@ -261,18 +273,34 @@ impl NameResolution {
                 let from_expr = bindings.inherit_froms[i];
                 if let Some(Some(ResolveResult::Builtin("builtins"))) = resolve_map.get(&from_expr)
                 {
-                    if ALL_BUILTINS.contains_key(&module[name].text) {
+                    if builtins.contains_key(&module[name].text) {
                         inherited_builtins.insert(name);
                     }
                 }
             }
         }
 
+        let mut with_builtins = HashMap::new();
+        for (&expr, res) in &resolve_map {
+            if_chain! {
+                if let Some(ResolveResult::WithExprs(withs)) = res;
+                if let &Expr::With(env, _) = &module[withs[0]];
+                if let Some(Some(ResolveResult::Builtin("builtins"))) = resolve_map.get(&env);
+                if let Expr::Reference(name) = &module[expr];
+                if let Some((name, _)) = builtins.get_entry(name);
+                then {
+                    with_builtins.insert(expr, name);
+                }
+            }
+        }
+
         resolve_map.shrink_to_fit();
         inherited_builtins.shrink_to_fit();
+        with_builtins.shrink_to_fit();
         Arc::new(Self {
             resolve_map,
             inherited_builtins,
+            with_builtins,
         })
     }
 
@@ -298,15 +326,9 @@ impl NameResolution {
                     return Some(&module[*name].text);
                 }
             }
-            ResolveResult::WithExprs(withs) => {
-                if_chain! {
-                    if let &Expr::With(env, _) = &module[withs[0]];
-                    if let Some(ResolveResult::Builtin("builtins")) = self.get(env);
-                    if let Expr::Reference(name) = &module[expr];
-                    if ALL_BUILTINS.contains_key(&**name);
-                    then {
-                        return Some(name);
-                    }
+            ResolveResult::WithExprs(_) => {
+                if let Some(&name) = self.with_builtins.get(&expr) {
+                    return Some(name);
                 }
             }
         }
@@ -318,15 +340,60 @@ impl NameResolution {
         db: &dyn DefDatabase,
         file_id: FileId,
     ) -> impl Iterator<Item = Diagnostic> + '_ {
+        let module = db.module(file_id);
         let source_map = db.source_map(file_id);
-        self.resolve_map
+        let builtins = db.builtins();
+
+        // Builtins from other Nix versions are still recognized, but they are unavailable here.
+        let undefined = self
+            .resolve_map
             .iter()
             .filter(|(_, res)| res.is_none())
-            .filter_map(move |(&e, _)| {
-                let ptr = source_map.node_for_expr(e)?;
-                let range = ptr.text_range();
-                Some(Diagnostic::new(range, DiagnosticKind::UndefinedName))
+            .filter_map({
+                let module = module.clone();
+                let source_map = source_map.clone();
+                move |(&e, _)| {
+                    let ptr = source_map.node_for_expr(e)?;
+                    let range = ptr.text_range();
+                    let kind = match &module[e] {
+                        Expr::Reference(name) if Builtins::is_known(name) => {
+                            DiagnosticKind::UnavailableBuiltin
+                        }
+                        _ => DiagnosticKind::UndefinedName,
+                    };
+                    Some(Diagnostic::new(range, kind))
+                }
+            });
+
+        // `builtins.xxx`
+        //           ^^^
+        // Guarded accesses like `builtins ? xxx` and `builtins.xxx or null` are fine.
+        let unavailable_fields = module
+            .exprs()
+            .filter_map(|(_, expr)| match expr {
+                Expr::Select(set, path, None) => {
+                    let Some(ResolveResult::Builtin("builtins")) = self.get(*set) else {
+                        return None;
+                    };
+                    let &attr = path.first()?;
+                    match &module[attr] {
+                        Expr::Literal(Literal::String(field))
+                            if !builtins.contains_key(field) && Builtins::is_known(field) =>
+                        {
+                            let ptr = source_map.node_for_expr(attr)?;
+                            Some(Diagnostic::new(
+                                ptr.text_range(),
+                                DiagnosticKind::UnavailableBuiltin,
+                            ))
+                        }
+                        _ => None,
+                    }
+                }
+                _ => None,
             })
+            .collect::<Vec<_>>();
+
+        undefined.chain(unavailable_fields)
     }
 }
 
@@ -378,6 +445,7 @@ mod tests {
     use super::*;
     use crate::def::{AstPtr, DefDatabase, ResolveResult};
     use crate::tests::TestDB;
+    use crate::SourceDatabase;
     use syntax::ast::{self, AstNode};
     use syntax::match_ast;
 
@@ -402,8 +470,8 @@ mod tests {
             .flat_map(|scope| match &scope.kind {
                 ScopeKind::Definitions(defs) => {
                     let mut poses = defs
-                        .iter()
-                        .map(|(_, name)| {
+                        .values()
+                        .map(|name| {
                             source_map
                                 .nodes_for_name(*name)
                                 .next()
@@ -572,4 +640,35 @@ mod tests {
         check_builtin("with builtins; with { }; $0tryEval", None);
         check_builtin("with builtins; $0not_exist", None);
     }
+
+    #[test]
+    fn unavailable_builtin() {
+        let (mut db, file) = TestDB::single_file(
+            "[ map fetchTree builtins.fetchTree (builtins ? getFlake) undefined builtins.undefined (builtins.getFlake or 1) ]",
+        )
+        .unwrap();
+        let attr_names = ["builtins", "map"].map(String::from);
+        db.set_builtins(Arc::new(Builtins::from_nix(
+            "2.3.16".into(),
+            &attr_names,
+            &Default::default(),
+        )));
+        let mut diags = db
+            .name_resolution(file)
+            .to_diagnostics(&db, file)
+            .collect::<Vec<_>>();
+        diags.sort_by_key(|diag| diag.range.start());
+        let got = diags
+            .iter()
+            .map(|diag| diag.debug_display().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            got,
+            [
+                "6..15: UnavailableBuiltin",
+                "25..34: UnavailableBuiltin",
+                "57..66: UndefinedName",
+            ],
+        );
+    }
 }
@@ -1,9 +1,10 @@
 use super::DefDatabase;
 use crate::tests::TestDB;
-use crate::{FlakeInfo, ModuleKind, SourceDatabase, VfsPath};
+use crate::{FlakeInfo, ModuleKind, ParseBase, SourceDatabase, VfsPath};
 use expect_test::expect;
 use itertools::Itertools;
 use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
 
 #[test]
 fn change_barrier() {
@@ -29,6 +30,21 @@ stdenv.mkDerivation {
     }
 }
 
+#[test]
+fn incremental_parse() {
+    let src = "{ a = 1; b = [ 2 ]; }";
+    let (mut db, file) = TestDB::single_file(src).unwrap();
+    let base = ParseBase {
+        content: db.file_content(file),
+        parse: db.parse(file),
+    };
+    db.set_file_parse_base(file, Some(Arc::new(base)));
+
+    let new_src = "{ a = 1; b = [ 2 3 ]; c = 4; }";
+    db.set_file_content(file, new_src.into());
+    assert_eq!(db.parse(file), syntax::parse_file(new_src));
+}
+
 #[test]
 fn source_map() {
     let (db, root) = TestDB::single_file("foo 123").unwrap();
@@ -194,6 +210,8 @@ fn source_root_flake() {
                 VfsPath::new("/nix/store/eeee"),
             )]),
             input_flake_outputs: HashMap::new(),
+            self_flake_output: None,
+            lock: None,
         },
     );
 }
@@ -1,5 +1,6 @@
 use crate::FileRange;
 use core::fmt;
+use nix_interop::info::NixDialect;
 use syntax::{ErrorKind as SynErrorKind, TextRange};
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -7,6 +8,7 @@ pub struct Diagnostic {
     pub range: TextRange,
     pub kind: DiagnosticKind,
     pub notes: Vec<(FileRange, String)>,
+    severity: Severity,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -22,16 +24,32 @@ pub enum DiagnosticKind {
     EmptyLetIn,
     LetAttrset,
     UriLiteral,
+    CurPos,
+    PipeOperator,
     MergePlainRecAttrset,
     MergeRecAttrset,
 
     // Name resolution.
     UndefinedName,
+    UnavailableBuiltin,
 
     // Liveness.
     UnusedBinding,
     UnusedWith,
     UnusedRec,
+
+    // Flake inputs.
+    UnusedFlakeInput,
+    UndefinedFlakeInput,
+    UnlockedFlakeInput,
+    UndefinedFollows,
+
+    // Flake lock.
+    InvalidFlakeLock,
+    OutdatedFlakeLock,
+
+    // Suppression.
+    UnusedSuppression,
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
@@ -47,9 +65,23 @@ impl Diagnostic {
             range,
             kind,
             notes: Vec::new(),
+            severity: kind.default_severity(),
         }
     }
 
+    /// Adjust the severity for syntax which is removed, rather than deprecated, in `dialect`.
+    pub fn with_dialect(mut self, dialect: NixDialect) -> Self {
+        if dialect == NixDialect::Lix
+            && matches!(
+                self.kind,
+                DiagnosticKind::UriLiteral | DiagnosticKind::LetAttrset
+            )
+        {
+            self.severity = Severity::Error;
+        }
+        self
+    }
+
     pub fn with_note(mut self, frange: FileRange, message: impl Into<String>) -> Self {
         self.notes.push((frange, message.into()));
         self
@@ -65,34 +97,55 @@ impl Diagnostic {
             DiagnosticKind::EmptyLetIn => "empty_let_in",
             DiagnosticKind::LetAttrset => "let_attrset",
             DiagnosticKind::UriLiteral => "uri_literal",
+            DiagnosticKind::CurPos => "cur_pos",
+            DiagnosticKind::PipeOperator => "pipe_operator",
             DiagnosticKind::MergePlainRecAttrset => "merge_plain_rec_attrset",
             DiagnosticKind::MergeRecAttrset => "merge_rec_attrset",
             DiagnosticKind::UndefinedName => "undefined_name",
+            DiagnosticKind::UnavailableBuiltin => "unavailable_builtin",
             DiagnosticKind::UnusedBinding => "unused_binding",
             DiagnosticKind::UnusedWith => "unused_with",
             DiagnosticKind::UnusedRec => "unused_rec",
+            DiagnosticKind::UnusedFlakeInput => "unused_flake_input",
+            DiagnosticKind::UndefinedFlakeInput => "undefined_flake_input",
+            DiagnosticKind::UnlockedFlakeInput => "unlocked_flake_input",
+            DiagnosticKind::UndefinedFollows => "undefined_follows",
+            DiagnosticKind::InvalidFlakeLock => "invalid_flake_lock",
+            DiagnosticKind::OutdatedFlakeLock => "outdated_flake_lock",
+            DiagnosticKind::UnusedSuppression => "unused_suppression",
         }
     }
 
-    pub fn severity(&self) -> Severity {
+    /// Ids of the quick fix assists resolving this diagnostic, which are safe to apply
+    /// mechanically.
+    pub fn quick_fix_ids(&self) -> &'static [&'static str] {
         match self.kind {
-            DiagnosticKind::SyntaxError(_)
-            | DiagnosticKind::InvalidDynamic
-            | DiagnosticKind::DuplicatedKey
-            | DiagnosticKind::DuplicatedParam
-            | DiagnosticKind::UndefinedName => Severity::Error,
-            DiagnosticKind::EmptyInherit
-            | DiagnosticKind::EmptyLetIn
-            | DiagnosticKind::LetAttrset
-            | DiagnosticKind::UriLiteral
-            | DiagnosticKind::MergePlainRecAttrset
-            | DiagnosticKind::MergeRecAttrset
-            | DiagnosticKind::UnusedBinding
-            | DiagnosticKind::UnusedWith
-            | DiagnosticKind::UnusedRec => Severity::Warning,
+            DiagnosticKind::EmptyInherit => &["remove_empty_inherit"],
+            DiagnosticKind::EmptyLetIn => &["remove_empty_let_in"],
+            DiagnosticKind::UriLiteral => &["rewrite_uri_to_string"],
+            DiagnosticKind::UnusedBinding => &["remove_unused_binding"],
+            DiagnosticKind::UnusedWith => &["remove_unused_with"],
+            DiagnosticKind::UnusedRec => &["remove_unused_rec"],
+            DiagnosticKind::UnusedFlakeInput => &["remove_unused_flake_input"],
+            _ => &[],
         }
     }
 
+    /// Ids of the quick fix assists resolving this diagnostic by guessing the intention, eg.
+    /// adding a parameter for an undefined name which may be a typo. They should only be
+    /// applied on explicit request.
+    pub fn guessed_quick_fix_ids(&self) -> &'static [&'static str] {
+        match self.kind {
+            DiagnosticKind::UndefinedName => &["add_to_top_level_lambda_param"],
+            DiagnosticKind::UndefinedFlakeInput => &["add_flake_input_follows"],
+            _ => &[],
+        }
+    }
+
+    pub fn severity(&self) -> Severity {
+        self.severity
+    }
+
     pub fn message(&self) -> String {
         match self.kind {
             DiagnosticKind::SyntaxError(kind) => return kind.to_string(),
@@ -108,6 +161,12 @@ impl Diagnostic {
             DiagnosticKind::UriLiteral => {
                 "URL literal is confusing and deprecated. Use strings instead"
             }
+            DiagnosticKind::CurPos => {
+                "`__curPos` is deprecated in Lix. Use `builtins.unsafeGetAttrPos` instead"
+            }
+            DiagnosticKind::PipeOperator => {
+                "Pipe operators are not supported by the Nix version in use"
+            }
             DiagnosticKind::MergePlainRecAttrset => {
                 "Merging non-rec-attrset with rec-attrset, the latter `rec` is implicitly ignored"
             }
@@ -116,10 +175,29 @@ impl Diagnostic {
             }
 
             DiagnosticKind::UndefinedName => "Undefined name",
+            DiagnosticKind::UnavailableBuiltin => {
+                "Builtin is not available in the Nix version in use"
+            }
 
             DiagnosticKind::UnusedBinding => "Unused binding",
             DiagnosticKind::UnusedWith => "Unused `with`",
             DiagnosticKind::UnusedRec => "Unused `rec`",
+
+            DiagnosticKind::UnusedFlakeInput => "Flake input is not used by `outputs`",
+            DiagnosticKind::UndefinedFlakeInput => {
+                "Flake input is neither declared in `inputs` nor locked in `flake.lock`"
+            }
+            DiagnosticKind::UnlockedFlakeInput => {
+                "Flake input is not locked in `flake.lock`. Run `nix flake lock` to update it"
+            }
+            DiagnosticKind::UndefinedFollows => "`follows` refers to a non-existent input",
+
+            DiagnosticKind::InvalidFlakeLock => "Invalid flake lock file",
+            DiagnosticKind::OutdatedFlakeLock => {
+                "Flake lock is out of sync with inputs of `flake.nix`. Run `nix flake lock` to update it"
+            }
+
+            DiagnosticKind::UnusedSuppression => "Suppression comment does not suppress anything",
         }
         .into()
     }
@@ -131,13 +209,15 @@ impl Diagnostic {
                 | DiagnosticKind::UnusedBinding
                 | DiagnosticKind::UnusedWith
                 | DiagnosticKind::UnusedRec
+                | DiagnosticKind::UnusedFlakeInput
+                | DiagnosticKind::UnusedSuppression
         )
     }
 
     pub fn is_deprecated(&self) -> bool {
         matches!(
             self.kind,
-            DiagnosticKind::LetAttrset | DiagnosticKind::UriLiteral
+            DiagnosticKind::LetAttrset | DiagnosticKind::UriLiteral | DiagnosticKind::CurPos
         )
     }
 
@@ -158,6 +238,37 @@ impl Diagnostic {
     }
 }
 
+impl DiagnosticKind {
+    fn default_severity(self) -> Severity {
+        match self {
+            DiagnosticKind::SyntaxError(_)
+            | DiagnosticKind::InvalidDynamic
+            | DiagnosticKind::DuplicatedKey
+            | DiagnosticKind::DuplicatedParam
+            | DiagnosticKind::PipeOperator
+            | DiagnosticKind::UndefinedName
+            | DiagnosticKind::UndefinedFollows
+            | DiagnosticKind::InvalidFlakeLock => Severity::Error,
+            DiagnosticKind::EmptyInherit
+            | DiagnosticKind::EmptyLetIn
+            | DiagnosticKind::LetAttrset
+            | DiagnosticKind::UriLiteral
+            | DiagnosticKind::CurPos
+            | DiagnosticKind::MergePlainRecAttrset
+            | DiagnosticKind::MergeRecAttrset
+            | DiagnosticKind::UnavailableBuiltin
+            | DiagnosticKind::UnusedBinding
+            | DiagnosticKind::UnusedWith
+            | DiagnosticKind::UnusedRec
+            | DiagnosticKind::UnusedFlakeInput
+            | DiagnosticKind::UndefinedFlakeInput
+            | DiagnosticKind::UnlockedFlakeInput
+            | DiagnosticKind::OutdatedFlakeLock
+            | DiagnosticKind::UnusedSuppression => Severity::Warning,
+        }
+    }
+}
+
 impl From<syntax::Error> for Diagnostic {
     fn from(err: syntax::Error) -> Self {
         Self::new(err.range, DiagnosticKind::SyntaxError(err.kind))
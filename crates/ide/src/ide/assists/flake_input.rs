@@ -0,0 +1,407 @@
+//! Fix flake inputs reported by the flake input check.
+//!
+//! Remove inputs unused by `outputs`:
+//! ```nix
+//! {
+//!   inputs.nixpkgs.url = "github:NixOS/nixpkgs";
+//!   inputs.unused.url = "github:foo/unused";
+//!   outputs = { nixpkgs, ... }: { };
+//! }
+//! ```
+//! =>
+//! ```nix
+//! {
+//!   inputs.nixpkgs.url = "github:NixOS/nixpkgs";
+//!   outputs = { nixpkgs, ... }: { };
+//! }
+//! ```
+//!
+//! Declare an undefined input of `outputs` following `nixpkgs`:
+//! ```nix
+//! {
+//!   inputs.nixpkgs.url = "github:NixOS/nixpkgs";
+//!   outputs = { nixpkgs, nixos, ... }: { };
+//! }
+//! ```
+//! =>
+//! ```nix
+//! {
+//!   inputs.nixpkgs.url = "github:NixOS/nixpkgs";
+//!   inputs.nixos.follows = "nixpkgs";
+//!   outputs = { nixpkgs, nixos, ... }: { };
+//! }
+//! ```
+use super::remove_unused::with_trailing_space;
+use super::{AssistKind, AssistsCtx};
+use crate::def::AstPtr;
+use crate::{ModuleKind, TextEdit};
+use syntax::ast::{self, AstNode, HasBindings};
+use syntax::semantic::AttrKind;
+use syntax::{NodeOrToken, SyntaxNode, TextRange};
+
+/// The input to follow for undefined inputs.
+const FOLLOWS_TARGET: &str = "nixpkgs";
+
+pub(super) fn remove_unused_flake_input(ctx: &mut AssistsCtx<'_>) -> Option<()> {
+    let attr = ctx.covering_node::<ast::Attr>()?;
+    let file = ctx.frange.file_id;
+    let source_map = ctx.db.source_map(file);
+    let name = source_map.name_for_node(AstPtr::new(attr.syntax()))?;
+    if !ctx.db.flake_input_check(file).is_unused_input(name) {
+        return None;
+    }
+
+    // Remove all bindings defining the input, like `inputs.foo.url` and `inputs.foo.flake`.
+    let edits = source_map
+        .nodes_for_name(name)
+        .map(|ptr| {
+            let binding = ptr
+                .to_node(ctx.ast.syntax())
+                .ancestors()
+                .find_map(ast::AttrpathValue::cast)?;
+            let binding = binding.syntax();
+            // Remove the leading space instead for the last binding, to keep the indentation
+            // of the closing `}`.
+            let delete = match binding.prev_sibling_or_token() {
+                Some(NodeOrToken::Token(space))
+                    if space.kind().is_space() && binding.next_sibling().is_none() =>
+                {
+                    space.text_range().cover(binding.text_range())
+                }
+                _ => with_trailing_space(binding.text_range(), &binding.last_token()?),
+            };
+            Some(TextEdit {
+                delete,
+                insert: Default::default(),
+            })
+        })
+        .collect::<Option<Vec<_>>>()?;
+
+    ctx.add(
+        "remove_unused_flake_input",
+        format!(
+            "Remove unused flake input `{}`",
+            ctx.db.module(file)[name].text
+        ),
+        AssistKind::QuickFix,
+        edits,
+    );
+
+    Some(())
+}
+
+pub(super) fn add_flake_input_follows(ctx: &mut AssistsCtx<'_>) -> Option<()> {
+    let node = ctx.covering_node::<ast::Name>()?;
+    let file = ctx.frange.file_id;
+    let name = ctx
+        .db
+        .source_map(file)
+        .name_for_node(AstPtr::new(node.syntax()))?;
+    if !ctx.db.flake_input_check(file).is_undefined_param(name) {
+        return None;
+    }
+    let input_name = ctx.db.module(file)[name].text.clone();
+    if input_name == FOLLOWS_TARGET {
+        return None;
+    }
+
+    // The target must be available.
+    let ModuleKind::FlakeNix {
+        explicit_inputs, ..
+    } = &*ctx.db.module_kind(file)
+    else {
+        return None;
+    };
+    let is_locked = || {
+        let info = ctx.db.source_root_flake_info(ctx.db.file_source_root(file));
+        info.as_ref()
+            .and_then(|info| info.lock.as_ref())
+            .is_some_and(|lock| lock.root_inputs().any(|name| name == FOLLOWS_TARGET))
+    };
+    if !explicit_inputs.contains_key(FOLLOWS_TARGET) && !is_locked() {
+        return None;
+    }
+
+    let flake_set = match ctx.ast.expr()?.flatten_paren()? {
+        ast::Expr::AttrSet(set) => set,
+        _ => return None,
+    };
+    let last_inputs = flake_set
+        .bindings()
+        .filter_map(|b| match b {
+            ast::Binding::AttrpathValue(b) => Some(b),
+            ast::Binding::Inherit(_) => None,
+        })
+        .filter(|b| {
+            b.attrpath()
+                .and_then(|path| path.attrs().next())
+                .is_some_and(
+                    |attr| matches!(AttrKind::of(attr), AttrKind::Static(Some(s)) if s == "inputs"),
+                )
+        })
+        .last();
+
+    // `inputs = { ... };`
+    let inputs_set = last_inputs
+        .as_ref()
+        .filter(|b| b.attrpath().is_some_and(|path| path.attrs().count() == 1))
+        .and_then(|b| match b.value()? {
+            ast::Expr::AttrSet(set) => Some(set),
+            _ => None,
+        });
+
+    let follows = format!("{input_name}.follows = \"{FOLLOWS_TARGET}\";");
+    let (pos, insert) = match (inputs_set, last_inputs) {
+        (Some(set), _) => match set.bindings().last() {
+            Some(last) => (
+                last.syntax().text_range().end(),
+                format!("{}{follows}", separator_before(last.syntax())),
+            ),
+            None => (
+                set.l_curly_token()?.text_range().end(),
+                format!(" {follows}"),
+            ),
+        },
+        // `inputs.foo.url = "...";`
+        (None, Some(binding)) => (
+            binding.syntax().text_range().end(),
+            format!("{}inputs.{follows}", separator_before(binding.syntax())),
+        ),
+        (None, None) => {
+            let first = flake_set.bindings().next()?;
+            (
+                first.syntax().text_range().start(),
+                format!("inputs.{follows}{}", separator_before(first.syntax())),
+            )
+        }
+    };
+
+    ctx.add(
+        "add_flake_input_follows",
+        format!("Declare flake input `{input_name}` following `{FOLLOWS_TARGET}`"),
+        AssistKind::QuickFix,
+        vec![TextEdit {
+            delete: TextRange::empty(pos),
+            insert: insert.into(),
+        }],
+    );
+
+    Some(())
+}
+
+/// Get the line break and indentation before a binding, or a space if it is not on its own line.
+fn separator_before(node: &SyntaxNode) -> String {
+    match node
+        .prev_sibling_or_token()
+        .and_then(|elem| elem.into_token())
+    {
+        Some(tok) if tok.kind().is_space() && tok.text().contains('\n') => {
+            let indent = &tok.text()[tok.text().rfind('\n').unwrap() + 1..];
+            format!("\n{indent}")
+        }
+        _ => " ".into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use expect_test::expect;
+
+    const LOCK: &str = r#"
+#- /flake.lock
+{
+  "nodes": {
+    "nixpkgs": { "locked": { "narHash": "sha256-AAAA" } },
+    "root": { "inputs": { "nixpkgs": "nixpkgs" } }
+  },
+  "root": "root",
+  "version": 7
+}
+"#;
+
+    mod remove {
+        use super::*;
+
+        define_check_assist!(super::super::remove_unused_flake_input);
+
+        #[test]
+        fn attrpath() {
+            check(
+                r#"
+#- /flake.nix
+{
+  inputs.nixpkgs.url = "github:NixOS/nixpkgs";
+  inputs.$0foo.url = "github:foo/foo";
+  inputs.foo.flake = false;
+  outputs = { nixpkgs, ... }: { };
+}
+                "#,
+                expect![[r#"
+                    {
+                      inputs.nixpkgs.url = "github:NixOS/nixpkgs";
+                      outputs = { nixpkgs, ... }: { };
+                    }
+                "#]],
+            );
+        }
+
+        #[test]
+        fn attrset() {
+            check(
+                r#"
+#- /flake.nix
+{
+  inputs = {
+    nixpkgs.url = "github:NixOS/nixpkgs";
+    $0foo = {
+      url = "github:foo/foo";
+    };
+  };
+  outputs = { nixpkgs, ... }: { };
+}
+                "#,
+                expect![[r#"
+                    {
+                      inputs = {
+                        nixpkgs.url = "github:NixOS/nixpkgs";
+                      };
+                      outputs = { nixpkgs, ... }: { };
+                    }
+                "#]],
+            );
+        }
+
+        #[test]
+        fn used() {
+            check_no(
+                r#"
+#- /flake.nix
+{
+  inputs.$0nixpkgs.url = "github:NixOS/nixpkgs";
+  outputs = { nixpkgs, ... }: { };
+}
+                "#,
+            );
+            // Followed by other inputs.
+            check_no(
+                r#"
+#- /flake.nix
+{
+  inputs.$0nixpkgs.url = "github:NixOS/nixpkgs";
+  inputs.foo.inputs.nixpkgs.follows = "nixpkgs";
+  outputs = { foo, ... }: { };
+}
+                "#,
+            );
+            // Maybe used via the `@` binding.
+            check_no(
+                r#"
+#- /flake.nix
+{
+  inputs.$0foo.url = "github:foo/foo";
+  outputs = { self, ... }@inputs: { };
+}
+                "#,
+            );
+        }
+    }
+
+    mod follows {
+        use super::*;
+
+        define_check_assist!(super::super::add_flake_input_follows);
+
+        #[test]
+        fn attrpath() {
+            check(
+                &format!(
+                    r#"
+#- /flake.nix
+{{
+  description = "Test";
+  inputs.nixpkgs.url = "github:NixOS/nixpkgs";
+  outputs = {{ nixpkgs, $0nixos, ... }}: {{ }};
+}}
+{LOCK}"#
+                ),
+                expect![[r#"
+                    {
+                      description = "Test";
+                      inputs.nixpkgs.url = "github:NixOS/nixpkgs";
+                      inputs.nixos.follows = "nixpkgs";
+                      outputs = { nixpkgs, nixos, ... }: { };
+                    }
+                "#]],
+            );
+        }
+
+        #[test]
+        fn attrset() {
+            check(
+                &format!(
+                    r#"
+#- /flake.nix
+{{
+  inputs = {{
+    nixpkgs.url = "github:NixOS/nixpkgs";
+  }};
+  outputs = {{ nixpkgs, $0nixos, ... }}: {{ }};
+}}
+{LOCK}"#
+                ),
+                expect![[r#"
+                    {
+                      inputs = {
+                        nixpkgs.url = "github:NixOS/nixpkgs";
+                        nixos.follows = "nixpkgs";
+                      };
+                      outputs = { nixpkgs, nixos, ... }: { };
+                    }
+                "#]],
+            );
+        }
+
+        #[test]
+        fn implicit_nixpkgs() {
+            check(
+                &format!(
+                    r#"
+#- /flake.nix
+{{
+  outputs = {{ nixpkgs, $0nixos, ... }}: {{ }};
+}}
+{LOCK}"#
+                ),
+                expect![[r#"
+                    {
+                      inputs.nixos.follows = "nixpkgs";
+                      outputs = { nixpkgs, nixos, ... }: { };
+                    }
+                "#]],
+            );
+        }
+
+        #[test]
+        fn not_applicable() {
+            // Locked.
+            check_no(&format!(
+                r#"
+#- /flake.nix
+{{
+  outputs = {{ $0nixpkgs, ... }}: {{ }};
+}}
+{LOCK}"#
+            ));
+            // No lock to check against.
+            check_no(
+                r#"
+#- /flake.nix
+{
+  inputs.nixpkgs.url = "github:NixOS/nixpkgs";
+  outputs = { nixpkgs, $0nixos, ... }: { };
+}
+                "#,
+            );
+        }
+    }
+}
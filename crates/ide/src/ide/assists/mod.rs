@@ -15,12 +15,15 @@ macro_rules! define_check_assist {
 
 mod add_to_top_level_lambda_param;
 mod convert_to_inherit;
+mod flake_input;
 mod flatten_attrset;
 mod pack_bindings;
 mod remove_empty_inherit;
 mod remove_empty_let_in;
+mod remove_unused;
 mod rewrite_string;
 
+use super::flake_lock;
 use crate::{DefDatabase, FileRange, TextEdit, WorkspaceEdit};
 use syntax::ast::{self, AstNode};
 use syntax::{best_token_at_offset, NixLanguage};
@@ -42,13 +45,21 @@ pub enum AssistKind {
 }
 
 pub(crate) fn assists(db: &dyn DefDatabase, frange: FileRange) -> Vec<Assist> {
+    if flake_lock::is_flake_lock(db, frange.file_id) {
+        return Vec::new();
+    }
     let handlers = [
         add_to_top_level_lambda_param::add_to_top_level_lambda_param,
         convert_to_inherit::convert_to_inherit,
+        flake_input::add_flake_input_follows,
+        flake_input::remove_unused_flake_input,
         flatten_attrset::flatten_attrset,
         pack_bindings::pack_bindings,
         remove_empty_inherit::remove_empty_inherit,
         remove_empty_let_in::remove_empty_let_in,
+        remove_unused::remove_unused_binding,
+        remove_unused::remove_unused_rec,
+        remove_unused::remove_unused_with,
         rewrite_string::quote_attr,
         rewrite_string::rewrite_indented_to_string,
         rewrite_string::rewrite_string_to_indented,
@@ -124,7 +135,6 @@ mod tests {
         fixture: &str,
     ) -> Option<String> {
         let (db, f) = TestDB::from_fixture(fixture).unwrap();
-        assert_eq!(f.files().len(), 1);
         let frange = f.unwrap_single_range_marker();
         let mut ctx = AssistsCtx::new(&db, frange);
         handler(&mut ctx);
@@ -162,4 +172,14 @@ mod tests {
             panic!("Unexpected applicatable:\n{got}");
         }
     }
+
+    #[test]
+    fn flake_lock() {
+        let src = r#"[ $0"7"$1 ]"#;
+        let (db, f) = TestDB::from_fixture(&format!("#- /a.nix\n{src}")).unwrap();
+        assert!(!assists(&db, f.unwrap_single_range_marker()).is_empty());
+        // Lock files are JSON, which should not get Nix assists.
+        let (db, f) = TestDB::from_fixture(&format!("#- /flake.lock\n{src}")).unwrap();
+        assert!(assists(&db, f.unwrap_single_range_marker()).is_empty());
+    }
 }
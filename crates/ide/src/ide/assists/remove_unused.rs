@@ -0,0 +1,251 @@
+//! Remove unused bindings, `with` and `rec` reported by liveness check.
+//!
+//! ```nix
+//! { lib, stdenv }: let foo = 1; in with lib; rec { bar = stdenv; }
+//! ```
+//! =>
+//! ```nix
+//! { stdenv }: { bar = stdenv; }
+//! ```
+use super::{AssistKind, AssistsCtx};
+use crate::def::AstPtr;
+use crate::TextEdit;
+use syntax::ast::{self, AstNode};
+use syntax::{SyntaxNode, SyntaxToken, TextRange, T};
+
+pub(super) fn remove_unused_binding(ctx: &mut AssistsCtx<'_>) -> Option<()> {
+    let attr = ctx.covering_node::<ast::Attr>()?;
+    let file = ctx.frange.file_id;
+    let name = ctx
+        .db
+        .source_map(file)
+        .name_for_node(AstPtr::new(attr.syntax()))?;
+    if !ctx.db.liveness_check(file).is_unused_name(name) {
+        return None;
+    }
+
+    let attr = attr.syntax();
+    let parent = attr.parent()?;
+    let range = if let Some(field) = ast::PatField::cast(parent.clone()) {
+        // `{ foo, bar }: ...`
+        let field = field.syntax();
+        if let Some(comma) = next_non_trivia(field).filter(|tok| tok.kind() == T![,]) {
+            with_trailing_space(field.text_range().cover(comma.text_range()), &comma)
+        } else if let Some(comma) = prev_non_trivia(field).filter(|tok| tok.kind() == T![,]) {
+            // Keep the trailing space of the field node.
+            let last = field
+                .descendants_with_tokens()
+                .filter_map(|elem| elem.into_token())
+                .filter(|tok| !tok.kind().is_trivia())
+                .last()?;
+            comma.text_range().cover(last.text_range())
+        } else {
+            with_trailing_space(field.text_range(), &field.last_token()?)
+        }
+    } else if let Some(param) = ast::Param::cast(parent.clone()) {
+        // `{ ... }@foo: ...` or `foo@{ ... }: ...`
+        let pat = param.pat()?.syntax().text_range();
+        let param = param.syntax().text_range();
+        if pat.start() == param.start() {
+            TextRange::new(pat.end(), param.end())
+        } else {
+            TextRange::new(param.start(), pat.start())
+        }
+    } else if let Some(inherit) = ast::Inherit::cast(parent.clone()) {
+        // `let inherit foo bar; in ...`
+        ast::LetIn::cast(inherit.syntax().parent()?)?;
+        if inherit.attrs().count() == 1 {
+            // Remove the whole `inherit` with its last name, instead of leaving an empty one.
+            let inherit = inherit.syntax();
+            with_trailing_space(inherit.text_range(), &inherit.last_token()?)
+        } else {
+            match attr.prev_sibling_or_token()?.into_token() {
+                Some(space) if space.kind().is_space() => {
+                    space.text_range().cover(attr.text_range())
+                }
+                _ => attr.text_range(),
+            }
+        }
+    } else {
+        // `let foo = 1; foo.bar = 2; in ...`
+        let path = ast::Attrpath::cast(parent)?;
+        if path.attrs().next()?.syntax() != attr {
+            return None;
+        }
+        let binding = ast::AttrpathValue::cast(path.syntax().parent()?)?;
+        ast::LetIn::cast(binding.syntax().parent()?)?;
+        let binding = binding.syntax();
+        with_trailing_space(binding.text_range(), &binding.last_token()?)
+    };
+
+    ctx.add(
+        "remove_unused_binding",
+        format!("Remove unused binding `{}`", attr.text()),
+        AssistKind::QuickFix,
+        vec![TextEdit {
+            delete: range,
+            insert: Default::default(),
+        }],
+    );
+
+    Some(())
+}
+
+pub(super) fn remove_unused_with(ctx: &mut AssistsCtx<'_>) -> Option<()> {
+    let node = ctx.covering_node::<ast::With>()?;
+    let file = ctx.frange.file_id;
+    let expr = ctx
+        .db
+        .source_map(file)
+        .expr_for_node(AstPtr::new(node.syntax()))?;
+    if !ctx.db.liveness_check(file).is_unused_with(expr) {
+        return None;
+    }
+
+    let semicolon = node.semicolon_token()?;
+    let range = with_trailing_space(
+        node.with_token()?
+            .text_range()
+            .cover(semicolon.text_range()),
+        &semicolon,
+    );
+
+    ctx.add(
+        "remove_unused_with",
+        "Remove unused `with`",
+        AssistKind::QuickFix,
+        vec![TextEdit {
+            delete: range,
+            insert: Default::default(),
+        }],
+    );
+
+    Some(())
+}
+
+pub(super) fn remove_unused_rec(ctx: &mut AssistsCtx<'_>) -> Option<()> {
+    let node = ctx.covering_node::<ast::AttrSet>()?;
+    let rec_token = node.rec_token()?;
+    let file = ctx.frange.file_id;
+    let expr = ctx
+        .db
+        .source_map(file)
+        .expr_for_node(AstPtr::new(node.syntax()))?;
+    if !ctx.db.liveness_check(file).is_unused_rec(expr) {
+        return None;
+    }
+
+    ctx.add(
+        "remove_unused_rec",
+        "Remove unused `rec`",
+        AssistKind::QuickFix,
+        vec![TextEdit {
+            delete: with_trailing_space(rec_token.text_range(), &rec_token),
+            insert: Default::default(),
+        }],
+    );
+
+    Some(())
+}
+
+pub(super) fn with_trailing_space(range: TextRange, last_token: &SyntaxToken) -> TextRange {
+    match last_token.next_token() {
+        Some(tok) if tok.kind().is_space() => range.cover(tok.text_range()),
+        _ => range,
+    }
+}
+
+fn next_non_trivia(node: &SyntaxNode) -> Option<SyntaxToken> {
+    std::iter::successors(node.next_sibling_or_token(), |elem| {
+        elem.next_sibling_or_token()
+    })
+    .filter_map(|elem| elem.into_token())
+    .find(|tok| !tok.kind().is_trivia())
+}
+
+fn prev_non_trivia(node: &SyntaxNode) -> Option<SyntaxToken> {
+    std::iter::successors(node.prev_sibling_or_token(), |elem| {
+        elem.prev_sibling_or_token()
+    })
+    .filter_map(|elem| elem.into_token())
+    .find(|tok| !tok.kind().is_trivia())
+}
+
+#[cfg(test)]
+mod tests {
+    use expect_test::expect;
+
+    mod binding {
+        use super::*;
+
+        define_check_assist!(super::super::remove_unused_binding);
+
+        #[test]
+        fn let_binding() {
+            check("let $0a = 1; b = 2; in b", expect!["let b = 2; in b"]);
+            check("let $0a.b = 1; a.c = 2; in 1", expect!["let a.c = 2; in 1"]);
+            check_no("let a.$0b = 1; a.c = 2; in 1");
+            check("let a = 1; $0b = 2; in a", expect!["let a = 1; in a"]);
+            check_no("let a = 1; $0b = 2; in b");
+            check_no("rec { $0a = 1; }");
+        }
+
+        #[test]
+        fn let_inherit() {
+            check(
+                "let inherit (x) $0a b; in b",
+                expect!["let inherit (x) b; in b"],
+            );
+            check("let inherit $0a; in 1", expect!["let in 1"]);
+            check(
+                "let inherit (x) $0a; b = 1; in b",
+                expect!["let b = 1; in b"],
+            );
+            check_no("{ inherit $0a; }");
+        }
+
+        #[test]
+        fn pat_field() {
+            check("{ $0a, b }: b { }", expect!["{ b }: b { }"]);
+            check("{ a, $0b, ... }: a { }", expect!["{ a, ... }: a { }"]);
+            check("{ a, $0b }: a { }", expect!["{ a }: a { }"]);
+            check("{ $0a }: f { }", expect!["{ }: f { }"]);
+            check_no("{ $0a }: a { }");
+            // Not a package.
+            check_no("{ $0a, b }: b");
+        }
+
+        #[test]
+        fn param() {
+            check("{ ... }@$0a: 1", expect!["{ ... }: 1"]);
+            check("$0a @ { ... }: 1", expect!["{ ... }: 1"]);
+            check_no("{ ... }@$0a: a");
+        }
+    }
+
+    mod with {
+        use super::*;
+
+        define_check_assist!(super::super::remove_unused_with);
+
+        #[test]
+        fn simple() {
+            check("$0with 1; 2", expect!["2"]);
+            check("[ (with a;$0 /*b*/ 1) ]", expect!["[ (/*b*/ 1) ]"]);
+            check_no("$0with a; b");
+        }
+    }
+
+    mod rec {
+        use super::*;
+
+        define_check_assist!(super::super::remove_unused_rec);
+
+        #[test]
+        fn simple() {
+            check("$0rec { a = 1; }", expect!["{ a = 1; }"]);
+            check_no("$0rec { a = 1; b = a; }");
+            check_no("{ $0a = 1; }");
+        }
+    }
+}
@@ -1,14 +1,16 @@
 use crate::def::{AstPtr, BindingValue, Expr, NameKind};
 use crate::ty::{self, AttrSource, DisplayConfig, Ty};
-use crate::{FileId, FilePos, TyDatabase};
-use builtin::{BuiltinKind, ALL_BUILTINS};
+use crate::{FileId, FilePos, FlakeOutputPath, TyDatabase};
+use builtin::{BuiltinKind, Builtins};
 use either::Either::{Left, Right};
+use nix_interop::flake_output::Type as OutputTy;
 use smol_str::SmolStr;
 use syntax::ast::{self, AstNode, Attr};
 use syntax::semantic::{escape_literal_attr, is_valid_ident, AttrKind};
-use syntax::{best_token_at_offset, match_ast, SyntaxKind, SyntaxNode, TextRange, T};
+use syntax::{best_token_at_offset, match_ast, SyntaxKind, SyntaxNode, SyntaxToken, TextRange, T};
 
-use super::hover::TY_DETAILED_DISPLAY;
+use super::flake_lock;
+use super::hover::{flake_output_doc, name_doc_comment, TY_DETAILED_DISPLAY};
 
 pub const TY_SIGNATURE_DISPLAY: DisplayConfig = DisplayConfig {
     max_lambda_lhs_depth: 2,
@@ -89,6 +91,10 @@ pub(crate) fn completions(
     fpos @ FilePos { file_id, pos }: FilePos,
     trigger_char: Option<char>,
 ) -> Option<Vec<CompletionItem>> {
+    if flake_lock::is_flake_lock(db, file_id) {
+        return None;
+    }
+
     let parse = db.parse(file_id);
 
     if let Some(items) =
@@ -148,6 +154,12 @@ fn complete_trigger(
 
     let trigger_tok = root_node.token_at_offset(pos).left_biased()?;
     let source_range = TextRange::empty(trigger_tok.text_range().end());
+    let (name_node, path_node) = name_after_trigger(&trigger_tok)?;
+    complete_attrpath(db, file_id, source_range, name_node, path_node)
+}
+
+/// Find the Attr right after the trigger character `.` or `?`, which is being typed.
+fn name_after_trigger(trigger_tok: &SyntaxToken) -> Option<(ast::Name, ast::Attrpath)> {
     let path_node = match_ast! {
         match (trigger_tok.parent()?) {
             // `foo.bar.|` or `foo?bar.|`
@@ -165,15 +177,16 @@ fn complete_trigger(
     // `{ a.| = 42; }`
     //     ^  \ NAME before here
     //     DOT is here
+    let trigger_end = trigger_tok.text_range().end();
     let Attr::Name(name_node) = path_node
         .attrs()
-        .find(|attr| source_range.start() <= attr.syntax().text_range().start())?
+        .find(|attr| trigger_end <= attr.syntax().text_range().start())?
     else {
         return None;
     };
 
     let path_node = ast::Attrpath::cast(name_node.syntax().parent()?)?;
-    complete_attrpath(db, file_id, source_range, name_node, path_node)
+    Some((name_node, path_node))
 }
 
 fn complete_expr(
@@ -240,15 +253,16 @@ fn complete_expr(
                     .then(|| ty.display_with(TY_SIGNATURE_DISPLAY).to_string())
             },
             description: None,
-            documentation: None,
+            documentation: name_doc_comment(db, file_id, name),
         })
         .for_each(&mut feed);
 
     // Global builtins.
-    ALL_BUILTINS
+    let builtins = db.builtins();
+    builtins
         .entries()
         .filter(|(_, b)| b.is_global)
-        .filter_map(|(name, _)| builtin_to_completion(source_range, name))
+        .filter_map(|(name, _)| builtin_to_completion(&builtins, source_range, name))
         .for_each(&mut feed);
 
     // TODO: Better sorting.
@@ -265,14 +279,11 @@ fn complete_attrpath(
     name_node: ast::Name,
     path_node: ast::Attrpath,
 ) -> Option<Vec<CompletionItem>> {
-    let (set_node, container_node) = match_ast! {
+    let container_node = match_ast! {
         match (path_node.syntax().parent()?){
-            ast::AttrpathValue(n) => {
-                let n = n.syntax().parent()?;
-                (n.clone(), n)
-            },
-            ast::HasAttr(n) => (n.set()?.syntax().clone(), n.syntax().clone()),
-            ast::Select(n) => (n.set()?.syntax().clone(), n.syntax().clone()),
+            ast::AttrpathValue(n) => n.syntax().parent()?,
+            ast::HasAttr(n) => n.syntax().clone(),
+            ast::Select(n) => n.syntax().clone(),
             _ => return None,
         }
     };
@@ -286,6 +297,7 @@ fn complete_attrpath(
 
     let module = db.module(file_id);
     let source_map = db.source_map(file_id);
+    let builtins = db.builtins();
 
     let mut items = Vec::new();
 
@@ -310,7 +322,7 @@ fn complete_attrpath(
         && path_node
             .attrs()
             .next()
-            .map_or(false, |attr| attr.syntax() == name_node.syntax())
+            .is_some_and(|attr| attr.syntax() == name_node.syntax())
     {
         if let Some(expr) = source_map.expr_for_node(AstPtr::new(&container_node)) {
             if let Expr::LetIn(b, _) = &module[expr] {
@@ -318,12 +330,11 @@ fn complete_attrpath(
                     b.statics
                         .iter()
                         .filter(|(_, v)| matches!(v, BindingValue::Expr(_)))
-                        .map(|&(name, _)| module[name].text.clone())
                         // We should not report current incomplete definition.
                         // This is covered by `no_incomplete_field`.
-                        .filter(|name| *name != current_input)
-                        .map(|name| {
-                            let escaped_name = escape_literal_attr(&name);
+                        .filter(|&&(name, _)| module[name].text != current_input)
+                        .map(|&(name, _)| {
+                            let escaped_name = escape_literal_attr(&module[name].text);
                             CompletionItem {
                                 label: escaped_name.as_ref().into(),
                                 source_range,
@@ -331,7 +342,7 @@ fn complete_attrpath(
                                 kind: CompletionItemKind::LetBinding,
                                 signature: None,
                                 description: None,
-                                documentation: None,
+                                documentation: name_doc_comment(db, file_id, name),
                             }
                         }),
                 );
@@ -344,27 +355,9 @@ fn complete_attrpath(
     // or non-first parts of a definition `{ a.b| }`.
     // Use type information
     (|| -> Option<()> {
-        let infer = db.infer(file_id);
-
-        let mut attrs = path_node.attrs();
-        let set_ty = if is_let {
-            let name = source_map.name_for_node(AstPtr::new(attrs.next()?.syntax()))?;
-            infer.ty_for_name(name)
-        } else {
-            let set_expr = source_map.expr_for_node(AstPtr::new(&set_node))?;
-            infer.ty_for_expr(set_expr)
-        };
-
-        // Resolve prefix paths, except for the current NAME.
-        // foo.a.b.c|.d
-        // ^-----^
-        let ty = attrs
-            .take_while(|attr| attr.syntax() != name_node.syntax())
-            .try_fold(set_ty, |set_ty, attr| match AttrKind::of(attr) {
-                AttrKind::Static(Some(field)) => set_ty.as_attrset()?.get(&field).cloned(),
-                _ => None,
-            })?;
+        let (ty, _) = resolve_attrpath_prefix(db, file_id, &name_node, &path_node)?;
         let set = ty.as_attrset()?;
+        let output_tys = db.flake_output_tys(db.file_source_root(file_id));
 
         items.extend(set.iter().filter_map(|(name, ty, src)| {
             // We should not report current incomplete definition.
@@ -374,24 +367,35 @@ fn complete_attrpath(
             }
 
             if src == AttrSource::Builtin {
-                return builtin_to_completion(source_range, name);
+                return builtin_to_completion(&builtins, source_range, name);
             }
 
             let escaped_name = escape_literal_attr(name);
+            let leaf = match src {
+                AttrSource::FlakeOutput(idx) => output_tys.leaf(idx),
+                _ => None,
+            };
 
             Some(CompletionItem {
                 label: escaped_name.as_ref().into(),
                 source_range,
                 replace: escaped_name.into(),
                 kind: match src {
-                    AttrSource::Unknown => CompletionItemKind::Field,
+                    AttrSource::Unknown | AttrSource::FlakeOutput(_) => CompletionItemKind::Field,
                     AttrSource::Name(name) => module[name].kind.into(),
                     // Handled above.
                     AttrSource::Builtin => unreachable!(),
                 },
                 signature: Some(ty.display_with(TY_SIGNATURE_DISPLAY).to_string()),
-                description: Some(ty.display_with(TY_DETAILED_DISPLAY).to_string()),
-                documentation: None,
+                // Prefer the description of packages.
+                description: leaf
+                    .and_then(|leaf| leaf.description.clone())
+                    .or_else(|| Some(ty.display_with(TY_DETAILED_DISPLAY).to_string())),
+                documentation: match src {
+                    AttrSource::Name(name) => name_doc_comment(db, file_id, name),
+                    AttrSource::FlakeOutput(idx) => flake_output_doc(db, file_id, idx),
+                    _ => None,
+                },
             })
         }));
 
@@ -401,6 +405,84 @@ fn complete_attrpath(
     Some(items)
 }
 
+/// Resolve the type and source of prefix paths of an Attrpath, except for the current NAME.
+/// ```text
+/// foo.a.b.c|.d
+/// ^-----^
+/// ```
+fn resolve_attrpath_prefix(
+    db: &dyn TyDatabase,
+    file_id: FileId,
+    name_node: &ast::Name,
+    path_node: &ast::Attrpath,
+) -> Option<(Ty, AttrSource)> {
+    let (set_node, is_let) = match_ast! {
+        match (path_node.syntax().parent()?) {
+            ast::AttrpathValue(n) => {
+                let n = n.syntax().parent()?;
+                let is_let = ast::LetIn::can_cast(n.kind());
+                (n, is_let)
+            },
+            ast::HasAttr(n) => (n.set()?.syntax().clone(), false),
+            ast::Select(n) => (n.set()?.syntax().clone(), false),
+            _ => return None,
+        }
+    };
+
+    let source_map = db.source_map(file_id);
+    let infer = db.infer(file_id);
+
+    let mut attrs = path_node.attrs();
+    let set_ty = if is_let {
+        let name = source_map.name_for_node(AstPtr::new(attrs.next()?.syntax()))?;
+        infer.ty_for_name(name)
+    } else {
+        let set_expr = source_map.expr_for_node(AstPtr::new(&set_node))?;
+        infer.ty_for_expr(set_expr)
+    };
+
+    attrs
+        .take_while(|attr| attr.syntax() != name_node.syntax())
+        .try_fold((set_ty, AttrSource::Unknown), |(set_ty, _), attr| {
+            let set = set_ty.as_attrset()?;
+            let (ty, src) = match AttrKind::of(attr) {
+                AttrKind::Static(Some(field)) => (set.get(&field)?, set.get_src(&field)?),
+                // Like `packages.${system}`.
+                AttrKind::Dynamic(_) => set.rest()?,
+                AttrKind::Static(None) => return None,
+            };
+            Some((ty.clone(), src))
+        })
+}
+
+/// Find the unevaluated flake output whose fields are being completed, like
+/// `nixpkgs.legacyPackages.${system}.python3Packages.|`, so that it can be evaluated on demand.
+pub(crate) fn unevaluated_flake_output(
+    db: &dyn TyDatabase,
+    FilePos { file_id, pos }: FilePos,
+) -> Option<FlakeOutputPath> {
+    let parse = db.parse(file_id);
+    let tok = parse.syntax_node().token_at_offset(pos).left_biased()?;
+    let (name_node, path_node) = match tok.kind() {
+        T![.] | T![?] => name_after_trigger(&tok)?,
+        _ => {
+            let name_node = tok.parent_ancestors().find_map(ast::Name::cast)?;
+            let path_node = ast::Attrpath::cast(name_node.syntax().parent()?)?;
+            (name_node, path_node)
+        }
+    };
+    let (_, AttrSource::FlakeOutput(idx)) =
+        resolve_attrpath_prefix(db, file_id, &name_node, &path_node)?
+    else {
+        return None;
+    };
+    let output_tys = db.flake_output_tys(db.file_source_root(file_id));
+    if output_tys.leaf(idx)?.type_ != OutputTy::Unevaluated {
+        return None;
+    }
+    output_tys.leaf_path(idx).cloned()
+}
+
 fn complete_pat_param(
     db: &dyn TyDatabase,
     file_id: FileId,
@@ -449,8 +531,12 @@ fn keyword_to_completion(kw: &str, source_range: TextRange) -> CompletionItem {
     }
 }
 
-fn builtin_to_completion(source_range: TextRange, name: &str) -> Option<CompletionItem> {
-    let builtin = ALL_BUILTINS.get(name)?;
+fn builtin_to_completion(
+    builtins: &Builtins,
+    source_range: TextRange,
+    name: &str,
+) -> Option<CompletionItem> {
+    let builtin = builtins.get(name)?;
     let ty = ty::known::BUILTINS
         .as_attrset()
         .unwrap()
@@ -470,7 +556,7 @@ fn builtin_to_completion(source_range: TextRange, name: &str) -> Option<Completi
             ty.display_with(TY_DETAILED_DISPLAY),
             builtin.summary,
         )),
-        documentation: builtin.doc.map(|s| s.to_owned()),
+        documentation: builtin.doc.clone(),
     })
 }
 
@@ -496,9 +582,9 @@ mod tests {
     use std::sync::Arc;
 
     use crate::base::SourceDatabase;
-    use crate::tests::TestDB;
+    use crate::tests::{set_test_flake_outputs, TestDB};
     use expect_test::{expect, Expect};
-    use nix_interop::nixos_options::{self, NixosOption, NixosOptions};
+    use nix_interop::nixos_options::{self, NixosOption, NixosOptions, OptionSource};
 
     #[track_caller]
     fn check_no(fixture: &str, label: &str) {
@@ -511,22 +597,25 @@ mod tests {
     #[track_caller]
     fn check_trigger(fixture: &str, trigger_char: Option<char>, label: &str, expect: Expect) {
         let (mut db, f) = TestDB::from_fixture(fixture).unwrap();
-        db.set_nixos_options(Arc::new(NixosOptions::from_iter([(
-            "nix".into(),
-            NixosOption {
-                ty: nixos_options::Ty::Attrset {
-                    fields: NixosOptions::from_iter([(
-                        "enable".into(),
-                        NixosOption {
-                            ty: nixos_options::Ty::Bool,
-                            ..NixosOption::default()
-                        },
-                    )]),
-                    rest: None,
+        db.set_module_options(
+            OptionSource::Nixos,
+            Arc::new(NixosOptions::from_iter([(
+                "nix".into(),
+                NixosOption {
+                    ty: nixos_options::Ty::Attrset {
+                        fields: NixosOptions::from_iter([(
+                            "enable".into(),
+                            NixosOption {
+                                ty: nixos_options::Ty::Bool,
+                                ..NixosOption::default()
+                            },
+                        )]),
+                        rest: None,
+                    },
+                    ..NixosOption::default()
                 },
-                ..NixosOption::default()
-            },
-        )])));
+            )])),
+        );
 
         let compes = super::completions(&db, f[0], trigger_char).expect("No completion");
         let item = compes
@@ -562,6 +651,24 @@ mod tests {
         check("if a th$0", "then", expect!["(Keyword) if a then"]);
     }
 
+    #[track_caller]
+    fn check_doc(fixture: &str, label: &str, expect: Option<&str>) {
+        let (db, f) = TestDB::from_fixture(fixture).unwrap();
+        let compes = super::completions(&db, f[0], None).expect("No completion");
+        let item = compes
+            .iter()
+            .find(|item| item.label == label)
+            .expect("No expected completion");
+        assert_eq!(item.documentation.as_deref(), expect);
+    }
+
+    #[test]
+    fn doc_comment() {
+        check_doc("let /** Foo. */ foo = 1; in f$0", "foo", Some("Foo."));
+        check_doc("{\n  # Foo.\n  foo = x: x;\n}.f$0", "foo", Some("Foo."));
+        check_doc("let foo = 1; in f$0", "foo", None);
+    }
+
     #[test]
     fn local_binding() {
         check(
@@ -858,4 +965,126 @@ stdenv.mkDerivation {
         check_no(r#"let "a b" = 1; in a$0"#, "a b");
         check_no(r#"let "a b" = 1; in a$0"#, r#""a b""#);
     }
+
+    #[track_caller]
+    fn check_flake_output(fixture: &str, label: &str, expect: Expect) {
+        let (mut db, f) = TestDB::from_fixture(fixture).unwrap();
+        set_test_flake_outputs(&mut db);
+        let compes = super::completions(&db, f[0], None).expect("No completion");
+        let item = compes
+            .iter()
+            .find(|item| item.label == label)
+            .expect("No expected completion");
+        let got = format!(
+            "({:?}) {}\n{}\n",
+            item.kind,
+            item.description.as_deref().unwrap_or_default(),
+            item.documentation.as_deref().unwrap_or_default(),
+        );
+        expect.assert_eq(&got);
+    }
+
+    #[test]
+    fn flake_output() {
+        check_flake_output(
+            r#"
+#- /flake.nix
+{
+  inputs.nixpkgs.url = "github:NixOS/nixpkgs";
+  outputs = { self, ... }@inputs: {
+    packages.x86_64-linux.default = inputs.nixpkgs.packages.x86_64-linux.h$0;
+  };
+}
+            "#,
+            "hello",
+            expect![[r#"
+                (Field) A program that produces a familiar, friendly greeting
+                *Name:* `hello-2.12.1`
+
+                A program that produces a familiar, friendly greeting
+            "#]],
+        );
+        check_flake_output(
+            r#"
+#- /flake.nix
+{
+  inputs.nixpkgs.url = "github:NixOS/nixpkgs";
+  outputs = { self, nixpkgs }: let system = "aarch64-linux"; in {
+    packages.${system}.default = nixpkgs.packages.${system}.h$0;
+  };
+}
+            "#,
+            "hello",
+            expect![[r#"
+                (Field) A program that produces a familiar, friendly greeting
+                *Name:* `hello-2.12.1`
+
+                A program that produces a familiar, friendly greeting
+            "#]],
+        );
+        check_flake_output(
+            r#"
+#- /flake.nix
+{
+  outputs = { self, nixpkgs }: {
+    nixosConfigurations.foo = nixpkgs.lib.nixosSystem {
+      modules = [ self.nixosModules.d$0 ];
+    };
+  };
+}
+            "#,
+            "default",
+            expect![[r#"
+                (Field) { }
+
+            "#]],
+        );
+    }
+
+    #[track_caller]
+    fn check_unevaluated(fixture: &str, expect: Expect) {
+        let (mut db, f) = TestDB::from_fixture(fixture).unwrap();
+        set_test_flake_outputs(&mut db);
+        let got = super::unevaluated_flake_output(&db, f[0]);
+        expect.assert_eq(&format!("{got:?}"));
+    }
+
+    #[test]
+    fn unevaluated_flake_output() {
+        let fixture = |path: &str| {
+            format!(
+                "
+#- /flake.nix
+{{
+  inputs.nixpkgs.url = \"github:NixOS/nixpkgs\";
+  outputs = {{ self, nixpkgs }}: let system = \"x86_64-linux\"; in {{
+    packages.${{system}}.default = {path};
+  }};
+}}
+                "
+            )
+        };
+        check_unevaluated(
+            &fixture("nixpkgs.legacyPackages.${system}.python3Packages.$0"),
+            expect![[
+                r#"Some(FlakeOutputPath { input: Some("nixpkgs"), attrpath: ["legacyPackages", "x86_64-linux", "python3Packages"] })"#
+            ]],
+        );
+        check_unevaluated(
+            &fixture("nixpkgs.legacyPackages.x86_64-linux.python3Packages.req$0"),
+            expect![[
+                r#"Some(FlakeOutputPath { input: Some("nixpkgs"), attrpath: ["legacyPackages", "x86_64-linux", "python3Packages"] })"#
+            ]],
+        );
+        // Already evaluated.
+        check_unevaluated(
+            &fixture("nixpkgs.legacyPackages.x86_64-linux.py$0"),
+            expect!["None"],
+        );
+        check_unevaluated(
+            &fixture("nixpkgs.packages.x86_64-linux.hello.$0"),
+            expect!["None"],
+        );
+        check_unevaluated(&fixture("self.nixosModules.$0"), expect!["None"]);
+    }
 }
@@ -1,6 +1,16 @@
-use crate::{DefDatabase, Diagnostic, FileId};
+use super::flake_lock;
+use crate::def::{Expr, ResolveResult};
+use crate::{DefDatabase, Diagnostic, DiagnosticKind, FileId};
+use nix_interop::info::NixDialect;
+use std::iter;
+use syntax::ast::{self, AstNode, BinaryOpKind};
+use syntax::{SyntaxKind, SyntaxToken, TextRange, TextSize};
 
 pub(crate) fn diagnostics(db: &dyn DefDatabase, file: FileId) -> Vec<Diagnostic> {
+    if flake_lock::is_flake_lock(db, file) {
+        return flake_lock::diagnostics(db, file);
+    }
+
     let mut diags = Vec::new();
 
     // Parsing.
@@ -18,13 +28,207 @@ pub(crate) fn diagnostics(db: &dyn DefDatabase, file: FileId) -> Vec<Diagnostic>
     let liveness = db.liveness_check(file);
     diags.extend(liveness.to_diagnostics(db, file));
 
+    // Flake inputs.
+    diags.extend(db.flake_input_check(file).to_diagnostics(db, file));
+
+    // Dialect specific syntax.
+    let dialect = db.nix_dialect();
+    diags.extend(dialect_diagnostics(db, file, dialect));
+    let diags = diags
+        .into_iter()
+        .map(|diag| diag.with_dialect(dialect))
+        .collect();
+
+    // Suppression comments.
+    suppress(db, file, diags)
+}
+
+fn dialect_diagnostics(db: &dyn DefDatabase, file: FileId, dialect: NixDialect) -> Vec<Diagnostic> {
+    let parse = db.parse(file);
+    let module = db.module(file);
+    let source_map = db.source_map(file);
+    let nameres = db.name_resolution(file);
+    // Both are experimental, since Lix 2.91 and Nix 2.24.
+    // Lix versions start from 2.90. Lower ones are CppNix-compatible versions reported by Lix,
+    // eg. `2.18.3-lix`, which tell nothing about Lix itself, so it is assumed to be recent.
+    let builtins = db.builtins();
+    let support_pipe = match dialect {
+        NixDialect::Lix => {
+            !builtins.nix_version_at_least("2.90") || builtins.nix_version_at_least("2.91")
+        }
+        NixDialect::CppNix => builtins.nix_version_at_least("2.24"),
+    };
+
+    let mut diags = Vec::new();
+    for (expr, kind) in module.exprs() {
+        let kind = match kind {
+            Expr::Binary(Some(BinaryOpKind::PipeRight | BinaryOpKind::PipeLeft), ..)
+                if !support_pipe =>
+            {
+                DiagnosticKind::PipeOperator
+            }
+            Expr::Reference(_)
+                if dialect == NixDialect::Lix
+                    && nameres.get(expr) == Some(&ResolveResult::Builtin("__curPos")) =>
+            {
+                DiagnosticKind::CurPos
+            }
+            _ => continue,
+        };
+        let Some(ptr) = source_map.node_for_expr(expr) else {
+            continue;
+        };
+        // Only report the operator for pipes.
+        let range = ast::BinaryOp::cast(ptr.to_node(&parse.syntax_node()))
+            .and_then(|op| op.op_token())
+            .map_or(ptr.text_range(), |tok| tok.text_range());
+        diags.push(Diagnostic::new(range, kind));
+    }
     diags
 }
 
+/// Remove diagnostics suppressed by comments, and report comments suppressing nothing.
+fn suppress(db: &dyn DefDatabase, file: FileId, diags: Vec<Diagnostic>) -> Vec<Diagnostic> {
+    let parse = db.parse(file);
+    let src = db.file_content(file);
+    let sups = parse
+        .syntax_node()
+        .descendants_with_tokens()
+        .filter_map(|elem| elem.into_token())
+        .filter(|tok| tok.kind() == SyntaxKind::COMMENT)
+        .filter_map(|tok| Suppression::parse(&src, &tok))
+        .collect::<Vec<_>>();
+    if sups.is_empty() {
+        return diags;
+    }
+
+    let mut used = vec![false; sups.len()];
+    let mut diags = diags
+        .into_iter()
+        .filter(|diag| {
+            let mut suppressed = false;
+            for (sup, used) in sups.iter().zip(&mut used) {
+                if sup.matches(diag) {
+                    *used = true;
+                    suppressed = true;
+                }
+            }
+            !suppressed
+        })
+        .collect::<Vec<_>>();
+    diags.extend(
+        sups.iter()
+            .zip(&used)
+            .filter(|(_, &used)| !used)
+            .map(|(sup, _)| Diagnostic::new(sup.comment, DiagnosticKind::UnusedSuppression))
+            .filter(|diag| !sups.iter().any(|sup| sup.matches(diag))),
+    );
+    diags
+}
+
+/// A suppression comment.
+/// - `# nil: ignore <code>...` suppresses diagnostics on the same line if it follows some code,
+///   otherwise on the next line, or the whole binding starting there.
+/// - `# nil: ignore-file <code>...` suppresses diagnostics in the whole file.
+///
+/// Codes are separated by spaces or commas. Everything after `--` is ignored as the reason.
+/// If no code is given, all diagnostics are suppressed.
+#[derive(Debug)]
+struct Suppression {
+    comment: TextRange,
+    /// `None` for the whole file.
+    scope: Option<TextRange>,
+    /// Empty for all codes.
+    codes: Vec<String>,
+}
+
+impl Suppression {
+    fn parse(src: &str, tok: &SyntaxToken) -> Option<Self> {
+        let text = tok.text();
+        let text = text
+            .strip_prefix('#')
+            .or_else(|| text.strip_prefix("/*")?.strip_suffix("*/"))?
+            .trim_start()
+            .strip_prefix("nil:")?
+            .trim_start();
+        let (is_file, rest) = match text.strip_prefix("ignore-file") {
+            Some(rest) => (true, rest),
+            None => (false, text.strip_prefix("ignore")?),
+        };
+        if rest.starts_with(|c: char| !c.is_whitespace()) {
+            return None;
+        }
+        let rest = rest.split_once("--").map_or(rest, |(codes, _reason)| codes);
+        let codes = rest
+            .split(|c: char| c.is_whitespace() || c == ',')
+            .filter(|code| !code.is_empty())
+            .map(Into::into)
+            .collect();
+
+        let comment = tok.text_range();
+        let scope = if is_file {
+            None
+        } else {
+            let line_of = |pos: TextSize| {
+                let pos = usize::from(pos);
+                let start = src[..pos].rfind('\n').map_or(0, |i| i + 1);
+                let end = src[pos..].find('\n').map_or(src.len(), |i| pos + i);
+                TextRange::new(
+                    TextSize::try_from(start).unwrap(),
+                    TextSize::try_from(end).unwrap(),
+                )
+            };
+            let line = line_of(comment.start());
+            if !src[TextRange::new(line.start(), comment.start())]
+                .trim()
+                .is_empty()
+            {
+                Some(line)
+            } else if let Some(next) = iter::successors(tok.next_token(), |tok| tok.next_token())
+                .find(|tok| !tok.kind().is_trivia())
+            {
+                let binding_end = next
+                    .parent_ancestors()
+                    .take_while(|node| node.text_range().start() >= comment.start())
+                    .filter(|node| {
+                        matches!(
+                            node.kind(),
+                            SyntaxKind::ATTR_PATH_VALUE
+                                | SyntaxKind::INHERIT
+                                | SyntaxKind::PAT_FIELD
+                        )
+                    })
+                    .last()
+                    .map_or(TextSize::from(0), |node| node.text_range().end());
+                let line = line_of(next.text_range().start());
+                Some(TextRange::new(line.start(), line.end().max(binding_end)))
+            } else {
+                Some(TextRange::empty(comment.end()))
+            }
+        };
+
+        Some(Self {
+            comment,
+            scope,
+            codes,
+        })
+    }
+
+    fn matches(&self, diag: &Diagnostic) -> bool {
+        self.scope
+            .map_or(true, |scope| scope.contains(diag.range.start()))
+            && (self.codes.is_empty() || self.codes.iter().any(|code| code == diag.code()))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::tests::TestDB;
+    use crate::SourceDatabase;
+    use builtin::Builtins;
     use expect_test::{expect, Expect};
+    use nix_interop::info::NixDialect;
+    use std::sync::Arc;
 
     fn check(fixture: &str, expect: Expect) {
         let (db, file_id) = TestDB::single_file(fixture).unwrap();
@@ -41,6 +245,26 @@ mod tests {
         expect.assert_eq(&got);
     }
 
+    fn check_dialect(dialect: NixDialect, fixture: &str, expect: Expect) {
+        check_target(dialect, None, fixture, expect);
+    }
+
+    fn check_target(dialect: NixDialect, version: Option<&str>, fixture: &str, expect: Expect) {
+        let (mut db, file_id) = TestDB::single_file(fixture).unwrap();
+        db.set_nix_dialect(dialect);
+        if let Some(version) = version {
+            db.set_builtins(Arc::new(
+                Builtins::clone(&Builtins::latest()).with_nix_version(version),
+            ));
+        }
+        let diags = super::diagnostics(&db, file_id);
+        let got = diags
+            .iter()
+            .map(|d| format!("{:?} {}\n", d.severity(), d.debug_display()))
+            .collect::<String>();
+        expect.assert_eq(&got);
+    }
+
     #[test]
     fn syntax_error() {
         check("1 == 2 == 3", expect!["7..9: SyntaxError(MultipleNoAssoc)"]);
@@ -86,4 +310,186 @@ mod tests {
             "#]],
         );
     }
+
+    #[test]
+    fn dialect() {
+        check_dialect(
+            NixDialect::CppNix,
+            "let { body = http://example.com; } // __curPos",
+            expect![[r#"
+                Warning 0..34: LetAttrset
+                Warning 13..31: UriLiteral
+            "#]],
+        );
+        check_dialect(
+            NixDialect::Lix,
+            "let { body = http://example.com; } // __curPos",
+            expect![[r#"
+                Error 0..34: LetAttrset
+                Error 13..31: UriLiteral
+                Warning 38..46: CurPos
+            "#]],
+        );
+    }
+
+    #[test]
+    fn pipe_operator() {
+        check_dialect(
+            NixDialect::CppNix,
+            "1 |> (x: x)",
+            expect![[r#"
+            Error 2..4: PipeOperator
+        "#]],
+        );
+        check_dialect(NixDialect::Lix, "1 |> (x: x)", expect![""]);
+        check_target(
+            NixDialect::CppNix,
+            Some("2.24.0"),
+            "1 |> (x: x)",
+            expect![""],
+        );
+        check_target(
+            NixDialect::Lix,
+            Some("2.90.0"),
+            "1 |> (x: x)",
+            expect![[r#"
+            Error 2..4: PipeOperator
+        "#]],
+        );
+        check_target(NixDialect::Lix, Some("2.91.0"), "1 |> (x: x)", expect![""]);
+        check_target(
+            NixDialect::Lix,
+            Some("2.18.3-lix"),
+            "1 |> (x: x)",
+            expect![""],
+        );
+    }
+
+    #[test]
+    fn suppression_next_line() {
+        check(
+            "
+let
+  # nil: ignore unused_binding
+  a = 1;
+  b = 2;
+  # nil: ignore unused_binding -- reason
+  c = let
+    d = 3;
+  in 1;
+  e = let
+    f = 4;
+  in 5;
+in x
+",
+            expect![[r#"
+                155..156: UndefinedName
+                46..47: UnusedBinding
+                125..126: UnusedBinding
+                137..138: UnusedBinding
+            "#]],
+        );
+    }
+
+    #[test]
+    fn suppression_same_line() {
+        check(
+            "
+let
+  a = 1; /* nil: ignore undefined_name, unused_with */
+  b = x;
+in b
+",
+            expect![[r#"
+                65..66: UndefinedName
+                6..7: UnusedBinding
+                13..58: UnusedSuppression
+            "#]],
+        );
+        check(
+            "let a = 1; in x # nil: ignore undefined_name\n",
+            expect!["4..5: UnusedBinding"],
+        );
+    }
+
+    #[test]
+    fn suppression_file() {
+        check(
+            "
+# nil: ignore-file unused_binding uri_literal
+let a = http://example.com; in x
+",
+            expect!["77..78: UndefinedName"],
+        );
+        let (db, file_id) = TestDB::single_file("# nil: ignore-file\nlet a = 1; in x").unwrap();
+        assert_eq!(super::diagnostics(&db, file_id), []);
+    }
+
+    #[test]
+    fn unused_suppression() {
+        check(
+            "
+# nil: ignore-file undefined_name
+# nil: ignore unused_binding
+let a = 1; in a
+",
+            expect![[r#"
+                0..33: UnusedSuppression
+                34..62: UnusedSuppression
+            "#]],
+        );
+        // Not a suppression comment.
+        check("# nil: ignored\nx", expect!["15..16: UndefinedName"]);
+    }
+
+    #[test]
+    fn flake_inputs() {
+        let (db, f) = TestDB::from_fixture(
+            r#"
+#- /flake.nix
+{
+  inputs.nixpkgs.url = "github:NixOS/nixpkgs";
+  inputs.unused.url = "github:foo/unused";
+  inputs.unlocked.url = "github:foo/unlocked";
+  inputs.hm.inputs.nixpkgs.follows = "nixpkgs";
+  inputs.hm.inputs.utils.follows = "nixpkgs/utils";
+  inputs.bad.follows = "missing";
+  outputs = { self, nixpkgs, hm, unlocked, bad, registry, undefined }: { };
+}
+#- /flake.lock
+{
+  "nodes": {
+    "hm": { "inputs": { "nixpkgs": [ "nixpkgs" ] }, "locked": { "narHash": "sha256-AAAA" } },
+    "nixpkgs": { "locked": { "narHash": "sha256-BBBB" } },
+    "registry": { "locked": { "narHash": "sha256-CCCC" } },
+    "root": {
+      "inputs": {
+        "bad": "nixpkgs",
+        "hm": "hm",
+        "nixpkgs": "nixpkgs",
+        "registry": "registry",
+        "unused": "nixpkgs"
+      }
+    }
+  },
+  "root": "root",
+  "version": 7
+}
+            "#,
+        )
+        .unwrap();
+        let got = super::diagnostics(&db, f["/flake.nix"])
+            .iter()
+            .filter(|d| d.code() != "unused_binding")
+            .map(|d| format!("{:?} {}\n", d.severity(), d.debug_display()))
+            .collect::<String>();
+        expect![[r#"
+            Warning 58..64: UnusedFlakeInput
+            Warning 101..109: UnlockedFlakeInput
+            Error 222..237: UndefinedFollows
+            Error 262..271: UndefinedFollows
+            Warning 331..340: UndefinedFlakeInput
+        "#]]
+        .assert_eq(&got);
+    }
 }
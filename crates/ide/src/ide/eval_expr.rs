@@ -0,0 +1,446 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use smol_str::SmolStr;
+use syntax::semantic::{escape_literal_attr, escape_string};
+use syntax::{best_token_at_offset, NodeOrToken, SyntaxKind, TextRange, TextSize};
+
+use super::flake_lock;
+use crate::def::{AstPtr, BindingValue, Expr, ExprId, NameId, NameResolution, ResolveResult};
+use crate::{DefDatabase, FileRange, Module, ModuleKind, ModuleSourceMap, NameKind};
+
+/// The flake defined by `flake.nix`, as relative paths are resolved against its directory.
+const SELF_FLAKE: &str = "builtins.getFlake (toString ./.)";
+
+/// A self-contained expression built from a selected one, to be evaluated by Nix.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClosedExpr {
+    /// The range of the selected expression.
+    pub range: TextRange,
+    /// The source to evaluate. Relative paths in it are relative to the directory of the file.
+    pub source: String,
+}
+
+/// Build a closed expression from the smallest expression covering `range`.
+///
+/// Free variables are bound from enclosing `let`s, `rec` attrsets and `with`s, and from the flake
+/// itself for parameters of `outputs` in `flake.nix`. The others are bound to `throw`s, so that
+/// the evaluation only fails if they are actually used.
+/// Bindings of each scope and `with`s are nested as in the source, so names resolve the same.
+pub(crate) fn closed_expr(
+    db: &dyn DefDatabase,
+    FileRange { file_id, range }: FileRange,
+) -> Option<ClosedExpr> {
+    if flake_lock::is_flake_lock(db, file_id) {
+        return None;
+    }
+    let parse = db.parse(file_id);
+    let source_map = db.source_map(file_id);
+
+    let elem = if range.is_empty() {
+        NodeOrToken::Token(best_token_at_offset(&parse.syntax_node(), range.start())?)
+    } else {
+        parse.syntax_node().covering_element(range)
+    };
+    let node = match elem {
+        NodeOrToken::Node(node) => Some(node),
+        NodeOrToken::Token(tok) => tok.parent(),
+    };
+    let (root_range, root) = std::iter::successors(node, |node| node.parent())
+        // Keys of attrpaths are lowered into string literals, which are not interesting.
+        .filter(|node| {
+            node.parent()
+                .map_or(true, |p| p.kind() != SyntaxKind::ATTR_PATH)
+        })
+        .find_map(|node| {
+            let expr = source_map.expr_for_node(AstPtr::new(&node))?;
+            Some((node.text_range(), expr))
+        })?;
+
+    let module = db.module(file_id);
+    let flake_params = match &*db.module_kind(file_id) {
+        &ModuleKind::FlakeNix {
+            outputs_expr: Some(outputs_expr),
+            ..
+        } => match &module[outputs_expr] {
+            Expr::Lambda(param, pat, _) => param
+                .iter()
+                .copied()
+                .chain(
+                    pat.iter()
+                        .flat_map(|pat| pat.fields.iter().filter_map(|&(name, _)| name)),
+                )
+                .collect(),
+            _ => HashSet::new(),
+        },
+        _ => HashSet::new(),
+    };
+
+    let mut definitions = HashMap::new();
+    let mut scopes = HashMap::new();
+    for (expr, kind) in module.exprs() {
+        let scope = source_map
+            .node_for_expr(expr)
+            .map_or(TextSize::default(), |ptr| ptr.text_range().start());
+        match kind {
+            Expr::LetIn(bindings, _) | Expr::RecAttrset(bindings) => {
+                for &(name, value) in bindings.statics.iter() {
+                    scopes.insert(name, scope);
+                    let def = match value {
+                        BindingValue::Expr(e) => Definition::Expr(e),
+                        BindingValue::Inherit(e) => Definition::Inherit(e),
+                        BindingValue::InheritFrom(idx) => {
+                            Definition::InheritFrom(bindings.inherit_froms[idx])
+                        }
+                    };
+                    definitions.insert(name, def);
+                }
+            }
+            Expr::Lambda(param, pat, _) => {
+                scopes.extend(param.iter().map(|&name| (name, scope)));
+                for &(name, default_expr) in pat.iter().flat_map(|pat| pat.fields.iter()) {
+                    let Some(name) = name else { continue };
+                    scopes.insert(name, scope);
+                    if let Some(e) = default_expr {
+                        definitions.insert(name, Definition::Expr(e));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let src = db.file_content(file_id);
+    let mut builder = Builder {
+        src: &src,
+        module: &module,
+        source_map: &source_map,
+        nameres: &db.name_resolution(file_id),
+        definitions,
+        scopes,
+        flake_params,
+        visited_names: HashSet::new(),
+        visited_withs: HashSet::new(),
+        layers: BTreeMap::new(),
+    };
+    builder.collect(root);
+
+    let mut source = String::new();
+    for layer in builder.layers.values() {
+        match layer {
+            Layer::Let(bindings) => {
+                source.push_str("let\n");
+                for (name, value) in bindings.values() {
+                    source += &format!("  {} = {value};\n", escape_literal_attr(name));
+                }
+                source.push_str("in\n");
+            }
+            Layer::With(env) => source += &format!("with {env};\n"),
+        }
+    }
+    source.push_str(&src[root_range]);
+
+    Some(ClosedExpr {
+        range: root_range,
+        source,
+    })
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Definition {
+    Expr(ExprId),
+    Inherit(ExprId),
+    InheritFrom(ExprId),
+}
+
+/// A scope wrapping the selected expression.
+enum Layer {
+    /// Bindings of a `let`, `rec` attrset or lambda, ordered by their definition sites.
+    Let(BTreeMap<TextSize, (SmolStr, String)>),
+    /// The environment of a `with`.
+    With(String),
+}
+
+struct Builder<'a> {
+    src: &'a str,
+    module: &'a Module,
+    source_map: &'a ModuleSourceMap,
+    nameres: &'a NameResolution,
+    definitions: HashMap<NameId, Definition>,
+    /// Start positions of the expressions defining names.
+    scopes: HashMap<NameId, TextSize>,
+    /// Parameters of `outputs` of `flake.nix`.
+    flake_params: HashSet<NameId>,
+    visited_names: HashSet<NameId>,
+    visited_withs: HashSet<ExprId>,
+    /// Scopes of free variables by their start positions, from the outermost to the innermost.
+    layers: BTreeMap<TextSize, Layer>,
+}
+
+impl Builder<'_> {
+    fn expr_range(&self, expr: ExprId) -> Option<TextRange> {
+        Some(self.source_map.node_for_expr(expr)?.text_range())
+    }
+
+    fn expr_text(&self, expr: ExprId) -> Option<&str> {
+        Some(&self.src[self.expr_range(expr)?])
+    }
+
+    /// Bind free variables of `root`, which are defined outside of it.
+    fn collect(&mut self, root: ExprId) {
+        let Some(root_range) = self.expr_range(root) else {
+            return;
+        };
+        let mut refs = Vec::new();
+        let mut stack = vec![root];
+        while let Some(e) = stack.pop() {
+            if let Expr::Reference(_) = &self.module[e] {
+                refs.push(e);
+            }
+            self.module[e].walk_child_exprs(|child| stack.push(child));
+        }
+        for e in refs {
+            self.collect_reference(e, root_range);
+        }
+    }
+
+    fn collect_reference(&mut self, expr: ExprId, root_range: TextRange) {
+        let is_outside =
+            |range: Option<TextRange>| range.map_or(true, |r| !root_range.contains_range(r));
+        let nameres = self.nameres;
+        match nameres.get(expr) {
+            None | Some(ResolveResult::Builtin(_)) => {}
+            Some(&ResolveResult::Definition(name)) => {
+                if is_outside(self.name_range(name)) {
+                    self.bind_name(name);
+                }
+            }
+            Some(ResolveResult::WithExprs(withs)) => {
+                for &with_expr in withs {
+                    if is_outside(self.expr_range(with_expr)) {
+                        self.bind_with(with_expr);
+                    }
+                }
+            }
+        }
+    }
+
+    fn name_range(&self, name: NameId) -> Option<TextRange> {
+        Some(self.source_map.nodes_for_name(name).next()?.text_range())
+    }
+
+    fn bind_name(&mut self, name: NameId) {
+        if !self.visited_names.insert(name) {
+            return;
+        }
+        let text = self.module[name].text.clone();
+        let value = if self.flake_params.contains(&name) {
+            if self.module[name].kind == NameKind::Param {
+                // The `@inputs` pattern.
+                format!("({SELF_FLAKE}).inputs // {{ self = {SELF_FLAKE}; }}")
+            } else if text == "self" {
+                SELF_FLAKE.into()
+            } else {
+                format!("({SELF_FLAKE}).inputs.{}", escape_literal_attr(&text))
+            }
+        } else {
+            match self.definitions.get(&name).copied() {
+                Some(Definition::Expr(e)) => {
+                    self.collect(e);
+                    self.expr_text(e).unwrap_or("null").into()
+                }
+                // `inherit x;` binds the same name from the outer scope.
+                Some(Definition::Inherit(e)) => {
+                    if let Some(range) = self.expr_range(e) {
+                        self.collect_reference(e, TextRange::empty(range.start()));
+                    }
+                    return;
+                }
+                Some(Definition::InheritFrom(from)) => {
+                    self.collect(from);
+                    format!(
+                        "({}).{}",
+                        self.expr_text(from).unwrap_or("null"),
+                        escape_literal_attr(&text),
+                    )
+                }
+                None => throw(&format!("`{text}` is not bound outside of the selection")),
+            }
+        };
+
+        let pos = self
+            .name_range(name)
+            .map_or(TextSize::default(), |r| r.start());
+        let scope = self.scopes.get(&name).copied().unwrap_or_default();
+        if let Layer::Let(bindings) = self
+            .layers
+            .entry(scope)
+            .or_insert_with(|| Layer::Let(BTreeMap::new()))
+        {
+            bindings.insert(pos, (text, value));
+        }
+    }
+
+    fn bind_with(&mut self, with_expr: ExprId) {
+        if !self.visited_withs.insert(with_expr) {
+            return;
+        }
+        let (Expr::With(env, _), Some(range)) =
+            (&self.module[with_expr], self.expr_range(with_expr))
+        else {
+            return;
+        };
+        let env = *env;
+        self.collect(env);
+        if let Some(text) = self.expr_text(env) {
+            self.layers
+                .insert(range.start(), Layer::With(format!("({text})")));
+        }
+    }
+}
+
+fn throw(msg: &str) -> String {
+    format!("throw {}", escape_string(msg))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::TestDB;
+    use expect_test::{expect, Expect};
+
+    fn check(fixture: &str, expect: Expect) {
+        let (db, f) = TestDB::from_fixture(fixture).unwrap();
+        let ret = super::closed_expr(&db, f.unwrap_single_range_marker()).unwrap();
+        expect.assert_eq(&ret.source);
+    }
+
+    #[test]
+    fn closed() {
+        check("$0x: x$1", expect!["x: x"]);
+        check(
+            "let a = 1; in $0let b = 2; in b$1",
+            expect!["let b = 2; in b"],
+        );
+        // Expand to the smallest expression.
+        check("[ 1 (2 $0+$1 3) ]", expect!["2 + 3"]);
+        check("{ a = 1; }.$0a", expect!["{ a = 1; }.a"]);
+    }
+
+    #[test]
+    fn let_bindings() {
+        check(
+            "let a = b + 1; b = 1; c = 2; in $0a * a$1",
+            expect![[r#"
+                let
+                  a = b + 1;
+                  b = 1;
+                in
+                a * a"#]],
+        );
+        check(
+            "rec { xs = [ 1 ] ++ xs; ys = $0xs$1; }",
+            expect![[r#"
+                let
+                  xs = [ 1 ] ++ xs;
+                in
+                xs"#]],
+        );
+        check(
+            "let a = 1; set = { b = 2; }; in let inherit a; inherit (set) b; in $0a + b$1",
+            expect![[r#"
+                let
+                  a = 1;
+                  set = { b = 2; };
+                in
+                let
+                  b = (set).b;
+                in
+                a + b"#]],
+        );
+    }
+
+    #[test]
+    fn with() {
+        check(
+            "let pkgs = { }; in with pkgs; with lib; $0[ hello ]$1",
+            expect![[r#"
+                let
+                  pkgs = { };
+                in
+                with (pkgs);
+                with (lib);
+                [ hello ]"#]],
+        );
+    }
+
+    #[test]
+    fn nested_scopes() {
+        // Bindings are nested inside the `with` they use.
+        check(
+            "with pkgs; let a = hello; in $0a$1",
+            expect![[r#"
+                with (pkgs);
+                let
+                  a = hello;
+                in
+                a"#]],
+        );
+        // Shadowed names are kept in their own scopes.
+        check(
+            "let a = 1; f = x: a; in let a = 2; in $0f a$1",
+            expect![[r#"
+                let
+                  a = 1;
+                  f = x: a;
+                in
+                let
+                  a = 2;
+                in
+                f a"#]],
+        );
+    }
+
+    #[test]
+    fn unbound() {
+        check(
+            "{ a, b ? a }: $0[ b ]$1",
+            expect![[r#"
+                let
+                  a = throw "`a` is not bound outside of the selection";
+                  b = a;
+                in
+                [ b ]"#]],
+        );
+        check(
+            "let a = 1; f = a: a; in let a = 2; in $0f a$1",
+            expect![[r#"
+                let
+                  f = a: a;
+                in
+                let
+                  a = 2;
+                in
+                f a"#]],
+        );
+    }
+
+    #[test]
+    fn flake_inputs() {
+        check(
+            r#"
+#- /flake.nix input:nixpkgs=/nix/store/eeee
+{
+    outputs = { self, nixpkgs }@inputs: {
+        x = $0[ self nixpkgs inputs ]$1;
+    };
+}
+            "#,
+            expect![[r#"
+                let
+                  self = builtins.getFlake (toString ./.);
+                  nixpkgs = (builtins.getFlake (toString ./.)).inputs.nixpkgs;
+                  inputs = (builtins.getFlake (toString ./.)).inputs // { self = builtins.getFlake (toString ./.); };
+                in
+                [ self nixpkgs inputs ]"#]],
+        );
+    }
+}
@@ -1,3 +1,4 @@
+use super::flake_lock;
 use crate::{DefDatabase, FileRange};
 use syntax::{best_token_at_offset, NodeOrToken, SyntaxKind, SyntaxNode, TextRange, T};
 
@@ -7,6 +8,9 @@ pub(crate) fn expand_selection(
     db: &dyn DefDatabase,
     FileRange { file_id, range }: FileRange,
 ) -> Option<Vec<TextRange>> {
+    if flake_lock::is_flake_lock(db, file_id) {
+        return None;
+    }
     let parse = db.parse(file_id);
 
     let mut ret = Vec::new();
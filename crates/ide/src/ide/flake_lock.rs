@@ -0,0 +1,650 @@
+//! Language features for `flake.lock`.
+//!
+//! The lock file is JSON rather than Nix, so it is parsed by [`DefDatabase::lock_file`]
+//! instead of going through the Nix parser and lowering.
+//!
+//! <https://github.com/NixOS/nix/blob/2.13.1/src/nix/flake.md#lock-files>
+use super::links::{try_resolve_link_uri, Link, LinkTarget};
+use super::{GotoDefinitionResult, HoverResult, NavigationTarget};
+use crate::def::LockElement;
+use crate::{DefDatabase, Diagnostic, DiagnosticKind, FileId, FilePos, FileRange, ModuleKind};
+use nix_interop::flake_lock::FlakeRefAttrs;
+use nix_interop::{FLAKE_FILE, FLAKE_LOCK_FILE};
+use std::collections::HashSet;
+
+pub(crate) fn is_flake_lock(db: &dyn DefDatabase, file: FileId) -> bool {
+    let source_root = db.source_root(db.file_source_root(file));
+    source_root
+        .path_for_file(file)
+        .as_path()
+        .and_then(|path| path.file_name())
+        .is_some_and(|name| name == FLAKE_LOCK_FILE)
+}
+
+/// Get the `flake.nix` alongside a lock file.
+fn flake_file_of_lock(db: &dyn DefDatabase, file: FileId) -> Option<FileId> {
+    let source_root = db.source_root(db.file_source_root(file));
+    let mut path = source_root.path_for_file(file).clone();
+    if !path.pop() {
+        return None;
+    }
+    source_root.file_for_path(&path.join(FLAKE_FILE)?)
+}
+
+pub(crate) fn diagnostics(db: &dyn DefDatabase, file: FileId) -> Vec<Diagnostic> {
+    let lock_file = match db.lock_file(file) {
+        Ok(lock_file) => lock_file,
+        Err(err) => {
+            return vec![Diagnostic::new(err.range, DiagnosticKind::InvalidFlakeLock)
+                .with_note(FileRange::new(file, err.range), err.message)]
+        }
+    };
+    let Some(flake_file) = flake_file_of_lock(db, file) else {
+        return Vec::new();
+    };
+    let ModuleKind::FlakeNix {
+        explicit_inputs,
+        param_inputs,
+        ..
+    } = &*db.module_kind(flake_file)
+    else {
+        return Vec::new();
+    };
+    let root_id = lock_file.lock().root();
+    let Some((root_key_range, _)) = lock_file.node_range(root_id) else {
+        return Vec::new();
+    };
+
+    let mut diags = Vec::new();
+    let mut locked = HashSet::new();
+    for (name, range) in lock_file.inputs(root_id) {
+        locked.insert(name);
+        if !explicit_inputs.contains_key(name) && !param_inputs.contains_key(name) {
+            diags.push(
+                Diagnostic::new(range, DiagnosticKind::OutdatedFlakeLock).with_note(
+                    FileRange::new(file, range),
+                    format!("Input `{name}` is not declared in `{FLAKE_FILE}`"),
+                ),
+            );
+        }
+    }
+
+    let mut missing = explicit_inputs
+        .keys()
+        .chain(param_inputs.keys())
+        .filter(|name| !locked.contains(&***name))
+        .collect::<Vec<_>>();
+    missing.sort_unstable();
+    missing.dedup();
+    if !missing.is_empty() {
+        // Report on the `inputs` key of the root node, or the root node itself.
+        let range = lock_file
+            .node_field_range(root_id, "inputs")
+            .unwrap_or(root_key_range);
+        let diag = missing.into_iter().fold(
+            Diagnostic::new(range, DiagnosticKind::OutdatedFlakeLock),
+            |diag, name| {
+                diag.with_note(
+                    FileRange::new(file, range),
+                    format!("Input `{name}` is declared in `{FLAKE_FILE}` but not locked"),
+                )
+            },
+        );
+        diags.push(diag);
+    }
+
+    diags
+}
+
+pub(crate) fn hover(
+    db: &dyn DefDatabase,
+    FilePos { file_id, pos }: FilePos,
+) -> Option<HoverResult> {
+    let lock_file = db.lock_file(file_id).ok()?;
+    let (id, range) = match lock_file.element_at(pos)? {
+        LockElement::Node { id, range } => (id, range),
+        LockElement::Input { target, range, .. } | LockElement::Reference { target, range } => {
+            (target?, range)
+        }
+    };
+    let lock = lock_file.lock();
+    let node = lock.node(id)?;
+
+    let mut sections = Vec::new();
+    if id == lock.root() {
+        sections.push(format!("Lock node `{id}` of the current flake"));
+    } else {
+        sections.push(format!("Lock node `{id}`"));
+    }
+    if let Some(original) = node.original().and_then(flake_ref) {
+        sections.push(format!("*Original:* `{original}`"));
+    }
+    if let Some(locked) = node.locked() {
+        if let Some(locked_ref) = flake_ref(locked) {
+            sections.push(format!("*Locked:* `{locked_ref}`"));
+        }
+        if let Some(time) = locked
+            .get("lastModified")
+            .and_then(serde_json::Value::as_i64)
+        {
+            sections.push(format!("*Last modified:* {}", format_timestamp(time)));
+        }
+    }
+    if !node.is_flake() {
+        sections.push("*Not a flake.*".into());
+    }
+    let followers = lock.followers(id);
+    if !followers.is_empty() {
+        let followers = followers
+            .iter()
+            .map(|path| format!("`{path}`"))
+            .collect::<Vec<_>>()
+            .join(", ");
+        sections.push(format!("*Followed by:* {followers}"));
+    }
+
+    Some(HoverResult {
+        range,
+        markup: sections.join("\n\n"),
+    })
+}
+
+pub(crate) fn goto_definition(
+    db: &dyn DefDatabase,
+    FilePos { file_id, pos }: FilePos,
+) -> Option<GotoDefinitionResult> {
+    let lock_file = db.lock_file(file_id).ok()?;
+    let lock = lock_file.lock();
+    let targets = match lock_file.element_at(pos)? {
+        // Nodes and inputs of the current flake go to the declarations in `flake.nix`.
+        LockElement::Node { id, .. } => {
+            let flake_file = flake_file_of_lock(db, file_id)?;
+            lock.node(lock.root())?
+                .input_names()
+                .filter(|name| lock.resolve_input(lock.root(), name) == Some(id))
+                .flat_map(|name| input_declarations(db, flake_file, name))
+                .collect()
+        }
+        LockElement::Input { owner, name, .. } if owner == lock.root() => {
+            let flake_file = flake_file_of_lock(db, file_id)?;
+            input_declarations(db, flake_file, name)
+        }
+        // Others go to the referred node in the lock file.
+        LockElement::Input { target, .. } | LockElement::Reference { target, .. } => {
+            let (focus_range, full_range) = lock_file.node_range(target?)?;
+            vec![NavigationTarget {
+                file_id,
+                focus_range,
+                full_range,
+            }]
+        }
+    };
+    if targets.is_empty() {
+        return None;
+    }
+    Some(GotoDefinitionResult::Targets(targets))
+}
+
+/// Get where an input is declared in `flake.nix`, either in `inputs` or the parameter of
+/// `outputs`.
+fn input_declarations(
+    db: &dyn DefDatabase,
+    flake_file: FileId,
+    name: &str,
+) -> Vec<NavigationTarget> {
+    let ModuleKind::FlakeNix {
+        explicit_inputs,
+        param_inputs,
+        ..
+    } = &*db.module_kind(flake_file)
+    else {
+        return Vec::new();
+    };
+    let Some(&name_id) = explicit_inputs.get(name).or_else(|| param_inputs.get(name)) else {
+        return Vec::new();
+    };
+    db.source_map(flake_file)
+        .nodes_for_name(name_id)
+        .map(|ptr| NavigationTarget {
+            file_id: flake_file,
+            focus_range: ptr.text_range(),
+            full_range: ptr.text_range(),
+        })
+        .collect()
+}
+
+pub(crate) fn links(db: &dyn DefDatabase, file: FileId) -> Vec<Link> {
+    let Ok(lock_file) = db.lock_file(file) else {
+        return Vec::new();
+    };
+    lock_file
+        .node_ids()
+        .filter_map(|id| {
+            let range = lock_file.node_field_range(id, "locked")?;
+            let locked = lock_file.lock().node(id)?.locked()?;
+            // Not all URL schemes of flake references can be converted, eg. `tarball+https`.
+            let uri = flake_ref(locked)
+                .and_then(|flake_ref| try_resolve_link_uri(&flake_ref))
+                .or_else(|| try_resolve_link_uri(locked.get("url")?.as_str()?))?;
+            Some(Link::Resolved {
+                range,
+                tooltip: uri.as_str().to_owned(),
+                target: LinkTarget::Uri(uri),
+            })
+        })
+        .collect()
+}
+
+/// Format attributes of `original` or `locked` as a flake reference.
+///
+/// <https://nixos.org/manual/nix/unstable/command-ref/new-cli/nix3-flake.html#types>
+fn flake_ref(attrs: &FlakeRefAttrs) -> Option<String> {
+    // Stringify scalars, as attributes of flake references.
+    let attr = |name: &str| match attrs.get(name)? {
+        serde_json::Value::String(s) => Some(s.clone()),
+        serde_json::Value::Number(n) => Some(n.to_string()),
+        serde_json::Value::Bool(b) => Some(if *b { "1" } else { "0" }.into()),
+        _ => None,
+    };
+    let ty = attr("type")?;
+    let mut query = Vec::new();
+    let mut ret = match &*ty {
+        "github" | "gitlab" | "sourcehut" => {
+            let mut ret = format!("{ty}:{}/{}", attr("owner")?, attr("repo")?);
+            if let Some(rev_or_ref) = attr("rev").or_else(|| attr("ref")) {
+                ret += "/";
+                ret += &rev_or_ref;
+            }
+            ret
+        }
+        "indirect" => {
+            let mut ret = format!("flake:{}", attr("id")?);
+            for name in ["ref", "rev"] {
+                if let Some(value) = attr(name) {
+                    ret += "/";
+                    ret += &value;
+                }
+            }
+            ret
+        }
+        "path" => format!("path:{}", attr("path")?),
+        "git" | "hg" => {
+            for name in ["ref", "rev"] {
+                if let Some(value) = attr(name) {
+                    query.push(format!("{name}={value}"));
+                }
+            }
+            format!("{ty}+{}", attr("url")?)
+        }
+        "tarball" | "file" => format!("{ty}+{}", attr("url")?),
+        _ => return None,
+    };
+    if let Some(dir) = attr("dir") {
+        query.push(format!("dir={dir}"));
+    }
+    if !query.is_empty() {
+        ret.push(if ret.contains('?') { '&' } else { '?' });
+        ret += &query.join("&");
+    }
+    Some(ret)
+}
+
+/// Format a Unix timestamp as a UTC date and time.
+fn format_timestamp(secs: i64) -> String {
+    // Civil from days. See: <https://howardhinnant.github.io/date_algorithms.html>
+    let (days, secs) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02}:{:02} UTC",
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::base::SourceDatabase;
+    use crate::tests::TestDB;
+    use expect_test::{expect, Expect};
+
+    const FIXTURE: &str = r#"
+#- /flake.nix
+{
+  inputs.nixpkgs.url = "github:NixOS/nixpkgs/nixos-unstable";
+  inputs.home-manager.url = "github:nix-community/home-manager";
+  inputs.home-manager.inputs.nixpkgs.follows = "nixpkgs";
+  outputs = { nixpkgs, home-manager, utils, ... }: { };
+}
+
+#- /flake.lock
+{
+  "nodes": {
+    "home-manager": {
+      "inputs": {
+        "nixpkgs": [
+          "nixpkgs"
+        ]
+      },
+      "locked": {
+        "lastModified": 1674440933,
+        "narHash": "sha256-AAAA",
+        "owner": "nix-community",
+        "repo": "home-manager",
+        "rev": "65c47ced082e3353113614f77b1bc18822dc731f",
+        "type": "github"
+      },
+      "original": {
+        "owner": "nix-community",
+        "repo": "home-manager",
+        "type": "github"
+      }
+    },
+    "nixpkgs": {
+      "locked": {
+        "lastModified": 1674236650,
+        "narHash": "sha256-BBBB",
+        "owner": "NixOS",
+        "repo": "nixpkgs",
+        "rev": "cfb43ad7b941d9c3606fb35d91228da7ebddbfc5",
+        "type": "github"
+      },
+      "original": {
+        "owner": "NixOS",
+        "ref": "nixos-unstable",
+        "repo": "nixpkgs",
+        "type": "github"
+      }
+    },
+    "old": {
+      "flake": false,
+      "locked": {
+        "lastModified": 1600000000,
+        "narHash": "sha256-CCCC",
+        "type": "tarball",
+        "url": "https://example.com/old.tar.gz"
+      },
+      "original": {
+        "type": "tarball",
+        "url": "https://example.com/old.tar.gz"
+      }
+    },
+    "root": {
+      "inputs": {
+        "home-manager": "home-manager",
+        "nixpkgs": "nixpkgs",
+        "old": "old"
+      }
+    }
+  },
+  "root": "root",
+  "version": 7
+}
+"#;
+
+    /// Put the marker before the `n`-th occurrence of `needle` in the lock file.
+    fn fixture_at(needle: &str, n: usize) -> String {
+        let lock_start = FIXTURE.find("#- /flake.lock").unwrap();
+        let pos = FIXTURE[lock_start..]
+            .match_indices(needle)
+            .nth(n)
+            .unwrap()
+            .0
+            + lock_start;
+        format!("{}$0{}", &FIXTURE[..pos], &FIXTURE[pos..])
+    }
+
+    #[track_caller]
+    fn check_hover(fixture: &str, full: &str, expect: Expect) {
+        let (db, f) = TestDB::from_fixture(fixture).unwrap();
+        let ret = super::hover(&db, f[0]).expect("No hover");
+        let src = db.file_content(f[0].file_id);
+        assert_eq!(full, &src[ret.range]);
+        expect.assert_eq(&(ret.markup + "\n"));
+    }
+
+    #[track_caller]
+    fn check_goto(fixture: &str, expect: Expect) {
+        let (db, f) = TestDB::from_fixture(fixture).unwrap();
+        let Some(GotoDefinitionResult::Targets(targets)) = goto_definition(&db, f[0]) else {
+            panic!("No definition");
+        };
+        let got = targets
+            .into_iter()
+            .map(|target| {
+                let src = db.file_content(target.file_id);
+                let path = db
+                    .source_root(db.file_source_root(target.file_id))
+                    .path_for_file(target.file_id)
+                    .clone();
+                let focus = &src[target.focus_range];
+                let full_lines = src[target.full_range].lines().count();
+                format!("{}: {focus} ({full_lines} lines)\n", path.display())
+            })
+            .collect::<String>();
+        expect.assert_eq(&got);
+    }
+
+    #[test]
+    fn timestamp() {
+        assert_eq!(format_timestamp(0), "1970-01-01 00:00:00 UTC");
+        assert_eq!(format_timestamp(1674236650), "2023-01-20 17:44:10 UTC");
+
+        // Leap years.
+        assert_eq!(format_timestamp(951_782_400), "2000-02-29 00:00:00 UTC");
+        assert_eq!(format_timestamp(1_709_208_000), "2024-02-29 12:00:00 UTC");
+        assert_eq!(format_timestamp(1_677_628_800), "2023-03-01 00:00:00 UTC");
+        assert_eq!(format_timestamp(4_107_542_400), "2100-03-01 00:00:00 UTC");
+
+        // Before the epoch.
+        assert_eq!(format_timestamp(-1), "1969-12-31 23:59:59 UTC");
+        assert_eq!(format_timestamp(-2_203_891_201), "1900-02-28 23:59:59 UTC");
+        assert_eq!(format_timestamp(-2_203_891_200), "1900-03-01 00:00:00 UTC");
+        assert_eq!(format_timestamp(-11_670_998_400), "1600-02-29 00:00:00 UTC");
+    }
+
+    #[test]
+    fn hover_node() {
+        check_hover(
+            &fixture_at("\"nixpkgs\": {", 0),
+            r#""nixpkgs""#,
+            expect![[r#"
+                Lock node `nixpkgs`
+
+                *Original:* `github:NixOS/nixpkgs/nixos-unstable`
+
+                *Locked:* `github:NixOS/nixpkgs/cfb43ad7b941d9c3606fb35d91228da7ebddbfc5`
+
+                *Last modified:* 2023-01-20 17:44:10 UTC
+
+                *Followed by:* `home-manager/nixpkgs`
+            "#]],
+        );
+        check_hover(
+            &fixture_at("\"old\"", 1),
+            r#""old""#,
+            expect![[r#"
+                Lock node `old`
+
+                *Original:* `tarball+https://example.com/old.tar.gz`
+
+                *Locked:* `tarball+https://example.com/old.tar.gz`
+
+                *Last modified:* 2020-09-13 12:26:40 UTC
+
+                *Not a flake.*
+            "#]],
+        );
+    }
+
+    #[test]
+    fn hover_follows() {
+        check_hover(
+            &fixture_at("[\n", 0),
+            "[\n          \"nixpkgs\"\n        ]",
+            expect![[r#"
+                Lock node `nixpkgs`
+
+                *Original:* `github:NixOS/nixpkgs/nixos-unstable`
+
+                *Locked:* `github:NixOS/nixpkgs/cfb43ad7b941d9c3606fb35d91228da7ebddbfc5`
+
+                *Last modified:* 2023-01-20 17:44:10 UTC
+
+                *Followed by:* `home-manager/nixpkgs`
+            "#]],
+        );
+    }
+
+    #[test]
+    fn goto_input_declaration() {
+        check_goto(
+            &fixture_at("\"nixpkgs\": {", 0),
+            expect![[r#"
+                /flake.nix: nixpkgs (1 lines)
+            "#]],
+        );
+        check_goto(
+            &fixture_at("\"home-manager\": \"", 0),
+            expect![[r#"
+                /flake.nix: home-manager (1 lines)
+                /flake.nix: home-manager (1 lines)
+            "#]],
+        );
+    }
+
+    #[test]
+    fn goto_lock_node() {
+        check_goto(
+            &fixture_at("\"old\"", 2),
+            expect![[r#"
+                /flake.lock: "old" (13 lines)
+            "#]],
+        );
+        check_goto(
+            &fixture_at("\"nixpkgs\": [", 0),
+            expect![[r#"
+                /flake.lock: "nixpkgs" (16 lines)
+            "#]],
+        );
+    }
+
+    #[test]
+    fn lock_links() {
+        let (db, f) = TestDB::from_fixture(FIXTURE).unwrap();
+        let file = f["/flake.lock"];
+        let src = db.file_content(file);
+        let got = links(&db, file)
+            .into_iter()
+            .map(|link| {
+                let Link::Resolved { range, tooltip, .. } = link else {
+                    panic!("Unresolved link");
+                };
+                let line = src[..range.start().into()].lines().count();
+                format!("{line}: {} -> {tooltip}\n", &src[range])
+            })
+            .collect::<String>();
+        expect![[r#"
+            9: "locked" -> https://github.com/nix-community/home-manager
+            24: "locked" -> https://github.com/NixOS/nixpkgs
+            41: "locked" -> https://example.com/old.tar.gz
+        "#]]
+        .assert_eq(&got);
+    }
+
+    #[test]
+    fn outdated() {
+        let (db, f) = TestDB::from_fixture(FIXTURE).unwrap();
+        let got = diagnostics(&db, f["/flake.lock"])
+            .iter()
+            .map(|diag| diag.debug_display().to_string() + "\n")
+            .collect::<String>();
+        expect![[r#"
+            1312..1317: OutdatedFlakeLock
+                1312..1317: Input `old` is not declared in `flake.nix`
+            1222..1230: OutdatedFlakeLock
+                1222..1230: Input `utils` is declared in `flake.nix` but not locked
+        "#]]
+        .assert_eq(&got);
+    }
+
+    #[test]
+    fn nix_features_are_empty() {
+        let (db, f) = TestDB::from_fixture(&fixture_at("\"nixpkgs\"", 0)).unwrap();
+        let file = f[0].file_id;
+        assert_eq!(
+            super::super::syntax_highlighting::highlight(&db, file, None),
+            []
+        );
+        assert_eq!(
+            super::super::symbol_hierarchy::symbol_hierarchy(&db, file),
+            []
+        );
+        assert_eq!(super::super::completion::completions(&db, f[0], None), None);
+    }
+
+    #[test]
+    fn invalid() {
+        #[track_caller]
+        fn check(lock_src: &str, expect: Expect) {
+            let (db, f) = TestDB::from_fixture(&format!("#- /flake.lock\n{lock_src}")).unwrap();
+            let got = diagnostics(&db, f["/flake.lock"])
+                .iter()
+                .map(|diag| diag.debug_display().to_string() + "\n")
+                .collect::<String>();
+            expect.assert_eq(&got);
+        }
+
+        check(
+            r#"{ "nodes": {}, "root": "root", }"#,
+            expect![[r#"
+            31..32: InvalidFlakeLock
+                31..32: trailing comma
+        "#]],
+        );
+        check(
+            r#"{ "nodes" {} }"#,
+            expect![[r#"
+            10..11: InvalidFlakeLock
+                10..11: expected `:`
+        "#]],
+        );
+        check(
+            r#"{ "nodes": {}, "root": "é"#,
+            expect![[r#"
+            26..26: InvalidFlakeLock
+                26..26: EOF while parsing a string
+        "#]],
+        );
+        check(
+            r#"{ "nodes": [], "root": "root", "version": 7 }"#,
+            expect![[r#"
+            11..13: InvalidFlakeLock
+                11..13: invalid type: sequence, expected a map
+        "#]],
+        );
+        check(
+            r#"{ "nodes": {}, "root": "root" }"#,
+            expect![[r#"
+            30..31: InvalidFlakeLock
+                30..31: missing field `version`
+        "#]],
+        );
+        check(
+            r#"{ "nodes": {}, "root": "root", "version": 7 } 1"#,
+            expect![[r#"
+                46..47: InvalidFlakeLock
+                    46..47: trailing characters
+            "#]],
+        );
+    }
+}
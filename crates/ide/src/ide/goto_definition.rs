@@ -1,6 +1,9 @@
+use super::flake_lock;
+use super::module_option::option_path_for_name;
 use super::NavigationTarget;
 use crate::def::{AstPtr, Expr, Literal, ResolveResult};
 use crate::{DefDatabase, FileId, FilePos, ModuleKind, VfsPath};
+use nix_interop::nixos_options::get_option;
 use nix_interop::FLAKE_FILE;
 use syntax::ast::{self, AstNode};
 use syntax::{best_token_at_offset, match_ast, SyntaxKind, SyntaxToken};
@@ -15,6 +18,10 @@ pub(crate) fn goto_definition(
     db: &dyn DefDatabase,
     FilePos { file_id, pos }: FilePos,
 ) -> Option<GotoDefinitionResult> {
+    if flake_lock::is_flake_lock(db, file_id) {
+        return flake_lock::goto_definition(db, FilePos { file_id, pos });
+    }
+
     let parse = db.parse(file_id);
     let tok = best_token_at_offset(&parse.syntax_node(), pos)?;
 
@@ -23,6 +30,11 @@ pub(crate) fn goto_definition(
         return Some(ret);
     }
 
+    // Special case for goto option declarations.
+    if let Some(ret) = goto_module_option(db, file_id, tok.clone()) {
+        return Some(ret);
+    }
+
     let ptr = tok.parent_ancestors().find_map(|node| {
         match_ast! {
             match node {
@@ -141,12 +153,29 @@ fn goto_flake_input(
     None
 }
 
+fn goto_module_option(
+    db: &dyn DefDatabase,
+    file: FileId,
+    tok: SyntaxToken,
+) -> Option<GotoDefinitionResult> {
+    let name_node = tok.parent_ancestors().find_map(ast::Name::cast)?;
+    let (source, path) = option_path_for_name(db, file, &name_node)?;
+    let opts = db.module_options(source);
+    let decl = get_option(&opts, &path)?
+        .declarations
+        .iter()
+        .find(|decl| decl.starts_with('/'))?;
+    Some(GotoDefinitionResult::Path(VfsPath::new(decl)))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::base::SourceDatabase;
-    use crate::tests::TestDB;
+    use crate::tests::{test_nixos_options, TestDB};
     use expect_test::{expect, Expect};
+    use nix_interop::nixos_options::OptionSource;
+    use std::sync::Arc;
 
     #[track_caller]
     fn check_no(fixture: &str) {
@@ -360,4 +389,21 @@ hello
             "#,
         );
     }
+
+    #[test]
+    fn module_option() {
+        let (mut db, f) =
+            TestDB::from_fixture("{ ... }: { services.nginx.$0enable = true; }").unwrap();
+        db.set_module_options(OptionSource::Nixos, Arc::new(test_nixos_options()));
+        assert_eq!(
+            goto_definition(&db, f[0]),
+            Some(GotoDefinitionResult::Path(VfsPath::new(
+                "/nix/store/nixpkgs/nixos/modules/services/web-servers/nginx/default.nix"
+            ))),
+        );
+
+        let (mut db, f) = TestDB::from_fixture("{ ... }: { services.$0nginx = { }; }").unwrap();
+        db.set_module_options(OptionSource::Nixos, Arc::new(test_nixos_options()));
+        assert_eq!(goto_definition(&db, f[0]), None);
+    }
 }
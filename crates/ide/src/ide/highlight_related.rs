@@ -1,3 +1,4 @@
+use super::flake_lock;
 use crate::def::{AstPtr, ResolveResult};
 use crate::{DefDatabase, FilePos};
 use syntax::ast::{self, AstNode};
@@ -15,6 +16,9 @@ pub struct HlRelated {
 }
 
 pub(crate) fn highlight_related(db: &dyn DefDatabase, fpos: FilePos) -> Option<Vec<HlRelated>> {
+    if flake_lock::is_flake_lock(db, fpos.file_id) {
+        return None;
+    }
     let parse = db.parse(fpos.file_id);
     let source_map = db.source_map(fpos.file_id);
     let tok = best_token_at_offset(&parse.syntax_node(), fpos.pos)?;
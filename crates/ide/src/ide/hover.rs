@@ -1,10 +1,13 @@
-use crate::def::{AstPtr, Expr, ResolveResult};
-use crate::ty::{DisplayConfig, Ty};
-use crate::{FilePos, NameKind, TyDatabase};
-use builtin::ALL_BUILTINS;
+use super::flake_lock;
+use super::module_option::option_path_for_name;
+use crate::def::{AstPtr, Expr, NameId, ResolveResult};
+use crate::ty::{AttrSource, DisplayConfig, Ty};
+use crate::{DefDatabase, FileId, FilePos, NameKind, TyDatabase};
+use builtin::Builtins;
 use if_chain::if_chain;
+use nix_interop::nixos_options::{get_option, Doc, Value};
 use std::fmt::Write;
-use syntax::ast::{self, AstNode};
+use syntax::ast::{self, AstNode, HasDocComment};
 use syntax::semantic::AttrKind;
 use syntax::{best_token_at_offset, match_ast, TextRange};
 
@@ -24,6 +27,10 @@ pub struct HoverResult {
 }
 
 pub(crate) fn hover(db: &dyn TyDatabase, FilePos { file_id, pos }: FilePos) -> Option<HoverResult> {
+    if flake_lock::is_flake_lock(db, file_id) {
+        return flake_lock::hover(db, FilePos { file_id, pos });
+    }
+
     let parse = db.parse(file_id);
     let tok = best_token_at_offset(&parse.syntax_node(), pos)?;
     let mut name_node = None;
@@ -48,12 +55,13 @@ pub(crate) fn hover(db: &dyn TyDatabase, FilePos { file_id, pos }: FilePos) -> O
     let source_map = db.source_map(file_id);
     let nameres = db.name_resolution(file_id);
     let infer = db.infer(file_id);
+    let builtins = db.builtins();
 
     let mut name = None;
 
     if let Some(expr) = source_map.expr_for_node(ptr.clone()) {
         if let Some(builtin) = nameres.check_builtin(expr, &module) {
-            return hover_builtin(builtin, range);
+            return hover_builtin(&builtins, builtin, range);
         }
 
         match nameres.get(expr) {
@@ -85,6 +93,13 @@ pub(crate) fn hover(db: &dyn TyDatabase, FilePos { file_id, pos }: FilePos) -> O
         }
     }
 
+    if let Some(ret) = name_node
+        .as_ref()
+        .and_then(|name_node| hover_module_option(db, file_id, name_node))
+    {
+        return Some(ret);
+    }
+
     if let Some(name) = name.or_else(|| source_map.name_for_node(ptr.clone())) {
         let ty = infer
             .ty_for_name(name)
@@ -98,10 +113,11 @@ pub(crate) fn hover(db: &dyn TyDatabase, FilePos { file_id, pos }: FilePos) -> O
             NameKind::Param => "Parameter",
             NameKind::PatField => "Field parameter",
         };
-        return Some(HoverResult {
-            range,
-            markup: format!("{kind} `{text}`\n`{ty}`"),
-        });
+        let mut markup = format!("{kind} `{text}`\n`{ty}`");
+        if let Some(doc) = name_doc_comment(db, file_id, name) {
+            write!(markup, "\n\n{doc}").unwrap();
+        }
+        return Some(HoverResult { range, markup });
     }
 
     // Selected attr type.
@@ -123,7 +139,7 @@ pub(crate) fn hover(db: &dyn TyDatabase, FilePos { file_id, pos }: FilePos) -> O
             if let Some(ResolveResult::Builtin("builtins")) = nameres.get(expr);
             if let Some(attr) = path_node.attrs().next();
             if let AttrKind::Static(Some(field)) = AttrKind::of(attr.clone());
-            if ALL_BUILTINS.contains_key(&field);
+            if builtins.contains_key(&field);
             then {
                 // `builtins.xxx.other`
                 //  ^^^^^^^^^^^^
@@ -133,28 +149,42 @@ pub(crate) fn hover(db: &dyn TyDatabase, FilePos { file_id, pos }: FilePos) -> O
                     .cover(attr.syntax().text_range());
                 // NB. Returns None when the field is invalid,
                 // since it is known to be incorrect.
-                return hover_builtin(&field, range);
+                return hover_builtin(&builtins, &field, range);
             }
         }
 
         let mut ty = infer.ty_for_expr(expr);
+        let mut src = AttrSource::Unknown;
         for attr in path_node.attrs() {
-            let AttrKind::Static(Some(field)) = AttrKind::of(attr.clone()) else {
-                return None;
+            let set = ty.as_attrset()?;
+            let (field_ty, field_src) = match AttrKind::of(attr.clone()) {
+                AttrKind::Static(Some(field)) => (set.get(&field)?, set.get_src(&field)?),
+                // Like `packages.${system}`.
+                AttrKind::Dynamic(_) => set.rest()?,
+                AttrKind::Static(None) => return None,
             };
-            ty = ty.as_attrset()?.get(&field)?.clone();
+            src = field_src;
+            ty = field_ty.clone();
             if attr.syntax() == name_node.syntax() {
                 break;
             }
         }
         let range = name_node.syntax().text_range();
-        let markup = format!(
+        let mut markup = format!(
             "Field `{}`\n`{}`",
             name_node
                 .token()
                 .map_or_else(String::new, |t| t.text().into()),
             ty.display_with(TY_DETAILED_DISPLAY),
         );
+        let doc = match src {
+            AttrSource::Name(name) => name_doc_comment(db, file_id, name),
+            AttrSource::FlakeOutput(idx) => flake_output_doc(db, file_id, idx),
+            AttrSource::Unknown | AttrSource::Builtin => None,
+        };
+        if let Some(doc) = doc {
+            write!(markup, "\n\n{doc}").unwrap();
+        }
         Some(HoverResult { range, markup })
     }) {
         return Some(ret);
@@ -163,8 +193,8 @@ pub(crate) fn hover(db: &dyn TyDatabase, FilePos { file_id, pos }: FilePos) -> O
     None
 }
 
-fn hover_builtin(name: &str, range: TextRange) -> Option<HoverResult> {
-    let b = ALL_BUILTINS.get(name)?;
+fn hover_builtin(builtins: &Builtins, name: &str, range: TextRange) -> Option<HoverResult> {
+    let b = builtins.get(name)?;
     let ty = crate::ty::known::BUILTINS
         .as_attrset()
         .unwrap()
@@ -175,16 +205,225 @@ fn hover_builtin(name: &str, range: TextRange) -> Option<HoverResult> {
         "`builtins.{name}`\n`{}`\n\n{}\n{}",
         ty.display_with(TY_DETAILED_DISPLAY),
         b.summary,
-        b.doc.unwrap_or("(No documentation from Nix)"),
+        b.doc.as_deref().unwrap_or("(No documentation from Nix)"),
     );
     Some(HoverResult { range, markup })
 }
 
+/// Hover on bindings defining options in config modules, eg. `enable` in `services.nginx.enable`.
+fn hover_module_option(
+    db: &dyn TyDatabase,
+    file_id: FileId,
+    name_node: &ast::Name,
+) -> Option<HoverResult> {
+    let (source, path) = option_path_for_name(db, file_id, name_node)?;
+    let opts = db.module_options(source);
+    let opt = get_option(&opts, &path)?;
+    // Option sets like `services.nginx` are not declared as options.
+    if opt.declarations.is_empty() && opt.description.is_none() {
+        return None;
+    }
+
+    let ptr = AstPtr::new(name_node.syntax());
+    let name = db.source_map(file_id).name_for_node(ptr.clone())?;
+    let ty = db
+        .infer(file_id)
+        .ty_for_name(name)
+        .display_with(TY_DETAILED_DISPLAY)
+        .to_string();
+    let mut markup = format!("{} option `{}`\n`{ty}`", source.title(), path.join("."));
+    match &opt.description {
+        Some(Doc::Markdown { text }) => write!(markup, "\n\n{}", text.trim()).unwrap(),
+        Some(Doc::DocBook { text }) => write!(markup, "\n\n{}", docbook_to_markdown(text)).unwrap(),
+        Some(Doc::Other) | None => {}
+    }
+    if let Some(default) = opt.default.as_ref().and_then(render_option_value) {
+        write!(markup, "\n\n*Default:*{default}").unwrap();
+    }
+    if let Some(example) = opt.example.as_ref().and_then(render_option_value) {
+        write!(markup, "\n\n*Example:*{example}").unwrap();
+    }
+    if opt.read_only {
+        markup += "\n\n*Read-only.*";
+    }
+    if !opt.declarations.is_empty() {
+        markup += "\n\n*Declared by:*";
+        for decl in &opt.declarations {
+            write!(markup, "\n- `{decl}`").unwrap();
+        }
+    }
+    Some(HoverResult {
+        range: ptr.text_range(),
+        markup,
+    })
+}
+
+/// Render an option value following a label, as either an inline code or a code block.
+fn render_option_value(value: &Value) -> Option<String> {
+    match value {
+        Value::Expression { text } if text.contains('\n') => {
+            Some(format!("\n```nix\n{}\n```", text.trim_end()))
+        }
+        Value::Expression { text } => Some(format!(" `{text}`")),
+        Value::Markdown { text } => Some(format!(" {}", text.trim())),
+        Value::DocBook { text } => Some(format!(" {}", docbook_to_markdown(text))),
+        Value::Other => None,
+    }
+}
+
+/// Convert DocBook used by old option descriptions to Markdown.
+/// Common inline and block tags are converted, and other tags are dropped with their text kept.
+fn docbook_to_markdown(text: &str) -> String {
+    let mut out = String::new();
+    let mut links = Vec::new();
+    let mut in_code = false;
+    fn push_text(out: &mut String, text: &str, in_code: bool) {
+        let text = text
+            .replace("&lt;", "<")
+            .replace("&gt;", ">")
+            .replace("&quot;", "\"")
+            .replace("&apos;", "'")
+            .replace("&amp;", "&");
+        if in_code {
+            let text = match out.ends_with("```\n") {
+                true => text.strip_prefix('\n').unwrap_or(&text),
+                false => &text,
+            };
+            *out += text;
+        } else {
+            // Indentation is meaningful in Markdown but not in DocBook.
+            let mut lines = text.split('\n');
+            *out += lines.next().unwrap_or_default();
+            for line in lines {
+                *out += "\n";
+                *out += line.trim_start();
+            }
+        }
+    }
+
+    let mut rest = text;
+    while let Some((text, tag, after)) = rest
+        .split_once('<')
+        .and_then(|(text, after)| Some((text, after.split_once('>')?)))
+        .map(|(text, (tag, after))| (text, tag, after))
+    {
+        push_text(&mut out, text, in_code);
+        rest = after;
+
+        let (closing, tag) = match tag.strip_prefix('/') {
+            Some(tag) => (true, tag),
+            None => (false, tag),
+        };
+        let (empty, tag) = match tag.strip_suffix('/') {
+            Some(tag) => (true, tag),
+            None => (false, tag),
+        };
+        let (name, attrs) = tag.split_once(char::is_whitespace).unwrap_or((tag, ""));
+        let attr = |key: &str| {
+            let (_, value) = attrs.split_once(&format!("{key}=\""))?;
+            Some(value.split_once('"')?.0.to_owned())
+        };
+        match name {
+            "literal" | "code" | "option" | "varname" | "filename" | "command" | "package"
+            | "envar" | "function" | "constant" => out.push('`'),
+            "emphasis" => out.push('*'),
+            "para" | "simpara" if !out.ends_with("- ") => out += "\n\n",
+            "programlisting" | "screen" => {
+                in_code = !closing;
+                out.truncate(out.trim_end().len());
+                out += if closing { "\n```\n\n" } else { "\n\n```\n" };
+            }
+            "listitem" if !closing => out += "\n- ",
+            "xref" => {
+                if let Some(id) = attr("linkend") {
+                    write!(out, "`{id}`").unwrap();
+                }
+            }
+            "link" | "ulink" => {
+                let href = attr("xlink:href").or_else(|| attr("url"));
+                if empty {
+                    if let Some(href) = href {
+                        write!(out, "<{href}>").unwrap();
+                    }
+                } else if closing {
+                    match links.pop().flatten() {
+                        Some(href) => write!(out, "]({href})").unwrap(),
+                        None => out.push(']'),
+                    }
+                } else {
+                    links.push(href);
+                    out.push('[');
+                }
+            }
+            _ => {}
+        }
+    }
+    push_text(&mut out, rest, in_code);
+
+    while out.contains("\n\n\n") {
+        out = out.replace("\n\n\n", "\n\n");
+    }
+    out.trim().to_owned()
+}
+
+/// Get the documentation of an evaluated flake output, from its derivation name and
+/// `meta.description`.
+pub(crate) fn flake_output_doc(db: &dyn TyDatabase, file_id: FileId, idx: u32) -> Option<String> {
+    let tys = db.flake_output_tys(db.file_source_root(file_id));
+    let leaf = tys.leaf(idx)?;
+    let doc = leaf
+        .name
+        .iter()
+        .map(|name| format!("*Name:* `{name}`"))
+        .chain(leaf.description.clone())
+        .collect::<Vec<_>>();
+    (!doc.is_empty()).then(|| doc.join("\n\n"))
+}
+
+/// Get the doc comment of the definition of a name.
+///
+/// For attributes, it is the doc comment of the binding, or of the lambda bound to it.
+pub(crate) fn name_doc_comment(
+    db: &dyn DefDatabase,
+    file_id: FileId,
+    name: NameId,
+) -> Option<String> {
+    let parse = db.parse(file_id);
+    let source_map = db.source_map(file_id);
+    let name_node = source_map
+        .nodes_for_name(name)
+        .next()?
+        .to_node(&parse.syntax_node());
+    let parent = name_node.parent()?;
+    match_ast! {
+        match parent {
+            ast::PatField(n) => n.doc_comment(),
+            ast::Attrpath(n) => {
+                // Only the last attribute of the path is defined by the binding.
+                if n.attrs().last()?.syntax() != &name_node {
+                    return None;
+                }
+                let binding = ast::AttrpathValue::cast(n.syntax().parent()?)?;
+                binding.doc_comment().or_else(|| {
+                    match binding.value()?.flatten_paren()? {
+                        ast::Expr::Lambda(lam) => lam.doc_comment(),
+                        _ => None,
+                    }
+                })
+            },
+            _ => None,
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use super::docbook_to_markdown;
     use crate::base::SourceDatabase;
-    use crate::tests::TestDB;
+    use crate::tests::{set_test_flake_outputs, test_nixos_options, TestDB};
     use expect_test::{expect, Expect};
+    use nix_interop::nixos_options::OptionSource;
+    use std::sync::Arc;
 
     #[track_caller]
     fn check(fixture: &str, full: &str, expect: Expect) {
@@ -200,6 +439,16 @@ mod tests {
         expect.assert_eq(&got);
     }
 
+    #[track_caller]
+    fn check_option(fixture: &str, full: &str, expect: Expect) {
+        let (mut db, f) = TestDB::from_fixture(fixture).unwrap();
+        db.set_module_options(OptionSource::Nixos, Arc::new(test_nixos_options()));
+        let ret = super::hover(&db, f[0]).expect("No hover");
+        let src = db.file_content(f[0].file_id);
+        assert_eq!(full, &src[ret.range]);
+        expect.assert_eq(&(ret.markup.trim().to_owned() + "\n"));
+    }
+
     #[track_caller]
     fn check_no(fixture: &str) {
         let (db, f) = TestDB::from_fixture(fixture).unwrap();
@@ -303,6 +552,59 @@ mod tests {
         );
     }
 
+    #[test]
+    fn doc_comment() {
+        check(
+            "let /** Foo bar. */ $0a = 1; in a",
+            "a",
+            expect![[r#"
+                Let binding `a`
+                `int`
+
+                Foo bar.
+            "#]],
+        );
+        check(
+            "
+let
+  # Increment.
+  #
+  # Returns `x + 1`.
+  inc = x: x + 1;
+in $0inc
+            ",
+            "inc",
+            expect![[r#"
+                Let binding `inc`
+                `int → int`
+
+                Increment.
+
+                Returns `x + 1`.
+            "#]],
+        );
+        check(
+            "{ f = /** Identity. */ x: x; }.$0f",
+            "f",
+            expect![[r#"
+                Field `f`
+                `? → ?`
+
+                Identity.
+            "#]],
+        );
+        check(
+            "{ /** The input. */ a }: $0a",
+            "a",
+            expect![[r#"
+                Field parameter `a`
+                `?`
+
+                The input.
+            "#]],
+        );
+    }
+
     #[test]
     fn with() {
         check(
@@ -497,4 +799,175 @@ mod tests {
             "#]],
         );
     }
+
+    #[test]
+    fn docbook() {
+        let check = |text: &str, expect: Expect| expect.assert_eq(&docbook_to_markdown(text));
+        check(
+            "Whether to run <command>foo</command> as <emphasis>root</emphasis>.",
+            expect!["Whether to run `foo` as *root*."],
+        );
+        check(
+            r#"<para>See <link xlink:href="https://example.com">the manual</link>,
+  <link xlink:href="https://example.org"/> and <xref linkend="opt-foo"/>.</para>
+<itemizedlist>
+  <listitem><para>a &amp; b</para></listitem>
+  <listitem><para><literal>&lt;c&gt;</literal></para></listitem>
+</itemizedlist>
+<programlisting>
+{
+  foo = 1;
+}
+</programlisting>"#,
+            expect![[r#"
+                See [the manual](https://example.com),
+                <https://example.org> and `opt-foo`.
+
+                - a & b
+
+                - `<c>`
+
+                ```
+                {
+                  foo = 1;
+                }
+                ```"#]],
+        );
+    }
+
+    #[test]
+    fn module_option() {
+        check_option(
+            "{ ... }: { services.nginx = { $0enable = true; }; }",
+            "enable",
+            expect![[r#"
+                NixOS option `services.nginx.enable`
+                `bool`
+
+                Whether to enable Nginx Web Server.
+
+                *Default:* `false`
+
+                *Example:* `true`
+
+                *Declared by:*
+                - `/nix/store/nixpkgs/nixos/modules/services/web-servers/nginx/default.nix`
+            "#]],
+        );
+        check_option(
+            "{ pkgs, ... }: { services.nginx.$0package = pkgs.nginx; }",
+            "package",
+            expect![[r#"
+                NixOS option `services.nginx.package`
+                `{ args: [string], builder: string, name: string, system: string }`
+
+                The `nginx` derivation to use.
+
+                *Default:*
+                ```nix
+                pkgs.nginx.override {
+                  withDebug = false;
+                }
+                ```
+
+                *Read-only.*
+
+                *Declared by:*
+                - `/nix/store/nixpkgs/nixos/modules/services/web-servers/nginx/default.nix`
+            "#]],
+        );
+        check_option(
+            "{ ... }: { users.users.alice = { $0home = /home/alice; }; }",
+            "home",
+            expect![[r#"
+                NixOS option `users.users.alice.home`
+                `path`
+
+                The user's home directory.
+
+                *Declared by:*
+                - `/nix/store/nixpkgs/nixos/modules/config/users-groups.nix`
+            "#]],
+        );
+        check_option(
+            "{ lib, ... }: { options = { }; config = lib.mkIf true (lib.mkMerge [ { services.nginx.$0enable = true; } ]); }",
+            "enable",
+            expect![[r#"
+                NixOS option `services.nginx.enable`
+                `bool`
+
+                Whether to enable Nginx Web Server.
+
+                *Default:* `false`
+
+                *Example:* `true`
+
+                *Declared by:*
+                - `/nix/store/nixpkgs/nixos/modules/services/web-servers/nginx/default.nix`
+            "#]],
+        );
+        // Not options.
+        check_option(
+            "{ ... }: { services.$0nginx.enable = true; }",
+            "nginx",
+            expect![[r#"
+                Attrset attribute `nginx`
+                `{ enable: bool, package: { args: [string], builder: string, name: string, system: string } }`
+            "#]],
+        );
+        check_option(
+            "{ ... }: { services.nginx.enable = let $0enable = true; in enable; }",
+            "enable",
+            expect![[r#"
+                Let binding `enable`
+                `bool`
+            "#]],
+        );
+    }
+
+    #[test]
+    fn flake_output() {
+        let check = |fixture: &str, full: &str, expect: Expect| {
+            let (mut db, f) = TestDB::from_fixture(fixture).unwrap();
+            set_test_flake_outputs(&mut db);
+            let ret = super::hover(&db, f[0]).expect("No hover");
+            let src = db.file_content(f[0].file_id);
+            assert_eq!(full, &src[ret.range]);
+            expect.assert_eq(&(ret.markup.trim().to_owned() + "\n"));
+        };
+        check(
+            r#"
+#- /flake.nix
+{
+  outputs = { self, nixpkgs }: let system = "x86_64-linux"; in {
+    packages.${system}.default = nixpkgs.packages.${system}.$0hello;
+  };
+}
+            "#,
+            "hello",
+            expect![[r#"
+                Field `hello`
+                `{ args: [string], builder: string, name: string, system: string }`
+
+                *Name:* `hello-2.12.1`
+
+                A program that produces a familiar, friendly greeting
+            "#]],
+        );
+        check(
+            r#"
+#- /flake.nix
+{
+  outputs = { self, nixpkgs }: {
+    nixosModules.foo = self.nixosModules.$0default;
+  };
+}
+            "#,
+            "default",
+            expect![[r#"
+                Field `default`
+                `{ }`
+            "#]],
+        );
+    }
 }
@@ -1,3 +1,4 @@
+use super::flake_lock;
 use crate::def::{AstPtr, Expr, ExprId, Literal};
 use crate::{DefDatabase, FileId, FileRange, VfsPath};
 use syntax::TextRange;
@@ -22,6 +23,10 @@ pub enum LinkTarget {
 }
 
 pub(crate) fn links(db: &dyn DefDatabase, file_id: FileId) -> Vec<Link> {
+    if flake_lock::is_flake_lock(db, file_id) {
+        return flake_lock::links(db, file_id);
+    }
+
     let module = db.module(file_id);
     let source_map = db.source_map(file_id);
 
@@ -71,7 +76,7 @@ pub(crate) fn link_resolve(db: &dyn DefDatabase, frange: FileRange) -> Option<Li
     })
 }
 
-fn try_resolve_link_uri(uri: &str) -> Option<Url> {
+pub(super) fn try_resolve_link_uri(uri: &str) -> Option<Url> {
     // We intentionally don't allow any string parsable as URI, mainly for:
     // 1. Efficiency.
     // 2. Less false-positives.
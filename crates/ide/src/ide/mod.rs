@@ -1,14 +1,19 @@
 mod assists;
 mod completion;
 mod diagnostics;
+mod eval_expr;
 mod expand_selection;
 mod file_references;
+mod flake_lock;
 mod goto_definition;
 mod highlight_related;
 mod hover;
 mod links;
+mod module_option;
 mod references;
 mod rename;
+mod signature_help;
+mod ssr;
 mod symbol_hierarchy;
 mod syntax_highlighting;
 
@@ -16,8 +21,12 @@ use crate::base::SourceDatabaseStorage;
 use crate::def::DefDatabaseStorage;
 use crate::ty::TyDatabaseStorage;
 use crate::{
-    Change, Diagnostic, FileId, FilePos, FileRange, FileSet, SourceRoot, VfsPath, WorkspaceEdit,
+    Change, Diagnostic, FileId, FilePos, FileRange, FileSet, FlakeOutputPath, ParseBase,
+    SourceRoot, TextEdit, VfsPath, WorkspaceEdit,
 };
+use builtin::Builtins;
+use nix_interop::info::{host_system, NixDialect};
+use nix_interop::nixos_options::OptionSource;
 use nix_interop::DEFAULT_IMPORT_FILE;
 use salsa::{Database, Durability, ParallelDatabase};
 use smol_str::SmolStr;
@@ -27,11 +36,14 @@ use syntax::TextRange;
 
 pub use assists::{Assist, AssistKind};
 pub use completion::{CompletionItem, CompletionItemKind};
+pub use eval_expr::ClosedExpr;
 pub use goto_definition::GotoDefinitionResult;
 pub use highlight_related::HlRelated;
 pub use hover::HoverResult;
 pub use links::{Link, LinkTarget};
 pub use rename::RenameResult;
+pub use signature_help::SignatureHelp;
+pub use ssr::{ReplaceOptions, SsrResult};
 pub use symbol_hierarchy::SymbolTree;
 pub use syntax_highlighting::{HlAttrField, HlKeyword, HlOperator, HlPunct, HlRange, HlTag};
 
@@ -82,7 +94,12 @@ impl Default for RootDatabase {
             .set_lru_capacity(DEFAULT_LRU_CAP);
 
         db.set_flake_graph_with_durability(Arc::default(), Durability::MEDIUM);
-        db.set_nixos_options_with_durability(Arc::default(), Durability::MEDIUM);
+        for source in OptionSource::ALL {
+            db.set_module_options_with_durability(source, Arc::default(), Durability::MEDIUM);
+        }
+        db.set_builtins_with_durability(Builtins::latest(), Durability::HIGH);
+        db.set_nix_dialect_with_durability(NixDialect::default(), Durability::HIGH);
+        db.set_nix_system_with_durability(host_system().into(), Durability::HIGH);
         db
     }
 }
@@ -120,8 +137,42 @@ impl AnalysisHost {
     }
 
     pub fn apply_change(&mut self, change: Change) {
+        use crate::SourceDatabase;
+        use salsa::debug::DebugQueryTable;
+
         self.request_cancellation();
+
+        // Keep the current parse results of edited files for incremental reparsing.
+        // They are typically still cached, since the file is being edited. Never parse here,
+        // and take the content from the tree since the memo may be from an older revision.
+        let parse_bases = if change.edited_files.is_empty() {
+            Vec::new()
+        } else {
+            crate::def::ParseQuery
+                .in_db(&self.db)
+                .entries::<Vec<_>>()
+                .into_iter()
+                .filter(|entry| change.edited_files.contains(&entry.key))
+                .filter_map(|entry| {
+                    let parse = entry.value?;
+                    let base = ParseBase {
+                        content: parse.syntax_node().to_string().into(),
+                        parse,
+                    };
+                    Some((entry.key, base))
+                })
+                .collect::<Vec<_>>()
+        };
+
         change.apply(&mut self.db);
+
+        for (file, base) in parse_bases {
+            self.db.set_file_parse_base_with_durability(
+                file,
+                Some(Arc::new(base)),
+                Durability::LOW,
+            );
+        }
     }
 }
 
@@ -168,6 +219,10 @@ impl Analysis {
         self.with_db(|db| completion::completions(db, pos, trigger_char))
     }
 
+    pub fn unevaluated_flake_output(&self, pos: FilePos) -> Cancellable<Option<FlakeOutputPath>> {
+        self.with_db(|db| completion::unevaluated_flake_output(db, pos))
+    }
+
     pub fn references(&self, pos: FilePos) -> Cancellable<Option<Vec<FileRange>>> {
         self.with_db(|db| references::references(db, pos))
     }
@@ -188,6 +243,10 @@ impl Analysis {
         self.with_db(|db| hover::hover(db, fpos))
     }
 
+    pub fn signature_help(&self, fpos: FilePos) -> Cancellable<Option<SignatureHelp>> {
+        self.with_db(|db| signature_help::signature_help(db, fpos))
+    }
+
     pub fn symbol_hierarchy(&self, file: FileId) -> Cancellable<Vec<SymbolTree>> {
         self.with_db(|db| symbol_hierarchy::symbol_hierarchy(db, file))
     }
@@ -210,6 +269,10 @@ impl Analysis {
 
     //// Custom extensions ////
 
+    pub fn closed_expr(&self, frange: FileRange) -> Cancellable<Option<ClosedExpr>> {
+        self.with_db(|db| eval_expr::closed_expr(db, frange))
+    }
+
     pub fn file_references(&self, file: FileId) -> Cancellable<Vec<FileId>> {
         self.with_db(|db| file_references::file_references(db, file))
     }
@@ -217,4 +280,58 @@ impl Analysis {
     pub fn file_referrers(&self, file: FileId) -> Cancellable<Vec<FileId>> {
         self.with_db(|db| file_references::file_referrers(db, file))
     }
+
+    pub fn ssr_search(
+        &self,
+        file: FileId,
+        pattern: &str,
+    ) -> Cancellable<SsrResult<Vec<TextRange>>> {
+        self.with_db(|db| ssr::ssr_search(db, file, pattern))
+    }
+
+    pub fn ssr_replace(
+        &self,
+        file: FileId,
+        pattern: &str,
+        template: &str,
+        opts: ReplaceOptions,
+    ) -> Cancellable<SsrResult<Vec<TextEdit>>> {
+        self.with_db(|db| ssr::ssr_replace(db, file, pattern, template, opts))
+    }
+
+    pub fn ssr_replace_workspace(
+        &self,
+        file: FileId,
+        pattern: &str,
+        template: &str,
+        opts: ReplaceOptions,
+        fixpoint: bool,
+    ) -> Cancellable<SsrResult<WorkspaceEdit>> {
+        self.with_db(|db| ssr::ssr_replace_workspace(db, file, pattern, template, opts, fixpoint))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AnalysisHost;
+    use crate::{Change, DefDatabase, SourceDatabase};
+
+    #[test]
+    fn apply_change_reuses_memoized_parse() {
+        let (mut host, file) = AnalysisHost::new_single_file("{ a = 1; }");
+
+        // Not parsed yet, so nothing to reuse.
+        let mut change = Change::default();
+        change.edit_file(file, "{ a = 2; }".into());
+        host.apply_change(change);
+        assert!(host.db.file_parse_base(file).is_none());
+
+        host.db.parse(file);
+        let mut change = Change::default();
+        change.edit_file(file, "{ a = 3; }".into());
+        host.apply_change(change);
+        let base = host.db.file_parse_base(file).unwrap();
+        assert_eq!(&*base.content, "{ a = 2; }");
+        assert_eq!(host.db.parse(file), syntax::parse_file("{ a = 3; }"));
+    }
 }
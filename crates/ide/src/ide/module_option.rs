@@ -0,0 +1,98 @@
+//! Locate the NixOS (or other module system) option defined by a binding in config modules.
+use crate::{DefDatabase, FileId, ModuleKind};
+use nix_interop::nixos_options::OptionSource;
+use smol_str::SmolStr;
+use syntax::ast::{self, AstNode};
+use syntax::semantic::AttrKind;
+use syntax::{SyntaxKind, SyntaxNode};
+
+/// Get the option path defined by an attribute name in a config module,
+/// like `services.nginx.enable` for `enable` in `{ services.nginx = { enable = true; }; }`.
+///
+/// Bindings wrapped by functions like `lib.mkIf cond { ... }` or `lib.mkMerge [ { ... } ]`
+/// are also followed.
+pub(crate) fn option_path_for_name(
+    db: &dyn DefDatabase,
+    file: FileId,
+    name: &ast::Name,
+) -> Option<(OptionSource, Vec<SmolStr>)> {
+    let has_config_field = match *db.module_kind(file) {
+        ModuleKind::ConfigModule { .. } => true,
+        ModuleKind::Config { .. } => false,
+        _ => return None,
+    };
+
+    let path_node = ast::Attrpath::cast(name.syntax().parent()?)?;
+    let attrs = path_node.attrs().collect::<Vec<_>>();
+    let pos = attrs
+        .iter()
+        .position(|attr| attr.syntax() == name.syntax())?;
+    // Keys are collected from inner to outer, and reversed at last.
+    let mut path = static_keys(&attrs[..=pos])?;
+    path.reverse();
+
+    let mut binding = path_node.syntax().parent()?;
+    loop {
+        let set = ast::AttrSet::cast(binding.parent()?)?;
+        if set.let_token().is_some() {
+            return None;
+        }
+        let parent = value_parent(set.syntax().clone())?;
+        match parent.kind() {
+            SyntaxKind::ATTR_PATH_VALUE => {
+                let outer_path = ast::AttrpathValue::cast(parent.clone())?.attrpath()?;
+                let mut keys = static_keys(&outer_path.attrs().collect::<Vec<_>>())?;
+                keys.reverse();
+                path.extend(keys);
+                binding = parent;
+            }
+            SyntaxKind::LAMBDA => {
+                value_parent(parent).filter(|n| n.kind() == SyntaxKind::SOURCE_FILE)?;
+                break;
+            }
+            SyntaxKind::SOURCE_FILE => break,
+            _ => return None,
+        }
+    }
+    path.reverse();
+
+    if has_config_field {
+        if path.first().map(|s| &**s) != Some("config") {
+            return None;
+        }
+        path.remove(0);
+    }
+    Some((db.module_option_source(file), path))
+}
+
+/// Get the parent of a value, skipping parentheses and function applications on it.
+fn value_parent(mut value: SyntaxNode) -> Option<SyntaxNode> {
+    loop {
+        let parent = value.parent()?;
+        match parent.kind() {
+            SyntaxKind::PAREN => {}
+            SyntaxKind::APPLY => {
+                let arg = ast::Apply::cast(parent.clone())?.argument()?;
+                if arg.syntax() != &value {
+                    return None;
+                }
+            }
+            // Only elements of lists passed to functions, like `lib.mkMerge`.
+            SyntaxKind::LIST => {
+                ast::Apply::cast(parent.parent()?)?;
+            }
+            _ => return Some(parent),
+        }
+        value = parent;
+    }
+}
+
+fn static_keys(attrs: &[ast::Attr]) -> Option<Vec<SmolStr>> {
+    attrs
+        .iter()
+        .map(|attr| match AttrKind::of(attr.clone()) {
+            AttrKind::Static(key) => key.map(SmolStr::from),
+            AttrKind::Dynamic(_) => None,
+        })
+        .collect()
+}
@@ -1,3 +1,4 @@
+use super::flake_lock;
 use crate::def::{AstPtr, ResolveResult};
 use crate::{DefDatabase, FilePos, FileRange};
 use syntax::ast::{self, AstNode};
@@ -12,6 +13,9 @@ pub(crate) fn references(
     db: &dyn DefDatabase,
     FilePos { file_id, pos }: FilePos,
 ) -> Option<Vec<FileRange>> {
+    if flake_lock::is_flake_lock(db, file_id) {
+        return None;
+    }
     let parse = db.parse(file_id);
     let tok = best_token_at_offset(&parse.syntax_node(), pos)?;
 
@@ -1,3 +1,4 @@
+use super::flake_lock;
 use crate::def::{AstPtr, NameId, ResolveResult};
 use crate::{DefDatabase, FilePos, TextEdit, WorkspaceEdit};
 use smol_str::SmolStr;
@@ -165,6 +166,9 @@ fn find_name(
     db: &dyn DefDatabase,
     FilePos { file_id, pos }: FilePos,
 ) -> Option<(TextRange, NameId)> {
+    if flake_lock::is_flake_lock(db, file_id) {
+        return None;
+    }
     let parse = db.parse(file_id);
     let tok = best_token_at_offset(&parse.syntax_node(), pos)?;
     let mut node = tok.parent_ancestors().find_map(|node| {
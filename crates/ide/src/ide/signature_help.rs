@@ -0,0 +1,317 @@
+use super::flake_lock;
+use super::hover::name_doc_comment;
+use crate::def::{AstPtr, ResolveResult};
+use crate::ty::{DisplayConfig, Ty};
+use crate::{FilePos, TyDatabase};
+use syntax::ast::{self, AstNode};
+use syntax::semantic::AttrKind;
+use syntax::{SyntaxKind, SyntaxNode, TextRange, TextSize};
+
+use super::completion::TY_SIGNATURE_DISPLAY;
+
+const TY_PARAM_DISPLAY: DisplayConfig = DisplayConfig {
+    lambda_need_parentheses: true,
+    ..TY_SIGNATURE_DISPLAY
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SignatureHelp {
+    /// The function being applied and its type, eg. `f: int → string → bool`.
+    pub label: String,
+    /// The range of each parameter type in `label`.
+    pub parameters: Vec<TextRange>,
+    /// The index of the argument under the cursor.
+    pub active_parameter: Option<usize>,
+    /// The documentation of the function, in Markdown.
+    pub documentation: Option<String>,
+}
+
+pub(crate) fn signature_help(
+    db: &dyn TyDatabase,
+    FilePos { file_id, pos }: FilePos,
+) -> Option<SignatureHelp> {
+    if flake_lock::is_flake_lock(db, file_id) {
+        return None;
+    }
+    let parse = db.parse(file_id);
+    let cursor_tok = parse.syntax_node().token_at_offset(pos).left_biased()?;
+    // `f a |` expects the next argument, while `f a|` is still typing the current one.
+    let after_space = cursor_tok.kind().is_trivia();
+    let tok = std::iter::successors(Some(cursor_tok), |tok| tok.prev_token())
+        .find(|tok| !tok.kind().is_trivia())?;
+    let end = tok.text_range().end();
+
+    // The argument or function just before the cursor.
+    // Nodes may contain trailing trivia due to parser lookahead.
+    let atom = tok
+        .parent_ancestors()
+        .take_while(|node| {
+            is_atom_kind(node.kind())
+                && std::iter::successors(node.last_token(), |tok| tok.prev_token())
+                    .find(|tok| !tok.kind().is_trivia())
+                    .as_ref()
+                    == Some(&tok)
+        })
+        .last()?;
+    let top = std::iter::successors(Some(atom), |node| {
+        node.parent().filter(|p| p.kind() == SyntaxKind::APPLY)
+    })
+    .last()?;
+
+    // Collect the function and its arguments along the spine of `APPLY`s.
+    let mut args = Vec::new();
+    let mut func = ast::Expr::cast(top)?;
+    while let ast::Expr::Apply(app) = func {
+        args.push(app.argument()?.syntax().clone());
+        func = app.function()?;
+    }
+    let func = func.flatten_paren()?;
+
+    let typed_args = args
+        .iter()
+        .filter(|arg| arg.text_range().end() <= end)
+        .count();
+    let active_parameter = if after_space {
+        typed_args
+    } else {
+        // Typing the function itself.
+        typed_args.checked_sub(1)?
+    };
+
+    let source_map = db.source_map(file_id);
+    let expr = source_map.expr_for_node(AstPtr::new(func.syntax()))?;
+    let module = db.module(file_id);
+    let nameres = db.name_resolution(file_id);
+    let infer = db.infer(file_id);
+
+    // `builtins.xxx`
+    let builtin_field = || {
+        let ast::Expr::Select(select) = &func else {
+            return None;
+        };
+        let set = source_map.expr_for_node(AstPtr::new(select.set()?.syntax()))?;
+        let Some(ResolveResult::Builtin("builtins")) = nameres.get(set) else {
+            return None;
+        };
+        let mut attrs = select.attrpath()?.attrs();
+        let (Some(attr), None) = (attrs.next(), attrs.next()) else {
+            return None;
+        };
+        match AttrKind::of(attr) {
+            AttrKind::Static(Some(field)) => Some(field),
+            _ => None,
+        }
+    };
+
+    let mut ty = infer.ty_for_expr(expr);
+    let documentation = if let Some(builtin) = nameres
+        .check_builtin(expr, &module)
+        .map(str::to_owned)
+        .or_else(builtin_field)
+    {
+        let b = db.builtins().get(&builtin)?.clone();
+        if let Some(builtin_ty) = crate::ty::known::BUILTINS
+            .as_attrset()
+            .unwrap()
+            .get(&builtin)
+        {
+            ty = builtin_ty.clone();
+        }
+        Some(format!(
+            "{}\n{}",
+            b.summary,
+            b.doc.as_deref().unwrap_or("(No documentation from Nix)"),
+        ))
+    } else if let Some(&ResolveResult::Definition(name)) = nameres.get(expr) {
+        // The type of the reference may be already specialized by the arguments.
+        ty = infer.ty_for_name(name);
+        name_doc_comment(db, file_id, name)
+    } else {
+        None
+    };
+
+    let func_text = func_label(func.syntax());
+    let mut label = format!("{func_text}: ");
+    let mut parameters = Vec::new();
+    while let Ty::Lambda(param, ret) = ty {
+        let start = TextSize::of(&*label);
+        label += &param.display_with(TY_PARAM_DISPLAY).to_string();
+        parameters.push(TextRange::new(start, TextSize::of(&*label)));
+        label += " → ";
+        ty = (*ret).clone();
+    }
+    if parameters.is_empty() && documentation.is_none() {
+        return None;
+    }
+    label += &ty.display_with(TY_SIGNATURE_DISPLAY).to_string();
+
+    Some(SignatureHelp {
+        label,
+        active_parameter: (active_parameter < parameters.len()).then_some(active_parameter),
+        parameters,
+        documentation,
+    })
+}
+
+/// Expressions which can be a function or an argument without parentheses.
+fn is_atom_kind(kind: SyntaxKind) -> bool {
+    matches!(
+        kind,
+        SyntaxKind::ATTR_PATH
+            | SyntaxKind::ATTR_SET
+            | SyntaxKind::INDENT_STRING
+            | SyntaxKind::LIST
+            | SyntaxKind::LITERAL
+            | SyntaxKind::NAME
+            | SyntaxKind::PAREN
+            | SyntaxKind::PATH_INTERPOLATION
+            | SyntaxKind::REF
+            | SyntaxKind::SELECT
+            | SyntaxKind::STRING
+    )
+}
+
+fn func_label(node: &SyntaxNode) -> String {
+    match node.kind() {
+        SyntaxKind::REF | SyntaxKind::SELECT => {
+            // Normalize whitespace in `lib . foo`.
+            node.text()
+                .to_string()
+                .split_whitespace()
+                .collect::<String>()
+        }
+        _ => "<function>".into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SignatureHelp;
+    use crate::tests::TestDB;
+    use expect_test::{expect, Expect};
+
+    #[track_caller]
+    fn check(fixture: &str, expect: Expect) {
+        let (db, f) = TestDB::from_fixture(fixture).unwrap();
+        assert_eq!(f.markers().len(), 1);
+        let got = match super::signature_help(&db, f[0]) {
+            None => "None".to_owned(),
+            Some(SignatureHelp {
+                label,
+                parameters,
+                active_parameter,
+                documentation,
+            }) => {
+                let parameters = parameters
+                    .iter()
+                    .map(|&range| &label[range])
+                    .collect::<Vec<_>>();
+                let mut got = format!("{label}\n{parameters:?} {active_parameter:?}\n");
+                if let Some(doc) = documentation {
+                    got += &doc;
+                    got += "\n";
+                }
+                got
+            }
+        };
+        expect.assert_eq(&got);
+    }
+
+    #[test]
+    fn lambda() {
+        check(
+            "let f = a: b: a + b + 1; in (f $0)",
+            expect![[r#"
+                f: int → int → int
+                ["int", "int"] Some(0)
+            "#]],
+        );
+        check(
+            "let f = a: b: a + b + 1; in (f 1 $0)",
+            expect![[r#"
+                f: int → int → int
+                ["int", "int"] Some(1)
+            "#]],
+        );
+        check(
+            "let f = a: b: a + b + 1; in f 1$0",
+            expect![[r#"
+                f: int → int → int
+                ["int", "int"] Some(0)
+            "#]],
+        );
+        check(
+            "let f = a: b: a + b + 1; in (f (f 1 2) $0)",
+            expect![[r#"
+                f: int → int → int
+                ["int", "int"] Some(1)
+            "#]],
+        );
+        check(
+            "let f = a: b: a + b + 1; in f (f 1$0 2) 3",
+            expect![[r#"
+                f: int → int → int
+                ["int", "int"] Some(0)
+            "#]],
+        );
+        check(
+            "let f = g: g 1; in (f $0)",
+            expect![[r#"
+                f: (int → ?) → ?
+                ["(int → ?)"] Some(0)
+            "#]],
+        );
+        check("let f = 1; in f$0", expect!["None"]);
+        check("let f = 1; in (f $0)", expect!["None"]);
+    }
+
+    #[test]
+    fn doc_comment() {
+        check(
+            "
+let
+  /**
+    Add two numbers.
+  */
+  add = a: b: a + b + 1;
+in (add 1 $0)
+            ",
+            expect![[r#"
+                add: int → int → int
+                ["int", "int"] Some(1)
+                Add two numbers.
+            "#]],
+        );
+    }
+
+    #[test]
+    fn builtin() {
+        check(
+            "(builtins.head $0)",
+            expect![[r#"
+            builtins.head: [?] → ?
+            ["[?]"] Some(0)
+            `builtins.head list`
+            Return the first element of a list; abort evaluation if the argument
+            isn’t a list or is an empty list. You can test whether a list is
+            empty by comparing it with `[]`.
+        "#]],
+        );
+        check(
+            "(map $0)",
+            expect![[r#"
+            map: (? → ?) → [?] → [?]
+            ["(? → ?)", "[?]"] Some(0)
+            `builtins.map f list`
+            Apply the function *f* to each element in the list *list*. For
+            example,
+
+            ```nix
+            map (x: "foo" + x) [ "bar" "bla" "abc" ]
+            ```
+
+            evaluates to `[ "foobar" "foobla" "fooabc" ]`.
+        "#]],
+        );
+    }
+}
@@ -0,0 +1,504 @@
+//! Structural search and replace on top of name resolution.
+//!
+//! References in patterns match expressions referring to the same thing, whatever they are
+//! spelled. Eg. `lib.mkIf` matches `mkIf` under `with lib;` or from `inherit (lib) mkIf;`,
+//! but not `lib.mkIf` where `lib` is a local definition.
+use super::{flake_lock, RootDatabase};
+use crate::def::{
+    AstPtr, BindingValue, Expr, ExprId, Literal, Module, ModuleScopes, ModuleSourceMap, NameId,
+    NameResolution, ResolveResult,
+};
+use crate::{Change, DefDatabase, FileId, FileSet, SourceRoot, TextEdit, WorkspaceEdit};
+use builtin::Builtins;
+use smol_str::SmolStr;
+pub use ssr::ReplaceOptions;
+use ssr::{
+    FixpointLimitReached, Pattern, RefPath, RefRoot, Resolver, Template, MAX_FIXPOINT_ROUNDS,
+};
+use std::collections::HashMap;
+use std::sync::Arc;
+use syntax::{SyntaxNode, TextRange, TextSize};
+
+pub type SsrResult<T> = Result<T, String>;
+
+/// The limit of following definitions, like `let a = b; b = lib.c; in a`.
+const MAX_RESOLVE_DEPTH: usize = 16;
+
+pub(crate) fn ssr_search(
+    db: &dyn DefDatabase,
+    file: FileId,
+    pattern: &str,
+) -> SsrResult<Vec<TextRange>> {
+    let pat = Pattern::parse(pattern).map_err(|err| format!("Invalid pattern: {err:#}"))?;
+    if flake_lock::is_flake_lock(db, file) {
+        return Ok(Vec::new());
+    }
+    let resolver = FileResolver::new(db, file);
+    let root = db.parse(file).syntax_node();
+    Ok(pat
+        .find_iter_semantic(&root, &resolver)
+        .map(|m| m.range)
+        .collect())
+}
+
+pub(crate) fn ssr_replace(
+    db: &dyn DefDatabase,
+    file: FileId,
+    pattern: &str,
+    template: &str,
+    opts: ReplaceOptions,
+) -> SsrResult<Vec<TextEdit>> {
+    let (pat, templ) = parse_pattern_template(pattern, template)?;
+    Ok(replace_in_file(db, file, &pat, &templ, opts))
+}
+
+/// Replace in all files of the source root containing `file`.
+/// With `fixpoint`, each file is replaced repeatedly until there is nothing to replace.
+pub(crate) fn ssr_replace_workspace(
+    db: &dyn DefDatabase,
+    file: FileId,
+    pattern: &str,
+    template: &str,
+    opts: ReplaceOptions,
+    fixpoint: bool,
+) -> SsrResult<WorkspaceEdit> {
+    let (pat, templ) = parse_pattern_template(pattern, template)?;
+    let sid = db.file_source_root(file);
+    let content_edits = db
+        .source_root(sid)
+        .files()
+        .map(|(file, _)| {
+            let edits = if fixpoint {
+                replace_fixpoint_in_file(db, file, &pat, &templ, opts)?
+            } else {
+                replace_in_file(db, file, &pat, &templ, opts)
+            };
+            Ok((!edits.is_empty()).then_some((file, edits)))
+        })
+        .filter_map(Result::transpose)
+        .collect::<SsrResult<_>>()?;
+    Ok(WorkspaceEdit { content_edits })
+}
+
+fn parse_pattern_template(pattern: &str, template: &str) -> SsrResult<(Pattern, Template)> {
+    let pat = Pattern::parse(pattern).map_err(|err| format!("Invalid pattern: {err:#}"))?;
+    let templ =
+        Template::parse(template, &pat).map_err(|err| format!("Invalid template: {err:#}"))?;
+    Ok((pat, templ))
+}
+
+fn replace_in_file(
+    db: &dyn DefDatabase,
+    file: FileId,
+    pat: &Pattern,
+    templ: &Template,
+    opts: ReplaceOptions,
+) -> Vec<TextEdit> {
+    if flake_lock::is_flake_lock(db, file) {
+        return Vec::new();
+    }
+    let resolver = FileResolver::new(db, file);
+    let root = db.parse(file).syntax_node();
+    pat.replace_edits_semantic(templ, &root, &resolver, opts)
+        .into_iter()
+        .map(|(delete, insert)| TextEdit {
+            delete,
+            insert: insert.into(),
+        })
+        .collect()
+}
+
+/// Replace in a file repeatedly. The replaced content is re-analyzed in a scratch database
+/// for following rounds, so that references are still matched semantically.
+fn replace_fixpoint_in_file(
+    db: &dyn DefDatabase,
+    file: FileId,
+    pat: &Pattern,
+    templ: &Template,
+    opts: ReplaceOptions,
+) -> SsrResult<Vec<TextEdit>> {
+    let edits = replace_in_file(db, file, pat, templ, opts);
+    if edits.is_empty() {
+        return Ok(edits);
+    }
+    let old_src = db.file_content(file);
+    let mut src = old_src.to_string();
+    for edit in edits.iter().rev() {
+        edit.apply(&mut src);
+    }
+
+    let mut scratch = RootDatabase::default();
+    let mut change = Change::default();
+    let mut file_set = FileSet::default();
+    let path = db
+        .source_root(db.file_source_root(file))
+        .path_for_file(file)
+        .clone();
+    file_set.insert(file, path);
+    change.set_roots(vec![SourceRoot::new_local(file_set, None)]);
+    change.builtins = Some(db.builtins());
+    change.nix_dialect = Some(db.nix_dialect());
+    for _ in 1..MAX_FIXPOINT_ROUNDS {
+        change.change_file(file, src.as_str().into());
+        change.apply(&mut scratch);
+        change = Change::default();
+
+        let edits = replace_in_file(&scratch, file, pat, templ, opts);
+        if edits.is_empty() {
+            return Ok(vec![TextEdit {
+                delete: TextRange::up_to(TextSize::of(&*old_src)),
+                insert: src.into(),
+            }]);
+        }
+        for edit in edits.iter().rev() {
+            edit.apply(&mut src);
+        }
+    }
+    Err(FixpointLimitReached.to_string())
+}
+
+struct FileResolver {
+    module: Arc<Module>,
+    source_map: Arc<ModuleSourceMap>,
+    scopes: Arc<ModuleScopes>,
+    nameres: Arc<NameResolution>,
+    builtins: Arc<Builtins>,
+    defs: HashMap<NameId, NameDef>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum NameDef {
+    /// Parameters of the top-level function, like `{ lib, ... }: ...`.
+    TopLevelParam,
+    /// `name = expr;` or `inherit name;` in `let` or `rec`.
+    Expr(ExprId),
+    /// `inherit (expr) name;` in `let` or `rec`.
+    InheritFrom(ExprId),
+}
+
+impl FileResolver {
+    fn new(db: &dyn DefDatabase, file: FileId) -> Self {
+        let module = db.module(file);
+        let mut defs = HashMap::new();
+        if let Expr::Lambda(param, pat, _) = &module[module.entry_expr()] {
+            let fields = pat.iter().flat_map(|pat| pat.fields.iter());
+            for name in param
+                .iter()
+                .chain(fields.filter_map(|(name, _)| name.as_ref()))
+            {
+                defs.insert(*name, NameDef::TopLevelParam);
+            }
+        }
+        for (_, expr) in module.exprs() {
+            let (Expr::LetIn(bindings, _)
+            | Expr::LetAttrset(bindings)
+            | Expr::RecAttrset(bindings)) = expr
+            else {
+                continue;
+            };
+            for &(name, value) in bindings.statics.iter() {
+                let def = match value {
+                    BindingValue::Expr(e) | BindingValue::Inherit(e) => NameDef::Expr(e),
+                    BindingValue::InheritFrom(i) => NameDef::InheritFrom(bindings.inherit_froms[i]),
+                };
+                defs.insert(name, def);
+            }
+        }
+
+        Self {
+            source_map: db.source_map(file),
+            scopes: db.scopes(file),
+            nameres: db.name_resolution(file),
+            builtins: db.builtins(),
+            module,
+            defs,
+        }
+    }
+
+    fn resolve_expr_id(&self, expr: ExprId, depth: usize) -> Option<RefPath> {
+        if depth > MAX_RESOLVE_DEPTH {
+            return None;
+        }
+        match &self.module[expr] {
+            Expr::Reference(name) => self.resolve_result(name, self.nameres.get(expr), depth),
+            Expr::Select(set, attrpath, None) => {
+                let attrs = attrpath
+                    .iter()
+                    .map(|&attr| match &self.module[attr] {
+                        Expr::Literal(Literal::String(s)) => Some(s.clone()),
+                        _ => None,
+                    })
+                    .collect::<Option<Vec<_>>>()?;
+                Some(self.resolve_expr_id(*set, depth + 1)?.join(attrs))
+            }
+            _ => None,
+        }
+    }
+
+    fn resolve_result(
+        &self,
+        name: &SmolStr,
+        res: Option<&ResolveResult>,
+        depth: usize,
+    ) -> Option<RefPath> {
+        match res {
+            None => Some(RefPath {
+                root: RefRoot::Global(name.clone()),
+                attrs: Vec::new(),
+            }),
+            Some(ResolveResult::Builtin(name)) => Some(builtin_path(name)),
+            Some(ResolveResult::WithExprs(withs)) => {
+                // The innermost one.
+                let &Expr::With(env, _) = &self.module[withs[0]] else {
+                    return None;
+                };
+                Some(self.resolve_expr_id(env, depth + 1)?.join([name.clone()]))
+            }
+            Some(&ResolveResult::Definition(name)) => {
+                let local = RefPath {
+                    root: RefRoot::Local(name.into_raw().into()),
+                    attrs: Vec::new(),
+                };
+                let path = match self.defs.get(&name) {
+                    Some(NameDef::TopLevelParam) => Some(RefPath {
+                        root: RefRoot::Global(self.module[name].text.clone()),
+                        attrs: Vec::new(),
+                    }),
+                    Some(&NameDef::Expr(e)) => self.resolve_expr_id(e, depth + 1),
+                    Some(&NameDef::InheritFrom(from)) => self
+                        .resolve_expr_id(from, depth + 1)
+                        .map(|path| path.join([self.module[name].text.clone()])),
+                    None => None,
+                };
+                Some(path.unwrap_or(local))
+            }
+        }
+    }
+
+    /// The expression where bindings of `node` are in scope, or `node` itself.
+    fn scope_expr(&self, node: &SyntaxNode) -> Option<ExprId> {
+        let expr = self.source_map.expr_for_node(AstPtr::new(node))?;
+        Some(match &self.module[expr] {
+            Expr::LetIn(_, body) => *body,
+            Expr::RecAttrset(bindings) | Expr::LetAttrset(bindings) => bindings
+                .statics
+                .iter()
+                .find_map(|&(_, value)| match value {
+                    BindingValue::Expr(e) => Some(e),
+                    _ => None,
+                })
+                .unwrap_or(expr),
+            _ => expr,
+        })
+    }
+}
+
+impl Resolver for FileResolver {
+    fn resolve_expr(&self, node: &SyntaxNode) -> Option<RefPath> {
+        let expr = self.source_map.expr_for_node(AstPtr::new(node))?;
+        self.resolve_expr_id(expr, 0)
+    }
+
+    fn resolve_name(&self, name: &str, scope: Option<&SyntaxNode>) -> Option<RefPath> {
+        let name = SmolStr::from(name);
+        match scope {
+            None => match self.builtins.get_entry(&name) {
+                Some((name, b)) if b.is_global => Some(builtin_path(name)),
+                _ => self.resolve_result(&name, None, 0),
+            },
+            Some(scope) => {
+                let expr = self.scope_expr(scope)?;
+                let res = self.scopes.resolve_name(expr, &name, &self.builtins);
+                self.resolve_result(&name, res.as_ref(), 0)
+            }
+        }
+    }
+}
+
+fn builtin_path(name: &str) -> RefPath {
+    RefPath {
+        root: RefRoot::Builtins,
+        attrs: if name == "builtins" {
+            Vec::new()
+        } else {
+            vec![name.into()]
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::base::SourceDatabase;
+    use crate::tests::TestDB;
+    use crate::TextEdit;
+    use expect_test::{expect, Expect};
+
+    #[track_caller]
+    fn check_search(src: &str, pattern: &str, expect: Expect) {
+        let (db, file) = TestDB::single_file(src).unwrap();
+        let ranges = super::ssr_search(&db, file, pattern).unwrap();
+        let mut got = db.file_content(file).to_string();
+        for range in ranges.iter().rev() {
+            got.insert(usize::from(range.end()), '>');
+            got.insert(usize::from(range.start()), '<');
+        }
+        expect.assert_eq(&got);
+    }
+
+    #[track_caller]
+    fn check_replace(src: &str, pattern: &str, template: &str, expect: Expect) {
+        let (db, file) = TestDB::single_file(src).unwrap();
+        let edits = super::ssr_replace(&db, file, pattern, template, Default::default()).unwrap();
+        let mut got = db.file_content(file).to_string();
+        for edit in edits.iter().rev() {
+            TextEdit::apply(edit, &mut got);
+        }
+        expect.assert_eq(&got);
+    }
+
+    #[test]
+    fn shadowed() {
+        check_search(
+            "{ lib }: [ lib.mkIf (let lib = { }; in lib.mkIf) ]",
+            "lib.mkIf",
+            expect!["{ lib }: [ <lib.mkIf> (let lib = { }; in lib.mkIf) ]"],
+        );
+    }
+
+    #[test]
+    fn with_and_inherit() {
+        check_search(
+            "{ lib }: [ (with lib; mkIf a b) (let inherit (lib) mkIf; in mkIf a b) (let m = lib.mkIf; in m a b) ]",
+            "lib.mkIf $c $v",
+            expect!["{ lib }: [ (with lib; <mkIf a b>) (let inherit (lib) mkIf; in <mkIf a b>) (let m = lib.mkIf; in <m a b>) ]"],
+        );
+    }
+
+    #[test]
+    fn builtins() {
+        check_search(
+            "[ toString builtins.toString (with builtins; toString) (let inherit (builtins) toString; in toString) ]",
+            "builtins.toString",
+            expect!["[ <toString> <builtins.toString> (with builtins; <toString>) (let inherit (builtins) toString; in <toString>) ]"],
+        );
+        check_search(
+            "let toString = 1; in toString",
+            "toString",
+            expect!["let toString = 1; in toString"],
+        );
+    }
+
+    #[test]
+    fn bound_in_pattern() {
+        check_search(
+            "let x = 1; in x",
+            "let x = $v; in x",
+            expect!["<let x = 1; in x>"],
+        );
+    }
+
+    #[test]
+    fn replace_workspace() {
+        let (db, f) = TestDB::from_fixture(
+            "
+#- /default.nix
+{ lib }: lib.mkIf a b
+#- /a.nix
+{ lib }: with lib; mkIf c d
+#- /b.nix
+let lib = { }; in lib.mkIf e f
+",
+        )
+        .unwrap();
+        let ws_edit = super::ssr_replace_workspace(
+            &db,
+            f["/b.nix"],
+            "lib.mkIf $c $v",
+            "lib.optionalAttrs $c $v",
+            Default::default(),
+            false,
+        )
+        .unwrap();
+        let mut got = ws_edit
+            .content_edits
+            .into_iter()
+            .map(|(file, edits)| {
+                let mut src = db.file_content(file).to_string();
+                for edit in edits.iter().rev() {
+                    edit.apply(&mut src);
+                }
+                src
+            })
+            .collect::<Vec<_>>();
+        got.sort();
+        expect![[r#"
+            [
+                "{ lib }: lib.optionalAttrs a b",
+                "{ lib }: with lib; lib.optionalAttrs c d",
+            ]
+        "#]]
+        .assert_debug_eq(&got);
+    }
+
+    #[test]
+    fn replace_workspace_fixpoint() {
+        let (db, f) = TestDB::from_fixture(
+            "
+#- /default.nix
+{ lib }: lib.id (lib.id (with lib; id (id a)))
+#- /a.nix
+let lib = { }; in lib.id (lib.id (lib.id b))
+",
+        )
+        .unwrap();
+        let ws_edit = super::ssr_replace_workspace(
+            &db,
+            f["/a.nix"],
+            "lib.id (lib.id $x)",
+            "lib.id $x",
+            Default::default(),
+            true,
+        )
+        .unwrap();
+        let got = ws_edit
+            .content_edits
+            .into_iter()
+            .map(|(file, edits)| {
+                let mut src = db.file_content(file).to_string();
+                for edit in edits.iter().rev() {
+                    edit.apply(&mut src);
+                }
+                src
+            })
+            .collect::<Vec<_>>();
+        expect![[r#"
+            [
+                "{ lib }: lib.id (with lib; lib.id a)",
+            ]
+        "#]]
+        .assert_debug_eq(&got);
+
+        let (db, f) = TestDB::from_fixture("#- /default.nix\n{ lib }: lib.id 1").unwrap();
+        let err = super::ssr_replace_workspace(
+            &db,
+            f["/default.nix"],
+            "lib.id $x",
+            "lib.id (lib.id $x)",
+            Default::default(),
+            true,
+        )
+        .unwrap_err();
+        expect!["replacement is still changing after 16 rounds, the rule may not terminate"]
+            .assert_eq(&err);
+    }
+
+    #[test]
+    fn replace_scope() {
+        check_replace(
+            "{ lib, stdenv }: [ stdenv.lib.mkIf (let lib = 1; in stdenv.lib.mkIf) ]",
+            "stdenv.lib.$x",
+            "lib.$x",
+            expect!["{ lib, stdenv }: [ lib.mkIf (let lib = 1; in stdenv.lib.mkIf) ]"],
+        );
+    }
+}
@@ -1,3 +1,4 @@
+use super::flake_lock;
 use crate::def::{BindingValue, Expr, ExprId};
 use crate::{DefDatabase, FileId, Module, ModuleSourceMap, NameKind};
 use smol_str::SmolStr;
@@ -14,6 +15,10 @@ pub struct SymbolTree {
 }
 
 pub(crate) fn symbol_hierarchy(db: &dyn DefDatabase, file: FileId) -> Vec<SymbolTree> {
+    if flake_lock::is_flake_lock(db, file) {
+        return Vec::new();
+    }
+
     let parse = db.parse(file);
     let module = db.module(file);
     let source_map = db.source_map(file);
@@ -1,8 +1,9 @@
 //! This is actually so-called "semantic highlighting".
 //! Ref: <https://github.com/rust-lang/rust-analyzer/blob/a670ff888437f4b6a3d24cc2996e9f969a87cbae/crates/ide/src/syntax_highlighting/tags.rs>
+use super::flake_lock;
 use crate::def::{AstPtr, Expr, Literal, NameKind, ResolveResult};
 use crate::{DefDatabase, FileId};
-use builtin::{BuiltinKind, ALL_BUILTINS};
+use builtin::BuiltinKind;
 use syntax::ast::AstNode;
 use syntax::{ast, match_ast, SyntaxKind, SyntaxToken, TextRange, T};
 
@@ -51,6 +52,7 @@ pub enum HlOperator {
     Comparison,
     Arithmetic,
     Aggregation,
+    Pipe,
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
@@ -73,10 +75,15 @@ pub(crate) fn highlight(
     file: FileId,
     range: Option<TextRange>,
 ) -> Vec<HlRange> {
+    if flake_lock::is_flake_lock(db, file) {
+        return Vec::new();
+    }
+
     let root_node = db.parse(file).syntax_node();
     let source_map = db.source_map(file);
     let nameres = db.name_resolution(file);
     let module = db.module(file);
+    let builtins = db.builtins();
 
     let ident_tag = |tok: &SyntaxToken| -> Option<HlTag> {
         match tok.parent() {
@@ -87,7 +94,10 @@ pub(crate) fn highlight(
                     if matches!(builtin, "true" | "false") {
                         return Some(HlTag::BoolLiteral);
                     }
-                    return Some(HlTag::Builtin(ALL_BUILTINS[builtin].kind));
+                    let kind = builtins
+                        .get(builtin)
+                        .map_or(BuiltinKind::Function, |b| b.kind);
+                    return Some(HlTag::Builtin(kind));
                 }
                 Some(match nameres.get(expr) {
                     None => HlTag::UnresolvedRef,
@@ -105,7 +115,10 @@ pub(crate) fn highlight(
                         // `inherit (builtins) head;`
                         //                     ^^^^
                         if nameres.is_inherited_builtin(name) {
-                            return Some(HlTag::Builtin(ALL_BUILTINS[&*module[name].text].kind));
+                            let kind = builtins
+                                .get(&module[name].text)
+                                .map_or(BuiltinKind::Function, |b| b.kind);
+                            return Some(HlTag::Builtin(kind));
                         }
 
                         Some(HlTag::NameDef(module[name].kind))
@@ -131,7 +144,7 @@ pub(crate) fn highlight(
                         // `builtins.xxx`
                         //           ^^^
                         if let Some(ResolveResult::Builtin("builtins")) = nameres.get(set_expr) {
-                            if let Some(b) = ALL_BUILTINS.get(attr_text) {
+                            if let Some(b) = builtins.get(attr_text) {
                                 return Some(HlTag::Builtin(b.kind));
                             }
                         }
@@ -156,6 +169,7 @@ pub(crate) fn highlight(
             SyntaxKind::STRING_FRAGMENT => return None,
 
             T![&&] | T![||] | T![->] | T![!] => HlTag::Operator(HlOperator::Logical),
+            T![|>] | T![<|] => HlTag::Operator(HlOperator::Pipe),
             T![==] | T![!=] | T![<] | T![>] | T![<=] | T![>=] => {
                 HlTag::Operator(HlOperator::Comparison)
             }
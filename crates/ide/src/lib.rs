@@ -11,16 +11,17 @@ mod text_edit;
 mod tests;
 
 pub use self::ide::{
-    Analysis, AnalysisHost, Assist, AssistKind, Cancelled, CompletionItem, CompletionItemKind,
-    GotoDefinitionResult, HlAttrField, HlKeyword, HlOperator, HlPunct, HlRange, HlRelated, HlTag,
-    HoverResult, Link, LinkTarget, NavigationTarget, RenameResult, SymbolTree,
+    Analysis, AnalysisHost, Assist, AssistKind, Cancelled, ClosedExpr, CompletionItem,
+    CompletionItemKind, GotoDefinitionResult, HlAttrField, HlKeyword, HlOperator, HlPunct, HlRange,
+    HlRelated, HlTag, HoverResult, Link, LinkTarget, NavigationTarget, RenameResult,
+    ReplaceOptions, SignatureHelp, SsrResult, SymbolTree,
 };
 pub use base::{
-    Change, FileId, FilePos, FileRange, FileSet, FlakeGraph, FlakeInfo, InFile, SourceDatabase,
-    SourceRoot, SourceRootId, VfsPath,
+    Change, FileId, FilePos, FileRange, FileSet, FlakeGraph, FlakeInfo, InFile, ParseBase,
+    SourceDatabase, SourceRoot, SourceRootId, VfsPath,
 };
-pub use builtin::BuiltinKind;
+pub use builtin::{BuiltinKind, Builtins};
 pub use def::{DefDatabase, Module, ModuleKind, ModuleSourceMap, NameKind};
 pub use diagnostic::{Diagnostic, DiagnosticKind, Severity};
 pub use text_edit::{TextEdit, WorkspaceEdit};
-pub use ty::{InferenceResult, TyDatabase};
+pub use ty::{FlakeOutputPath, InferenceResult, TyDatabase};
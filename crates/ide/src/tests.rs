@@ -6,8 +6,13 @@ use crate::{
     SourceDatabase, SourceRoot, SourceRootId, VfsPath,
 };
 use anyhow::{bail, ensure, Context, Result};
+use builtin::Builtins;
 use indexmap::IndexMap;
-use nix_interop::{DEFAULT_IMPORT_FILE, FLAKE_FILE};
+use nix_interop::flake_lock::FlakeLock;
+use nix_interop::flake_output::{FlakeOutput, Leaf, Type};
+use nix_interop::info::NixDialect;
+use nix_interop::nixos_options::{self, Doc, NixosOption, NixosOptions, OptionSource, Value};
+use nix_interop::{DEFAULT_IMPORT_FILE, FLAKE_FILE, FLAKE_LOCK_FILE};
 use std::collections::HashMap;
 use std::sync::Arc;
 use std::{mem, ops};
@@ -48,7 +53,12 @@ impl TestDB {
             nodes: HashMap::from_iter(f.flake_info.clone().map(|info| (SourceRootId(0), info))),
         };
         change.set_flake_graph(flake_graph);
-        db.set_nixos_options(Arc::default());
+        for source in OptionSource::ALL {
+            db.set_module_options(source, Arc::default());
+        }
+        db.set_builtins(Builtins::latest());
+        db.set_nix_dialect(NixDialect::default());
+        db.set_nix_system("x86_64-linux".into());
         change.apply(&mut db);
         Ok((db, f))
     }
@@ -67,6 +77,139 @@ impl TestDB {
     }
 }
 
+/// Set evaluated flake outputs of the fixture flake: `packages.x86_64-linux.hello` and unevaluated
+/// `legacyPackages.x86_64-linux.python3Packages` for the input `nixpkgs`, and
+/// `nixosModules.default` for itself.
+pub fn set_test_flake_outputs(db: &mut TestDB) {
+    let leaf = |type_, name: Option<&str>, description: Option<&str>| {
+        FlakeOutput::Leaf(Leaf {
+            type_,
+            name: name.map(Into::into),
+            description: description.map(Into::into),
+        })
+    };
+    let set = |fields: Vec<(&str, FlakeOutput)>| {
+        FlakeOutput::Attrset(fields.into_iter().map(|(k, v)| (k.into(), v)).collect())
+    };
+    let nixpkgs = set(vec![
+        (
+            "packages",
+            set(vec![(
+                "x86_64-linux",
+                set(vec![(
+                    "hello",
+                    leaf(
+                        Type::Derivation,
+                        Some("hello-2.12.1"),
+                        Some("A program that produces a familiar, friendly greeting"),
+                    ),
+                )]),
+            )]),
+        ),
+        (
+            "legacyPackages",
+            set(vec![(
+                "x86_64-linux",
+                set(vec![(
+                    "python3Packages",
+                    leaf(Type::Unevaluated, None, None),
+                )]),
+            )]),
+        ),
+    ]);
+    let this = set(vec![(
+        "nixosModules",
+        set(vec![("default", leaf(Type::NixosModule, None, None))]),
+    )]);
+
+    let mut graph = (*db.flake_graph()).clone();
+    let info = graph
+        .nodes
+        .get_mut(&SourceRootId(0))
+        .expect("Fixture must be a flake");
+    info.input_flake_outputs.insert("nixpkgs".into(), nixpkgs);
+    info.self_flake_output = Some(this);
+    db.set_flake_graph(Arc::new(graph));
+}
+
+/// A small NixOS option tree with `services.nginx.{enable,package}` and `users.users.<name>.home`.
+pub fn test_nixos_options() -> NixosOptions {
+    let enable = NixosOption {
+        description: Some(Doc::Markdown {
+            text: "Whether to enable Nginx Web Server.".into(),
+        }),
+        declarations: vec![
+            "/nix/store/nixpkgs/nixos/modules/services/web-servers/nginx/default.nix".into(),
+        ],
+        ty: nixos_options::Ty::Bool,
+        default: Some(Value::Expression {
+            text: "false".into(),
+        }),
+        example: Some(Value::Expression {
+            text: "true".into(),
+        }),
+        ..NixosOption::default()
+    };
+    let package = NixosOption {
+        description: Some(Doc::DocBook {
+            text: "The <literal>nginx</literal> derivation to use.".into(),
+        }),
+        declarations: vec![
+            "/nix/store/nixpkgs/nixos/modules/services/web-servers/nginx/default.nix".into(),
+        ],
+        read_only: true,
+        ty: nixos_options::Ty::Derivation,
+        default: Some(Value::Expression {
+            text: "pkgs.nginx.override {\n  withDebug = false;\n}".into(),
+        }),
+        ..NixosOption::default()
+    };
+    let home = NixosOption {
+        description: Some(Doc::Markdown {
+            text: "The user's home directory.".into(),
+        }),
+        declarations: vec!["/nix/store/nixpkgs/nixos/modules/config/users-groups.nix".into()],
+        ty: nixos_options::Ty::Path,
+        ..NixosOption::default()
+    };
+    let set = |fields: NixosOptions, rest: Option<nixos_options::Ty>| NixosOption {
+        ty: nixos_options::Ty::Attrset {
+            fields,
+            rest: rest.map(Box::new),
+        },
+        ..NixosOption::default()
+    };
+    let user_ty = set(NixosOptions::from_iter([("home".into(), home)]), None).ty;
+    NixosOptions::from_iter([
+        (
+            "services".into(),
+            set(
+                NixosOptions::from_iter([(
+                    "nginx".into(),
+                    set(
+                        NixosOptions::from_iter([
+                            ("enable".into(), enable),
+                            ("package".into(), package),
+                        ]),
+                        None,
+                    ),
+                )]),
+                None,
+            ),
+        ),
+        (
+            "users".into(),
+            set(
+                NixosOptions::from_iter([(
+                    "users".into(),
+                    set(NixosOptions::new(), Some(user_ty)),
+                )]),
+                None,
+            ),
+        ),
+    ])
+}
+
 #[derive(Default, Debug)]
 pub struct Fixture {
     files: IndexMap<VfsPath, String>,
@@ -118,6 +261,8 @@ impl Fixture {
                         flake_file: cur_file,
                         input_store_paths: HashMap::new(),
                         input_flake_outputs: HashMap::new(),
+                        self_flake_output: None,
+                        lock: None,
                     });
                     for prop in iter {
                         if let Some((name, target)) = prop
@@ -166,6 +311,12 @@ impl Fixture {
         }
         this.insert_file(cur_path.context("Empty fixture")?, cur_text)?;
 
+        if let Some(flake_info) = &mut this.flake_info {
+            if let Some(lock_src) = this.files.get(&VfsPath::new(format!("/{FLAKE_LOCK_FILE}"))) {
+                flake_info.lock = Some(FlakeLock::from_slice(lock_src.as_bytes())?);
+            }
+        }
+
         let marker_len = markers
             .iter()
             .rposition(|p| p.is_some())
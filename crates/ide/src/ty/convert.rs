@@ -1,20 +1,16 @@
 //! Convert structures from Nix evaluation result into `Ty`s.
-use std::collections::HashMap;
 use std::sync::Arc;
 
-use nix_interop::flake_output::{FlakeOutput, Type as OutputTy};
-use nix_interop::nixos_options::Ty as OptionTy;
+use nix_interop::flake_output::{FlakeOutput, Leaf, Type as OutputTy};
+use nix_interop::nixos_options::{OptionSource, Ty as OptionTy};
 
 use crate::{SourceRootId, TyDatabase};
 
 use super::known::FLAKE_OUTPUT_GENERIC_SYSTEM_FIELDS;
-use super::{AttrSource, Attrset, Ty};
+use super::{AttrSource, Attrset, FlakeOutputPath, FlakeOutputTys, Ty};
 
-// TODO: Get this at runtime.
-const NIX_SYSTEM: &str = "x86_64-linux";
-
-pub(crate) fn options_to_config_ty(db: &dyn TyDatabase) -> Ty {
-    let opts = db.nixos_options();
+pub(crate) fn options_to_config_ty(db: &dyn TyDatabase, source: OptionSource) -> Ty {
+    let opts = db.module_options(source);
     let fields = opts
         .iter()
         .map(|(name, opt)| (name.as_str(), from_raw_ty(&opt.ty), AttrSource::Unknown));
@@ -44,59 +40,101 @@ fn from_raw_ty(ty: &OptionTy) -> Ty {
     }
 }
 
-pub(crate) fn flake_input_tys(db: &dyn TyDatabase, sid: SourceRootId) -> Arc<HashMap<String, Ty>> {
+pub(crate) fn flake_output_tys(db: &dyn TyDatabase, sid: SourceRootId) -> Arc<FlakeOutputTys> {
     let Some(info) = db.source_root_flake_info(sid) else {
         return Arc::default();
     };
-    let tys = info
+    let system = db.nix_system();
+    let mut leaves = Vec::new();
+    let mut convert = |input: Option<&String>, output: &FlakeOutput| {
+        let mut path = FlakeOutputPath {
+            input: input.cloned(),
+            attrpath: Vec::new(),
+        };
+        from_flake_output(output, &system, &mut path, &mut leaves)
+    };
+    let self_ty = info
+        .self_flake_output
+        .as_ref()
+        .map(|output| convert(None, output));
+    let input_tys = info
         .input_flake_outputs
         .iter()
-        .map(|(name, output)| (name.clone(), from_flake_output(output)))
+        .map(|(name, output)| (name.clone(), convert(Some(name), output)))
         .collect();
-    Arc::new(tys)
+    Arc::new(FlakeOutputTys {
+        self_ty,
+        input_tys,
+        leaves,
+    })
 }
 
-fn from_flake_output(out: &FlakeOutput) -> Ty {
+/// Convert flake outputs, where the fields for `system` are also used for any other
+/// non-static systems. Leaves are collected into `leaves` with their paths, and referenced by
+/// `AttrSource::FlakeOutput`.
+fn from_flake_output(
+    out: &FlakeOutput,
+    system: &str,
+    path: &mut FlakeOutputPath,
+    leaves: &mut Vec<(FlakeOutputPath, Leaf)>,
+) -> Ty {
     let FlakeOutput::Attrset(set) = out else {
-        return from_flake_output_inner(out, None);
+        return from_flake_output_inner(out, None, system, path, leaves).0;
     };
-    let fields = set.iter().map(|(key, output)| {
-        let generic_system_depth = FLAKE_OUTPUT_GENERIC_SYSTEM_FIELDS
-            .iter()
-            .find_map(|&(k, depth)| (k == key).then_some(depth));
-        (
-            &**key,
-            from_flake_output_inner(output, generic_system_depth),
-            AttrSource::Unknown,
-        )
-    });
+    let fields = set
+        .iter()
+        .map(|(key, output)| {
+            let generic_system_depth = FLAKE_OUTPUT_GENERIC_SYSTEM_FIELDS
+                .iter()
+                .find_map(|&(k, depth)| (k == key).then_some(depth));
+            path.attrpath.push(key.clone());
+            let (ty, src) =
+                from_flake_output_inner(output, generic_system_depth, system, path, leaves);
+            path.attrpath.pop();
+            (&**key, ty, src)
+        })
+        .collect::<Vec<_>>();
     Ty::Attrset(Attrset::from_internal(fields, None))
 }
 
-fn from_flake_output_inner(out: &FlakeOutput, generic_system_depth: Option<usize>) -> Ty {
+fn from_flake_output_inner(
+    out: &FlakeOutput,
+    generic_system_depth: Option<usize>,
+    system: &str,
+    path: &mut FlakeOutputPath,
+    leaves: &mut Vec<(FlakeOutputPath, Leaf)>,
+) -> (Ty, AttrSource) {
     match out {
-        FlakeOutput::Leaf(leaf) => match leaf.type_ {
-            OutputTy::NixosModule => ty!({}),
-            OutputTy::Derivation => ty!(derivation),
-            OutputTy::Unknown => ty!(?),
-        },
+        FlakeOutput::Leaf(leaf) => {
+            let ty = match leaf.type_ {
+                OutputTy::NixosModule => ty!({}),
+                OutputTy::Derivation => ty!(derivation),
+                OutputTy::Unevaluated | OutputTy::Unknown => ty!(?),
+            };
+            let src = AttrSource::FlakeOutput(leaves.len() as u32);
+            leaves.push((path.clone(), leaf.clone()));
+            (ty, src)
+        }
         FlakeOutput::Attrset(set) => {
             let set_rest = generic_system_depth == Some(0);
             let generic_system_depth = generic_system_depth.and_then(|i| i.checked_sub(1));
-            let fields = set.iter().map(|(key, output)| {
-                (
-                    &**key,
-                    from_flake_output_inner(output, generic_system_depth),
-                    AttrSource::Unknown,
-                )
-            });
+            let fields = set
+                .iter()
+                .map(|(key, output)| {
+                    path.attrpath.push(key.clone());
+                    let (ty, src) =
+                        from_flake_output_inner(output, generic_system_depth, system, path, leaves);
+                    path.attrpath.pop();
+                    (&**key, ty, src)
+                })
+                .collect::<Vec<_>>();
             let mut set = Attrset::from_internal(fields, None);
             if set_rest {
-                if let Some(ty) = set.get(NIX_SYSTEM) {
+                if let Some(ty) = set.get(system) {
                     set.rest = Some(Arc::new((ty.clone(), AttrSource::Unknown)));
                 }
             }
-            Ty::Attrset(set)
+            (Ty::Attrset(set), AttrSource::Unknown)
         }
     }
 }
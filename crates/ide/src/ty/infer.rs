@@ -222,6 +222,17 @@ impl<'db> InferCtx<'db> {
                 };
 
                 match op {
+                    // `x |> f` and `f <| x` are `f x`.
+                    BinaryOpKind::PipeRight | BinaryOpKind::PipeLeft => {
+                        let (lam_ty, arg_ty) = if op == BinaryOpKind::PipeRight {
+                            (rhs_ty, lhs_ty)
+                        } else {
+                            (lhs_ty, rhs_ty)
+                        };
+                        let ret_ty = self.new_ty_var();
+                        self.unify_var_ty(lam_ty, Ty::Lambda(arg_ty, ret_ty));
+                        ret_ty
+                    }
                     BinaryOpKind::Equal | BinaryOpKind::NotEqual => Ty::Bool.intern(self),
                     BinaryOpKind::Imply | BinaryOpKind::Or | BinaryOpKind::And => {
                         self.unify_var_ty(lhs_ty, Ty::Bool);
@@ -86,7 +86,7 @@ static GENERIC_INPUT_DECL: Lazy<Ty> = Lazy::new(|| {
 });
 
 /// <https://nixos.wiki/wiki/Flakes>
-pub fn flake(inputs: &[(&str, Ty)]) -> Ty {
+pub fn flake(inputs: &[(&str, Ty)], self_ty: Ty) -> Ty {
     let inputs_decl_ty = Ty::Attrset(Attrset::from_internal(
         inputs.iter().map(|(name, ty)| {
             let ty = merge_attrset(ty, &GENERIC_INPUT_DECL);
@@ -110,7 +110,7 @@ pub fn flake(inputs: &[(&str, Ty)]) -> Ty {
                 );
                 (*name, ty, AttrSource::Unknown)
             })
-            .chain(Some(("self", ty!({}), AttrSource::Unknown))),
+            .chain(Some(("self", self_ty, AttrSource::Unknown))),
         None,
     ));
 
@@ -212,9 +212,7 @@ pub fn flake(inputs: &[(&str, Ty)]) -> Ty {
 /// The first `&str` is the field name of flake output attrset, the second `usize` is the depth
 /// of the system field under that the former field. Eg.
 /// - `("packages", 0)` means "outputs.packages.<system>".
-///                                              ^ 0
 /// - `("hydraJobs", 1)` means "outputs.hydraJobs.<name>.<system>".
-///                                               ^0     ^1
 pub const FLAKE_OUTPUT_GENERIC_SYSTEM_FIELDS: &[(&str, usize)] = &[
     ("apps", 0),
     ("checks", 0),
@@ -79,6 +79,8 @@ mod tests;
 
 use crate::def::NameId;
 use crate::{DefDatabase, FileId, ModuleKind, SourceRootId};
+use nix_interop::flake_output::Leaf;
+use nix_interop::nixos_options::OptionSource;
 use std::collections::HashMap;
 use std::fmt;
 use std::sync::Arc;
@@ -96,10 +98,10 @@ pub trait TyDatabase: DefDatabase {
     fn infer(&self, file: FileId) -> Arc<InferenceResult>;
 
     #[salsa::invoke(convert::options_to_config_ty)]
-    fn nixos_config_ty(&self) -> Ty;
+    fn config_ty(&self, source: OptionSource) -> Ty;
 
-    #[salsa::invoke(convert::flake_input_tys)]
-    fn flake_input_tys(&self, sid: SourceRootId) -> Arc<HashMap<String, Ty>>;
+    #[salsa::invoke(convert::flake_output_tys)]
+    fn flake_output_tys(&self, sid: SourceRootId) -> Arc<FlakeOutputTys>;
 }
 
 #[derive(Clone, PartialEq, Eq)]
@@ -214,6 +216,11 @@ impl Attrset {
         Some(self.get_all(field)?.1)
     }
 
+    /// Get the type of all non-static fields.
+    pub fn rest(&self) -> Option<(&Ty, AttrSource)> {
+        self.rest.as_ref().map(|rest| (&rest.0, rest.1))
+    }
+
     pub fn iter(&self) -> impl Iterator<Item = (&SmolStr, &Ty, AttrSource)> + '_ {
         self.fields.iter().map(|(k, ty, src)| (k, ty, *src))
     }
@@ -228,6 +235,37 @@ pub enum AttrSource {
     Name(NameId),
     /// A builtin name.
     Builtin,
+    /// A leaf of evaluated flake outputs, indexing [`FlakeOutputTys::leaf`].
+    FlakeOutput(u32),
+}
+
+/// Types of evaluated flake outputs of a source root.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct FlakeOutputTys {
+    /// Outputs of the flake itself.
+    pub self_ty: Option<Ty>,
+    /// Outputs of input flakes.
+    pub input_tys: HashMap<String, Ty>,
+    leaves: Vec<(FlakeOutputPath, Leaf)>,
+}
+
+impl FlakeOutputTys {
+    pub fn leaf(&self, idx: u32) -> Option<&Leaf> {
+        Some(&self.leaves.get(idx as usize)?.1)
+    }
+
+    pub fn leaf_path(&self, idx: u32) -> Option<&FlakeOutputPath> {
+        Some(&self.leaves.get(idx as usize)?.0)
+    }
+}
+
+/// The location of a value in evaluated flake outputs.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct FlakeOutputPath {
+    /// The input flake name, or `None` for the flake itself.
+    pub input: Option<String>,
+    /// The attribute path from the root of outputs.
+    pub attrpath: Vec<String>,
 }
 
 fn module_expected_ty(db: &dyn TyDatabase, file: FileId) -> Option<Ty> {
@@ -239,12 +277,13 @@ fn module_expected_ty(db: &dyn TyDatabase, file: FileId) -> Option<Ty> {
             ..
         } => {
             let sid = db.file_source_root(file);
-            let input_tys = db.flake_input_tys(sid);
+            let output_tys = db.flake_output_tys(sid);
             let mut inputs = explicit_inputs
                 .keys()
                 .chain(param_inputs.keys())
                 .map(|s| {
-                    let input_ty = input_tys
+                    let input_ty = output_tys
+                        .input_tys
                         .get(&**s)
                         .cloned()
                         // NB. This must be an `Attrset`, so that `known::flake` will merge it
@@ -255,10 +294,19 @@ fn module_expected_ty(db: &dyn TyDatabase, file: FileId) -> Option<Ty> {
                 .collect::<Vec<_>>();
             inputs.sort_by_key(|(name, _)| *name);
             inputs.dedup_by_key(|(name, _)| *name);
-            Some(known::flake(&inputs))
+            let self_ty = output_tys
+                .self_ty
+                .clone()
+                .unwrap_or_else(|| Ty::Attrset(Attrset::default()));
+            Some(known::flake(&inputs, self_ty))
         }
         ModuleKind::Package { .. } => Some(known::PACKAGE.clone()),
-        ModuleKind::ConfigModule { .. } => Some(known::config_module(db.nixos_config_ty())),
-        ModuleKind::Config { .. } => Some(known::config(db.nixos_config_ty())),
+        ModuleKind::ConfigModule { .. } => {
+            let config_ty = db.config_ty(db.module_option_source(file));
+            Some(known::config_module(config_ty))
+        }
+        ModuleKind::Config { .. } => {
+            Some(known::config(db.config_ty(db.module_option_source(file))))
+        }
     }
 }
@@ -348,6 +348,8 @@ fn input_flake_ty() {
                 flake_file: file,
                 input_store_paths: HashMap::new(),
                 input_flake_outputs: HashMap::from_iter([("nixpkgs".into(), nixpkgs_output)]),
+                self_flake_output: None,
+                lock: None,
             },
         )]),
     }));
@@ -363,3 +365,69 @@ fn input_flake_ty() {
     expect_output.assert_eq(&ty_for_name("export_output"));
     assert_eq!(ty_for_name("export_pkg_name"), "string");
 }
+
+#[test]
+fn input_flake_ty_system() {
+    let src = r#"
+#- /flake.nix
+{
+    inputs.nixpkgs = "...";
+    outputs = { self, nixpkgs }: let system = "x"; in {
+        pkgs = nixpkgs.packages.${system};
+    };
+}
+    "#;
+
+    let leaf = |type_| {
+        FlakeOutput::Leaf(nix_interop::flake_output::Leaf {
+            type_,
+            name: None,
+            description: None,
+        })
+    };
+    let nixpkgs_output = FlakeOutput::Attrset(HashMap::from_iter([(
+        "packages".into(),
+        FlakeOutput::Attrset(HashMap::from_iter([
+            (
+                "x86_64-linux".into(),
+                FlakeOutput::Attrset(HashMap::from_iter([(
+                    "hello".into(),
+                    leaf(Type::Derivation),
+                )])),
+            ),
+            (
+                "aarch64-darwin".into(),
+                FlakeOutput::Attrset(HashMap::from_iter([("darwin".into(), leaf(Type::Unknown))])),
+            ),
+        ])),
+    )]));
+
+    let (mut db, file) = TestDB::single_file(src).unwrap();
+    let sid = db.file_source_root(file);
+    db.set_flake_graph(Arc::new(FlakeGraph {
+        nodes: HashMap::from_iter([(
+            sid,
+            FlakeInfo {
+                flake_file: file,
+                input_store_paths: HashMap::new(),
+                input_flake_outputs: HashMap::from_iter([("nixpkgs".into(), nixpkgs_output)]),
+                self_flake_output: None,
+                lock: None,
+            },
+        )]),
+    }));
+    let ty_for_name = |db: &TestDB, name: &str| {
+        let name = db
+            .module(file)
+            .names()
+            .find(|(_, n)| n.text == name)
+            .expect("Name not found")
+            .0;
+        db.infer(file).ty_for_name(name).debug().to_string()
+    };
+    expect!["{ hello: { args: [string], builder: string, name: string, system: string } }"]
+        .assert_eq(&ty_for_name(&db, "pkgs"));
+
+    db.set_nix_system("aarch64-darwin".into());
+    expect!["{ darwin: ? }"].assert_eq(&ty_for_name(&db, "pkgs"));
+}
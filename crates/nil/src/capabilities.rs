@@ -3,8 +3,9 @@ use lsp_types::{
     CodeActionProviderCapability, CompletionOptions, DocumentLinkOptions, HoverProviderCapability,
     InitializeParams, OneOf, RenameOptions, SelectionRangeProviderCapability,
     SemanticTokensFullOptions, SemanticTokensLegend, SemanticTokensOptions,
-    SemanticTokensServerCapabilities, ServerCapabilities, TextDocumentSyncCapability,
-    TextDocumentSyncKind, TextDocumentSyncOptions, WorkDoneProgressOptions,
+    SemanticTokensServerCapabilities, ServerCapabilities, SignatureHelpOptions,
+    TextDocumentSyncCapability, TextDocumentSyncKind, TextDocumentSyncOptions,
+    TextDocumentSyncSaveOptions, WorkDoneProgressOptions,
 };
 
 macro_rules! test {
@@ -22,7 +23,7 @@ pub(crate) fn negotiate_capabilities(
     let is_neovim = init_params
         .client_info
         .as_ref()
-        .map_or(false, |info| info.name == "Neovim");
+        .is_some_and(|info| info.name == "Neovim");
 
     let final_caps = NegotiatedCapabilities {
         client_show_message_request: test!(
@@ -58,7 +59,8 @@ pub(crate) fn negotiate_capabilities(
                 change: Some(TextDocumentSyncKind::INCREMENTAL),
                 will_save: None,
                 will_save_wait_until: None,
-                save: None,
+                // Nix workers are recycled on saving, since they cache files read from disk.
+                save: Some(TextDocumentSyncSaveOptions::Supported(true)),
             },
         )),
         definition_provider: Some(OneOf::Left(true)),
@@ -84,6 +86,13 @@ pub(crate) fn negotiate_capabilities(
             },
         )),
         hover_provider: Some(HoverProviderCapability::Simple(true)),
+        signature_help_provider: Some(SignatureHelpOptions {
+            // Typing a space would trigger requests everywhere. Only use it to update the active
+            // parameter when signature help is already shown for an application.
+            trigger_characters: None,
+            retrigger_characters: Some(vec![" ".into()]),
+            work_done_progress_options: WorkDoneProgressOptions::default(),
+        }),
         document_symbol_provider: Some(OneOf::Left(true)),
         document_formatting_provider: Some(OneOf::Left(true)),
         document_link_provider: Some(DocumentLinkOptions {
@@ -1,10 +1,18 @@
-use anyhow::ensure;
-use lsp_types::Url;
-use std::collections::HashSet;
-use std::path::PathBuf;
+use crate::glob::{self, PathGlob};
+use anyhow::{ensure, Context};
+use ide::Builtins;
+use nix_interop::info::NixDialect;
+use nix_interop::nixos_options::OptionSource;
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
 
 pub const CONFIG_KEY: &str = "nil";
 
+/// The project configuration file in the workspace root or its nearest ancestor.
+/// It has the same structure as LSP settings under `CONFIG_KEY`, in TOML.
+pub const PROJECT_CONFIG_FILE: &str = ".nil.toml";
+
 macro_rules! define_config {
     (
         $(#[$meta:meta])*
@@ -66,14 +74,20 @@ macro_rules! define_config {
 pub struct Config {
     pub root_path: PathBuf,
 
-    #[parse("/diagnostics/excludedFiles", parse = Config::parse_rooted_file_paths)]
-    pub diagnostics_excluded_files: Vec<Url>,
+    #[parse("/diagnostics/excludedFiles", parse = Config::parse_rooted_globs)]
+    pub diagnostics_excluded_files: Vec<PathGlob>,
     #[parse("/diagnostics/ignored")]
     pub diagnostics_ignored: HashSet<String>,
     #[parse("/formatting/command", parse = Config::parse_optional_command)]
     pub formatting_command: Option<Vec<String>>,
     #[parse("/nix/binary", default = "nix".into())]
     pub nix_binary: PathBuf,
+    #[parse("/nix/dialect")]
+    pub nix_dialect: Option<NixDialect>,
+    #[parse("/nix/version")]
+    pub nix_version: Option<String>,
+    #[parse("/nix/system")]
+    pub nix_system: Option<String>,
     #[parse("/nix/maxMemoryMB", default = Some(2048))]
     pub nix_max_memory_mb: Option<u64>,
     #[parse("/nix/flake/autoArchive")]
@@ -82,15 +96,15 @@ pub struct Config {
     pub nix_flake_auto_eval_inputs: bool,
     #[parse("/nix/flake/nixpkgsInputName", default = Some("nixpkgs".into()))]
     pub nix_flake_nixpkgs_input_name: Option<String>,
+    #[parse("/nix/flake/optionSources")]
+    pub nix_flake_option_sources: Option<BTreeMap<OptionSource, String>>,
 }
 
 impl Config {
-    fn parse_rooted_file_paths(&mut self, v: Vec<String>) -> anyhow::Result<Vec<Url>> {
-        Ok(v.into_iter()
-            .map(|path| {
-                Url::from_file_path(self.root_path.join(path)).expect("Root path is absolute")
-            })
-            .collect())
+    fn parse_rooted_globs(&mut self, v: Vec<String>) -> anyhow::Result<Vec<PathGlob>> {
+        v.iter()
+            .map(|pat| PathGlob::new(&self.root_path, pat).map_err(Into::into))
+            .collect()
     }
 
     fn parse_optional_command(
@@ -104,4 +118,174 @@ impl Config {
     pub fn nix_max_memory(&self) -> Option<u64> {
         self.nix_max_memory_mb?.checked_mul(1 << 20)
     }
+
+    /// Option sources with their flake input names, from `nix.flake.optionSources`.
+    /// If it is not set, only NixOS options are evaluated from `nix.flake.nixpkgsInputName`.
+    pub fn option_sources(&self) -> Vec<(OptionSource, &str)> {
+        match &self.nix_flake_option_sources {
+            Some(sources) => sources
+                .iter()
+                .map(|(&source, input_name)| (source, &**input_name))
+                .collect(),
+            None => self
+                .nix_flake_nixpkgs_input_name
+                .iter()
+                .map(|input_name| (OptionSource::Nixos, &**input_name))
+                .collect(),
+        }
+    }
+
+    /// The nixpkgs input to evaluate option sources other than NixOS with.
+    pub fn options_nixpkgs_input_name(&self) -> Option<&str> {
+        self.nix_flake_option_sources
+            .as_ref()
+            .and_then(|sources| sources.get(&OptionSource::Nixos))
+            .or(self.nix_flake_nixpkgs_input_name.as_ref())
+            .map(|s| &**s)
+    }
+
+    pub fn is_diagnostics_excluded(&self, path: &Path) -> bool {
+        self.diagnostics_excluded_files
+            .iter()
+            .any(|glob| glob.matches(path))
+    }
+
+    /// The builtins of `version` for `dialect`, for configured `nix.version`.
+    /// For Lix, `version` is the version of Lix itself and is kept for version-gated syntax.
+    pub fn builtins_for_version(dialect: NixDialect, version: &str) -> Builtins {
+        match dialect {
+            // Lix is forked from Nix 2.18.
+            NixDialect::Lix => Builtins::for_version("2.18").with_nix_version(version),
+            NixDialect::CppNix => Builtins::for_version(version),
+        }
+    }
+}
+
+/// Find `PROJECT_CONFIG_FILE` in `dir` or its nearest ancestor.
+/// This is shared by the language server and CLI commands.
+pub fn find_project_config(dir: &Path) -> Option<PathBuf> {
+    dir.ancestors()
+        .map(|dir| dir.join(PROJECT_CONFIG_FILE))
+        .find(|path| path.is_file())
+}
+
+/// Load settings from the project configuration file at `path`.
+/// Relative `diagnostics.excludedFiles` are resolved against the directory of the file,
+/// which may be above the workspace root.
+pub fn load_project_settings(path: &Path) -> anyhow::Result<serde_json::Value> {
+    let src = fs::read_to_string(path).with_context(|| format!("Failed to read {path:?}"))?;
+    let mut v = toml::from_str::<serde_json::Value>(&src)
+        .with_context(|| format!("Failed to parse {path:?}"))?;
+    let dir = path.parent().expect("Is a file");
+    if let Some(serde_json::Value::Array(pats)) = v.pointer_mut("/diagnostics/excludedFiles") {
+        for pat in pats {
+            if let serde_json::Value::String(pat) = pat {
+                *pat = glob::rooted_pattern(dir, pat);
+            }
+        }
+    }
+    Ok(v)
+}
+
+/// Settings which make the language server run programs. They are ignored in a project
+/// configuration file unless the client sets `trustProjectConfig`, since opening a checkout
+/// should not run binaries it chooses.
+pub const UNTRUSTED_PROJECT_SETTINGS: &[&str] = &["/nix/binary", "/formatting/command"];
+
+/// Remove `UNTRUSTED_PROJECT_SETTINGS` from `v`, and return the keys of removed ones.
+pub fn remove_untrusted_settings(v: &mut serde_json::Value) -> Vec<String> {
+    UNTRUSTED_PROJECT_SETTINGS
+        .iter()
+        .filter(|pointer| {
+            let (parent, key) = pointer.rsplit_once('/').expect("Is a pointer");
+            v.pointer_mut(parent)
+                .and_then(|parent| parent.as_object_mut()?.remove(key))
+                .is_some()
+        })
+        .map(|pointer| pointer[1..].replace('/', "."))
+        .collect()
+}
+
+/// Recursively merge `overlay` into `base`. Non-object values in `overlay` take precedence.
+pub fn merge_settings(base: &mut serde_json::Value, overlay: serde_json::Value) {
+    match (base, overlay) {
+        (serde_json::Value::Object(base), serde_json::Value::Object(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(base) => merge_settings(base, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn merge() {
+        let mut base = json!({
+            "diagnostics": { "ignored": ["a"], "excludedFiles": ["b"] },
+            "nix": { "binary": "nix" },
+        });
+        merge_settings(
+            &mut base,
+            json!({
+                "diagnostics": { "ignored": ["c"] },
+                "formatting": { "command": ["fmt"] },
+            }),
+        );
+        assert_eq!(
+            base,
+            json!({
+                "diagnostics": { "ignored": ["c"], "excludedFiles": ["b"] },
+                "formatting": { "command": ["fmt"] },
+                "nix": { "binary": "nix" },
+            }),
+        );
+    }
+
+    #[test]
+    fn project_settings() {
+        let root = std::env::temp_dir().join(format!("nil-config-test-{}", std::process::id()));
+        let sub = root.join("sub");
+        fs::create_dir_all(&sub).unwrap();
+        assert_eq!(find_project_config(&sub), None);
+        let path = root.join(PROJECT_CONFIG_FILE);
+        fs::write(
+            &path,
+            "[diagnostics]\nignored = [\"unused_binding\"]\nexcludedFiles = [\"gen/**\"]\n",
+        )
+        .unwrap();
+        assert_eq!(find_project_config(&sub), Some(path.clone()));
+
+        // Globs are relative to the file, even for a workspace in a subdirectory.
+        let mut config = Config::new(sub.clone());
+        let mut errors = Vec::new();
+        config.update(load_project_settings(&path).unwrap(), &mut errors);
+        assert!(errors.is_empty(), "{errors:?}");
+        assert!(config.diagnostics_ignored.contains("unused_binding"));
+        assert!(config.is_diagnostics_excluded(&root.join("gen/a/b.nix")));
+        assert!(!config.is_diagnostics_excluded(&sub.join("gen/a/b.nix")));
+
+        fs::write(
+            &path,
+            "[nix]\nbinary = \"./evil\"\nflake.autoArchive = true\n",
+        )
+        .unwrap();
+        let mut v = load_project_settings(&path).unwrap();
+        assert_eq!(remove_untrusted_settings(&mut v), ["nix.binary"]);
+        assert_eq!(v, json!({ "nix": { "flake": { "autoArchive": true } } }));
+
+        fs::write(&path, "[diagnostics\n").unwrap();
+        load_project_settings(&path).unwrap_err();
+
+        fs::remove_dir_all(&root).unwrap();
+    }
 }
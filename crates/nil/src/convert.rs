@@ -9,8 +9,8 @@ use lsp_types::{
     self as lsp, CodeAction, CodeActionKind, CodeActionOrCommand, DiagnosticRelatedInformation,
     DiagnosticSeverity, DiagnosticTag, DocumentHighlight, DocumentHighlightKind, DocumentLink,
     DocumentSymbol, Documentation, Hover, Location, MarkupContent, MarkupKind, NumberOrString,
-    Position, PrepareRenameResponse, Range, SemanticToken, SymbolKind, TextDocumentIdentifier,
-    TextDocumentPositionParams, Url,
+    Position, PrepareRenameResponse, Range, SemanticToken, SignatureHelp, SymbolKind,
+    TextDocumentIdentifier, TextDocumentPositionParams, Url,
 };
 use nix_interop::DEFAULT_IMPORT_FILE;
 use std::sync::Arc;
@@ -270,6 +270,37 @@ pub(crate) fn to_hover(line_map: &LineMap, hover: HoverResult) -> Hover {
     }
 }
 
+pub(crate) fn to_signature_help(sig: ide::SignatureHelp) -> SignatureHelp {
+    // Offsets are in UTF-16 code units, which differ from bytes for `→`.
+    let utf16_offset = |pos: TextSize| sig.label[..usize::from(pos)].encode_utf16().count() as u32;
+    let parameters = sig
+        .parameters
+        .iter()
+        .map(|range| lsp::ParameterInformation {
+            label: lsp::ParameterLabel::LabelOffsets([
+                utf16_offset(range.start()),
+                utf16_offset(range.end()),
+            ]),
+            documentation: None,
+        })
+        .collect();
+    SignatureHelp {
+        signatures: vec![lsp::SignatureInformation {
+            label: sig.label,
+            documentation: sig.documentation.map(|doc| {
+                Documentation::MarkupContent(MarkupContent {
+                    kind: MarkupKind::Markdown,
+                    value: doc,
+                })
+            }),
+            parameters: Some(parameters),
+            active_parameter: sig.active_parameter.map(|idx| idx as u32),
+        }],
+        active_signature: Some(0),
+        active_parameter: None,
+    }
+}
+
 pub(crate) fn to_document_symbols(
     line_map: &LineMap,
     syms: Vec<SymbolTree>,
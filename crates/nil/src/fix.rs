@@ -0,0 +1,226 @@
+//! Apply quick fixes of diagnostics in batch, for the command line.
+use crate::workspace::{Workspace, WorkspaceFile};
+use ide::{Analysis, AssistKind, Diagnostic, DiagnosticKind, FileRange, TextEdit};
+use similar::TextDiff;
+use std::path::Path;
+use std::sync::Arc;
+
+/// The limit of rounds to re-check and fix, in case some fixes never converge.
+const MAX_ROUNDS: usize = 16;
+
+/// Context lines around changes in diff output.
+const DIFF_CONTEXT: usize = 3;
+
+/// Apply quick fixes of diagnostics in all files until there is nothing more to fix.
+/// Only diagnostics accepted by `filter` are fixed. Fixes guessing the intention, like adding
+/// a parameter for an undefined name, are only applied with `guess`.
+///
+/// Returns the number of fixes applied for each file, in the same order as `Workspace::files`.
+/// Files with syntax errors are skipped.
+pub fn fix_workspace(
+    ws: &mut Workspace,
+    guess: bool,
+    filter: impl Fn(&WorkspaceFile, &Diagnostic) -> bool + Sync,
+) -> Vec<usize> {
+    let mut counts = vec![0usize; ws.files().len()];
+    for _ in 0..MAX_ROUNDS {
+        let fixes = ws.par_map(|snap, file| collect_fixes(snap, file, guess, &filter));
+        let mut changed = false;
+        for (i, fixes) in fixes.into_iter().enumerate() {
+            let file = &ws.files()[i];
+            let (file_id, mut src) = (file.file, file.src.to_string());
+            let cnt = apply_fixes(&mut src, fixes);
+            if cnt != 0 {
+                counts[i] += cnt;
+                changed = true;
+                ws.set_file_content(file_id, Arc::from(src));
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+    counts
+}
+
+/// Collect edits of quick fixes for each diagnostic. Fixes may overlap with each other.
+fn collect_fixes(
+    snap: &Analysis,
+    ws_file: &WorkspaceFile,
+    guess: bool,
+    filter: &impl Fn(&WorkspaceFile, &Diagnostic) -> bool,
+) -> Vec<Vec<TextEdit>> {
+    let file = ws_file.file;
+    let diags = snap.diagnostics(file).expect("No cancellation");
+    if diags
+        .iter()
+        .any(|diag| matches!(diag.kind, DiagnosticKind::SyntaxError(_)))
+    {
+        return Vec::new();
+    }
+
+    let mut fixes = Vec::new();
+    for diag in &diags {
+        if !filter(ws_file, diag) {
+            continue;
+        }
+        let assists = snap
+            .assists(FileRange::new(file, diag.range))
+            .expect("No cancellation");
+        // Other quick fixes may cover the range, like `rec` around a URI literal.
+        let Some(assist) = assists.into_iter().find(|assist| {
+            assist.kind == AssistKind::QuickFix
+                && (diag.quick_fix_ids().contains(&&*assist.id)
+                    || (guess && diag.guessed_quick_fix_ids().contains(&&*assist.id)))
+        }) else {
+            continue;
+        };
+        // Assists only edit the current file for now.
+        let Some(edits) = assist.edits.content_edits.get(&file) else {
+            continue;
+        };
+        if !fixes.contains(edits) {
+            fixes.push(edits.clone());
+        }
+    }
+    fixes
+}
+
+/// Apply non-overlapping fixes in order and return the number of applied fixes.
+/// Overlapping ones are dropped and should be re-checked after applying others.
+fn apply_fixes(src: &mut String, fixes: Vec<Vec<TextEdit>>) -> usize {
+    let mut accepted = Vec::<TextEdit>::new();
+    let mut cnt = 0;
+    for edits in fixes {
+        let overlaps = edits.iter().any(|edit| {
+            accepted.iter().any(|prev| {
+                let (a, b) = (edit.delete, prev.delete);
+                a.start() == b.start() || (a.start() < b.end() && b.start() < a.end())
+            })
+        });
+        if !overlaps {
+            accepted.extend(edits);
+            cnt += 1;
+        }
+    }
+    accepted.sort_unstable_by_key(|edit| edit.delete.start());
+    for edit in accepted.iter().rev() {
+        edit.apply(src);
+    }
+    cnt
+}
+
+/// Render a unified diff of a file, as `diff -u` does.
+/// Returns an empty string if there is no difference.
+pub fn unified_diff(path: &Path, old: &str, new: &str) -> String {
+    let path = path.display().to_string();
+    // Absolute paths and stdin `-` are shown as is.
+    let (old_header, new_header) = if path == "-" || Path::new(&path).is_absolute() {
+        (path.clone(), path)
+    } else {
+        (format!("a/{path}"), format!("b/{path}"))
+    };
+    TextDiff::from_lines(old, new)
+        .unified_diff()
+        .context_radius(DIFF_CONTEXT)
+        .header(&old_header, &new_header)
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use expect_test::{expect, Expect};
+    use std::path::PathBuf;
+
+    #[track_caller]
+    fn check(src: &str, only: &[&str], expect: Expect) {
+        let mut ws = Workspace::single_file(PathBuf::from("test.nix"), src.into());
+        let counts = fix_workspace(&mut ws, !only.is_empty(), |_, diag| {
+            only.is_empty() || only.contains(&diag.code())
+        });
+        let got = format!("{} {}", counts[0], ws.files()[0].src);
+        expect.assert_eq(&got);
+    }
+
+    #[test]
+    fn fixpoint() {
+        check(
+            "let a = 1; inherit (a) b; in with c; rec { d = 1; }",
+            &[],
+            expect!["5 { d = 1; }"],
+        );
+    }
+
+    #[test]
+    fn only() {
+        check(
+            "let a = 1; in rec { d = http://example.com; }",
+            &["uri_literal", "unused_rec"],
+            expect![[r#"2 let a = 1; in { d = "http://example.com"; }"#]],
+        );
+        // Only fixes of the selected diagnostics are applied.
+        check(
+            "let a = 1; in rec { d = http://example.com; }",
+            &["uri_literal"],
+            expect![[r#"1 let a = 1; in rec { d = "http://example.com"; }"#]],
+        );
+    }
+
+    #[test]
+    fn guess() {
+        // Undefined names may be typos, which are left alone unless explicitly requested.
+        check(
+            "{ pkgs }: pkgs.hello // lbi.foo",
+            &[],
+            expect!["0 { pkgs }: pkgs.hello // lbi.foo"],
+        );
+        check(
+            "{ pkgs }: pkgs.hello // lbi.foo",
+            &["undefined_name"],
+            expect!["1 { pkgs, lbi }: pkgs.hello // lbi.foo"],
+        );
+    }
+
+    #[test]
+    fn syntax_error() {
+        check("let a = 1; in (", &[], expect!["0 let a = 1; in ("]);
+    }
+
+    #[test]
+    fn diff() {
+        let old = "a\nb\nc\nd\ne\nf\ng\nh\ni\nj\nk\nl\nm";
+        let new = "a\nB\nc\nd\ne\nf\ng\nh\ni\nj\nk\nm\nn";
+        expect![[r#"
+            --- a/test.nix
+            +++ b/test.nix
+            @@ -1,5 +1,5 @@
+             a
+            -b
+            +B
+             c
+             d
+             e
+            @@ -9,5 +9,5 @@
+             i
+             j
+             k
+            -l
+            -m
+            \ No newline at end of file
+            +m
+            +n
+            \ No newline at end of file
+        "#]]
+        .assert_eq(&unified_diff(Path::new("test.nix"), old, new));
+        assert_eq!(unified_diff(Path::new("test.nix"), old, old), "");
+        expect![[r#"
+            --- -
+            +++ -
+            @@ -1 +1 @@
+            -a
+            +b
+        "#]]
+        .assert_eq(&unified_diff(Path::new("-"), "a\n", "b\n"));
+    }
+}
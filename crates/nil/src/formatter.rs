@@ -0,0 +1,30 @@
+//! Running the external formatter configured by `formatting.command`.
+use anyhow::{ensure, Context, Result};
+use std::process;
+
+/// Run the formatter with `src` as stdin, and return its stdout.
+pub fn run_formatter(cmd: &[String], src: impl AsRef<[u8]> + Send + 'static) -> Result<String> {
+    run_with_stdin(cmd, src).with_context(|| format!("Failed to run formatter {cmd:?}"))
+}
+
+fn run_with_stdin(cmd: &[String], stdin_data: impl AsRef<[u8]> + Send + 'static) -> Result<String> {
+    let mut child = process::Command::new(&cmd[0])
+        .args(&cmd[1..])
+        .stdin(process::Stdio::piped())
+        .stdout(process::Stdio::piped())
+        .stderr(process::Stdio::piped())
+        .spawn()?;
+    let mut stdin = child.stdin.take().unwrap();
+    std::thread::spawn(move || {
+        let _ = std::io::copy(&mut stdin_data.as_ref(), &mut stdin);
+    });
+    let output = child.wait_with_output()?;
+    ensure!(
+        output.status.success(),
+        "Formatter exited with {}, stderr: {}",
+        output.status,
+        String::from_utf8_lossy(&output.stderr),
+    );
+    let stdout = String::from_utf8(output.stdout)?;
+    Ok(stdout)
+}
@@ -0,0 +1,83 @@
+//! Glob patterns for file paths.
+use std::path::Path;
+
+use globset::{GlobBuilder, GlobMatcher};
+
+/// A glob pattern matching absolute file paths, in the syntax of [`globset`].
+/// - `*` matches any characters except `/`.
+/// - `**` matches any characters including `/`. `**/` also matches nothing.
+/// - `?` matches any one character except `/`.
+#[derive(Debug, Clone)]
+pub struct PathGlob(GlobMatcher);
+
+impl PathGlob {
+    /// Create a pattern relative to `root`. Absolute patterns are kept as-is.
+    pub fn new(root: &Path, pattern: &str) -> Result<Self, globset::Error> {
+        let glob = GlobBuilder::new(&rooted_pattern(root, pattern))
+            .literal_separator(true)
+            .build()?;
+        Ok(Self(glob.compile_matcher()))
+    }
+
+    pub fn matches(&self, path: &Path) -> bool {
+        self.0.is_match(path)
+    }
+}
+
+impl PartialEq for PathGlob {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.glob() == other.0.glob()
+    }
+}
+
+impl Eq for PathGlob {}
+
+/// Join a relative `pattern` to `root`, whose special characters are escaped.
+pub fn rooted_pattern(root: &Path, pattern: &str) -> String {
+    if Path::new(pattern).is_absolute() {
+        return pattern.to_owned();
+    }
+    let root = root.to_string_lossy();
+    format!("{}/{pattern}", globset::escape(root.trim_end_matches('/')),)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PathGlob;
+    use std::path::Path;
+
+    #[test]
+    fn matches() {
+        let root = Path::new("/root");
+        let check =
+            |pat: &str, path: &str| PathGlob::new(root, pat).unwrap().matches(Path::new(path));
+
+        assert!(check("Cargo.nix", "/root/Cargo.nix"));
+        assert!(!check("Cargo.nix", "/root/sub/Cargo.nix"));
+        assert!(check("/abs/foo.nix", "/abs/foo.nix"));
+
+        assert!(check("*.nix", "/root/foo.nix"));
+        assert!(!check("*.nix", "/root/sub/foo.nix"));
+        assert!(check("gen-?.nix", "/root/gen-1.nix"));
+        assert!(!check("gen-?.nix", "/root/gen-10.nix"));
+
+        assert!(check("**/*.gen.nix", "/root/foo.gen.nix"));
+        assert!(check("**/*.gen.nix", "/root/a/b/foo.gen.nix"));
+        assert!(check("generated/**", "/root/generated/a/b.nix"));
+        assert!(!check("generated/**", "/root/other/a/b.nix"));
+
+        // Special characters in the root are literal.
+        let root = Path::new("/[root]");
+        assert!(PathGlob::new(root, "*.nix")
+            .unwrap()
+            .matches(Path::new("/[root]/foo.nix")));
+        assert!(PathGlob::new(root, "[").is_err());
+    }
+
+    #[test]
+    fn long_path() {
+        // Linear time, unlike naive backtracking.
+        let pat = PathGlob::new(Path::new("/"), &"/**/a".repeat(20)).unwrap();
+        assert!(!pat.matches(Path::new(&format!("{}/b", "/a".repeat(100)))));
+    }
+}
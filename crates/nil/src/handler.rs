@@ -1,18 +1,20 @@
-use crate::{convert, StateSnapshot};
-use anyhow::{ensure, Context, Result};
+use crate::lsp_ext::{EvalExprParams, SsrParams};
+use crate::{convert, formatter, StateSnapshot};
+use anyhow::Result;
 use async_lsp::{ErrorCode, ResponseError};
-use ide::{FileRange, GotoDefinitionResult};
+use ide::{ClosedExpr, FileRange, FlakeOutputPath, GotoDefinitionResult, ReplaceOptions};
 use lsp_types::{
-    CodeActionParams, CodeActionResponse, CompletionParams, CompletionResponse,
+    CodeActionParams, CodeActionResponse, CompletionList, CompletionParams, CompletionResponse,
     DocumentFormattingParams, DocumentHighlight, DocumentHighlightParams, DocumentLink,
     DocumentLinkParams, DocumentSymbolParams, DocumentSymbolResponse, GotoDefinitionParams,
     GotoDefinitionResponse, Hover, HoverParams, Location, Position, PrepareRenameResponse, Range,
     ReferenceParams, RenameParams, SelectionRange, SelectionRangeParams, SemanticTokens,
     SemanticTokensParams, SemanticTokensRangeParams, SemanticTokensRangeResult,
-    SemanticTokensResult, TextDocumentPositionParams, TextEdit, Url, WorkspaceEdit,
+    SemanticTokensResult, SignatureHelp, SignatureHelpParams, TextDocumentPositionParams, TextEdit,
+    Url, WorkspaceEdit,
 };
-use nix_interop::DEFAULT_IMPORT_FILE;
-use std::process;
+use nix_interop::{DEFAULT_IMPORT_FILE, FLAKE_LOCK_FILE};
+use std::path::PathBuf;
 use std::sync::Arc;
 use text_size::TextRange;
 
@@ -68,22 +70,37 @@ pub(crate) fn references(
     Ok(Some(locs))
 }
 
+/// Also returns the unevaluated flake output being completed, which should be evaluated.
 pub(crate) fn completion(
     snap: StateSnapshot,
     params: CompletionParams,
-) -> Result<Option<CompletionResponse>> {
+) -> Result<(Option<CompletionResponse>, Option<FlakeOutputPath>)> {
     let (fpos, line_map) = convert::from_file_pos(&snap.vfs(), &params.text_document_position)?;
     let trigger_char = params
         .context
         .and_then(|ctx| ctx.trigger_character?.chars().next());
+    let unevaluated = if snap.config.nix_flake_auto_eval_inputs {
+        snap.analysis.unevaluated_flake_output(fpos)?
+    } else {
+        None
+    };
     let Some(items) = snap.analysis.completions(fpos, trigger_char)? else {
-        return Ok(None);
+        return Ok((None, unevaluated));
     };
     let items = items
         .into_iter()
         .map(|item| convert::to_completion_item(&line_map, item))
         .collect::<Vec<_>>();
-    Ok(Some(CompletionResponse::Array(items)))
+    // Ask for completion again, after the unevaluated output is evaluated.
+    let resp = if unevaluated.is_some() {
+        CompletionResponse::List(CompletionList {
+            is_incomplete: true,
+            items,
+        })
+    } else {
+        CompletionResponse::Array(items)
+    };
+    Ok((Some(resp), unevaluated))
 }
 
 pub(crate) fn selection_range(
@@ -174,6 +191,15 @@ pub(crate) fn semantic_token_range(
     })))
 }
 
+pub(crate) fn signature_help(
+    snap: StateSnapshot,
+    params: SignatureHelpParams,
+) -> Result<Option<SignatureHelp>> {
+    let (fpos, _) = convert::from_file_pos(&snap.vfs(), &params.text_document_position_params)?;
+    let ret = snap.analysis.signature_help(fpos)?;
+    Ok(ret.map(convert::to_signature_help))
+}
+
 pub(crate) fn hover(snap: StateSnapshot, params: HoverParams) -> Result<Option<Hover>> {
     let (fpos, line_map) =
         convert::from_file_pos(&snap.vfs(), &params.text_document_position_params)?;
@@ -196,34 +222,18 @@ pub(crate) fn formatting(
     snap: StateSnapshot,
     params: DocumentFormattingParams,
 ) -> Result<Option<Vec<TextEdit>>> {
-    fn run_with_stdin(
-        cmd: &[String],
-        stdin_data: impl AsRef<[u8]> + Send + 'static,
-    ) -> Result<String> {
-        let mut child = process::Command::new(&cmd[0])
-            .args(&cmd[1..])
-            .stdin(process::Stdio::piped())
-            .stdout(process::Stdio::piped())
-            .stderr(process::Stdio::piped())
-            .spawn()?;
-        let mut stdin = child.stdin.take().unwrap();
-        std::thread::spawn(move || {
-            let _ = std::io::copy(&mut stdin_data.as_ref(), &mut stdin);
-        });
-        let output = child.wait_with_output()?;
-        ensure!(
-            output.status.success(),
-            "Formatter exited with {}, stderr: {}",
-            output.status,
-            String::from_utf8_lossy(&output.stderr),
-        );
-        let stdout = String::from_utf8(output.stdout)?;
-        Ok(stdout)
-    }
-
     let Some(cmd) = &snap.config.formatting_command else {
         return Ok(None);
     };
+    // The lock file is JSON, not Nix.
+    if params
+        .text_document
+        .uri
+        .path()
+        .ends_with(&format!("/{FLAKE_LOCK_FILE}"))
+    {
+        return Ok(None);
+    }
 
     let (file_content, line_map) = {
         let vfs = snap.vfs();
@@ -231,8 +241,7 @@ pub(crate) fn formatting(
         (vfs.content_for_file(file), line_map)
     };
 
-    let new_content = run_with_stdin(cmd, <Arc<[u8]>>::from(file_content.clone()))
-        .with_context(|| format!("Failed to run formatter {cmd:?}"))?;
+    let new_content = formatter::run_formatter(cmd, <Arc<[u8]>>::from(file_content.clone()))?;
 
     if new_content == *file_content {
         return Ok(None);
@@ -323,3 +332,56 @@ pub(crate) fn parent_module(
         .collect();
     Ok(Some(GotoDefinitionResponse::Array(locs)))
 }
+
+pub(crate) fn ssr(snap: StateSnapshot, params: SsrParams) -> Result<WorkspaceEdit> {
+    let invalid =
+        |msg: String| anyhow::Error::new(ResponseError::new(ErrorCode::INVALID_PARAMS, msg));
+    let (pattern, template) = params
+        .query
+        .split_once("==>>")
+        .ok_or_else(|| invalid("Missing `==>>` between the pattern and the template".into()))?;
+    let (pattern, template) = (pattern.trim(), template.trim());
+    if params.parse_only {
+        let pat = ssr::Pattern::parse(pattern)
+            .map_err(|err| invalid(format!("Invalid pattern: {err:#}")))?;
+        ssr::Template::parse(template, &pat)
+            .map_err(|err| invalid(format!("Invalid template: {err:#}")))?;
+        return Ok(WorkspaceEdit::default());
+    }
+    let (file, _) = convert::from_file(&snap.vfs(), &params.text_document)?;
+    let ws_edit = snap
+        .analysis
+        .ssr_replace_workspace(
+            file,
+            pattern,
+            template,
+            ReplaceOptions {
+                nested: params.nested,
+            },
+            params.fixpoint,
+        )?
+        .map_err(invalid)?;
+    Ok(convert::to_workspace_edit(&snap.vfs(), ws_edit))
+}
+
+/// Build the closed expression to evaluate for `nil/evalExpr`, its range, and the directory
+/// to resolve relative paths against.
+pub(crate) fn closed_expr(
+    snap: StateSnapshot,
+    params: EvalExprParams,
+) -> Result<Option<(ClosedExpr, Range, PathBuf)>> {
+    let (file, _) = convert::from_file(&snap.vfs(), &params.text_document)?;
+    let (line_map, range) = convert::from_range(&snap.vfs(), file, params.range)?;
+    let Some(expr) = snap.analysis.closed_expr(FileRange::new(file, range))? else {
+        return Ok(None);
+    };
+    let range = convert::to_range(&line_map, expr.range);
+    let base_dir = params
+        .text_document
+        .uri
+        .to_file_path()
+        .ok()
+        .and_then(|path| Some(path.parent()?.to_owned()))
+        .unwrap_or_else(|| snap.config.root_path.clone());
+    Ok(Some((expr, range, base_dir)))
+}
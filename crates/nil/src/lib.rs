@@ -1,12 +1,17 @@
 mod capabilities;
-mod config;
+pub mod config;
 mod convert;
+pub mod fix;
+pub mod formatter;
+mod glob;
 mod handler;
 mod lsp_ext;
 mod meter;
+pub mod report;
 mod semantic_tokens;
 mod server;
 mod vfs;
+pub mod workspace;
 
 use anyhow::Result;
 use async_lsp::client_monitor::ClientProcessMonitorLayer;
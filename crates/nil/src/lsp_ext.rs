@@ -1,5 +1,6 @@
 use lsp_types::notification::Notification;
 use lsp_types::request::Request;
+use serde::{Deserialize, Serialize};
 
 /// <https://github.com/microsoft/language-server-protocol/issues/1002>
 pub enum ParentModule {}
@@ -16,3 +17,59 @@ impl Notification for ReloadFlake {
     type Params = ();
     const METHOD: &'static str = "nil/reloadFlake";
 }
+
+/// Structural search and replace in all files of the workspace containing the document.
+/// Modeled after rust-analyzer's `experimental/ssr`.
+pub enum Ssr {}
+
+impl Request for Ssr {
+    type Params = SsrParams;
+    type Result = lsp_types::WorkspaceEdit;
+    const METHOD: &'static str = "experimental/ssr";
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SsrParams {
+    /// The pattern and the template, separated by `==>>`. Eg. `stdenv.lib.$x ==>> lib.$x`.
+    pub query: String,
+    /// Only check the query and return an empty edit.
+    #[serde(default)]
+    pub parse_only: bool,
+    /// Also replace matches inside captures of outer matches.
+    #[serde(default)]
+    pub nested: bool,
+    /// Replace repeatedly until there is nothing to replace. Fail if it does not finish.
+    #[serde(default)]
+    pub fixpoint: bool,
+    pub text_document: lsp_types::TextDocumentIdentifier,
+}
+
+/// Evaluate the smallest expression covering the range and pretty-print its value.
+/// Free variables are bound from enclosing scopes where possible.
+pub enum EvalExpr {}
+
+impl Request for EvalExpr {
+    type Params = EvalExprParams;
+    type Result = Option<EvalExprResult>;
+    const METHOD: &'static str = "nil/evalExpr";
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EvalExprParams {
+    pub text_document: lsp_types::TextDocumentIdentifier,
+    pub range: lsp_types::Range,
+    /// The maximum depth of nested attrsets and lists to print.
+    #[serde(default)]
+    pub depth: Option<u32>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EvalExprResult {
+    /// The range of the evaluated expression.
+    pub range: lsp_types::Range,
+    /// The value in Nix syntax.
+    pub value: String,
+}
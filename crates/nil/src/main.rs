@@ -1,10 +1,22 @@
-use anyhow::{Context, Result};
+use anyhow::{bail, ensure, Context, Result};
 use argh::FromArgs;
 use codespan_reporting::term::termcolor::WriteColor;
-use ide::{AnalysisHost, Severity};
+use ide::{Analysis, Builtins, Severity};
+use nil::config::{self, Config};
+use nil::fix::{fix_workspace, unified_diff};
+use nil::formatter::run_formatter;
+use nil::report::{
+    ssr_matches_to_json, write_report, write_ssr_matches_human, FileDiagnostics, FileMatches,
+    ReportFormat,
+};
+use nil::workspace::{Workspace, WorkspaceFile};
+use nix_interop::cache::{ClearFilter, EvalCache};
+use ssr::{FixpointLimitReached, ReplaceOptions, MAX_FIXPOINT_ROUNDS};
 use std::io::IsTerminal;
+use std::io::Write;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
 use std::{env, fs, io, process};
 use text_size::TextRange;
 use tracing_subscriber::fmt::writer::BoxMakeWriter;
@@ -32,22 +44,56 @@ struct Args {
 #[derive(Debug, FromArgs)]
 #[argh(subcommand)]
 enum Subcommand {
+    Cache(CacheArgs),
     Diagnostics(DiagnosticsArgs),
+    Fix(FixArgs),
     Parse(ParseArgs),
     Ssr(SsrArgs),
 }
 
 #[derive(Debug, FromArgs)]
 #[argh(subcommand, name = "diagnostics")]
-/// Check and print diagnostics for a file.
+/// Check and print diagnostics for files or directories.
+/// Directories are searched recursively for `*.nix` files, and all files are checked together
+/// so that relative imports resolve.
 /// Exit with non-zero code if there are any diagnostics. (`1` for errors, `2` if only warnings)
-/// WARNING: The output format is for human and should not be relied on.
+/// WARNING: The `human` output format is for human and should not be relied on.
 struct DiagnosticsArgs {
-    /// nix file to check, or read from stdin for `-`.
+    /// output format: `human` (default), `json`, `sarif` or `github`.
+    /// `json` and `sarif` have stable schemas, and `github` emits workflow commands for
+    /// annotations.
+    #[argh(option, default = "ReportFormat::Human")]
+    format: ReportFormat,
+    /// resolve flake inputs from `flake.lock` of the first directory, for input-aware checks.
+    /// This invokes `nix` and requires the inputs to be already fetched.
+    #[argh(switch)]
+    flake_inputs: bool,
+    /// nix files or directories to check, or read from stdin for a single `-`.
     /// NB. You need `--` before `-` for paths starting with `-`,
     /// to disambiguous it from flags.
     #[argh(positional)]
-    path: PathBuf,
+    paths: Vec<PathBuf>,
+}
+
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "fix")]
+/// Apply quick fixes of diagnostics and write files in place.
+/// Fixes are applied repeatedly until there is nothing more to fix.
+/// Files with syntax errors are skipped.
+struct FixArgs {
+    /// only fix diagnostics with this code, eg. `unused_binding`. Can be specified multiple times.
+    /// Fixes guessing the intention, like adding a parameter for `undefined_name`, are only
+    /// applied with this option.
+    #[argh(option)]
+    only: Vec<String>,
+    /// print a unified diff of changes to stdout instead of writing files.
+    #[argh(switch)]
+    dry_run: bool,
+    /// nix files or directories to fix, or read from stdin and write to stdout for a single `-`.
+    /// NB. You need `--` before `-` for paths starting with `-`,
+    /// to disambiguous it from flags.
+    #[argh(positional)]
+    paths: Vec<PathBuf>,
 }
 
 #[derive(Debug, FromArgs)]
@@ -78,7 +124,9 @@ fn main() {
 
     if let Some(subcommand) = args.subcommand {
         return match subcommand {
+            Subcommand::Cache(args) => main_cache(args),
             Subcommand::Diagnostics(args) => main_diagnostics(args),
+            Subcommand::Fix(args) => main_fix(args),
             Subcommand::Parse(args) => main_parse(args),
             Subcommand::Ssr(args) => main_ssr(args),
         };
@@ -114,24 +162,50 @@ fn main_diagnostics(args: DiagnosticsArgs) {
     use codespan_reporting::term::termcolor::{ColorChoice, StandardStream};
 
     let ret = (|| -> Result<Option<Severity>> {
-        let path = &*args.path;
-
-        let src = if path.as_os_str() == "-" {
-            io::read_to_string(io::stdin().lock()).context("Failed to read from stdin")?
-        } else {
-            fs::read_to_string(path).context("Failed to read file")?
-        };
-
-        let (analysis, file) = AnalysisHost::new_single_file(&src);
-        let diags = analysis
-            .snapshot()
-            .diagnostics(file)
-            .expect("No cancellation");
+        let config = load_config(&args.paths)?;
+        let mut workspace = load_workspace(&args.paths, &config)?;
+        if args.flake_inputs {
+            workspace.load_flake_inputs(&config.nix_binary)?;
+        }
 
-        let mut writer = StandardStream::stdout(ColorChoice::Auto);
-        emit_diagnostics(path, &src, &mut writer, &mut diags.iter().cloned())?;
+        let diags = workspace.par_map(|snap, file| {
+            if is_excluded(&config, file) {
+                return Vec::new();
+            }
+            let mut diags = snap.diagnostics(file.file).expect("No cancellation");
+            diags.retain(|diag| !config.diagnostics_ignored.contains(diag.code()));
+            diags
+        });
+
+        match args.format {
+            ReportFormat::Human => {
+                let mut writer = StandardStream::stdout(ColorChoice::Auto);
+                for (file, diags) in workspace.files().iter().zip(&diags) {
+                    emit_diagnostics(
+                        &file.path,
+                        &file.src,
+                        &mut writer,
+                        &mut diags.iter().cloned(),
+                    )?;
+                }
+            }
+            ReportFormat::Machine(format) => {
+                let files = workspace
+                    .files()
+                    .iter()
+                    .zip(&diags)
+                    .map(|(file, diags)| FileDiagnostics {
+                        path: &file.path,
+                        src: &file.src,
+                        diagnostics: diags,
+                    })
+                    .collect::<Vec<_>>();
+                write_report(format, &files, &mut io::stdout().lock())
+                    .context("Failed to write diagnostics")?;
+            }
+        }
 
-        Ok(diags.iter().map(|diag| diag.severity()).max())
+        Ok(diags.iter().flatten().map(|diag| diag.severity()).max())
     })();
     match ret {
         Ok(None) => process::exit(0),
@@ -139,7 +213,7 @@ fn main_diagnostics(args: DiagnosticsArgs) {
             if max_severity > Severity::Warning {
                 process::exit(1)
             } else {
-                process::exit(0)
+                process::exit(2)
             }
         }
         Err(err) => {
@@ -149,6 +223,117 @@ fn main_diagnostics(args: DiagnosticsArgs) {
     }
 }
 
+fn main_fix(args: FixArgs) {
+    let ret = (|| -> Result<()> {
+        let config = load_config(&args.paths)?;
+        let mut workspace = load_workspace(&args.paths, &config)?;
+        let is_stdin = matches!(&*args.paths, [path] if path.as_os_str() == "-");
+        let old_srcs = workspace
+            .files()
+            .iter()
+            .map(|file| file.src.clone())
+            .collect::<Vec<_>>();
+
+        // Fixes guessing the intention are only applied for explicitly selected diagnostics.
+        let counts = fix_workspace(&mut workspace, !args.only.is_empty(), |file, diag| {
+            let code = diag.code();
+            !is_excluded(&config, file)
+                && !config.diagnostics_ignored.contains(code)
+                && (args.only.is_empty() || args.only.iter().any(|only| only == code))
+        });
+
+        let mut stdout = io::stdout().lock();
+        for ((file, old_src), &cnt) in workspace.files().iter().zip(&old_srcs).zip(&counts) {
+            if args.dry_run {
+                let diff = unified_diff(&file.path, old_src, &file.src);
+                stdout.write_all(diff.as_bytes())?;
+            } else if is_stdin {
+                stdout.write_all(file.src.as_bytes())?;
+            } else if cnt != 0 {
+                fs::write(&file.path, &*file.src)
+                    .with_context(|| format!("Failed to write {}", file.path.display()))?;
+            }
+        }
+
+        let total = counts.iter().sum::<usize>();
+        let files = counts.iter().filter(|&&cnt| cnt != 0).count();
+        eprintln!("Applied {total} fixes in {files} files");
+        Ok(())
+    })();
+    if let Err(err) = ret {
+        eprintln!("{err:#}");
+        process::exit(1);
+    }
+}
+
+/// Load the project configuration file from the nearest ancestor directory of the first path,
+/// or the current directory for stdin.
+fn load_config(paths: &[PathBuf]) -> Result<Config> {
+    let cwd = env::current_dir().context("Failed to get the current directory")?;
+    let start = match paths.first() {
+        Some(path) if path.as_os_str() != "-" => {
+            let path = cwd.join(path);
+            if path.is_dir() {
+                path
+            } else {
+                path.parent().unwrap_or(&cwd).to_owned()
+            }
+        }
+        _ => cwd.clone(),
+    };
+    let project_config = config::find_project_config(&start);
+    let root = project_config
+        .as_deref()
+        .and_then(Path::parent)
+        .unwrap_or(&cwd)
+        .to_owned();
+
+    let mut config = Config::new(root);
+    if let Some(path) = &project_config {
+        let mut errors = Vec::new();
+        config.update(config::load_project_settings(path)?, &mut errors);
+        if !errors.is_empty() {
+            bail!(
+                "Invalid settings in {}:\n{}",
+                path.display(),
+                errors.join("\n"),
+            );
+        }
+    }
+    Ok(config)
+}
+
+/// Load paths as a workspace, or read a single file from stdin for `-`.
+fn load_workspace(paths: &[PathBuf], config: &Config) -> Result<Workspace> {
+    let mut workspace = match paths {
+        [path] if path.as_os_str() == "-" => {
+            let src =
+                io::read_to_string(io::stdin().lock()).context("Failed to read from stdin")?;
+            Workspace::single_file(path.clone(), src)
+        }
+        _ => Workspace::load(paths)?,
+    };
+    // Only use the Nix target if configured. Don't invoke `nix` for detection.
+    if config.nix_dialect.is_some() || config.nix_version.is_some() {
+        let dialect = config.nix_dialect.unwrap_or_default();
+        let builtins = match &config.nix_version {
+            Some(version) => Config::builtins_for_version(dialect, version),
+            None => Builtins::clone(&Builtins::latest()),
+        };
+        workspace.set_nix_target(dialect, builtins);
+    }
+    if let Some(system) = &config.nix_system {
+        workspace.set_nix_system(system);
+    }
+    Ok(workspace)
+}
+
+fn is_excluded(config: &Config, file: &WorkspaceFile) -> bool {
+    file.abs_path
+        .as_ref()
+        .is_some_and(|path| config.is_diagnostics_excluded(path))
+}
+
 fn main_parse(args: ParseArgs) {
     use codespan_reporting::term::termcolor::{ColorChoice, StandardStream};
 
@@ -188,55 +373,183 @@ fn main_parse(args: ParseArgs) {
 #[derive(Debug, FromArgs)]
 #[argh(subcommand, name = "ssr")]
 /// Search structural patterns and optionaly replace them.
+/// Directories are searched recursively for `*.nix` files.
+/// Without `--write` or `--diff`, the replaced content of a single file is printed to stdout.
 /// WARNING: This functionality is experimental.
 struct SsrArgs {
-    /// nix file to check, or read from stdin for `-`.
+    /// nix file or directory to search, or read from stdin for `-`.
     /// NB. You need `--` before `-` for paths starting with `-`,
     /// to disambiguous it from flags.
     #[argh(positional)]
     path: PathBuf,
-    /// expression pattern to search. Placeholders `$name` can be used to capture sub-expressions.
+    // NB. argh formats doc comments, so braces are escaped by doubling.
+    /// expression or bindings pattern to search. Placeholders `$name` can be used to capture
+    /// sub-expressions, attributes or attrpaths, and `$...name` to capture a list of bindings.
+    /// Placeholders can be constrained, like `${{name:ident}}`, with constraints
+    /// `literal`, `string(regex)`, `ident(regex)` or `kind(list)`. Regexes are optional
+    /// and must match the whole text.
     #[argh(positional)]
     pattern: String,
-    /// expression template to replace. Placeholders `$name` can be used to substitute
-    /// sub-expressions from the pattern.
+    /// expression or bindings template to replace. Placeholders `$name` can be used to
+    /// substitute captures from the pattern.
     #[argh(positional)]
     template: Option<String>,
+    /// match references by what they refer to, rather than their spelling.
+    /// Eg. `lib.mkIf` also matches `mkIf` under `with lib;`.
+    #[argh(switch)]
+    semantic: bool,
+    /// write replaced files in place.
+    #[argh(switch)]
+    write: bool,
+    /// print a unified diff of replacements to stdout instead of writing files.
+    #[argh(switch)]
+    diff: bool,
+    /// run the formatter of `formatting.command` from `.nil.toml` on replaced files.
+    #[argh(switch)]
+    format: bool,
+    /// also replace matches inside captures of outer matches, bottom-up.
+    #[argh(switch)]
+    nested: bool,
+    /// replace repeatedly until there is nothing to replace, for rules creating new matches.
+    /// Fail without any change if it does not finish in 16 rounds.
+    #[argh(switch)]
+    fixpoint: bool,
+    /// print matches in JSON, with 1-based lines and columns in UTF-16 code units.
+    /// See `crates/nil/src/report.rs` for the schema.
+    #[argh(switch)]
+    json: bool,
 }
 
 fn main_ssr(args: SsrArgs) {
     let ret = (|| -> Result<()> {
-        let path = &*args.path;
+        // Validate them early. They are parsed again in each worker since syntax trees are not
+        // `Sync`.
+        let pat = ssr::Pattern::parse(&args.pattern).context("invalid SSR pattern")?;
+        if let Some(templ) = &args.template {
+            ssr::Template::parse(templ, &pat).context("invalid SSR template")?;
+        }
 
-        let src = if path.as_os_str() == "-" {
-            io::read_to_string(io::stdin().lock()).context("Failed to read from stdin")?
-        } else {
-            fs::read_to_string(path).context("Failed to read file")?
+        let paths = std::slice::from_ref(&args.path);
+        let config = load_config(paths)?;
+        let mut workspace = load_workspace(paths, &config)?;
+        let is_stdin = args.path.as_os_str() == "-";
+
+        let Some(templ) = &args.template else {
+            ensure!(
+                !args.write && !args.diff && !args.format && !args.nested && !args.fixpoint,
+                "`--write`, `--diff`, `--format`, `--nested` and `--fixpoint` require a template"
+            );
+            let ranges = workspace
+                .par_map(|snap, file| ssr_search_file(snap, file, &args.pattern, args.semantic))
+                .into_iter()
+                .collect::<Result<Vec<_>>>()?;
+            let files = workspace
+                .files()
+                .iter()
+                .zip(&ranges)
+                .map(|(file, ranges)| FileMatches {
+                    path: &file.path,
+                    src: &file.src,
+                    ranges,
+                })
+                .collect::<Vec<_>>();
+            let mut stdout = io::stdout().lock();
+            if args.json {
+                serde_json::to_writer_pretty(&mut stdout, &ssr_matches_to_json(&files))?;
+                writeln!(stdout)?;
+            } else {
+                write_ssr_matches_human(&files, &mut stdout)?;
+            }
+            return Ok(());
         };
 
-        let parse = syntax::parse_file(&src);
-        let pat = ssr::Pattern::parse(&args.pattern).context("invalid SSR pattern")?;
+        ensure!(!args.json, "`--json` is only for searching");
+        let formatter = match &config.formatting_command {
+            Some(cmd) if args.format => Some(cmd),
+            None if args.format => bail!("`--format` requires `formatting.command` to be set"),
+            _ => None,
+        };
+        ensure!(
+            !(args.write && args.diff),
+            "`--write` and `--diff` cannot be used together"
+        );
+        ensure!(
+            !args.write || !is_stdin,
+            "`--write` cannot be used with stdin"
+        );
+        ensure!(
+            args.write || args.diff || workspace.files().len() == 1,
+            "`--write` or `--diff` is required to replace in multiple files"
+        );
 
-        match args.template {
-            None => {
-                for n in pat.find_iter(&parse.syntax_node()) {
-                    let range = n.text_range();
-                    println!(
-                        "{}:{}-{}:{}",
-                        args.path.display(),
-                        u32::from(range.start()),
-                        u32::from(range.end()),
-                        &src[range],
-                    );
-                }
+        let old_srcs = workspace
+            .files()
+            .iter()
+            .map(|file| file.src.clone())
+            .collect::<Vec<_>>();
+        let opts = ReplaceOptions {
+            nested: args.nested,
+        };
+        // Re-analyze in each round, so that it works in the semantic mode.
+        let rounds = if args.fixpoint {
+            MAX_FIXPOINT_ROUNDS
+        } else {
+            1
+        };
+        let mut converged = !args.fixpoint;
+        for _ in 0..rounds {
+            let new_srcs = workspace
+                .par_map(|snap, file| {
+                    ssr_replace_file(snap, file, &args.pattern, templ, args.semantic, opts)
+                })
+                .into_iter()
+                .collect::<Result<Vec<_>>>()?;
+            let changes = workspace
+                .files()
+                .iter()
+                .zip(new_srcs)
+                .filter(|(file, new_src)| *file.src != **new_src)
+                .map(|(file, new_src)| (file.file, new_src))
+                .collect::<Vec<_>>();
+            if changes.is_empty() {
+                converged = true;
+                break;
             }
-            Some(templ) => {
-                let templ = ssr::Template::parse(&templ, &pat).context("invalid SSR template")?;
-                let ret = pat.replace(&src, &templ, &parse.syntax_node());
-                print!("{ret}");
+            for (file, new_src) in changes {
+                workspace.set_file_content(file, new_src.into());
             }
         }
+        // Nothing is written or printed for non-terminating rules.
+        if !converged {
+            return Err(FixpointLimitReached.into());
+        }
 
+        let mut stdout = io::stdout().lock();
+        let mut changed = 0usize;
+        for (file, old_src) in workspace.files().iter().zip(&old_srcs) {
+            let mut new_src = file.src.to_string();
+            if let Some(cmd) = formatter.filter(|_| *old_src != file.src) {
+                new_src = run_formatter(cmd, new_src)
+                    .with_context(|| format!("Failed to format {}", file.path.display()))?;
+            }
+            if **old_src != new_src {
+                changed += 1;
+            }
+            if args.diff {
+                let diff = unified_diff(&file.path, old_src, &new_src);
+                stdout.write_all(diff.as_bytes())?;
+            } else if args.write {
+                if **old_src != new_src {
+                    fs::write(&file.path, &new_src)
+                        .with_context(|| format!("Failed to write {}", file.path.display()))?;
+                }
+            } else {
+                stdout.write_all(new_src.as_bytes())?;
+            }
+        }
+        if args.write || args.diff {
+            eprintln!("Replaced in {changed} files");
+        }
         Ok(())
     })();
 
@@ -249,6 +562,52 @@ fn main_ssr(args: SsrArgs) {
     }
 }
 
+fn ssr_search_file(
+    snap: &Analysis,
+    file: &WorkspaceFile,
+    pattern: &str,
+    semantic: bool,
+) -> Result<Vec<TextRange>> {
+    if semantic {
+        return snap
+            .ssr_search(file.file, pattern)
+            .expect("No cancellation")
+            .map_err(anyhow::Error::msg);
+    }
+    let pat = ssr::Pattern::parse(pattern)?;
+    let parse = syntax::parse_file(&file.src);
+    Ok(pat
+        .find_iter(&parse.syntax_node())
+        .map(|m| m.range)
+        .collect())
+}
+
+/// Returns the replaced content of the file.
+fn ssr_replace_file(
+    snap: &Analysis,
+    file: &WorkspaceFile,
+    pattern: &str,
+    template: &str,
+    semantic: bool,
+    opts: ReplaceOptions,
+) -> Result<String> {
+    if semantic {
+        let edits = snap
+            .ssr_replace(file.file, pattern, template, opts)
+            .expect("No cancellation")
+            .map_err(anyhow::Error::msg)?;
+        let mut ret = file.src.to_string();
+        for edit in edits.iter().rev() {
+            edit.apply(&mut ret);
+        }
+        return Ok(ret);
+    }
+    let pat = ssr::Pattern::parse(pattern)?;
+    let templ = ssr::Template::parse(template, &pat)?;
+    let parse = syntax::parse_file(&file.src);
+    Ok(pat.replace(&file.src, &templ, &parse.syntax_node(), opts))
+}
+
 fn emit_diagnostics(
     path: &Path,
     src: &str,
@@ -309,3 +668,107 @@ fn setup_logger() {
         .with_writer(writer)
         .init();
 }
+
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "cache")]
+/// Manage the on-disk cache of evaluated NixOS options and flake outputs.
+/// The cache is stored under `$XDG_CACHE_HOME/nil`, or `~/.cache/nil` if it is unset.
+/// Entries are keyed by the `narHash` of locked inputs and the Nix version.
+struct CacheArgs {
+    #[argh(subcommand)]
+    subcommand: CacheSubcommand,
+}
+
+#[derive(Debug, FromArgs)]
+#[argh(subcommand)]
+enum CacheSubcommand {
+    Dir(CacheDirArgs),
+    List(CacheListArgs),
+    Clear(CacheClearArgs),
+}
+
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "dir")]
+/// Print the cache directory.
+struct CacheDirArgs {}
+
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "list")]
+/// List cache entries, with their kinds, input hashes, Nix versions, target systems and sizes.
+/// Entries in an outdated format are listed as `outdated`.
+struct CacheListArgs {}
+
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "clear")]
+/// Remove cache entries, or only some of them with filters.
+struct CacheClearArgs {
+    /// only remove entries which are unreadable or in an outdated format.
+    #[argh(switch)]
+    unreadable: bool,
+    /// only remove unreadable entries and entries stored more than this many days ago.
+    #[argh(option)]
+    older_than_days: Option<u64>,
+}
+
+fn main_cache(args: CacheArgs) {
+    let ret = (|| -> Result<()> {
+        let cache = EvalCache::from_env()
+            .context("Cannot locate the cache directory: neither XDG_CACHE_HOME nor HOME is set")?;
+        match args.subcommand {
+            CacheSubcommand::Dir(CacheDirArgs {}) => println!("{}", cache.dir().display()),
+            CacheSubcommand::List(CacheListArgs {}) => {
+                let mut stdout = io::stdout().lock();
+                for ent in cache.entries()? {
+                    let (hash, version, system) = match &ent.key {
+                        Some(key) => (
+                            &*key.nar_hash,
+                            &*key.nix_version,
+                            key.system.as_deref().unwrap_or("-"),
+                        ),
+                        None => ("outdated", "-", "-"),
+                    };
+                    writeln!(
+                        stdout,
+                        "{}\t{hash}\t{version}\t{system}\t{}",
+                        ent.kind, ent.size,
+                    )?;
+                }
+            }
+            CacheSubcommand::Clear(CacheClearArgs {
+                unreadable,
+                older_than_days,
+            }) => {
+                let filter = match (unreadable, older_than_days) {
+                    (false, None) => ClearFilter::All,
+                    (true, None) => ClearFilter::Unreadable,
+                    (false, Some(days)) => {
+                        ClearFilter::OlderThan(Duration::from_secs(days * 24 * 60 * 60))
+                    }
+                    (true, Some(_)) => {
+                        bail!("--unreadable and --older-than-days cannot be used together")
+                    }
+                };
+                let cnt = cache.clear(filter)?;
+                eprintln!("Removed {cnt} cache entries");
+            }
+        }
+        Ok(())
+    })();
+    if let Err(err) = ret {
+        eprintln!("{err:#}");
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ssr_help() {
+        let help = SsrArgs::from_args(&["nil ssr"], &["--help"])
+            .unwrap_err()
+            .output;
+        assert!(help.contains("like `${name:ident}`"), "{help}");
+    }
+}
@@ -0,0 +1,577 @@
+//! Machine readable output formats of diagnostics and structural search matches for the
+//! command line.
+//!
+//! Positions are 1-based lines and 1-based columns counted in UTF-16 code units, as required by
+//! SARIF and GitHub workflow commands. The JSON format is stable and versioned by the top-level
+//! `version` field.
+use ide::{Diagnostic, Severity};
+use serde_json::{json, Value};
+use std::io::{self, Write};
+use std::path::Path;
+use std::str::FromStr;
+use text_size::{TextRange, TextSize};
+
+/// The version of the JSON output format.
+/// It is bumped on any incompatible schema changes.
+pub const JSON_FORMAT_VERSION: u32 = 1;
+
+const SARIF_SCHEMA: &str = "https://json.schemastore.org/sarif-2.1.0.json";
+const INFORMATION_URI: &str = "https://github.com/oxalica/nil";
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ReportFormat {
+    /// Colored text for human, emitted by codespan-reporting. The format should not be relied on.
+    #[default]
+    Human,
+    Machine(MachineFormat),
+}
+
+/// Machine readable formats, written by `write_report`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MachineFormat {
+    Json,
+    Sarif,
+    /// GitHub Actions workflow commands, which create annotations on pull requests.
+    Github,
+}
+
+impl FromStr for ReportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "human" => Self::Human,
+            "json" => Self::Machine(MachineFormat::Json),
+            "sarif" => Self::Machine(MachineFormat::Sarif),
+            "github" => Self::Machine(MachineFormat::Github),
+            _ => {
+                return Err(format!(
+                    "unknown format `{s}`, expecting one of: human, json, sarif, github"
+                ))
+            }
+        })
+    }
+}
+
+/// Diagnostics of a single file.
+#[derive(Debug, Clone, Copy)]
+pub struct FileDiagnostics<'a> {
+    pub path: &'a Path,
+    pub src: &'a str,
+    pub diagnostics: &'a [Diagnostic],
+}
+
+/// Structural search matches of a single file.
+#[derive(Debug, Clone, Copy)]
+pub struct FileMatches<'a> {
+    pub path: &'a Path,
+    pub src: &'a str,
+    pub ranges: &'a [TextRange],
+}
+
+/// A 1-based line and column.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct LineCol {
+    line: u32,
+    col: u32,
+}
+
+fn line_col(src: &str, pos: TextSize) -> LineCol {
+    let before = &src[..usize::from(pos)];
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    let col = before[line_start..]
+        .chars()
+        .map(|c| c.len_utf16() as u32)
+        .sum::<u32>();
+    LineCol {
+        line: before.matches('\n').count() as u32 + 1,
+        col: col + 1,
+    }
+}
+
+fn range_to_json(src: &str, range: TextRange) -> Value {
+    let (start, end) = (line_col(src, range.start()), line_col(src, range.end()));
+    json!({
+        "start": { "line": start.line, "column": start.col },
+        "end": { "line": end.line, "column": end.col },
+    })
+}
+
+fn severity_str(severity: Severity) -> &'static str {
+    match severity {
+        Severity::Error | Severity::IncompleteSyntax => "error",
+        Severity::Warning => "warning",
+    }
+}
+
+/// Write diagnostics in the given machine readable format.
+pub fn write_report(
+    format: MachineFormat,
+    files: &[FileDiagnostics<'_>],
+    w: &mut dyn Write,
+) -> io::Result<()> {
+    match format {
+        MachineFormat::Json => {
+            serde_json::to_writer_pretty(&mut *w, &to_json(files))?;
+            writeln!(w)
+        }
+        MachineFormat::Sarif => {
+            serde_json::to_writer_pretty(&mut *w, &to_sarif(files))?;
+            writeln!(w)
+        }
+        MachineFormat::Github => write_github(files, w),
+    }
+}
+
+/// The JSON format.
+///
+/// ```json
+/// {
+///   "version": 1,
+///   "diagnostics": [
+///     {
+///       "path": "foo.nix",
+///       "code": "unused_binding",
+///       "severity": "warning",
+///       "message": "Unused binding",
+///       "range": { "start": { "line": 1, "column": 5 }, "end": { "line": 1, "column": 6 } },
+///       "notes": [ { "range": { ... }, "message": "..." } ]
+///     }
+///   ]
+/// }
+/// ```
+pub fn to_json(files: &[FileDiagnostics<'_>]) -> Value {
+    let diagnostics = files
+        .iter()
+        .flat_map(|file| {
+            file.diagnostics.iter().map(|diag| {
+                json!({
+                    "path": file.path.display().to_string(),
+                    "code": diag.code(),
+                    "severity": severity_str(diag.severity()),
+                    "message": diag.message(),
+                    "range": range_to_json(file.src, diag.range),
+                    "notes": diag
+                        .notes
+                        .iter()
+                        .map(|(frange, msg)| json!({
+                            "range": range_to_json(file.src, frange.range),
+                            "message": msg,
+                        }))
+                        .collect::<Vec<_>>(),
+                })
+            })
+        })
+        .collect::<Vec<_>>();
+    json!({
+        "version": JSON_FORMAT_VERSION,
+        "diagnostics": diagnostics,
+    })
+}
+
+/// The [SARIF 2.1.0](https://docs.oasis-open.org/sarif/sarif/v2.1.0/sarif-v2.1.0.html) format,
+/// accepted by code scanning services.
+pub fn to_sarif(files: &[FileDiagnostics<'_>]) -> Value {
+    let physical_location = |file: &FileDiagnostics<'_>, range: TextRange| {
+        let (start, end) = (
+            line_col(file.src, range.start()),
+            line_col(file.src, range.end()),
+        );
+        json!({
+            "artifactLocation": { "uri": path_to_uri(file.path) },
+            "region": {
+                "startLine": start.line,
+                "startColumn": start.col,
+                "endLine": end.line,
+                "endColumn": end.col,
+            },
+        })
+    };
+
+    let mut rules = files
+        .iter()
+        .flat_map(|file| file.diagnostics.iter().map(|diag| diag.code()))
+        .collect::<Vec<_>>();
+    rules.sort_unstable();
+    rules.dedup();
+
+    let results = files
+        .iter()
+        .flat_map(|file| {
+            file.diagnostics.iter().map(move |diag| {
+                json!({
+                    "ruleId": diag.code(),
+                    "level": severity_str(diag.severity()),
+                    "message": { "text": diag.message() },
+                    "locations": [{ "physicalLocation": physical_location(file, diag.range) }],
+                    "relatedLocations": diag
+                        .notes
+                        .iter()
+                        .zip(0..)
+                        .map(|((frange, msg), id)| json!({
+                            "id": id,
+                            "physicalLocation": physical_location(file, frange.range),
+                            "message": { "text": msg },
+                        }))
+                        .collect::<Vec<_>>(),
+                })
+            })
+        })
+        .collect::<Vec<_>>();
+
+    json!({
+        "$schema": SARIF_SCHEMA,
+        "version": "2.1.0",
+        "runs": [{
+            "tool": {
+                "driver": {
+                    "name": "nil",
+                    "informationUri": INFORMATION_URI,
+                    "version": option_env!("CFG_RELEASE").unwrap_or("unknown"),
+                    "rules": rules
+                        .iter()
+                        .map(|code| json!({ "id": code }))
+                        .collect::<Vec<_>>(),
+                },
+            },
+            "results": results,
+        }],
+    })
+}
+
+/// The JSON format of structural search matches.
+///
+/// ```json
+/// {
+///   "version": 1,
+///   "matches": [
+///     {
+///       "path": "foo.nix",
+///       "range": { "start": { "line": 1, "column": 5 }, "end": { "line": 1, "column": 6 } },
+///       "text": "lib.mkIf cond"
+///     }
+///   ]
+/// }
+/// ```
+pub fn ssr_matches_to_json(files: &[FileMatches<'_>]) -> Value {
+    let matches = files
+        .iter()
+        .flat_map(|file| {
+            file.ranges.iter().map(|&range| {
+                json!({
+                    "path": file.path.display().to_string(),
+                    "range": range_to_json(file.src, range),
+                    "text": &file.src[range],
+                })
+            })
+        })
+        .collect::<Vec<_>>();
+    json!({
+        "version": JSON_FORMAT_VERSION,
+        "matches": matches,
+    })
+}
+
+/// Structural search matches for human, one `path:line:column: text` per match.
+/// Only the first line of multi-line matches is shown.
+pub fn write_ssr_matches_human(files: &[FileMatches<'_>], w: &mut dyn Write) -> io::Result<()> {
+    for file in files {
+        for &range in file.ranges {
+            let pos = line_col(file.src, range.start());
+            let text = &file.src[range];
+            let first_line = text.lines().next().unwrap_or_default();
+            let ellipsis = if first_line.len() < text.len() {
+                " ..."
+            } else {
+                ""
+            };
+            writeln!(
+                w,
+                "{}:{}:{}: {first_line}{ellipsis}",
+                file.path.display(),
+                pos.line,
+                pos.col,
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// SARIF requires URI references. Relative paths are kept relative to be resolved against the
+/// checkout root.
+fn path_to_uri(path: &Path) -> String {
+    path.display()
+        .to_string()
+        .replace('\\', "/")
+        .replace('%', "%25")
+        .replace(' ', "%20")
+}
+
+/// GitHub workflow commands.
+/// See: <https://docs.github.com/en/actions/using-workflows/workflow-commands-for-github-actions>
+pub fn write_github(files: &[FileDiagnostics<'_>], w: &mut dyn Write) -> io::Result<()> {
+    fn escape_data(s: &str) -> String {
+        s.replace('%', "%25")
+            .replace('\r', "%0D")
+            .replace('\n', "%0A")
+    }
+    fn escape_property(s: &str) -> String {
+        escape_data(s).replace(':', "%3A").replace(',', "%2C")
+    }
+
+    for file in files {
+        for diag in file.diagnostics {
+            let (start, end) = (
+                line_col(file.src, diag.range.start()),
+                line_col(file.src, diag.range.end()),
+            );
+            let mut message = diag.message();
+            for (frange, note) in &diag.notes {
+                let pos = line_col(file.src, frange.range.start());
+                message += &format!("\n{}:{}: {note}", pos.line, pos.col);
+            }
+            writeln!(
+                w,
+                "::{} file={},line={},col={},endLine={},endColumn={},title={}::{}",
+                severity_str(diag.severity()),
+                escape_property(&file.path.display().to_string()),
+                start.line,
+                start.col,
+                end.line,
+                end.col,
+                escape_property(&format!("nil: {}", diag.code())),
+                escape_data(&message),
+            )?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use expect_test::{expect, Expect};
+    use ide::AnalysisHost;
+
+    #[track_caller]
+    fn check(format: MachineFormat, src: &str, expect: Expect) {
+        let (analysis, file) = AnalysisHost::new_single_file(src);
+        let diagnostics = analysis.snapshot().diagnostics(file).unwrap();
+        let files = [FileDiagnostics {
+            path: Path::new("dir/foo.nix"),
+            src,
+            diagnostics: &diagnostics,
+        }];
+        let mut out = Vec::new();
+        write_report(format, &files, &mut out).unwrap();
+        // The release version is set in CI builds.
+        let got = String::from_utf8(out)
+            .unwrap()
+            .replace(option_env!("CFG_RELEASE").unwrap_or("unknown"), "<version>");
+        expect.assert_eq(&got);
+    }
+
+    #[test]
+    fn ssr_matches() {
+        let src = "[\n  (f 1)\n  (f {\n  })\n]";
+        let ranges = [
+            TextRange::new(5.into(), 8.into()),
+            TextRange::new(13.into(), 20.into()),
+        ];
+        let files = [FileMatches {
+            path: Path::new("dir/foo.nix"),
+            src,
+            ranges: &ranges,
+        }];
+
+        let mut out = Vec::new();
+        write_ssr_matches_human(&files, &mut out).unwrap();
+        expect![[r#"
+            dir/foo.nix:2:4: f 1
+            dir/foo.nix:3:4: f { ...
+        "#]]
+        .assert_eq(&String::from_utf8(out).unwrap());
+
+        expect![[r#"
+            {
+              "matches": [
+                {
+                  "path": "dir/foo.nix",
+                  "range": {
+                    "end": {
+                      "column": 7,
+                      "line": 2
+                    },
+                    "start": {
+                      "column": 4,
+                      "line": 2
+                    }
+                  },
+                  "text": "f 1"
+                },
+                {
+                  "path": "dir/foo.nix",
+                  "range": {
+                    "end": {
+                      "column": 4,
+                      "line": 4
+                    },
+                    "start": {
+                      "column": 4,
+                      "line": 3
+                    }
+                  },
+                  "text": "f {\n  }"
+                }
+              ],
+              "version": 1
+            }"#]]
+        .assert_eq(&serde_json::to_string_pretty(&ssr_matches_to_json(&files)).unwrap());
+    }
+
+    #[test]
+    fn line_col_utf16() {
+        let src = "a\n字𝄞b\n";
+        let pos = |s: &str| TextSize::from(src.find(s).unwrap() as u32);
+        assert_eq!(line_col(src, pos("a")), LineCol { line: 1, col: 1 });
+        assert_eq!(line_col(src, pos("字")), LineCol { line: 2, col: 1 });
+        assert_eq!(line_col(src, pos("b")), LineCol { line: 2, col: 4 });
+        assert_eq!(
+            line_col(src, TextSize::of(src)),
+            LineCol { line: 3, col: 1 }
+        );
+    }
+
+    #[test]
+    fn json() {
+        check(
+            MachineFormat::Json,
+            "{\n  a = 1;\n  a = 2;\n}",
+            expect![[r#"
+                {
+                  "diagnostics": [
+                    {
+                      "code": "duplicated_key",
+                      "message": "Duplicated name definition",
+                      "notes": [
+                        {
+                          "message": "Previously defined here",
+                          "range": {
+                            "end": {
+                              "column": 4,
+                              "line": 2
+                            },
+                            "start": {
+                              "column": 3,
+                              "line": 2
+                            }
+                          }
+                        }
+                      ],
+                      "path": "dir/foo.nix",
+                      "range": {
+                        "end": {
+                          "column": 4,
+                          "line": 3
+                        },
+                        "start": {
+                          "column": 3,
+                          "line": 3
+                        }
+                      },
+                      "severity": "error"
+                    }
+                  ],
+                  "version": 1
+                }
+            "#]],
+        );
+    }
+
+    #[test]
+    fn sarif() {
+        check(
+            MachineFormat::Sarif,
+            "{\n  a = 1;\n  a = 2;\n}",
+            expect![[r#"
+                {
+                  "$schema": "https://json.schemastore.org/sarif-2.1.0.json",
+                  "runs": [
+                    {
+                      "results": [
+                        {
+                          "level": "error",
+                          "locations": [
+                            {
+                              "physicalLocation": {
+                                "artifactLocation": {
+                                  "uri": "dir/foo.nix"
+                                },
+                                "region": {
+                                  "endColumn": 4,
+                                  "endLine": 3,
+                                  "startColumn": 3,
+                                  "startLine": 3
+                                }
+                              }
+                            }
+                          ],
+                          "message": {
+                            "text": "Duplicated name definition"
+                          },
+                          "relatedLocations": [
+                            {
+                              "id": 0,
+                              "message": {
+                                "text": "Previously defined here"
+                              },
+                              "physicalLocation": {
+                                "artifactLocation": {
+                                  "uri": "dir/foo.nix"
+                                },
+                                "region": {
+                                  "endColumn": 4,
+                                  "endLine": 2,
+                                  "startColumn": 3,
+                                  "startLine": 2
+                                }
+                              }
+                            }
+                          ],
+                          "ruleId": "duplicated_key"
+                        }
+                      ],
+                      "tool": {
+                        "driver": {
+                          "informationUri": "https://github.com/oxalica/nil",
+                          "name": "nil",
+                          "rules": [
+                            {
+                              "id": "duplicated_key"
+                            }
+                          ],
+                          "version": "<version>"
+                        }
+                      }
+                    }
+                  ],
+                  "version": "2.1.0"
+                }
+            "#]],
+        );
+    }
+
+    #[test]
+    fn github() {
+        check(
+            MachineFormat::Github,
+            "let\n  a = 1;\n  a = 2;\nin http://a,b",
+            expect![[r#"
+                ::error file=dir/foo.nix,line=3,col=3,endLine=3,endColumn=4,title=nil%3A duplicated_key::Duplicated name definition%0A2:3: Previously defined here
+                ::warning file=dir/foo.nix,line=4,col=4,endLine=4,endColumn=14,title=nil%3A uri_literal::URL literal is confusing and deprecated. Use strings instead
+                ::warning file=dir/foo.nix,line=2,col=3,endLine=2,endColumn=4,title=nil%3A unused_binding::Unused binding
+                ::warning file=dir/foo.nix,line=3,col=3,endLine=3,endColumn=4,title=nil%3A unused_binding::Unused binding
+            "#]],
+        );
+    }
+}
@@ -1,26 +1,36 @@
 use crate::capabilities::{negotiate_capabilities, NegotiatedCapabilities};
-use crate::config::{Config, CONFIG_KEY};
+use crate::config::{self, Config, CONFIG_KEY, PROJECT_CONFIG_FILE};
+use crate::lsp_ext::{EvalExprParams, EvalExprResult};
 use crate::{convert, handler, lsp_ext, UrlExt, Vfs, MAX_FILE_LEN};
 use anyhow::{bail, ensure, Context, Result};
 use async_lsp::router::Router;
 use async_lsp::{ClientSocket, ErrorCode, LanguageClient, ResponseError};
-use ide::{Analysis, AnalysisHost, Cancelled, FlakeInfo, VfsPath};
+use ide::Builtins;
+use ide::{Analysis, AnalysisHost, Cancelled, FlakeInfo, FlakeOutputPath, VfsPath};
 use lsp_types::notification::Notification;
 use lsp_types::request::{self as req, Request};
 use lsp_types::{
-    notification as notif, ConfigurationItem, ConfigurationParams, DidChangeConfigurationParams,
-    DidChangeTextDocumentParams, DidChangeWatchedFilesParams,
-    DidChangeWatchedFilesRegistrationOptions, DidCloseTextDocumentParams,
-    DidOpenTextDocumentParams, FileChangeType, FileEvent, FileSystemWatcher, GlobPattern,
-    InitializeParams, InitializeResult, InitializedParams, MessageActionItem,
-    MessageActionItemProperty, MessageType, NumberOrString, OneOf, ProgressParams,
-    ProgressParamsValue, PublishDiagnosticsParams, Registration, RegistrationParams,
-    RelativePattern, ServerInfo, ShowMessageParams, ShowMessageRequestParams, Url,
-    WorkDoneProgress, WorkDoneProgressBegin, WorkDoneProgressCreateParams, WorkDoneProgressEnd,
-    WorkDoneProgressReport,
+    notification as notif, CompletionParams, CompletionResponse, ConfigurationItem,
+    ConfigurationParams, DidChangeConfigurationParams, DidChangeTextDocumentParams,
+    DidChangeWatchedFilesParams, DidChangeWatchedFilesRegistrationOptions,
+    DidCloseTextDocumentParams, DidOpenTextDocumentParams, DidSaveTextDocumentParams,
+    FileChangeType, FileEvent, FileSystemWatcher, GlobPattern, InitializeParams, InitializeResult,
+    InitializedParams, MessageActionItem, MessageActionItemProperty, MessageType, NumberOrString,
+    OneOf, ProgressParams, ProgressParamsValue, PublishDiagnosticsParams, Registration,
+    RegistrationParams, RelativePattern, ServerInfo, ShowMessageParams, ShowMessageRequestParams,
+    Url, WorkDoneProgress, WorkDoneProgressBegin, WorkDoneProgressCreateParams,
+    WorkDoneProgressEnd, WorkDoneProgressReport,
 };
-use nix_interop::nixos_options::{self, NixosOptions};
-use nix_interop::{flake_lock, flake_output, FlakeUrl, FLAKE_FILE, FLAKE_LOCK_FILE};
+use nix_interop::cache::{CacheKey, CacheKind, ClearFilter, EvalCache};
+use nix_interop::eval::Evaluator;
+use nix_interop::flake_lock::FlakeLock;
+use nix_interop::flake_output::FlakeOutput;
+use nix_interop::info::{host_system, NixDialect};
+use nix_interop::nixos_options::{self, NixosOptions, OptionSource};
+use nix_interop::worker::WorkerPool;
+use nix_interop::{eval, flake_lock, flake_output, FlakeUrl, FLAKE_FILE, FLAKE_LOCK_FILE};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
 use std::backtrace::Backtrace;
 use std::borrow::BorrowMut;
 use std::cell::Cell;
@@ -41,19 +51,39 @@ use tokio::task::JoinHandle;
 const LSP_SERVER_NAME: &str = "nil";
 const FLAKE_ARCHIVE_PROGRESS_TOKEN: &str = "nil/flakeArchiveProgress";
 const LOAD_INPUT_FLAKE_PROGRESS_TOKEN: &str = "nil/loadInputFlakeProgress";
-const LOAD_NIXOS_OPTIONS_PROGRESS_TOKEN: &str = "nil/loadNixosOptionsProgress";
+const EVAL_FLAKE_OUTPUT_PROGRESS_TOKEN: &str = "nil/evalFlakeOutputProgress";
+const LOAD_OPTIONS_PROGRESS_TOKEN: &str = "nil/loadOptionsProgress";
 
 const MAX_DIAGNOSTICS_CNT: usize = 128;
 
+/// The depth to evaluate flake outputs on demand, which includes descriptions of packages.
+const LAZY_FLAKE_OUTPUT_DEPTH: u32 = 2;
+const LAZY_FLAKE_OUTPUT_TIMEOUT: Duration = Duration::from_secs(60);
+/// The maximum number of persistent Nix workers for evaluation.
+const MAX_EVAL_WORKERS: usize = 2;
+/// The timeout of quick evaluations, like information about Nix and paths of flake inputs.
+const QUICK_EVAL_TIMEOUT: Duration = Duration::from_secs(60);
+/// Module options may take minutes to evaluate.
+const LOAD_OPTIONS_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// The default depth to print values for `nil/evalExpr`.
+const EVAL_EXPR_DEPTH: u32 = 3;
+const EVAL_EXPR_TIMEOUT: Duration = Duration::from_secs(60);
+
 const PROGRESS_REPORT_PERIOD: Duration = Duration::from_millis(100);
 const LOAD_FLAKE_WORKSPACE_DEBOUNCE_DURATION: Duration = Duration::from_millis(100);
+/// Cache entries older than this are evicted, so that they are evaluated again eventually.
+const EVAL_CACHE_MAX_AGE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
 
 type NotifyResult = ControlFlow<async_lsp::Result<()>>;
 
 struct UpdateConfigEvent(serde_json::Value);
 struct UpdateDiagnostics(u64, Vec<(Url, Vec<lsp_types::Diagnostic>)>);
 struct SetFlakeInfoEvent(Option<FlakeInfo>);
-struct SetNixosOptionsEvent(NixosOptions);
+struct EvalFlakeOutputEvent(FlakeOutputPath);
+struct SetLazyFlakeOutputEvent(FlakeOutputPath, FlakeOutput);
+struct SetModuleOptionsEvent(OptionSource, NixosOptions);
+struct SetNixTargetEvent(NixDialect, Builtins, String);
 
 pub struct Server {
     // States.
@@ -62,11 +92,26 @@ pub struct Server {
     vfs: Arc<RwLock<Vfs>>,
     opened_files: HashMap<Url, FileData>,
     config: Arc<Config>,
+    /// Settings from the project configuration file.
+    project_settings: serde_json::Value,
+    /// Settings from the client, which take precedence over `project_settings`.
+    client_settings: serde_json::Value,
+    /// Warned about ignored untrusted keys of `project_settings`?
+    warned_untrusted_settings: bool,
     /// Tried to load flake?
     /// This is used to reload flake only once after the configuration is first loaded.
     tried_flake_load: bool,
     /// Is this workspace a flake?
     workspace_is_flake: bool,
+    /// The latest loaded flake info, without `lazy_flake_outputs`.
+    flake_info: Option<FlakeInfo>,
+    /// Flake outputs evaluated on demand, or `None` if being evaluated.
+    /// They are merged into `flake_info`, and discarded when the flake workspace is reloaded.
+    lazy_flake_outputs: HashMap<FlakeOutputPath, Option<FlakeOutput>>,
+    /// The target system, for evaluating flake outputs on demand.
+    nix_system: String,
+    /// Persistent Nix workers for evaluation, except for flake outputs of the whole workspace.
+    eval_workers: Arc<WorkerPool>,
     diagnostic_version: u64,
 
     // Ongoing tasks.
@@ -85,6 +130,28 @@ struct FileData {
     diagnostics: Vec<lsp_types::Diagnostic>,
 }
 
+/// The on-disk evaluation cache for the current Nix.
+struct EvalCacheForNix {
+    cache: EvalCache,
+    nix_version: String,
+}
+
+impl EvalCacheForNix {
+    fn key(&self, kind: CacheKind, nar_hash: &str) -> CacheKey {
+        CacheKey::new(kind, nar_hash, &self.nix_version)
+    }
+
+    fn load<T: DeserializeOwned>(&self, key: &CacheKey) -> Option<T> {
+        self.cache.load(key)
+    }
+
+    fn store<T: Serialize>(&self, key: &CacheKey, value: &T) {
+        if let Err(err) = self.cache.store(key, value) {
+            tracing::warn!("Failed to update cache: {err:#}");
+        }
+    }
+}
+
 impl Server {
     pub fn new_router(client: ClientSocket, init_messages: Vec<ShowMessageParams>) -> Router<Self> {
         let this = Self::new(client, init_messages);
@@ -99,6 +166,7 @@ impl Server {
             .notification::<notif::DidOpenTextDocument>(Self::on_did_open)
             .notification::<notif::DidCloseTextDocument>(Self::on_did_close)
             .notification::<notif::DidChangeTextDocument>(Self::on_did_change)
+            .notification::<notif::DidSaveTextDocument>(Self::on_did_save)
             .notification::<notif::DidChangeConfiguration>(Self::on_did_change_configuration)
             // NB. This handler is mandatory.
             // > In former implementations clients pushed file events without the server actively asking for it.
@@ -108,13 +176,14 @@ impl Server {
             //// Requests ////
             .request_snap::<req::GotoDefinition>(handler::goto_definition)
             .request_snap::<req::References>(handler::references)
-            .request_snap::<req::Completion>(handler::completion)
+            .request::<req::Completion, _>(Self::on_completion)
             .request_snap::<req::SelectionRangeRequest>(handler::selection_range)
             .request_snap::<req::PrepareRenameRequest>(handler::prepare_rename)
             .request_snap::<req::Rename>(handler::rename)
             .request_snap::<req::SemanticTokensFullRequest>(handler::semantic_token_full)
             .request_snap::<req::SemanticTokensRangeRequest>(handler::semantic_token_range)
             .request_snap::<req::HoverRequest>(handler::hover)
+            .request_snap::<req::SignatureHelpRequest>(handler::signature_help)
             .request_snap::<req::DocumentSymbolRequest>(handler::document_symbol)
             .request_snap::<req::Formatting>(handler::formatting)
             .request_snap::<req::DocumentLinkRequest>(handler::document_links)
@@ -122,9 +191,14 @@ impl Server {
             .request_snap::<req::CodeActionRequest>(handler::code_action)
             .request_snap::<req::DocumentHighlightRequest>(handler::document_highlight)
             .request_snap::<lsp_ext::ParentModule>(handler::parent_module)
+            .request_snap::<lsp_ext::Ssr>(handler::ssr)
+            .request::<lsp_ext::EvalExpr, _>(Self::on_eval_expr)
             //// Events ////
             .event(Self::on_set_flake_info)
-            .event(Self::on_set_nixos_options)
+            .event(Self::on_eval_flake_output)
+            .event(Self::on_set_lazy_flake_output)
+            .event(Self::on_set_module_options)
+            .event(Self::on_set_nix_target)
             .event(Self::on_update_config)
             .event(Self::on_update_diagnostics)
             // Loopback event.
@@ -133,14 +207,22 @@ impl Server {
     }
 
     pub fn new(client: ClientSocket, init_messages: Vec<ShowMessageParams>) -> Self {
+        // Will be set during initialization.
+        let config = Config::new("/non-existing-path".into());
         Self {
+            eval_workers: Self::new_eval_workers(&config),
             host: AnalysisHost::default(),
             vfs: Arc::new(RwLock::new(Vfs::new())),
             opened_files: HashMap::default(),
-            // Will be set during initialization.
-            config: Arc::new(Config::new("/non-existing-path".into())),
+            config: Arc::new(config),
+            project_settings: serde_json::Value::Null,
+            client_settings: serde_json::Value::Null,
+            warned_untrusted_settings: false,
             tried_flake_load: false,
             workspace_is_flake: false,
+            flake_info: None,
+            lazy_flake_outputs: HashMap::new(),
+            nix_system: host_system(),
             diagnostic_version: 0,
 
             load_flake_workspace_fut: None,
@@ -174,12 +256,22 @@ impl Server {
         // Allow the client to pass initial settings through `initializationOptions`, especially
         // when they do not support `workspace/configuration`.
         *Arc::get_mut(&mut self.config).expect("No concurrent access yet") = Config::new(root_path);
+        match self.load_project_settings() {
+            Ok(settings) => self.project_settings = settings,
+            Err(err) => self.init_messages.push(ShowMessageParams {
+                typ: MessageType::ERROR,
+                message: format!("{err:#}"),
+            }),
+        }
         if let Some(options) = params.initialization_options {
             if options.as_object().filter(|o| !o.is_empty()).is_some() {
                 tracing::debug!("Initialization options: {options}");
-                self.on_update_config(UpdateConfigEvent(options));
+                self.client_settings = options;
             }
         }
+        if !self.project_settings.is_null() || !self.client_settings.is_null() {
+            let _ = self.apply_settings();
+        }
 
         ready(Ok(InitializeResult {
             capabilities: server_caps,
@@ -236,21 +328,31 @@ impl Server {
         caps: &NegotiatedCapabilities,
         client: &mut ClientSocket,
     ) {
-        let to_watcher = |pat: &str| FileSystemWatcher {
+        let to_watcher = |dir: &Path, pat: &str| FileSystemWatcher {
             glob_pattern: if caps.watch_files_relative_pattern {
-                let root_uri = Url::from_file_path(&config.root_path).expect("Must be absolute");
+                let base_uri = Url::from_file_path(dir).expect("Must be absolute");
                 GlobPattern::Relative(RelativePattern {
-                    base_uri: OneOf::Right(root_uri),
+                    base_uri: OneOf::Right(base_uri),
                     pattern: pat.into(),
                 })
             } else {
-                GlobPattern::String(format!("{}/{}", config.root_path.display(), pat))
+                GlobPattern::String(format!("{}/{}", dir.display(), pat))
             },
             // All events.
             kind: None,
         };
+        // The project configuration file is looked up in ancestors too.
         let register_options = DidChangeWatchedFilesRegistrationOptions {
-            watchers: [FLAKE_LOCK_FILE, FLAKE_FILE].map(to_watcher).into(),
+            watchers: [FLAKE_LOCK_FILE, FLAKE_FILE]
+                .into_iter()
+                .map(|pat| to_watcher(&config.root_path, pat))
+                .chain(
+                    config
+                        .root_path
+                        .ancestors()
+                        .map(|dir| to_watcher(dir, PROJECT_CONFIG_FILE)),
+                )
+                .collect(),
         };
         let params = RegistrationParams {
             registrations: vec![Registration {
@@ -262,10 +364,10 @@ impl Server {
         if let Err(err) = client.register_capability(params).await {
             client.show_message_ext(
                 MessageType::ERROR,
-                format!("Failed to watch flake files: {err:#}"),
+                format!("Failed to watch flake and config files: {err:#}"),
             );
         }
-        tracing::info!("Registered file watching for flake files");
+        tracing::info!("Registered file watching for flake and config files");
     }
 
     fn on_did_open(&mut self, params: DidOpenTextDocumentParams) -> NotifyResult {
@@ -291,9 +393,9 @@ impl Server {
                 .ok()
                 .as_ref()
                 .and_then(|path| path.file_name())
-                .map_or(false, |name| name == FLAKE_FILE)
+                .is_some_and(|name| name == FLAKE_FILE)
         {
-            self.spawn_load_flake_workspace();
+            self.spawn_load_flake_workspace(false);
         }
 
         self.spawn_update_diagnostics();
@@ -354,6 +456,12 @@ impl Server {
         ControlFlow::Continue(())
     }
 
+    fn on_did_save(&mut self, _params: DidSaveTextDocumentParams) -> NotifyResult {
+        // Workers cache evaluated files, which may be imported by evaluations.
+        self.eval_workers.recycle();
+        ControlFlow::Continue(())
+    }
+
     fn on_did_change_configuration(
         &mut self,
         _params: DidChangeConfigurationParams,
@@ -366,16 +474,27 @@ impl Server {
 
     fn on_did_change_watched_files(&mut self, params: DidChangeWatchedFilesParams) -> NotifyResult {
         tracing::debug!("Watched files changed: {params:?}");
+        self.eval_workers.recycle();
 
         let mut flake_files_changed = false;
+        let mut project_config_changed = false;
         for &FileEvent { ref uri, mut typ } in &params.changes {
+            let Ok(path) = uri.to_file_path() else {
+                continue;
+            };
+            // It is always read from disk.
+            if path.file_name() == Some(PROJECT_CONFIG_FILE.as_ref())
+                && path
+                    .parent()
+                    .is_some_and(|dir| self.config.root_path.starts_with(dir))
+            {
+                project_config_changed = true;
+                continue;
+            }
             // Don't reload files maintained by the client.
             if self.opened_files.contains_key(uri) {
                 continue;
             }
-            let Ok(path) = uri.to_file_path() else {
-                continue;
-            };
 
             if matches!(typ, FileChangeType::CREATED | FileChangeType::CHANGED) {
                 match (|| -> std::io::Result<_> {
@@ -429,23 +548,40 @@ impl Server {
         }
 
         if flake_files_changed {
-            self.spawn_load_flake_workspace();
+            self.spawn_load_flake_workspace(false);
+        }
+
+        if project_config_changed {
+            match self.load_project_settings() {
+                Ok(settings) => {
+                    self.project_settings = settings;
+                    self.warned_untrusted_settings = false;
+                    self.apply_settings()?;
+                }
+                Err(err) => self
+                    .client
+                    .show_message_ext(MessageType::ERROR, format_args!("{err:#}")),
+            }
         }
 
         ControlFlow::Continue(())
     }
 
     fn on_reload_flake(&mut self, (): ()) -> NotifyResult {
-        self.spawn_load_flake_workspace();
+        self.spawn_load_flake_workspace(true);
         ControlFlow::Continue(())
     }
 
     /// Spawn a task to (re)load the flake workspace via `flake.{nix,lock}`, including flake info,
-    /// NixOS options and outputs (TODO).
-    fn spawn_load_flake_workspace(&mut self) {
+    /// module options and flake outputs.
+    /// Cached evaluation results are used unless `refresh` is set.
+    fn spawn_load_flake_workspace(&mut self, refresh: bool) {
+        self.lazy_flake_outputs.clear();
         let fut = task::spawn(Self::load_flake_workspace(
             self.vfs.clone(),
             self.config.clone(),
+            self.eval_workers.clone(),
+            refresh,
             self.capabilities.clone(),
             self.client.clone(),
         ));
@@ -457,6 +593,8 @@ impl Server {
     async fn load_flake_workspace(
         vfs: Arc<RwLock<Vfs>>,
         config: Arc<Config>,
+        workers: Arc<WorkerPool>,
+        refresh: bool,
         caps: NegotiatedCapabilities,
         mut client: ClientSocket,
     ) {
@@ -465,9 +603,11 @@ impl Server {
 
         tracing::info!("Loading flake workspace");
 
-        let flake_info = match Self::load_flake_info(&vfs, &config).await {
+        let flake_info = match Self::load_flake_info(&vfs, &config, &workers).await {
             Ok(ret) => {
-                let _: Result<_, _> = client.emit(SetFlakeInfoEvent(ret.clone()));
+                let _: Result<_, _> = client.emit(SetFlakeInfoEvent(
+                    ret.as_ref().map(|(info, _)| info.clone()),
+                ));
                 ret
             }
             Err(err) => {
@@ -478,7 +618,9 @@ impl Server {
                 return;
             }
         };
-        let Some(flake_info) = flake_info else { return };
+        let Some((flake_info, input_nar_hashes)) = flake_info else {
+            return;
+        };
 
         let missing_paths = || {
             flake_info
@@ -569,49 +711,179 @@ impl Server {
             }
         }
 
-        if let Some((input_name, nixpkgs_path)) = (|| {
-            let input_name = config.nix_flake_nixpkgs_input_name.as_ref()?;
-            let path = flake_info
+        let input_path = |input_name: &str| {
+            flake_info
                 .input_store_paths
                 .get(input_name)?
                 .as_path()
-                .filter(|p| p.exists())?;
-            Some((input_name, path))
-        })() {
-            tracing::info!("Evaluating NixOS options from {}", nixpkgs_path.display());
-
-            let _progress = Progress::new(
-                &client,
-                &caps,
-                LOAD_NIXOS_OPTIONS_PROGRESS_TOKEN,
-                format!("Loading NixOS options from '{input_name}'"),
-                None,
-            )
-            .await;
+                .filter(|p| p.exists())
+        };
+        let option_sources = config
+            .option_sources()
+            .into_iter()
+            .filter_map(|(source, input_name)| Some((source, input_name, input_path(input_name)?)))
+            .collect::<Vec<_>>();
+        let nixpkgs_input = config
+            .options_nixpkgs_input_name()
+            .and_then(|input_name| Some((input_name, input_path(input_name)?)));
+        if option_sources.is_empty() && !config.nix_flake_auto_eval_inputs {
+            return;
+        }
 
-            let ret = nixos_options::eval_all_options(&config.nix_binary, nixpkgs_path)
-                .await
-                .context("Failed to evaluate NixOS options");
-            match ret {
-                // Sanity check.
-                Ok(opts) if !opts.is_empty() => {
-                    tracing::info!("Loaded NixOS options ({} top-level options)", opts.len());
-                    let _: Result<_, _> = client.emit(SetNixosOptionsEvent(opts));
+        let nix_info =
+            match nix_interop::info::get(Evaluator::Workers(&workers, QUICK_EVAL_TIMEOUT)).await {
+                Ok(info) => {
+                    tracing::debug!("Nix info: {info:?}");
+                    Some(info)
                 }
-                Ok(_) => tracing::error!("Empty NixOS options?"),
                 Err(err) => {
-                    client.show_message_ext(MessageType::ERROR, format_args!("{err:#}"));
+                    client.show_message_ext(
+                        MessageType::ERROR,
+                        format!("Failed to get information about Nix: {err:#}"),
+                    );
+                    None
                 }
+            };
+        // Results are only cached when we know which Nix evaluates them.
+        let cache = nix_info.as_ref().and_then(|info| {
+            Some(EvalCacheForNix {
+                cache: EvalCache::from_env()?,
+                nix_version: info.version.clone(),
+            })
+        });
+        if let Some(cache) = &cache {
+            match cache
+                .cache
+                .clear(ClearFilter::OlderThan(EVAL_CACHE_MAX_AGE))
+            {
+                Ok(0) => {}
+                Ok(cnt) => tracing::info!("Evicted {cnt} old cache entries"),
+                Err(err) => tracing::warn!("Failed to evict old cache entries: {err:#}"),
             }
         }
 
+        for (source, input_name, src_path) in option_sources {
+            // Other sources are evaluated with `lib` and `pkgs` from nixpkgs.
+            let nixpkgs = match (source, nixpkgs_input) {
+                (OptionSource::Nixos, _) => (input_name, src_path),
+                (_, Some(nixpkgs)) => nixpkgs,
+                (_, None) => {
+                    client.show_message_ext(
+                        MessageType::ERROR,
+                        format!("Cannot evaluate {} options without nixpkgs", source.title()),
+                    );
+                    continue;
+                }
+            };
+            let nar_hash = (|| {
+                let hash = input_nar_hashes.get(input_name)?;
+                if source == OptionSource::Nixos {
+                    return Some(hash.clone());
+                }
+                Some(format!("{hash},{}", input_nar_hashes.get(nixpkgs.0)?))
+            })();
+            Self::load_module_options(
+                source,
+                (input_name, src_path),
+                nixpkgs.1,
+                nar_hash.zip(cache.as_ref()),
+                refresh,
+                &workers,
+                &caps,
+                &mut client,
+            )
+            .await;
+        }
+
         if config.nix_flake_auto_eval_inputs {
-            Self::load_input_flakes(flake_info, &config, &caps, &mut client).await;
+            let system = config
+                .nix_system
+                .clone()
+                .or_else(|| Some(nix_info?.system))
+                .unwrap_or_else(host_system);
+            Self::load_flake_outputs(
+                flake_info,
+                &input_nar_hashes,
+                &system,
+                cache.as_ref(),
+                refresh,
+                &config,
+                &caps,
+                &mut client,
+            )
+            .await;
+        }
+    }
+
+    /// Load options of `source` from the cache if any, or evaluate them from the input `src`.
+    #[allow(clippy::too_many_arguments)]
+    async fn load_module_options(
+        source: OptionSource,
+        (input_name, src_path): (&str, &Path),
+        nixpkgs_path: &Path,
+        cache: Option<(String, &EvalCacheForNix)>,
+        refresh: bool,
+        workers: &WorkerPool,
+        caps: &NegotiatedCapabilities,
+        client: &mut ClientSocket,
+    ) {
+        let title = source.title();
+        let cache =
+            cache.map(|(hash, cache)| (cache.key(CacheKind::options(source), &hash), cache));
+        if !refresh {
+            if let Some(opts) = cache
+                .as_ref()
+                .and_then(|(key, cache)| cache.load::<NixosOptions>(key))
+            {
+                tracing::info!(
+                    "Loaded cached {title} options ({} top-level options)",
+                    opts.len()
+                );
+                let _: Result<_, _> = client.emit(SetModuleOptionsEvent(source, opts));
+                return;
+            }
+        }
+
+        tracing::info!("Evaluating {title} options from {}", src_path.display());
+
+        let _progress = Progress::new(
+            client,
+            caps,
+            LOAD_OPTIONS_PROGRESS_TOKEN,
+            format!("Loading {title} options from '{input_name}'"),
+            None,
+        )
+        .await;
+
+        let evaluator = Evaluator::Workers(workers, LOAD_OPTIONS_TIMEOUT);
+        let ret = nixos_options::eval_options(evaluator, source, src_path, nixpkgs_path)
+            .await
+            .with_context(|| format!("Failed to evaluate {title} options"));
+        match ret {
+            // Sanity check.
+            Ok(opts) if !opts.is_empty() => {
+                tracing::info!("Loaded {title} options ({} top-level options)", opts.len());
+                if let Some((key, cache)) = &cache {
+                    cache.store(key, &opts);
+                }
+                let _: Result<_, _> = client.emit(SetModuleOptionsEvent(source, opts));
+            }
+            Ok(_) => tracing::error!("Empty {title} options?"),
+            Err(err) => {
+                client.show_message_ext(MessageType::ERROR, format_args!("{err:#}"));
+            }
         }
     }
 
-    async fn load_input_flakes(
+    /// Evaluate outputs of input flakes and then the flake itself.
+    /// Outputs of inputs are loaded from the cache if any, unless `refresh` is set.
+    #[allow(clippy::too_many_arguments)]
+    async fn load_flake_outputs(
         mut flake_info: FlakeInfo,
+        input_nar_hashes: &HashMap<String, String>,
+        system: &str,
+        cache: Option<&EvalCacheForNix>,
+        refresh: bool,
         config: &Config,
         caps: &NegotiatedCapabilities,
         client: &mut ClientSocket,
@@ -627,60 +899,80 @@ impl Server {
             })
             .collect::<Vec<_>>();
 
-        // Fast path.
-        if input_paths.is_empty() {
-            return;
-        }
-
         // Sort by input names to keep evaluation order stable.
         input_paths.sort_by_key(|&(name, _)| name);
 
-        let input_cnt = input_paths.len();
-        tracing::info!("Evaluating {input_cnt} flake inputs");
+        // Outputs are only evaluated for the target system.
+        let cache_key = |input_name: &str| {
+            let cache = cache?;
+            let key = cache.key(CacheKind::FlakeOutput, input_nar_hashes.get(input_name)?);
+            Some((key.with_system(system), cache))
+        };
+
+        // Only inputs missing in the cache are evaluated.
+        let mut cached_cnt = 0;
+        if !refresh {
+            input_paths.retain(|&(input_name, _)| {
+                let Some(output) =
+                    cache_key(input_name).and_then(|(key, cache)| cache.load::<FlakeOutput>(&key))
+                else {
+                    return true;
+                };
+                flake_info
+                    .input_flake_outputs
+                    .insert(input_name.clone(), output);
+                cached_cnt += 1;
+                false
+            });
+        }
+        if cached_cnt != 0 {
+            tracing::info!("Loaded {cached_cnt} cached flake input outputs");
+            let _: Result<_, _> = client.emit(SetFlakeInfoEvent(Some(flake_info.clone())));
+        }
+
+        // The flake itself goes last, since its outputs are never cached.
+        let flakes = input_paths
+            .iter()
+            .map(|&(input_name, path)| (Some(input_name), FlakeUrl::new_path(path)))
+            .chain(Some((None, FlakeUrl::new_local(&config.root_path))))
+            .collect::<Vec<_>>();
+        let flake_cnt = flakes.len();
+        tracing::info!(
+            "Evaluating {} flake inputs and the flake itself",
+            flake_cnt - 1
+        );
 
         let progress = Progress::new(
             client,
             caps,
             LOAD_INPUT_FLAKE_PROGRESS_TOKEN,
-            "Evaluating input flakes",
-            format!("[0/{input_cnt}]"),
+            "Evaluating flake outputs",
+            format!("[0/{flake_cnt}]"),
         )
         .await;
 
-        let include_legacy = match nix_interop::info::get(&config.nix_binary).await {
-            Ok(info) => {
-                tracing::debug!("Nix info: {info:?}");
-                info.flake_show_filter_systems
-            }
-            Err(err) => {
-                client.show_message_ext(
-                    MessageType::ERROR,
-                    format!("Failed to get information about Nix: {err:#}"),
-                );
-                false
-            }
-        };
-
         let mut error_cnt = 0;
-        for (i, (input_name, path)) in input_paths.iter().copied().enumerate() {
+        for (i, (input_name, flake_url)) in flakes.iter().enumerate() {
+            let title = input_name.map_or("self", |name| name);
             let report = |path: &str| {
                 let dot = if path.is_empty() { "" } else { "." };
                 progress.report(
-                    (i * 100 / input_cnt) as u32,
-                    format!("[{i}/{input_cnt}] {input_name}{dot}{path}"),
+                    (i * 100 / flake_cnt) as u32,
+                    format!("[{i}/{flake_cnt}] {title}{dot}{path}"),
                 );
             };
             report("");
 
-            tracing::info!("Evaluating flake input {input_name:?}");
+            tracing::info!("Evaluating flake {title:?}");
 
             let (watcher_tx, watcher_rx) = watch::channel(String::new());
-            let flake_url = FlakeUrl::new_path(path);
             let mut eval_fut = pin!(flake_output::eval_flake_output(
                 &config.nix_binary,
-                &flake_url,
+                flake_url,
+                &[],
+                flake_output::DEFAULT_DEPTH,
+                system,
                 Some(watcher_tx),
-                include_legacy,
                 config.nix_max_memory(),
             ));
             let ret = loop {
@@ -696,27 +988,41 @@ impl Server {
                     // Don't spam on configuration errors (eg. bad Nix path).
                     error_cnt += 1;
                     if error_cnt <= 3 {
-                        client.show_message_ext(
-                            MessageType::ERROR,
-                            format!("Flake input {input_name:?} cannot be evaluated: {err:#}"),
-                        );
+                        let msg = match input_name {
+                            Some(name) => format!("Flake input {name:?} cannot be evaluated"),
+                            None => "Flake outputs cannot be evaluated".into(),
+                        };
+                        client.show_message_ext(MessageType::ERROR, format!("{msg}: {err:#}"));
                     }
                     continue;
                 }
             };
+            let Some(input_name) = input_name else {
+                flake_info.self_flake_output = Some(output);
+                let _: Result<_, _> = client.emit(SetFlakeInfoEvent(Some(flake_info.clone())));
+                continue;
+            };
+            if let Some((key, cache)) = cache_key(input_name) {
+                cache.store(&key, &output);
+            }
             flake_info
                 .input_flake_outputs
-                .insert(input_name.clone(), output);
+                .insert((*input_name).clone(), output);
             let _: Result<_, _> = client.emit(SetFlakeInfoEvent(Some(flake_info.clone())));
         }
 
-        tracing::info!("Finished loading flake inputs. {error_cnt}/{input_cnt} failed");
+        tracing::info!("Finished loading flake outputs. {error_cnt}/{flake_cnt} failed");
         let msg =
-            (error_cnt != 0).then(|| format!("{error_cnt}/{input_cnt} input(s) failed to load"));
+            (error_cnt != 0).then(|| format!("{error_cnt}/{flake_cnt} flake(s) failed to load"));
         progress.done(msg);
     }
 
-    async fn load_flake_info(vfs: &RwLock<Vfs>, config: &Config) -> Result<Option<FlakeInfo>> {
+    /// Load flake info and NAR hashes of locked inputs.
+    async fn load_flake_info(
+        vfs: &RwLock<Vfs>,
+        config: &Config,
+        workers: &WorkerPool,
+    ) -> Result<Option<(FlakeInfo, HashMap<String, String>)>> {
         tracing::info!("Loading flake info");
 
         let (flake_file, lock_src) = {
@@ -730,48 +1036,323 @@ impl Server {
 
             let lock_vpath = VfsPath::new(config.root_path.join(FLAKE_LOCK_FILE));
             let Ok(lock_file) = vfs.file_for_path(&lock_vpath) else {
-                return Ok(Some(FlakeInfo {
+                let info = FlakeInfo {
                     flake_file,
                     input_store_paths: HashMap::new(),
                     input_flake_outputs: HashMap::new(),
-                }));
+                    self_flake_output: None,
+                    lock: None,
+                };
+                return Ok(Some((info, HashMap::new())));
             };
             let lock_src = vfs.content_for_file(lock_file);
             (flake_file, lock_src)
         };
 
-        let inputs =
-            flake_lock::resolve_flake_locked_inputs(&config.nix_binary, lock_src.as_bytes())
-                .await
-                .context("Failed to resolve flake inputs from lock file")?;
+        let lock = FlakeLock::from_slice(lock_src.as_bytes())?;
+        let evaluator = Evaluator::Workers(workers, QUICK_EVAL_TIMEOUT);
+        let inputs = flake_lock::resolve_flake_locked_inputs(evaluator, lock_src.as_bytes())
+            .await
+            .context("Failed to resolve flake inputs from lock file")?;
 
-        // We only need the map for input -> store path.
+        let input_nar_hashes = inputs
+            .iter()
+            .map(|(key, input)| (key.clone(), input.nar_hash.clone()))
+            .collect();
         let input_store_paths = inputs
             .into_iter()
             .map(|(key, input)| (key, VfsPath::new(input.store_path)))
             .collect();
-        Ok(Some(FlakeInfo {
+        let info = FlakeInfo {
             flake_file,
             input_store_paths,
             input_flake_outputs: HashMap::new(),
-        }))
+            self_flake_output: None,
+            lock: Some(lock),
+        };
+        Ok(Some((info, input_nar_hashes)))
     }
 
     fn on_set_flake_info(&mut self, info: SetFlakeInfoEvent) -> NotifyResult {
         tracing::debug!("Set flake info: {:?}", info.0);
         self.workspace_is_flake = info.0.is_some();
-        self.vfs.write().unwrap().set_flake_info(info.0);
+        self.flake_info = info.0;
+        self.update_flake_info();
+        ControlFlow::Continue(())
+    }
+
+    /// Set the flake info with flake outputs evaluated on demand merged.
+    fn update_flake_info(&mut self) {
+        let mut flake_info = self.flake_info.clone();
+        if let Some(info) = &mut flake_info {
+            let mut lazy_outputs = self
+                .lazy_flake_outputs
+                .iter()
+                .filter_map(|(path, output)| Some((path, output.as_ref()?)))
+                .collect::<Vec<_>>();
+            // Parents go first, so that they will not override children.
+            lazy_outputs.sort_by_key(|(path, _)| path.attrpath.len());
+            for (path, output) in lazy_outputs {
+                let root = match &path.input {
+                    Some(input) => info.input_flake_outputs.get_mut(input),
+                    None => info.self_flake_output.as_mut(),
+                };
+                if let Some(root) = root {
+                    root.insert(&path.attrpath, output.clone());
+                }
+            }
+        }
+        self.vfs.write().unwrap().set_flake_info(flake_info);
+        self.apply_vfs_change();
+    }
+
+    fn on_completion(
+        &mut self,
+        params: CompletionParams,
+    ) -> impl Future<Output = Result<Option<CompletionResponse>, ResponseError>> {
+        let client = self.client.clone();
+        let task = self.spawn_with_snapshot(move |snap| {
+            with_catch_unwind(req::Completion::METHOD, move || {
+                handler::completion(snap, params)
+            })
+        });
+        async move {
+            let (resp, unevaluated) = task
+                .await
+                .expect("Already catch_unwind")
+                .map_err(error_to_response)?;
+            if let Some(path) = unevaluated {
+                let _: Result<_, _> = client.emit(EvalFlakeOutputEvent(path));
+            }
+            Ok(resp)
+        }
+    }
+
+    fn on_eval_expr(
+        &mut self,
+        params: EvalExprParams,
+    ) -> impl Future<Output = Result<Option<EvalExprResult>, ResponseError>> {
+        let config = self.config.clone();
+        let depth = params.depth.unwrap_or(EVAL_EXPR_DEPTH);
+        let task = self.spawn_with_snapshot(move |snap| {
+            with_catch_unwind(lsp_ext::EvalExpr::METHOD, move || {
+                handler::closed_expr(snap, params)
+            })
+        });
+        async move {
+            let Some((expr, range, base_dir)) = task
+                .await
+                .expect("Already catch_unwind")
+                .map_err(error_to_response)?
+            else {
+                return Ok(None);
+            };
+            let value = eval::nix_eval_pretty(
+                &config.nix_binary,
+                &expr.source,
+                &base_dir,
+                depth,
+                config.nix_max_memory(),
+                EVAL_EXPR_TIMEOUT,
+            )
+            .await
+            .map_err(error_to_response)?;
+            Ok(Some(EvalExprResult { range, value }))
+        }
+    }
+
+    /// Spawn a task to evaluate an unevaluated flake output being completed, if not yet.
+    fn on_eval_flake_output(
+        &mut self,
+        EvalFlakeOutputEvent(path): EvalFlakeOutputEvent,
+    ) -> NotifyResult {
+        if !self.config.nix_flake_auto_eval_inputs || self.lazy_flake_outputs.contains_key(&path) {
+            return ControlFlow::Continue(());
+        }
+        let flake_url = match &path.input {
+            Some(input) => {
+                let Some(store_path) = self
+                    .flake_info
+                    .as_ref()
+                    .and_then(|info| info.input_store_paths.get(input)?.as_path())
+                else {
+                    return ControlFlow::Continue(());
+                };
+                FlakeUrl::new_path(store_path)
+            }
+            None => FlakeUrl::new_local(&self.config.root_path),
+        };
+        self.lazy_flake_outputs.insert(path.clone(), None);
+
+        task::spawn(Self::load_lazy_flake_output(
+            path,
+            flake_url,
+            self.nix_system.clone(),
+            self.eval_workers.clone(),
+            self.capabilities.clone(),
+            self.client.clone(),
+        ));
+        ControlFlow::Continue(())
+    }
+
+    async fn load_lazy_flake_output(
+        path: FlakeOutputPath,
+        flake_url: FlakeUrl,
+        system: String,
+        workers: Arc<WorkerPool>,
+        caps: NegotiatedCapabilities,
+        mut client: ClientSocket,
+    ) {
+        let title = format!(
+            "{}.{}",
+            path.input.as_deref().unwrap_or("self"),
+            path.attrpath.join("."),
+        );
+        tracing::info!("Evaluating flake output {title}");
+        let progress = Progress::new(
+            &client,
+            &caps,
+            format!("{EVAL_FLAKE_OUTPUT_PROGRESS_TOKEN}/{title}"),
+            "Evaluating flake outputs",
+            title.clone(),
+        )
+        .await;
+        let ret = flake_output::eval_flake_output_in(
+            &workers,
+            &flake_url,
+            &path.attrpath,
+            LAZY_FLAKE_OUTPUT_DEPTH,
+            &system,
+            LAZY_FLAKE_OUTPUT_TIMEOUT,
+        )
+        .await;
+        progress.done(None);
+        match ret {
+            Ok(output) => {
+                let _: Result<_, _> = client.emit(SetLazyFlakeOutputEvent(path, output));
+            }
+            Err(err) => client.show_message_ext(
+                MessageType::ERROR,
+                format!("Flake output {title} cannot be evaluated: {err:#}"),
+            ),
+        }
+    }
+
+    fn on_set_lazy_flake_output(&mut self, event: SetLazyFlakeOutputEvent) -> NotifyResult {
+        let SetLazyFlakeOutputEvent(path, output) = event;
+        // Discard outdated results from before reloading.
+        if let Some(slot) = self.lazy_flake_outputs.get_mut(&path) {
+            *slot = Some(output);
+            self.update_flake_info();
+        }
+        ControlFlow::Continue(())
+    }
+
+    fn on_set_module_options(&mut self, opts: SetModuleOptionsEvent) -> NotifyResult {
+        let SetModuleOptionsEvent(source, opts) = opts;
+        tracing::debug!("Set {source} options ({:?} top-levels)", opts.len());
+        self.vfs.write().unwrap().set_module_options(source, opts);
         self.apply_vfs_change();
         ControlFlow::Continue(())
     }
 
-    fn on_set_nixos_options(&mut self, opts: SetNixosOptionsEvent) -> NotifyResult {
-        tracing::debug!("Set NixOS options ({:?} top-levels)", opts.0.len());
-        self.vfs.write().unwrap().set_nixos_options(opts.0);
+    /// Spawn a task to detect the dialect, builtins and system of `nix.binary`, unless they
+    /// are configured by `nix.dialect`, `nix.version` and `nix.system`.
+    /// The default dialect, builtins of the latest snapshot and the host system are used before
+    /// it finishes.
+    fn spawn_load_nix_target(&self) {
+        let config = self.config.clone();
+        let workers = self.eval_workers.clone();
+        let client = self.client.clone();
+        tokio::spawn(async move {
+            let (dialect, builtins, system) = Self::load_nix_target(&config, &workers).await;
+            tracing::info!(
+                "Targeting {dialect:?} on {system} with {} builtins from Nix {}",
+                builtins.entries().count(),
+                builtins.nix_version(),
+            );
+            let _: Result<_, _> = client.emit(SetNixTargetEvent(dialect, builtins, system));
+        });
+    }
+
+    async fn load_nix_target(
+        config: &Config,
+        workers: &WorkerPool,
+    ) -> (NixDialect, Builtins, String) {
+        let configured_builtins = Config::builtins_for_version;
+        if let (Some(dialect), Some(version), Some(system)) =
+            (config.nix_dialect, &config.nix_version, &config.nix_system)
+        {
+            return (
+                dialect,
+                configured_builtins(dialect, version),
+                system.clone(),
+            );
+        }
+
+        let evaluator = Evaluator::Workers(workers, QUICK_EVAL_TIMEOUT);
+        let info = match nix_interop::info::get(evaluator).await {
+            Ok(info) => info,
+            Err(err) => {
+                tracing::warn!("Failed to get information about Nix, using defaults: {err:#}");
+                let dialect = config.nix_dialect.unwrap_or_default();
+                let builtins = match &config.nix_version {
+                    Some(version) => configured_builtins(dialect, version),
+                    None => Builtins::clone(&Builtins::latest()),
+                };
+                let system = config.nix_system.clone().unwrap_or_else(host_system);
+                return (dialect, builtins, system);
+            }
+        };
+        tracing::debug!("Nix info: {info:?}");
+        let dialect = config.nix_dialect.unwrap_or(info.dialect);
+        let system = config.nix_system.clone().unwrap_or(info.system.clone());
+        if let Some(version) = &config.nix_version {
+            return (dialect, configured_builtins(dialect, version), system);
+        }
+        let builtins = match nix_interop::builtins::get(evaluator, &info).await {
+            Ok(builtins) => builtins,
+            Err(err) => {
+                tracing::warn!("Failed to load builtins, using snapshots: {err:#}");
+                Builtins::for_version(&info.version)
+            }
+        };
+        (dialect, builtins, system)
+    }
+
+    fn on_set_nix_target(&mut self, target: SetNixTargetEvent) -> NotifyResult {
+        let SetNixTargetEvent(dialect, builtins, system) = target;
+        tracing::debug!(
+            "Set {dialect:?}, {system} and builtins of Nix {}",
+            builtins.nix_version()
+        );
+        {
+            let mut vfs = self.vfs.write().unwrap();
+            vfs.set_nix_dialect(dialect);
+            vfs.set_builtins(builtins);
+            vfs.set_nix_system(&system);
+        }
+        self.nix_system = system;
         self.apply_vfs_change();
         ControlFlow::Continue(())
     }
 
+    fn new_eval_workers(config: &Config) -> Arc<WorkerPool> {
+        Arc::new(WorkerPool::new(
+            config.nix_binary.clone(),
+            MAX_EVAL_WORKERS,
+            config.nix_max_memory(),
+        ))
+    }
+
+    /// Load settings from the project configuration file of the workspace, if any.
+    fn load_project_settings(&self) -> Result<serde_json::Value> {
+        match config::find_project_config(&self.config.root_path) {
+            Some(path) => config::load_project_settings(&path),
+            None => Ok(serde_json::Value::Null),
+        }
+    }
+
     fn spawn_reload_config(&self) {
         if !self.capabilities.workspace_configuration {
             return;
@@ -803,9 +1384,31 @@ impl Server {
     }
 
     fn on_update_config(&mut self, value: UpdateConfigEvent) -> NotifyResult {
-        let mut config = Config::clone(&self.config);
+        config::merge_settings(&mut self.client_settings, value.0);
+        self.apply_settings()
+    }
+
+    /// Rebuild the config from project and client settings, and reload what depends on it.
+    fn apply_settings(&mut self) -> NotifyResult {
+        let mut settings = self.project_settings.clone();
+        if self.client_settings.pointer("/trustProjectConfig") != Some(&true.into()) {
+            let ignored = config::remove_untrusted_settings(&mut settings);
+            if !ignored.is_empty() && !self.warned_untrusted_settings {
+                self.warned_untrusted_settings = true;
+                self.client.show_message_ext(
+                    MessageType::WARNING,
+                    format_args!(
+                        "Ignored `{}` in {}, set `trustProjectConfig` to allow them",
+                        ignored.join("`, `"),
+                        config::PROJECT_CONFIG_FILE,
+                    ),
+                );
+            }
+        }
+        config::merge_settings(&mut settings, self.client_settings.clone());
+        let mut config = Config::new(self.config.root_path.clone());
         let mut errors = Vec::new();
-        config.update(value.0, &mut errors);
+        config.update(settings, &mut errors);
 
         let updated_diagnostics = (
             &self.config.diagnostics_excluded_files,
@@ -815,6 +1418,25 @@ impl Server {
             &config.diagnostics_ignored,
         );
 
+        let updated_nix_target = (
+            &self.config.nix_binary,
+            self.config.nix_dialect,
+            &self.config.nix_version,
+            &self.config.nix_system,
+        ) != (
+            &config.nix_binary,
+            config.nix_dialect,
+            &config.nix_version,
+            &config.nix_system,
+        );
+
+        // Workers are lazily spawned, and old ones are killed after finishing their requests.
+        if (&self.config.nix_binary, self.config.nix_max_memory())
+            != (&config.nix_binary, config.nix_max_memory())
+        {
+            self.eval_workers = Self::new_eval_workers(&config);
+        }
+
         tracing::info!("Updated config, errors: {errors:?}, config: {config:?}");
         self.config = Arc::new(config);
 
@@ -826,10 +1448,14 @@ impl Server {
             self.client.show_message_ext(MessageType::ERROR, msg);
         }
 
-        // If this is the first load, load the flake workspace, which depends on `nix.binary`.
+        // If this is the first load, load the flake workspace and the Nix target,
+        // which depend on `nix.binary`.
         if !self.tried_flake_load {
             self.tried_flake_load = true;
-            self.spawn_load_flake_workspace();
+            self.spawn_load_flake_workspace(false);
+            self.spawn_load_nix_target();
+        } else if updated_nix_target {
+            self.spawn_load_nix_target();
         }
 
         // Refresh all diagnostics since the filter may be changed.
@@ -862,7 +1488,10 @@ impl Server {
                 opened_files
                     .into_iter()
                     .map(|(uri, file, line_map)| {
-                        let diags = if !snap.config.diagnostics_excluded_files.contains(&uri) {
+                        let is_excluded = uri
+                            .to_file_path()
+                            .is_ok_and(|path| snap.config.is_diagnostics_excluded(&path));
+                        let diags = if !is_excluded {
                             let mut diags = snap.analysis.diagnostics(file)?;
                             diags.retain(|diag| {
                                 !snap.config.diagnostics_ignored.contains(diag.code())
@@ -1057,7 +1686,7 @@ impl Drop for Progress {
 fn with_catch_unwind<T>(ctx: &str, f: impl FnOnce() -> Result<T> + UnwindSafe) -> Result<T> {
     static INSTALL_PANIC_HOOK: Once = Once::new();
     thread_local! {
-        static PANIC_LOCATION: Cell<String> = Cell::new(String::new());
+        static PANIC_LOCATION: Cell<String> = const { Cell::new(String::new()) };
     }
 
     INSTALL_PANIC_HOOK.call_once(|| {
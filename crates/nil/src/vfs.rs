@@ -1,8 +1,11 @@
 use crate::UrlExt;
 use anyhow::{ensure, Context, Result};
-use ide::{Change, FileId, FileSet, FlakeGraph, FlakeInfo, SourceRoot, SourceRootId, VfsPath};
+use ide::{
+    Builtins, Change, FileId, FileSet, FlakeGraph, FlakeInfo, SourceRoot, SourceRootId, VfsPath,
+};
 use lsp_types::Url;
-use nix_interop::nixos_options::NixosOptions;
+use nix_interop::info::NixDialect;
+use nix_interop::nixos_options::{NixosOptions, OptionSource};
 use slab::Slab;
 use std::collections::HashMap;
 use std::sync::Arc;
@@ -45,8 +48,20 @@ impl Vfs {
         });
     }
 
-    pub fn set_nixos_options(&mut self, opts: NixosOptions) {
-        self.change.set_nixos_options(opts);
+    pub fn set_module_options(&mut self, source: OptionSource, opts: NixosOptions) {
+        self.change.set_module_options(source, opts);
+    }
+
+    pub fn set_builtins(&mut self, builtins: Builtins) {
+        self.change.set_builtins(Arc::new(builtins));
+    }
+
+    pub fn set_nix_dialect(&mut self, dialect: NixDialect) {
+        self.change.set_nix_dialect(dialect);
+    }
+
+    pub fn set_nix_system(&mut self, system: &str) {
+        self.change.set_nix_system(system.into());
     }
 
     pub fn set_path_content(&mut self, path: VfsPath, text: String) -> FileId {
@@ -56,7 +71,7 @@ impl Vfs {
         match self.local_file_set.file_for_path(&path) {
             Some(file) => {
                 self.files[file.0 as usize] = (text.clone(), line_map);
-                self.change.change_file(file, text);
+                self.change.edit_file(file, text);
                 self.root_changed = true;
                 file
             }
@@ -100,7 +115,7 @@ impl Vfs {
         let new_text = <Arc<str>>::from(new_text);
         log::trace!("File {:?} content changed: {:?}", file, new_text);
         self.files[file.0 as usize] = (new_text.clone(), Arc::new(line_map));
-        self.change.change_file(file, new_text);
+        self.change.edit_file(file, new_text);
         Ok(())
     }
 
@@ -190,6 +205,7 @@ impl LineMap {
         for ((&start, &end), i) in line_starts.iter().zip(&line_starts[1..]).zip(0u32..) {
             let mut diffs = Vec::new();
             for (&b, pos) in bytes[start as usize..end as usize].iter().zip(0u32..) {
+                #[allow(clippy::manual_range_patterns)]
                 let diff = match b {
                     0b0000_0000..=0b0111_1111 |                      // utf8_len == 1, utf16_len == 1
                     0b1000_0000..=0b1011_1111 => continue,           // Continuation bytes.
@@ -0,0 +1,308 @@
+//! Loading a set of files and directories as a single workspace, for the command line.
+use anyhow::{bail, ensure, Context, Result};
+use ide::{
+    Analysis, AnalysisHost, Builtins, Change, FileId, FileSet, FlakeGraph, FlakeInfo, SourceRoot,
+    SourceRootId, VfsPath,
+};
+use nix_interop::eval::Evaluator;
+use nix_interop::flake_lock::{self, FlakeLock};
+use nix_interop::info::NixDialect;
+use nix_interop::{FLAKE_FILE, FLAKE_LOCK_FILE};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::{fs, thread};
+
+const NIX_EXTENSION: &str = "nix";
+
+/// All Nix files loaded in a single `SourceRoot`, so that relative imports resolve.
+pub struct Workspace {
+    host: AnalysisHost,
+    files: Vec<WorkspaceFile>,
+    /// The flake.nix and flake.lock at the root of the first directory, if any.
+    flake: Option<(FileId, PathBuf)>,
+}
+
+#[derive(Debug, Clone)]
+pub struct WorkspaceFile {
+    pub file: FileId,
+    /// The path for display, which is relative if the user passed a relative path.
+    pub path: PathBuf,
+    /// The canonical path, or `None` for stdin.
+    pub abs_path: Option<PathBuf>,
+    pub src: Arc<str>,
+}
+
+impl Workspace {
+    /// Load files and recursively `*.nix` files under directories.
+    ///
+    /// Hidden directories and symlinks inside directories are skipped.
+    pub fn load(paths: &[PathBuf]) -> Result<Self> {
+        ensure!(!paths.is_empty(), "No path to check");
+
+        let mut display_paths = Vec::new();
+        let mut flake_dir = None;
+        for (i, path) in paths.iter().enumerate() {
+            let meta =
+                fs::metadata(path).with_context(|| format!("Failed to read {}", path.display()))?;
+            if meta.is_dir() {
+                if i == 0 && path.join(FLAKE_FILE).is_file() {
+                    flake_dir = Some(path.clone());
+                }
+                walk_dir(path, &mut display_paths)
+                    .with_context(|| format!("Failed to read directory {}", path.display()))?;
+            } else {
+                display_paths.push(path.clone());
+            }
+        }
+
+        let mut change = Change::default();
+        let mut file_set = FileSet::default();
+        let mut seen = HashMap::new();
+        let mut files = Vec::new();
+        let mut flake = None;
+        for path in display_paths {
+            let abs_path = path
+                .canonicalize()
+                .with_context(|| format!("Failed to resolve {}", path.display()))?;
+            if seen.contains_key(&abs_path) {
+                continue;
+            }
+            let src = fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read {}", path.display()))?;
+            let src = <Arc<str>>::from(src);
+
+            let file = FileId(files.len().try_into().expect("Too many files"));
+            if let Some(dir) = &flake_dir {
+                if path == dir.join(FLAKE_FILE) {
+                    flake = Some((file, dir.join(FLAKE_LOCK_FILE)));
+                }
+            }
+            change.change_file(file, src.clone());
+            file_set.insert(file, VfsPath::Path(abs_path.clone()));
+            seen.insert(abs_path.clone(), file);
+            files.push(WorkspaceFile {
+                file,
+                path,
+                abs_path: Some(abs_path),
+                src,
+            });
+        }
+
+        let entry = flake.as_ref().map(|(file, _)| *file);
+        change.set_roots(vec![SourceRoot::new_local(file_set, entry)]);
+        if let Some((flake_file, lock_path)) = &flake {
+            // The lock graph is used for input-aware diagnostics, without resolving inputs.
+            let lock = fs::read(lock_path)
+                .ok()
+                .and_then(|src| FlakeLock::from_slice(&src).ok());
+            change.set_flake_graph(FlakeGraph {
+                nodes: HashMap::from_iter([(
+                    SourceRootId(0),
+                    FlakeInfo {
+                        flake_file: *flake_file,
+                        input_store_paths: HashMap::new(),
+                        input_flake_outputs: HashMap::new(),
+                        self_flake_output: None,
+                        lock,
+                    },
+                )]),
+            });
+        }
+
+        let mut host = AnalysisHost::new();
+        host.apply_change(change);
+        Ok(Self { host, files, flake })
+    }
+
+    /// Load a single file from stdin, or other sources.
+    pub fn single_file(path: PathBuf, src: String) -> Self {
+        let (host, file) = AnalysisHost::new_single_file(&src);
+        Self {
+            host,
+            files: vec![WorkspaceFile {
+                file,
+                path,
+                abs_path: None,
+                src: src.into(),
+            }],
+            flake: None,
+        }
+    }
+
+    /// Resolve inputs of the flake in the workspace via `flake.lock`, for input-aware checks.
+    /// This requires calling `nix` and the inputs to be already in the store.
+    pub fn load_flake_inputs(&mut self, nix_binary: &Path) -> Result<()> {
+        let Some((flake_file, lock_path)) = &self.flake else {
+            return Ok(());
+        };
+        if !lock_path.is_file() {
+            return Ok(());
+        }
+        let lock_src = fs::read(lock_path)
+            .with_context(|| format!("Failed to read {}", lock_path.display()))?;
+        let inputs = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .context("Failed to spawn tokio runtime")?
+            .block_on(flake_lock::resolve_flake_locked_inputs(
+                Evaluator::Command(nix_binary),
+                &lock_src,
+            ))
+            .context("Failed to resolve flake inputs from lock file")?;
+        let lock = FlakeLock::from_slice(&lock_src)?;
+
+        let input_store_paths = inputs
+            .into_iter()
+            .map(|(key, input)| (key, VfsPath::new(input.store_path)))
+            .collect();
+        let mut change = Change::default();
+        change.set_flake_graph(FlakeGraph {
+            nodes: HashMap::from_iter([(
+                SourceRootId(0),
+                FlakeInfo {
+                    flake_file: *flake_file,
+                    input_store_paths,
+                    input_flake_outputs: HashMap::new(),
+                    self_flake_output: None,
+                    lock: Some(lock),
+                },
+            )]),
+        });
+        self.host.apply_change(change);
+        Ok(())
+    }
+
+    pub fn files(&self) -> &[WorkspaceFile] {
+        &self.files
+    }
+
+    /// Set the Nix dialect and builtins to check against.
+    pub fn set_nix_target(&mut self, dialect: NixDialect, builtins: Builtins) {
+        let mut change = Change::default();
+        change.set_nix_dialect(dialect);
+        change.set_builtins(Arc::new(builtins));
+        self.host.apply_change(change);
+    }
+
+    /// Set the system to evaluate flake outputs for, eg. `x86_64-linux`.
+    pub fn set_nix_system(&mut self, system: &str) {
+        let mut change = Change::default();
+        change.set_nix_system(system.into());
+        self.host.apply_change(change);
+    }
+
+    /// Update the content of a file, eg. after applying edits.
+    pub fn set_file_content(&mut self, file: FileId, src: Arc<str>) {
+        let mut change = Change::default();
+        change.change_file(file, src.clone());
+        self.host.apply_change(change);
+        if let Some(f) = self.files.iter_mut().find(|f| f.file == file) {
+            f.src = src;
+        }
+    }
+
+    pub fn snapshot(&self) -> Analysis {
+        self.host.snapshot()
+    }
+
+    /// Run `f` on each file in parallel, each thread with its own database snapshot.
+    /// Results are in the same order as `files()`.
+    pub fn par_map<T: Send>(&self, f: impl Fn(&Analysis, &WorkspaceFile) -> T + Sync) -> Vec<T> {
+        let threads = thread::available_parallelism()
+            .map_or(1, |n| n.get())
+            .min(self.files.len());
+        let (files, next) = (&*self.files, AtomicUsize::new(0));
+        let mut rets = thread::scope(|s| {
+            let handles = (0..threads)
+                .map(|_| {
+                    let snap = self.snapshot();
+                    let (next, f) = (&next, &f);
+                    s.spawn(move || {
+                        let mut rets = Vec::new();
+                        loop {
+                            let i = next.fetch_add(1, Ordering::Relaxed);
+                            let Some(file) = files.get(i) else {
+                                break rets;
+                            };
+                            rets.push((i, f(&snap, file)));
+                        }
+                    })
+                })
+                .collect::<Vec<_>>();
+            handles
+                .into_iter()
+                .flat_map(|h| h.join().expect("Worker panicked"))
+                .collect::<Vec<_>>()
+        });
+        rets.sort_unstable_by_key(|(i, _)| *i);
+        rets.into_iter().map(|(_, ret)| ret).collect()
+    }
+}
+
+fn walk_dir(dir: &Path, out: &mut Vec<PathBuf>) -> Result<()> {
+    let mut entries = fs::read_dir(dir)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    entries.sort();
+    for path in entries {
+        let meta = fs::symlink_metadata(&path)?;
+        if meta.is_dir() {
+            let is_hidden = path
+                .file_name()
+                .is_some_and(|name| name.to_string_lossy().starts_with('.'));
+            if !is_hidden {
+                walk_dir(&path, out)?;
+            }
+        } else if meta.is_file() && path.extension().is_some_and(|ext| ext == NIX_EXTENSION) {
+            out.push(path);
+        } else if meta.is_symlink() {
+            // Skip symlinks, like `result` from `nix build`, which may point to huge trees.
+        } else if !meta.is_file() && !meta.is_dir() {
+            bail!("Unsupported file type: {}", path.display());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn load_dir() {
+        let dir = std::env::temp_dir().join(format!("nil-workspace-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::create_dir_all(dir.join(".git")).unwrap();
+        fs::write(
+            dir.join("flake.nix"),
+            "{ outputs = { self }: import ./sub/a.nix; }",
+        )
+        .unwrap();
+        fs::write(dir.join("sub/a.nix"), "let x = 1; in y").unwrap();
+        fs::write(dir.join("sub/README.md"), "").unwrap();
+        fs::write(dir.join(".git/ignored.nix"), "").unwrap();
+
+        let ws = Workspace::load(std::slice::from_ref(&dir)).unwrap();
+        let paths = ws
+            .files()
+            .iter()
+            .map(|f| f.path.strip_prefix(&dir).unwrap().to_owned())
+            .collect::<Vec<_>>();
+        assert_eq!(paths, [Path::new("flake.nix"), Path::new("sub/a.nix")]);
+        assert!(ws.flake.is_some());
+
+        let diags = ws.par_map(|snap, file| {
+            snap.diagnostics(file.file)
+                .unwrap()
+                .iter()
+                .map(|d| d.code())
+                .collect::<Vec<_>>()
+        });
+        assert_eq!(diags, [vec![], vec!["undefined_name", "unused_binding"]],);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,61 @@
+//! Builtins of the user's Nix.
+use std::path::Path;
+use std::process::Stdio;
+
+use anyhow::{ensure, Context, Result};
+use builtin::{Builtins, DumpLanguage};
+use tokio::process::Command;
+
+use crate::eval::Evaluator;
+use crate::info::NixInfo;
+
+/// Load all builtins available in the given Nix, whose information is `info`.
+///
+/// Documentation is queried by `nix __dump-language`, if it is supported.
+pub async fn get(evaluator: Evaluator<'_>, info: &NixInfo) -> Result<Builtins> {
+    let attr_names = evaluator
+        .eval_json::<Vec<String>>("builtins.attrNames builtins")
+        .await?;
+    // Not supported before Nix 2.17. Missing documentation will be taken from snapshots.
+    let language = dump_language(evaluator.nix_command())
+        .await
+        .unwrap_or_default();
+    Ok(Builtins::from_nix(
+        info.version.clone(),
+        &attr_names,
+        &language,
+    ))
+}
+
+async fn dump_language(nix_command: &Path) -> Result<DumpLanguage> {
+    let output = Command::new(nix_command)
+        .kill_on_drop(true)
+        .args(["__dump-language"])
+        .stdin(Stdio::null())
+        // Configures stdout/stderr automatically.
+        .output()
+        .await
+        .with_context(|| format!("Failed to spawn {nix_command:?}"))?;
+
+    ensure!(
+        output.status.success(),
+        "`nix __dump-language` failed with {}.\nStderr: {}",
+        output.status,
+        String::from_utf8_lossy(&output.stderr),
+    );
+
+    let language = serde_json::from_slice(&output.stdout)?;
+    Ok(language)
+}
+
+#[cfg(test)]
+mod tests {
+    #[tokio::test]
+    #[ignore = "requires calling 'nix'"]
+    async fn simple() {
+        let evaluator = crate::eval::Evaluator::Command("nix".as_ref());
+        let info = crate::info::get(evaluator).await.unwrap();
+        let builtins = super::get(evaluator, &info).await.unwrap();
+        assert!(builtins.get("map").unwrap().is_global);
+    }
+}
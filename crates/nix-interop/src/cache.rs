@@ -0,0 +1,458 @@
+//! Persistent on-disk cache for results of expensive evaluations, like NixOS options and
+//! flake outputs.
+//!
+//! Entries are keyed by the NAR hash of the locked flake input and the Nix version which
+//! evaluated it, so a cache entry stays valid until `flake.lock` or Nix itself changes.
+//! Each file records its format version and key, and any mismatch is treated as a miss.
+//! Since hits are not revalidated, old entries should be evicted by [`EvalCache::clear`].
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use std::{env, fmt, fs, io};
+
+use anyhow::{Context, Result};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::nixos_options::OptionSource;
+
+/// Bump this when the layout of cache files or cached types changes.
+pub const CACHE_FORMAT_VERSION: u32 = 2;
+
+const CACHE_DIR_NAME: &str = "nil";
+const CACHE_FILE_EXT: &str = "json";
+
+/// What is cached in an entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CacheKind {
+    /// [`NixosOptions`](crate::nixos_options::NixosOptions) evaluated from nixpkgs.
+    NixosOptions,
+    /// Options evaluated from Home Manager.
+    HomeManagerOptions,
+    /// Options evaluated from nix-darwin.
+    NixDarwinOptions,
+    /// [`FlakeOutput`](crate::flake_output::FlakeOutput) of an input flake.
+    FlakeOutput,
+}
+
+impl CacheKind {
+    pub const ALL: [Self; 4] = [
+        Self::NixosOptions,
+        Self::HomeManagerOptions,
+        Self::NixDarwinOptions,
+        Self::FlakeOutput,
+    ];
+
+    /// The kind for options of `source`.
+    /// Since non-NixOS sources also depend on nixpkgs, their keys should contain
+    /// hashes of both inputs.
+    pub fn options(source: OptionSource) -> Self {
+        match source {
+            OptionSource::Nixos => Self::NixosOptions,
+            OptionSource::HomeManager => Self::HomeManagerOptions,
+            OptionSource::NixDarwin => Self::NixDarwinOptions,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::NixosOptions => "nixos-options",
+            Self::HomeManagerOptions => "home-manager-options",
+            Self::NixDarwinOptions => "nix-darwin-options",
+            Self::FlakeOutput => "flake-output",
+        }
+    }
+}
+
+impl fmt::Display for CacheKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.as_str().fmt(f)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CacheKey {
+    pub kind: CacheKind,
+    /// The `narHash` of the locked input, eg. `sha256-...=`.
+    /// For results depending on multiple inputs, hashes are joined by `,`.
+    pub nar_hash: String,
+    /// The version string reported by `builtins.nixVersion`.
+    pub nix_version: String,
+    /// The target system, for results evaluated only for it, like flake outputs.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub system: Option<String>,
+}
+
+impl CacheKey {
+    pub fn new(
+        kind: CacheKind,
+        nar_hash: impl Into<String>,
+        nix_version: impl Into<String>,
+    ) -> Self {
+        Self {
+            kind,
+            nar_hash: nar_hash.into(),
+            nix_version: nix_version.into(),
+            system: None,
+        }
+    }
+
+    pub fn with_system(mut self, system: impl Into<String>) -> Self {
+        self.system = Some(system.into());
+        self
+    }
+
+    /// The file name for this key. Characters unsafe for file names are replaced,
+    /// thus different keys may collide, which is detected by the key stored in the file.
+    fn file_name(&self) -> String {
+        let sanitize = |s: &str| {
+            s.chars()
+                .map(|c| match c {
+                    'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '-' => c,
+                    _ => '_',
+                })
+                .collect::<String>()
+        };
+        let mut name = format!(
+            "{}-{}",
+            sanitize(&self.nar_hash),
+            sanitize(&self.nix_version)
+        );
+        if let Some(system) = &self.system {
+            name += "-";
+            name += &sanitize(system);
+        }
+        name + "." + CACHE_FILE_EXT
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct CacheFileRef<'a, T> {
+    format: u32,
+    key: &'a CacheKey,
+    data: &'a T,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CacheFile<T> {
+    format: u32,
+    key: CacheKey,
+    data: T,
+}
+
+/// Header of a cache file, for listing entries without parsing the data.
+#[derive(Deserialize)]
+struct CacheFileHeader {
+    format: u32,
+    key: CacheKey,
+}
+
+/// An entry on disk, returned by [`EvalCache::entries`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CacheEntry {
+    pub path: PathBuf,
+    pub kind: CacheKind,
+    /// `None` if the file is unreadable or of an unsupported format.
+    pub key: Option<CacheKey>,
+    pub size: u64,
+    pub modified: Option<SystemTime>,
+}
+
+/// Which entries to remove by [`EvalCache::clear`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClearFilter {
+    All,
+    /// Entries which are unreadable or of an unsupported format, thus never hit.
+    Unreadable,
+    /// Unreadable entries, and entries stored longer than the duration ago.
+    OlderThan(Duration),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EvalCache {
+    dir: PathBuf,
+}
+
+impl EvalCache {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /// The cache under `$XDG_CACHE_HOME/nil`, or `$HOME/.cache/nil` if it is unset.
+    /// Returns `None` if neither is available.
+    pub fn from_env() -> Option<Self> {
+        let base = env::var_os("XDG_CACHE_HOME")
+            .map(PathBuf::from)
+            .filter(|path| path.is_absolute())
+            .or_else(|| {
+                let home = PathBuf::from(env::var_os("HOME")?);
+                Some(home.join(".cache"))
+            })?;
+        Some(Self::new(base.join(CACHE_DIR_NAME)))
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn kind_dir(&self, kind: CacheKind) -> PathBuf {
+        self.dir.join(kind.as_str())
+    }
+
+    fn entry_path(&self, key: &CacheKey) -> PathBuf {
+        self.kind_dir(key.kind).join(key.file_name())
+    }
+
+    /// Load the cached value for `key`.
+    /// Missing, unreadable, outdated or mismatched entries are all misses.
+    pub fn load<T: DeserializeOwned>(&self, key: &CacheKey) -> Option<T> {
+        let path = self.entry_path(key);
+        let src = fs::read(&path).ok()?;
+        let file = serde_json::from_slice::<CacheFile<T>>(&src).ok()?;
+        (file.format == CACHE_FORMAT_VERSION && file.key == *key).then_some(file.data)
+    }
+
+    /// Store `value` for `key`, replacing the previous entry atomically.
+    pub fn store<T: Serialize>(&self, key: &CacheKey, value: &T) -> Result<()> {
+        let path = self.entry_path(key);
+        let dir = path.parent().expect("Has parent");
+        fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create cache directory {}", dir.display()))?;
+
+        let content = serde_json::to_vec(&CacheFileRef {
+            format: CACHE_FORMAT_VERSION,
+            key,
+            data: value,
+        })?;
+        // Write to a temporary file in the same directory and rename it, so that concurrent
+        // readers never see a partial file.
+        let tmp_path = path.with_extension(format!("{}.tmp", std::process::id()));
+        fs::write(&tmp_path, content)
+            .and_then(|()| fs::rename(&tmp_path, &path))
+            .map_err(|err| {
+                let _ = fs::remove_file(&tmp_path);
+                err
+            })
+            .with_context(|| format!("Failed to write cache file {}", path.display()))?;
+        Ok(())
+    }
+
+    /// List all entries on disk, sorted by paths.
+    pub fn entries(&self) -> Result<Vec<CacheEntry>> {
+        let mut ret = Vec::new();
+        for kind in CacheKind::ALL {
+            let dir = self.kind_dir(kind);
+            let iter = match fs::read_dir(&dir) {
+                Ok(iter) => iter,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                Err(err) => {
+                    return Err(err).with_context(|| format!("Failed to read {}", dir.display()))
+                }
+            };
+            for ent in iter {
+                let ent = ent?;
+                let path = ent.path();
+                if path.extension().map_or(true, |ext| ext != CACHE_FILE_EXT) {
+                    continue;
+                }
+                let meta = ent.metadata()?;
+                let key = fs::read(&path)
+                    .ok()
+                    .and_then(|src| serde_json::from_slice::<CacheFileHeader>(&src).ok())
+                    .filter(|header| header.format == CACHE_FORMAT_VERSION)
+                    .map(|header| header.key);
+                ret.push(CacheEntry {
+                    path,
+                    kind,
+                    key,
+                    size: meta.len(),
+                    modified: meta.modified().ok(),
+                });
+            }
+        }
+        ret.sort_by(|lhs, rhs| lhs.path.cmp(&rhs.path));
+        Ok(ret)
+    }
+
+    /// Remove entries selected by `filter`.
+    /// Returns the number of removed files.
+    pub fn clear(&self, filter: ClearFilter) -> Result<usize> {
+        let now = SystemTime::now();
+        let mut cnt = 0;
+        for ent in self.entries()? {
+            let remove = match filter {
+                ClearFilter::All => true,
+                ClearFilter::Unreadable => ent.key.is_none(),
+                ClearFilter::OlderThan(age) => {
+                    ent.key.is_none()
+                        || ent
+                            .modified
+                            .and_then(|time| now.duration_since(time).ok())
+                            .is_some_and(|elapsed| elapsed > age)
+                }
+            };
+            if !remove {
+                continue;
+            }
+            fs::remove_file(&ent.path)
+                .with_context(|| format!("Failed to remove {}", ent.path.display()))?;
+            cnt += 1;
+        }
+        Ok(cnt)
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::eval::Evaluator;
+    use crate::flake_output::{eval_flake_output, FlakeOutput, Type, DEFAULT_DEPTH};
+    use crate::nixos_options::{eval_all_options, NixosOptions, Ty};
+    use crate::FlakeUrl;
+
+    use super::*;
+
+    const NAR_HASH: &str = "sha256-xU6Rv9sgnwaWK7tgCPadV6HhI2Y/fl4lKxJoG2+m9qs=";
+
+    fn fake_nix() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fake_nix/nix")
+    }
+
+    struct TempCache(EvalCache);
+
+    impl TempCache {
+        fn new(name: &str) -> Self {
+            let dir = env::temp_dir().join(format!("nil-cache-test-{}-{name}", std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            Self(EvalCache::new(dir))
+        }
+    }
+
+    impl Drop for TempCache {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(self.0.dir());
+        }
+    }
+
+    #[tokio::test]
+    async fn nixos_options_roundtrip() {
+        let cache = TempCache::new("nixos-options");
+        let cache = &cache.0;
+        let opts = eval_all_options(
+            Evaluator::Command(&fake_nix()),
+            "/nix/store/fake-nixpkgs".as_ref(),
+        )
+        .await
+        .unwrap();
+        let Ty::Attrset { fields, .. } = &opts["nix"].ty else {
+            panic!("Invalid options: {opts:?}");
+        };
+        assert_eq!(fields["enable"].ty, Ty::Bool);
+
+        let key = CacheKey::new(CacheKind::NixosOptions, NAR_HASH, "2.18.1");
+        assert_eq!(cache.load::<NixosOptions>(&key), None);
+        cache.store(&key, &opts).unwrap();
+        assert_eq!(cache.load::<NixosOptions>(&key), Some(opts.clone()));
+
+        // Different Nix versions or inputs miss.
+        let key2 = CacheKey::new(CacheKind::NixosOptions, NAR_HASH, "2.19.0");
+        assert_eq!(cache.load::<NixosOptions>(&key2), None);
+        let key3 = CacheKey::new(CacheKind::NixosOptions, "sha256-other=", "2.18.1");
+        assert_eq!(cache.load::<NixosOptions>(&key3), None);
+        // So are other target systems.
+        let key5 = key.clone().with_system("x86_64-linux");
+        assert_eq!(cache.load::<NixosOptions>(&key5), None);
+        // So are entries of another kind.
+        let key4 = CacheKey::new(CacheKind::FlakeOutput, NAR_HASH, "2.18.1");
+        assert_eq!(cache.load::<FlakeOutput>(&key4), None);
+    }
+
+    #[tokio::test]
+    async fn flake_output_roundtrip() {
+        let cache = TempCache::new("flake-output");
+        let cache = &cache.0;
+        let flake_url = FlakeUrl::new_path("/nix/store/fake-flake");
+        let output = eval_flake_output(
+            &fake_nix(),
+            &flake_url,
+            &[],
+            DEFAULT_DEPTH,
+            "x86_64-linux",
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        let leaf = (|| {
+            output.as_attrset()?["packages"].as_attrset()?["x86_64-linux"].as_attrset()?["hello"]
+                .as_leaf()
+        })()
+        .unwrap();
+        assert_eq!(leaf.type_, Type::Derivation);
+
+        let key =
+            CacheKey::new(CacheKind::FlakeOutput, NAR_HASH, "2.18.1").with_system("x86_64-linux");
+        cache.store(&key, &output).unwrap();
+        assert_eq!(cache.load::<FlakeOutput>(&key), Some(output));
+
+        let entries = cache.entries().unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].kind, CacheKind::FlakeOutput);
+        assert_eq!(entries[0].key.as_ref(), Some(&key));
+    }
+
+    #[test]
+    fn outdated_format() {
+        let cache = TempCache::new("outdated");
+        let cache = &cache.0;
+        let key = CacheKey::new(CacheKind::FlakeOutput, NAR_HASH, "2.18.1");
+        let path = cache.entry_path(&key);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+
+        // Corrupted.
+        fs::write(&path, "{").unwrap();
+        assert_eq!(cache.load::<FlakeOutput>(&key), None);
+
+        // Other format versions.
+        let content = serde_json::json!({
+            "format": CACHE_FORMAT_VERSION + 1,
+            "key": key,
+            "data": {},
+        });
+        fs::write(&path, content.to_string()).unwrap();
+        assert_eq!(cache.load::<FlakeOutput>(&key), None);
+        assert_eq!(cache.entries().unwrap()[0].key, None);
+
+        // The same key in a current format is kept when clearing unreadable entries.
+        let key2 = CacheKey::new(CacheKind::FlakeOutput, NAR_HASH, "2.19.0");
+        cache
+            .store(&key2, &FlakeOutput::Attrset(Default::default()))
+            .unwrap();
+        assert_eq!(cache.clear(ClearFilter::Unreadable).unwrap(), 1);
+        assert_eq!(cache.entries().unwrap().len(), 1);
+        assert_eq!(cache.clear(ClearFilter::All).unwrap(), 1);
+        assert_eq!(cache.entries().unwrap(), Vec::new());
+    }
+
+    #[test]
+    fn clear_older_than() {
+        let cache = TempCache::new("older-than");
+        let cache = &cache.0;
+        let key = CacheKey::new(CacheKind::FlakeOutput, NAR_HASH, "2.18.1");
+        cache
+            .store(&key, &FlakeOutput::Attrset(Default::default()))
+            .unwrap();
+        let hour = Duration::from_secs(60 * 60);
+        assert_eq!(cache.clear(ClearFilter::OlderThan(hour)).unwrap(), 0);
+        std::thread::sleep(Duration::from_millis(10));
+        assert_eq!(
+            cache.clear(ClearFilter::OlderThan(Duration::ZERO)).unwrap(),
+            1
+        );
+        assert_eq!(cache.entries().unwrap(), Vec::new());
+    }
+}
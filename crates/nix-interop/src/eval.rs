@@ -1,12 +1,63 @@
 //! Wrapper for `nix eval`.
+use std::collections::BTreeMap;
+use std::fmt::Write;
 use std::path::Path;
 use std::process::Stdio;
+use std::time::Duration;
 
 use anyhow::{ensure, Context, Result};
 use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use syntax::semantic::{escape_literal_attr, escape_string};
 use tokio::process::Command;
 
+use crate::worker::{NixTimeout, WorkerPool};
+use crate::{is_out_of_memory_error, set_memory_limit, NixOutOfMemory};
+
+/// Where expressions are evaluated.
+#[derive(Debug, Clone, Copy)]
+pub enum Evaluator<'a> {
+    /// A new `nix eval` process for each expression.
+    Command(&'a Path),
+    /// Persistent workers of the pool, with a timeout for each expression.
+    Workers(&'a WorkerPool, Duration),
+}
+
+impl<'a> Evaluator<'a> {
+    pub fn nix_command(self) -> &'a Path {
+        match self {
+            Self::Command(nix_command) => nix_command,
+            Self::Workers(pool, _) => pool.nix_command(),
+        }
+    }
+
+    /// Evaluate `expr` into JSON and deserialize it, in impure mode.
+    pub async fn eval_json<T: DeserializeOwned>(self, expr: &str) -> Result<T> {
+        match self {
+            Self::Command(nix_command) => nix_eval_impure_expr_json(nix_command, expr).await,
+            Self::Workers(pool, timeout) => pool.eval_json(expr, timeout).await,
+        }
+    }
+}
+
 pub async fn nix_eval_expr_json<T: DeserializeOwned>(nix_command: &Path, expr: &str) -> Result<T> {
+    nix_eval_expr_json_impl(nix_command, expr, false).await
+}
+
+/// Same as [`nix_eval_expr_json`] but in impure mode,
+/// so that impure builtins like `builtins.currentSystem` are available.
+pub async fn nix_eval_impure_expr_json<T: DeserializeOwned>(
+    nix_command: &Path,
+    expr: &str,
+) -> Result<T> {
+    nix_eval_expr_json_impl(nix_command, expr, true).await
+}
+
+async fn nix_eval_expr_json_impl<T: DeserializeOwned>(
+    nix_command: &Path,
+    expr: &str,
+    impure: bool,
+) -> Result<T> {
     let output = Command::new(nix_command)
         .kill_on_drop(true)
         .args([
@@ -15,9 +66,9 @@ pub async fn nix_eval_expr_json<T: DeserializeOwned>(nix_command: &Path, expr: &
             "nix-command",
             "--read-only",
             "--json",
-            "--expr",
-            expr,
         ])
+        .args(impure.then_some("--impure"))
+        .args(["--expr", expr])
         .stdin(Stdio::null())
         // Configures stdout/stderr automatically.
         .output()
@@ -36,10 +87,168 @@ pub async fn nix_eval_expr_json<T: DeserializeOwned>(nix_command: &Path, expr: &
     Ok(val)
 }
 
+/// Evaluate `expr` and pretty-print its value as Nix, at most `depth` levels deep.
+///
+/// The evaluation is impure, so that flakes can be fetched via `builtins.getFlake`, but read-only.
+/// Relative paths in `expr` are resolved against `base_dir`.
+/// The process is killed if it does not finish in `timeout`.
+pub async fn nix_eval_pretty(
+    nix_command: &Path,
+    expr: &str,
+    base_dir: &Path,
+    depth: u32,
+    memory_limit: Option<u64>,
+    timeout: Duration,
+) -> Result<String> {
+    let expr = format!(
+        "({}) {{ depth = {depth}; }} (\n{expr}\n)",
+        include_str!("./pretty_print.nix"),
+    );
+    let mut command = Command::new(nix_command);
+    command
+        .kill_on_drop(true)
+        .current_dir(base_dir)
+        .args([
+            "eval",
+            "--experimental-features",
+            "nix-command flakes",
+            "--read-only",
+            "--impure",
+            "--json",
+            "--expr",
+            &expr,
+        ])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    set_memory_limit(&mut command, memory_limit);
+
+    let output = command
+        .spawn()
+        .with_context(|| format!("Failed to spawn {nix_command:?}"))?
+        .wait_with_output();
+    // The process is killed on drop.
+    let output = tokio::time::timeout(timeout, output)
+        .await
+        .map_err(|_| NixTimeout(timeout))??;
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if stderr.lines().any(is_out_of_memory_error) {
+        return Err(NixOutOfMemory.into());
+    }
+    ensure!(
+        output.status.success(),
+        "Nix eval failed with {}.\nStderr: {}",
+        output.status,
+        stderr,
+    );
+
+    let value = serde_json::from_slice::<Value>(&output.stdout)?;
+    let mut out = String::new();
+    value.render(0, &mut out);
+    Ok(out)
+}
+
+/// A value tree converted by `pretty_print.nix`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum Value {
+    Null,
+    Bool {
+        value: bool,
+    },
+    Int {
+        value: i64,
+    },
+    Float {
+        value: f64,
+    },
+    String {
+        value: String,
+    },
+    Path {
+        value: String,
+    },
+    Lambda,
+    Derivation {
+        #[serde(rename = "drvPath")]
+        drv_path: Option<String>,
+    },
+    /// Attributes are `None` if truncated.
+    Set {
+        attrs: Option<BTreeMap<String, Value>>,
+    },
+    /// Items are `None` if truncated.
+    List {
+        items: Option<Vec<Value>>,
+    },
+    Error,
+}
+
+impl Value {
+    /// Render in the format of `nix repl`, but with one attribute or item per line.
+    fn render(&self, indent: usize, out: &mut String) {
+        match self {
+            Self::Null => out.push_str("null"),
+            Self::Bool { value } => write!(out, "{value}").unwrap(),
+            Self::Int { value } => write!(out, "{value}").unwrap(),
+            Self::Float { value } => write!(out, "{value}").unwrap(),
+            Self::String { value } => out.push_str(&escape_string(value)),
+            Self::Path { value } => out.push_str(value),
+            Self::Lambda => out.push_str("«lambda»"),
+            Self::Derivation { drv_path: None } => out.push_str("«derivation»"),
+            Self::Derivation {
+                drv_path: Some(drv_path),
+            } => write!(out, "«derivation {drv_path}»").unwrap(),
+            Self::Error => out.push_str("«error»"),
+            Self::Set { attrs: None } => out.push_str("{ ... }"),
+            Self::Set { attrs: Some(attrs) } if attrs.is_empty() => out.push_str("{ }"),
+            Self::Set { attrs: Some(attrs) } => {
+                out.push_str("{\n");
+                for (name, value) in attrs {
+                    write!(
+                        out,
+                        "{:1$}{2} = ",
+                        "",
+                        indent + 2,
+                        escape_literal_attr(name)
+                    )
+                    .unwrap();
+                    value.render(indent + 2, out);
+                    out.push_str(";\n");
+                }
+                write!(out, "{:1$}}}", "", indent).unwrap();
+            }
+            Self::List { items: None } => out.push_str("[ ... ]"),
+            Self::List { items: Some(items) } if items.is_empty() => out.push_str("[ ]"),
+            Self::List { items: Some(items) } => {
+                out.push_str("[\n");
+                for value in items {
+                    write!(out, "{:1$}", "", indent + 2).unwrap();
+                    value.render(indent + 2, out);
+                    out.push('\n');
+                }
+                write!(out, "{:1$}]", "", indent).unwrap();
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[tokio::test]
+    #[cfg(unix)]
+    async fn nix_eval_pretty_fake() {
+        let nix = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fake_nix/nix");
+        let timeout = Duration::from_secs(5);
+        let eval = |expr, timeout| nix_eval_pretty(&nix, expr, "/".as_ref(), 1, None, timeout);
+        assert_eq!(eval("1 + 1", timeout).await.unwrap(), "2");
+        assert_eq!(eval("cwd", timeout).await.unwrap(), "/");
+        let err = eval("sleep", Duration::from_millis(100)).await.unwrap_err();
+        assert!(err.is::<NixTimeout>(), "{err:#}");
+    }
+
     #[tokio::test]
     #[ignore = "requires calling 'nix'"]
     async fn nix_eval_simple() {
@@ -56,4 +265,67 @@ mod tests {
             .await
             .unwrap_err();
     }
+
+    #[test]
+    fn render_value() {
+        let value = serde_json::from_str::<Value>(
+            r#"{ "type": "set", "attrs": {
+                "a-b": { "type": "list", "items": [
+                    { "type": "int", "value": 1 },
+                    { "type": "string", "value": "x\\n${y}" },
+                    { "type": "set", "attrs": null },
+                    { "type": "set", "attrs": {} }
+                ] },
+                "c": { "type": "derivation", "drvPath": "/nix/store/eeee-hello.drv" },
+                "d": { "type": "lambda" },
+                "e": { "type": "error" },
+                "f": { "type": "path", "value": "/tmp/foo" },
+                "g": { "type": "null", "value": null }
+            } }"#,
+        )
+        .unwrap();
+        let mut out = String::new();
+        value.render(0, &mut out);
+        let expect = r#"{
+  a-b = [
+    1
+    "x\\n\${y}"
+    { ... }
+    { }
+  ];
+  c = «derivation /nix/store/eeee-hello.drv»;
+  d = «lambda»;
+  e = «error»;
+  f = /tmp/foo;
+  g = null;
+}"#;
+        assert_eq!(out, expect);
+    }
+
+    #[tokio::test]
+    #[ignore = "requires calling 'nix'"]
+    async fn nix_eval_pretty_simple() {
+        let ret = nix_eval_pretty(
+            "nix".as_ref(),
+            "{ a = [ 1 (throw \"x\") ]; b.c.d = ./.; f = x: x; }",
+            "/".as_ref(),
+            2,
+            None,
+            Duration::from_secs(60),
+        )
+        .await
+        .unwrap();
+        let expect = "\
+{
+  a = [
+    1
+    «error»
+  ];
+  b = {
+    c = { ... };
+  };
+  f = «lambda»;
+}";
+        assert_eq!(ret, expect);
+    }
 }
@@ -4,7 +4,7 @@
 //! which may be very costly for large repositories like nixpkgs.
 //!
 //! <https://github.com/NixOS/nix/blob/2.13.1/src/nix/flake.md#lock-files>
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::path::Path;
 use std::process::Stdio;
 
@@ -13,22 +13,23 @@ use serde::Deserialize;
 use serde_repr::Deserialize_repr;
 use tokio::process::Command;
 
-use crate::eval::nix_eval_expr_json;
+use crate::eval::Evaluator;
 use crate::FlakeUrl;
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct ResolvedInput {
     pub store_path: String,
+    /// The `narHash` of the locked input.
+    pub nar_hash: String,
     pub is_flake: bool,
 }
 
 /// Resolve all root inputs from a flake lock.
 pub async fn resolve_flake_locked_inputs(
-    nix_command: &Path,
+    evaluator: Evaluator<'_>,
     lock_src: &[u8],
 ) -> Result<HashMap<String, ResolvedInput>> {
-    let lock =
-        serde_json::from_slice::<FlakeLock>(lock_src).context("Failed to parse flake lock")?;
+    let lock = FlakeLock::from_slice(lock_src)?;
 
     let mut resolver = Resolver::new(&lock);
     let root_node = resolver.get_node(&lock.root).context("Missing root node")?;
@@ -58,9 +59,8 @@ pub async fn resolve_flake_locked_inputs(
         })
         .collect::<Result<String>>()?;
 
-    let store_paths = nix_eval_expr_json::<Vec<String>>(
-        nix_command,
-        &format!(
+    let store_paths = evaluator
+        .eval_json::<Vec<String>>(&format!(
             r#"
             builtins.map (hash: (derivation {{
                 name = "source";
@@ -71,14 +71,19 @@ pub async fn resolve_flake_locked_inputs(
                 outputHash = hash;
             }}).outPath) [ {hashes} ]
             "#
-        ),
-    )
-    .await?;
+        ))
+        .await?;
 
     let resolved = std::iter::zip(inputs, store_paths)
         .map(|((input_name, node), store_path)| {
             let resolved = ResolvedInput {
                 is_flake: node.flake,
+                nar_hash: node
+                    .locked
+                    .as_ref()
+                    .expect("Checked above")
+                    .nar_hash
+                    .clone(),
                 store_path,
             };
             (input_name.to_owned(), resolved)
@@ -146,29 +151,111 @@ impl<'a> Resolver<'a> {
     }
 }
 
+/// The parsed lock graph of a flake, from `flake.lock`.
 #[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
-struct FlakeLock {
+pub struct FlakeLock {
     version: Version,
     root: String,
     nodes: HashMap<String, FlakeNode>,
 }
 
+impl FlakeLock {
+    pub fn from_slice(lock_src: &[u8]) -> Result<Self> {
+        serde_json::from_slice(lock_src).context("Failed to parse flake lock")
+    }
+
+    /// Names of inputs of the root flake.
+    pub fn root_inputs(&self) -> impl Iterator<Item = &str> + '_ {
+        self.nodes
+            .get(&self.root)
+            .into_iter()
+            .flat_map(|node| node.inputs.keys())
+            .map(|name| &**name)
+    }
+
+    /// Check if an input path like `nixpkgs` or `home-manager/nixpkgs`, as the target of
+    /// `follows`, exists in the lock graph.
+    pub fn has_input_path(&self, path: &[impl AsRef<str>]) -> bool {
+        let path = FlakeInput::Follow(path.iter().map(|s| s.as_ref().to_owned()).collect());
+        Resolver::new(self).resolve_input_node_id(&path).is_ok()
+    }
+
+    /// The node ID of the root flake.
+    pub fn root(&self) -> &str {
+        &self.root
+    }
+
+    pub fn node(&self, node_id: &str) -> Option<&FlakeNode> {
+        self.nodes.get(node_id)
+    }
+
+    /// Resolve an input of a node to the node ID it refers to, following `follows` paths.
+    pub fn resolve_input(&self, node_id: &str, input_name: &str) -> Option<&str> {
+        let input = self.nodes.get(node_id)?.inputs.get(input_name)?;
+        Resolver::new(self).resolve_input_node_id(input).ok()
+    }
+
+    /// Get input paths like `home-manager/nixpkgs` which `follows` the given node.
+    pub fn followers(&self, node_id: &str) -> Vec<String> {
+        let mut ret = Vec::new();
+        for (owner, node) in &self.nodes {
+            for (input_name, input) in &node.inputs {
+                if matches!(input, FlakeInput::Follow(_))
+                    && Resolver::new(self).resolve_input_node_id(input).ok() == Some(node_id)
+                {
+                    if *owner == self.root {
+                        ret.push(input_name.clone());
+                    } else {
+                        ret.push(format!("{owner}/{input_name}"));
+                    }
+                }
+            }
+        }
+        ret.sort();
+        ret
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize_repr)]
 #[repr(u8)]
 enum Version {
     V7 = 7,
 }
 
+/// Attributes of a flake reference, like `type`, `owner` and `rev`.
+pub type FlakeRefAttrs = BTreeMap<String, serde_json::Value>;
+
 #[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
-struct FlakeNode {
+pub struct FlakeNode {
     #[serde(default)]
     inputs: HashMap<String, FlakeInput>,
     /// For the root node (the current flake), this is `None`.
     locked: Option<LockedFlakeRef>,
+    original: Option<FlakeRefAttrs>,
     #[serde(default = "const_true")]
     flake: bool,
 }
 
+impl FlakeNode {
+    pub fn input_names(&self) -> impl Iterator<Item = &str> + '_ {
+        self.inputs.keys().map(|name| &**name)
+    }
+
+    /// The reference written in `flake.nix`, before locking.
+    pub fn original(&self) -> Option<&FlakeRefAttrs> {
+        self.original.as_ref()
+    }
+
+    /// The locked reference, without `narHash`.
+    pub fn locked(&self) -> Option<&FlakeRefAttrs> {
+        self.locked.as_ref().map(|locked| &locked.attrs)
+    }
+
+    pub fn is_flake(&self) -> bool {
+        self.flake
+    }
+}
+
 fn const_true() -> bool {
     true
 }
@@ -184,7 +271,8 @@ enum FlakeInput {
 #[serde(rename_all = "camelCase")]
 struct LockedFlakeRef {
     nar_hash: String,
-    // ...
+    #[serde(flatten)]
+    attrs: FlakeRefAttrs,
 }
 
 // NB. The output of `nix flake archive` doesn't contain followed inputs. We should still use
@@ -224,7 +312,7 @@ mod tests {
     #[ignore = "requires calling 'nix'"]
     async fn resolve_flake_lock_inputs() {
         let lock_src = std::fs::read("./tests/test_flake/flake.lock").unwrap();
-        let got = resolve_flake_locked_inputs("nix".as_ref(), &lock_src)
+        let got = resolve_flake_locked_inputs(Evaluator::Command("nix".as_ref()), &lock_src)
             .await
             .unwrap();
         let expect = HashMap::from_iter([
@@ -232,6 +320,7 @@ mod tests {
                 "nixpkgs".to_owned(),
                 ResolvedInput {
                     store_path: "/nix/store/hap5a6iw5rccl21adfxh5b3lk2c8qnmj-source".to_owned(),
+                    nar_hash: "sha256-xU6Rv9sgnwaWK7tgCPadV6HhI2Y/fl4lKxJoG2+m9qs=".to_owned(),
                     is_flake: true,
                 },
             ),
@@ -239,6 +328,7 @@ mod tests {
                 "nix".to_owned(),
                 ResolvedInput {
                     store_path: "/nix/store/5598lqiaw5qjgn661w74q2a6kivgiksa-source".to_owned(),
+                    nar_hash: "sha256-jUc2ccTR8f6MGY2pUKgujm+lxSPNGm/ZAP+toX+nMNc=".to_owned(),
                     is_flake: false,
                 },
             ),
@@ -246,6 +336,24 @@ mod tests {
         assert_eq!(got, expect);
     }
 
+    #[test]
+    fn lock_graph() {
+        let lock_src = std::fs::read("./tests/test_flake/flake.lock").unwrap();
+        let lock = FlakeLock::from_slice(&lock_src).unwrap();
+        let mut inputs = lock.root_inputs().collect::<Vec<_>>();
+        inputs.sort();
+        assert_eq!(inputs, ["nix", "nixpkgs"]);
+        assert!(lock.has_input_path(&["nixpkgs"]));
+        assert!(!lock.has_input_path(&["nixpkgs", "nixpkgs"]));
+        assert!(!lock.has_input_path(&["flake-utils"]));
+        assert_eq!(lock.resolve_input(lock.root(), "nixpkgs"), Some("nixpkgs"));
+        assert_eq!(lock.followers("nixpkgs"), Vec::<String>::new());
+        let nix = lock.node("nix").unwrap();
+        assert!(!nix.is_flake());
+        assert_eq!(nix.locked().unwrap()["type"], "github");
+        assert_eq!(nix.original().unwrap()["repo"], "nix");
+    }
+
     #[tokio::test]
     #[ignore = "requires calling 'nix' and network access"]
     async fn archive() {
@@ -0,0 +1,60 @@
+# Lazily evaluate flake outputs under `attrPath`, at most `depth` levels deep.
+#
+# Only outputs for `system` are walked into for per-system outputs, and huge sets
+# like `legacyPackages.<system>` are only listed. Attrsets not evaluated are
+# reported as `unevaluated` leaves, and can be requested later by `attrPath`.
+#
+# The result is a list of nodes `{ path, leaf }` in evaluation order, where
+# `path` is relative to `attrPath` and `leaf` is omitted for attrsets.
+{ flakeRef, attrPath, depth, system }:
+let
+  inherit (builtins)
+    attrNames concatMap elem elemAt foldl' getFlake head isAttrs isString length tryEval;
+
+  # Outputs having systems as the second level.
+  perSystemOutputs = [ "apps" "checks" "devShells" "formatter" "legacyPackages" "packages" ];
+
+  # The maximal length of full attrpaths to be walked into, for outputs which
+  # are too large to be evaluated entirely. The requested root is always walked.
+  walkLimits = {
+    legacyPackages = 2;
+    lib = 2;
+    nixosConfigurations = 1;
+    darwinConfigurations = 1;
+    homeConfigurations = 1;
+  };
+
+  tryString = v: let r = tryEval v; in if r.success && isString r.value then r.value else null;
+
+  walk = fullPath: path: depth: value:
+    let
+      len = length fullPath;
+      output = head fullPath;
+      name = elemAt fullPath (len - 1);
+      forced = tryEval value;
+      v = forced.value;
+      report = leaf: [ { inherit path leaf; } ];
+      isDerivation = isAttrs v && (tryEval (v.type or null)).value == "derivation";
+      canWalk = path == [ ] || len <= walkLimits.${output} or len;
+    in
+    if len == 1 && name == "nixosModule" || len == 2 && output == "nixosModules" then
+      report { type = "nixos-module"; }
+    else if depth == 0 || !canWalk || len == 2 && elem output perSystemOutputs && name != system then
+      report { type = "unevaluated"; }
+    else if !forced.success then
+      report { type = "unknown"; }
+    else if isDerivation then
+      report {
+        type = "derivation";
+        name = tryString (v.name or null);
+        description = tryString (v.meta.description or null);
+      }
+    else if isAttrs v then
+      [ { inherit path; } ]
+      ++ concatMap (k: walk (fullPath ++ [ k ]) (path ++ [ k ]) (depth - 1) v.${k}) (attrNames v)
+    else
+      report { type = "unknown"; };
+
+  root = foldl' (v: k: v.${k}) (getFlake flakeRef).outputs attrPath;
+in
+walk attrPath [ ] depth root
@@ -1,67 +1,75 @@
 use std::collections::HashMap;
 use std::path::Path;
 use std::process::Stdio;
+use std::time::Duration;
 
 use anyhow::{ensure, Context, Result};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use syntax::semantic::escape_string;
 use tokio::io::{AsyncBufReadExt, BufReader};
 use tokio::process::Command;
 use tokio::sync::watch;
 
-use crate::{FlakeUrl, NixOutOfMemory};
+use crate::worker::WorkerPool;
+use crate::{is_out_of_memory_error, set_memory_limit, FlakeUrl, NixOutOfMemory};
 
+/// The depth to evaluate from the root of flake outputs by default, which reaches packages like
+/// `packages.<system>.<name>`.
+pub const DEFAULT_DEPTH: u32 = 4;
+
+/// Evaluate flake outputs under `attr_path` of at most `depth` levels, via a bundled Nix
+/// expression instead of `nix flake show`, so that only requested attributes are evaluated.
+///
+/// Per-system outputs are only evaluated for `system`. Attrsets not evaluated, including huge
+/// package sets like `legacyPackages.<system>.*`, are reported as [`Type::Unevaluated`] leaves,
+/// which can be evaluated later by calling this again with their paths.
+///
+/// Evaluated nodes are streamed from Nix, and the path of the latest one is sent to
+/// `watcher_tx` for progress.
 pub async fn eval_flake_output(
     nix_command: &Path,
     flake_url: &FlakeUrl,
+    attr_path: &[String],
+    depth: u32,
+    system: &str,
     watcher_tx: Option<watch::Sender<String>>,
-    legacy: bool,
     memory_limit: Option<u64>,
 ) -> Result<FlakeOutput> {
+    let args = eval_args(flake_url, attr_path, depth, system);
+    // Report nodes as soon as they are evaluated.
+    let apply = format!(
+        "args: builtins.foldl' (n: node: builtins.trace (builtins.toJSON node) (n + 1)) 0 (({}) args)",
+        include_str!("./flake_output.nix"),
+    );
+
     let mut command = Command::new(nix_command);
     command
         .kill_on_drop(true)
         .args([
-            "flake",
-            "show",
+            "eval",
             "--experimental-features",
             "nix-command flakes",
+            "--impure",
             "--json",
+            "--expr",
+            &args,
+            // Workaround: `--argstr` is broken currently.
+            // https://github.com/NixOS/nix/issues/2678
+            "--apply",
+            &apply,
         ])
-        .args(watcher_tx.is_some().then_some("-v"))
-        .args(legacy.then_some("--legacy"))
-        .arg(flake_url)
         .stdin(Stdio::null())
         .stdout(Stdio::piped())
         .stderr(Stdio::piped());
-
-    // MacOS does not respect `RLIMIT_DATA`.
-    // See: https://bugs.chromium.org/p/chromium/issues/detail?id=853873#c2
-    #[cfg(target_os = "linux")]
-    unsafe {
-        if let Some(limit) = memory_limit {
-            use rustix::process::{setrlimit, Resource, Rlimit};
-            command.pre_exec(move || {
-                // NB. RSS limit has no effect on modern Linux. We set DATA limit instead.
-                setrlimit(
-                    Resource::Data,
-                    Rlimit {
-                        current: Some(limit),
-                        maximum: Some(limit),
-                    },
-                )?;
-                Ok(())
-            });
-        }
-    }
-
-    #[cfg(not(target_os = "linux"))]
-    let _unused = memory_limit;
+    set_memory_limit(&mut command, memory_limit);
 
     let mut child = command.spawn().context("Failed to spawn `nix`")?;
 
     let stderr = child.stderr.take().expect("Piped");
     let mut error_msg = String::new();
     let mut oom = false;
+    let mut root = None::<FlakeOutput>;
+    let mut node_cnt = 0usize;
     let consume_stderr_fut = async {
         let mut stderr = BufReader::new(stderr);
         let mut line = String::new();
@@ -70,17 +78,21 @@ pub async fn eval_flake_output(
             matches!(stderr.read_line(&mut line).await, Ok(n) if n != 0)
         } {
             let line = line.trim();
-            if let Some(inner) = line.strip_prefix("evaluating '") {
-                if let Some(inner) = inner.strip_suffix("'...") {
-                    if let Some(tx) = &watcher_tx {
-                        tx.send_modify(|buf| {
-                            buf.clear();
-                            buf.push_str(inner);
-                        });
-                    }
+            // Traces from the flake itself are not nodes, and are ignored.
+            if let Some(inner) = line.strip_prefix("trace: ") {
+                let Ok(node) = serde_json::from_str::<Node>(inner) else {
+                    continue;
+                };
+                if let Some(tx) = &watcher_tx {
+                    tx.send_modify(|buf| {
+                        buf.clear();
+                        buf.push_str(&node.path.join("."));
+                    });
                 }
+                node.insert_into(&mut root);
+                node_cnt += 1;
             } else {
-                if line == "error: out of memory" {
+                if is_out_of_memory_error(line) {
                     oom = true;
                 }
                 error_msg.push_str(line);
@@ -96,17 +108,78 @@ pub async fn eval_flake_output(
 
     ensure!(
         output.status.success(),
-        "`nix flake show {}` failed with {}. Stderr:\n{}",
+        "Evaluating outputs of flake {} failed with {}. Stderr:\n{}",
         flake_url,
         output.status,
         error_msg,
     );
 
-    let val = serde_json::from_slice(&output.stdout)?;
-    Ok(val)
+    // Sanity check that no node is lost.
+    let expect_cnt = serde_json::from_slice::<usize>(&output.stdout)?;
+    ensure!(
+        node_cnt == expect_cnt,
+        "Expect {expect_cnt} nodes from evaluation, but got {node_cnt}",
+    );
+    root.context("No output is evaluated")
+}
+
+/// Same as [`eval_flake_output`], but evaluated by a persistent worker of `pool` in at most
+/// `timeout`, without progress. This is fast for repeated evaluation of the same flake.
+pub async fn eval_flake_output_in(
+    pool: &WorkerPool,
+    flake_url: &FlakeUrl,
+    attr_path: &[String],
+    depth: u32,
+    system: &str,
+    timeout: Duration,
+) -> Result<FlakeOutput> {
+    let expr = format!(
+        "({}) {}",
+        include_str!("./flake_output.nix"),
+        eval_args(flake_url, attr_path, depth, system),
+    );
+    let nodes = pool.eval_json::<Vec<Node>>(&expr, timeout).await?;
+    let mut root = None;
+    for node in nodes {
+        node.insert_into(&mut root);
+    }
+    root.context("No output is evaluated")
+}
+
+fn eval_args(flake_url: &FlakeUrl, attr_path: &[String], depth: u32, system: &str) -> String {
+    format!(
+        "{{ flakeRef = {}; attrPath = [ {} ]; depth = {depth}; system = {}; }}",
+        escape_string(flake_url.as_str()),
+        attr_path
+            .iter()
+            .map(|key| escape_string(key) + " ")
+            .collect::<String>(),
+        escape_string(system),
+    )
+}
+
+/// A node reported by the evaluator, where `leaf` is `None` for attrsets.
+#[derive(Debug, Deserialize)]
+struct Node {
+    path: Vec<String>,
+    leaf: Option<Leaf>,
+}
+
+impl Node {
+    /// Insert this node into the output tree. Parents must be inserted before children.
+    fn insert_into(self, root: &mut Option<FlakeOutput>) {
+        let value = match self.leaf {
+            Some(leaf) => FlakeOutput::Leaf(leaf),
+            None => FlakeOutput::Attrset(HashMap::new()),
+        };
+        match root {
+            Some(root) => root.insert(&self.path, value),
+            None => *root = Some(value),
+        }
+    }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
 #[serde(untagged)]
 pub enum FlakeOutput {
     Leaf(Leaf),
@@ -127,9 +200,27 @@ impl FlakeOutput {
             _ => None,
         }
     }
+
+    /// Set the output at `path` to `value`, creating attrsets on the path if necessary.
+    /// This is used to merge outputs evaluated later, which replace `Unevaluated` leaves.
+    pub fn insert(&mut self, path: &[String], value: FlakeOutput) {
+        let mut cur = self;
+        for key in path {
+            if !matches!(cur, Self::Attrset(_)) {
+                *cur = Self::Attrset(HashMap::new());
+            }
+            let Self::Attrset(set) = cur else {
+                unreachable!()
+            };
+            cur = set
+                .entry(key.clone())
+                .or_insert_with(|| Self::Attrset(HashMap::new()));
+        }
+        *cur = value;
+    }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Leaf {
     #[serde(rename = "type")]
@@ -138,12 +229,16 @@ pub struct Leaf {
     pub description: Option<String>,
 }
 
+// Reported by `./flake_output.nix`, with some types from `nix flake show`.
 // https://github.com/NixOS/nix/blob/2.14.1/src/nix/flake.cc#L1105
-#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub enum Type {
+    #[serde(rename = "nixos-module")]
     NixosModule,
     Derivation,
+    /// A value not evaluated yet, due to the depth limit or the size of its parent.
+    Unevaluated,
     #[serde(other)]
     Unknown,
 }
@@ -152,25 +247,95 @@ pub enum Type {
 mod tests {
     use super::*;
 
+    #[test]
+    fn insert() {
+        let leaf = |type_| {
+            FlakeOutput::Leaf(Leaf {
+                type_,
+                name: None,
+                description: None,
+            })
+        };
+        let path = |s: &str| s.split('.').map(String::from).collect::<Vec<_>>();
+
+        let mut output = FlakeOutput::Attrset(HashMap::new());
+        output.insert(&path("packages.x86_64-linux"), leaf(Type::Unevaluated));
+        output.insert(&path("packages.x86_64-linux.hello"), leaf(Type::Derivation));
+        output.insert(&path("lib"), leaf(Type::Unknown));
+        let set = output.as_attrset().unwrap();
+        assert_eq!(set["lib"], leaf(Type::Unknown));
+        let pkgs = set["packages"].as_attrset().unwrap()["x86_64-linux"]
+            .as_attrset()
+            .unwrap();
+        assert_eq!(pkgs["hello"], leaf(Type::Derivation));
+
+        output.insert(&[], leaf(Type::Unknown));
+        assert_eq!(output, leaf(Type::Unknown));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn eval_in_worker() {
+        let nix = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fake_nix_repl/nix");
+        let pool = WorkerPool::new(nix, 1, None);
+        let flake_url = FlakeUrl::new_path("/nix/store/fake-flake");
+        let attr_path = ["packages".to_owned(), "x86_64-linux".to_owned()];
+        let timeout = Duration::from_secs(5);
+        let output =
+            eval_flake_output_in(&pool, &flake_url, &attr_path, 2, "x86_64-linux", timeout)
+                .await
+                .unwrap();
+        let leaf = output.as_attrset().unwrap()["hello"].as_leaf().unwrap();
+        assert_eq!(leaf.type_, Type::Derivation);
+        assert_eq!(leaf.name.as_deref(), Some("hello-1.2.3"));
+    }
+
     #[tokio::test]
     #[ignore = "requires calling 'nix' and network access"]
     async fn eval_outputs() {
         let flake_url = FlakeUrl::new_path("./tests/test_flake");
-        let (tx, rx) = watch::channel(String::new());
-        let output = eval_flake_output("nix".as_ref(), &flake_url, Some(tx), false, None)
-            .await
-            .unwrap();
-        // Even if the system is omitted, the attrpath is still printed in progress.
-        assert_eq!(*rx.borrow(), "packages.x86_64-linux.hello");
-
         let system = crate::tests::get_nix_system().await;
-        let leaf = (|| {
-            output.as_attrset()?["packages"].as_attrset()?[&system].as_attrset()?["hello"].as_leaf()
-        })()
+        let (tx, rx) = watch::channel(String::new());
+        let output = eval_flake_output(
+            "nix".as_ref(),
+            &flake_url,
+            &[],
+            DEFAULT_DEPTH,
+            &system,
+            Some(tx),
+            None,
+        )
+        .await
         .unwrap();
+        assert!(rx.borrow().starts_with("packages."));
+
+        let packages = output.as_attrset().unwrap()["packages"]
+            .as_attrset()
+            .unwrap();
+        let leaf = packages[&system].as_attrset().unwrap()["hello"]
+            .as_leaf()
+            .unwrap();
         assert_eq!(leaf.type_, Type::Derivation);
         assert_eq!(leaf.name.as_ref().unwrap(), "hello-1.2.3");
         assert_eq!(leaf.description.as_deref(), Some("A test derivation"));
+
+        // Other systems are not evaluated, until requested.
+        let (other_system, other) = packages.iter().find(|(k, _)| **k != system).unwrap();
+        assert_eq!(other.as_leaf().unwrap().type_, Type::Unevaluated);
+        let attr_path = ["packages".to_owned(), other_system.clone()];
+        let output = eval_flake_output(
+            "nix".as_ref(),
+            &flake_url,
+            &attr_path,
+            2,
+            &system,
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        let leaf = output.as_attrset().unwrap()["hello"].as_leaf().unwrap();
+        assert_eq!(leaf.type_, Type::Derivation);
     }
 
     #[cfg(target_os = "linux")]
@@ -180,9 +345,17 @@ mod tests {
         let flake_url = FlakeUrl::new_path("./tests/oom_flake");
         // 64MiB. This should be large enough to start the Nix evaluator itself without crash.
         let limit = 64 << 20;
-        let err = eval_flake_output("nix".as_ref(), &flake_url, None, false, Some(limit))
-            .await
-            .unwrap_err();
+        let err = eval_flake_output(
+            "nix".as_ref(),
+            &flake_url,
+            &[],
+            DEFAULT_DEPTH,
+            "x86_64-linux",
+            None,
+            Some(limit),
+        )
+        .await
+        .unwrap_err();
         assert!(err.is::<NixOutOfMemory>(), "expect OOM but got: {err}");
     }
 }
@@ -1,45 +1,102 @@
 //! Various information about Nix itself and environment.
-use std::path::Path;
-
 use anyhow::Result;
 use serde::Deserialize;
 
+use crate::eval::Evaluator;
+
 #[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
 pub struct NixInfo {
     /// The version string reported by `builtins.nixVersion`.
     /// Note that this does not follow semver.
     pub version: String,
+    /// The implementation of Nix.
+    pub dialect: NixDialect,
     /// Flake support.
     /// Requires nix >= 2.4
     pub flake: bool,
-    /// Support for `--all-systems` and reduced output of `--legacy`.
-    /// Requires nix >= 2.14
-    pub flake_show_filter_systems: bool,
+    /// The current system reported by `builtins.currentSystem`, eg. `x86_64-linux`.
+    pub system: String,
+}
+
+/// The system of the host in Nix's naming, eg. `x86_64-linux` or `aarch64-darwin`.
+/// This is a fallback before or if `builtins.currentSystem` is unavailable.
+/// Unknown architectures or OSes fall back to `x86_64-linux`.
+pub fn host_system() -> String {
+    nix_system(std::env::consts::ARCH, std::env::consts::OS)
+        .unwrap_or_else(|| "x86_64-linux".into())
+}
+
+/// Map Rust's architecture and OS names to a Nix system.
+fn nix_system(arch: &str, os: &str) -> Option<String> {
+    let arch = match arch {
+        "x86" => "i686",
+        "x86_64" | "aarch64" | "riscv64" | "loongarch64" | "s390x" => arch,
+        "arm" if cfg!(target_feature = "v7") => "armv7l",
+        "arm" => "armv6l",
+        "powerpc64" if cfg!(target_endian = "little") => "powerpc64le",
+        "powerpc64" => "powerpc64",
+        _ => return None,
+    };
+    let os = match os {
+        "macos" => "darwin",
+        "linux" | "freebsd" | "netbsd" | "openbsd" => os,
+        _ => return None,
+    };
+    Some(format!("{arch}-{os}"))
 }
 
-pub async fn get(nix_command: &Path) -> Result<NixInfo> {
-    crate::eval::nix_eval_expr_json(
-        nix_command,
-        r#"
+/// Implementations of Nix, which differ in supported syntax and builtins.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NixDialect {
+    /// The original implementation at <https://github.com/NixOS/nix>.
+    #[default]
+    CppNix,
+    /// The fork at <https://lix.systems>.
+    Lix,
+}
+
+pub async fn get(evaluator: Evaluator<'_>) -> Result<NixInfo> {
+    evaluator
+        .eval_json(
+            r#"
 let
     inherit (builtins) nixVersion compareVersions;
     atLeast = v: compareVersions nixVersion v >= 0;
 in {
     version = nixVersion;
+    # Lix reports a CppNix-compatible version with a suffix, eg. `2.18.3-lix`.
+    dialect = if builtins.match ".*[Ll]ix.*" nixVersion != null then "lix" else "cppnix";
     flake = atLeast "2.4";
-    flake_show_filter_systems = atLeast "2.14";
+    system = builtins.currentSystem;
 }
         "#,
-    )
-    .await
+        )
+        .await
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::eval::Evaluator;
+
     #[tokio::test]
     #[ignore = "requires calling 'nix'"]
     async fn simple() {
-        let info = super::get("nix".as_ref()).await.unwrap();
+        let info = super::get(Evaluator::Command("nix".as_ref()))
+            .await
+            .unwrap();
         assert!(info.flake);
+        assert_eq!(info.system, crate::tests::get_nix_system().await);
+    }
+
+    #[test]
+    fn nix_system() {
+        let sys = |arch, os| super::nix_system(arch, os);
+        assert_eq!(sys("x86_64", "linux").unwrap(), "x86_64-linux");
+        assert_eq!(sys("x86", "linux").unwrap(), "i686-linux");
+        assert_eq!(sys("aarch64", "macos").unwrap(), "aarch64-darwin");
+        assert_eq!(sys("riscv64", "linux").unwrap(), "riscv64-linux");
+        assert_eq!(sys("sparc64", "linux"), None);
+        assert_eq!(sys("x86_64", "windows"), None);
     }
 }
@@ -1,13 +1,19 @@
 //! Nix defined file structures and interoperation with Nix.
+use std::borrow::Cow;
 use std::ffi::OsStr;
 use std::fmt;
 use std::path::{Path, PathBuf};
 
+use tokio::process::Command;
+
+pub mod builtins;
+pub mod cache;
 pub mod eval;
 pub mod flake_lock;
 pub mod flake_output;
 pub mod info;
 pub mod nixos_options;
+pub mod worker;
 
 pub const DEFAULT_IMPORT_FILE: &str = "default.nix";
 pub const FLAKE_FILE: &str = "flake.nix";
@@ -17,6 +23,61 @@ pub const FLAKE_LOCK_FILE: &str = "flake.lock";
 #[error("Nix exceeds memory limit")]
 pub struct NixOutOfMemory;
 
+/// Check if a line of Nix's stderr reports running out of memory.
+fn is_out_of_memory_error(line: &str) -> bool {
+    strip_ansi_escapes(line).contains("error: out of memory")
+}
+
+/// Remove ANSI escape sequences like colors, which Nix may print even if stderr is not a terminal.
+fn strip_ansi_escapes(s: &str) -> Cow<'_, str> {
+    if !s.contains('\x1B') {
+        return Cow::Borrowed(s);
+    }
+    let mut ret = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\x1B' {
+            ret.push(c);
+            continue;
+        }
+        // CSI sequences end with a byte in `@..=~`. Others have only one more character.
+        if chars.next() == Some('[') {
+            for c in chars.by_ref() {
+                if ('@'..='~').contains(&c) {
+                    break;
+                }
+            }
+        }
+    }
+    Cow::Owned(ret)
+}
+
+/// Limit the memory usage of a spawned `nix`. This only works on Linux.
+fn set_memory_limit(command: &mut Command, memory_limit: Option<u64>) {
+    // MacOS does not respect `RLIMIT_DATA`.
+    // See: https://bugs.chromium.org/p/chromium/issues/detail?id=853873#c2
+    #[cfg(target_os = "linux")]
+    unsafe {
+        if let Some(limit) = memory_limit {
+            use rustix::process::{setrlimit, Resource, Rlimit};
+            command.pre_exec(move || {
+                // NB. RSS limit has no effect on modern Linux. We set DATA limit instead.
+                setrlimit(
+                    Resource::Data,
+                    Rlimit {
+                        current: Some(limit),
+                        maximum: Some(limit),
+                    },
+                )?;
+                Ok(())
+            });
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    let _unused = (command, memory_limit);
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct FlakeUrl(String);
 
@@ -25,6 +86,12 @@ impl FlakeUrl {
         Self(format!("path:{}", path.as_ref().display()))
     }
 
+    /// A local flake which is fetched via Git if it's inside a repository, so that untracked
+    /// files are not copied into the store. `path` must be absolute.
+    pub fn new_local(path: impl AsRef<Path>) -> Self {
+        Self(path.as_ref().display().to_string())
+    }
+
     pub fn as_str(&self) -> &str {
         &self.0
     }
@@ -66,6 +133,16 @@ mod tests {
 
     use tokio::process::Command;
 
+    #[test]
+    fn out_of_memory_error() {
+        assert!(super::is_out_of_memory_error("error: out of memory"));
+        assert!(super::is_out_of_memory_error(
+            "\x1B[31;1merror:\x1B[0m out of memory"
+        ));
+        assert!(!super::is_out_of_memory_error("error: out of bounds"));
+        assert_eq!(super::strip_ansi_escapes("\x1B[1ma\x1B[0mb\x1Bc"), "ab");
+    }
+
     pub(crate) async fn get_nix_system() -> String {
         let output = Command::new("nix")
             .kill_on_drop(true)
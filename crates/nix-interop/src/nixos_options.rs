@@ -1,55 +1,132 @@
 use std::collections::HashMap;
+use std::fmt;
 use std::path::Path;
-use std::process::Stdio;
+use std::str::FromStr;
 
-use anyhow::{ensure, Context, Result};
-use serde::{de, Deserialize};
+use anyhow::{Context, Result};
+use serde::{de, Deserialize, Serialize};
 use syntax::semantic::escape_string;
-use tokio::process::Command;
-
-pub async fn eval_all_options(nix_command: &Path, nixpkgs_path: &Path) -> Result<NixosOptions> {
-    let nixpkgs_path = nixpkgs_path
-        .to_str()
-        .filter(|path| path.starts_with('/'))
-        .with_context(|| format!("Invalid path to nixpkgs: {}", nixpkgs_path.display()))?;
-
-    let output = Command::new(nix_command)
-        .kill_on_drop(true)
-        .args([
-            "eval",
-            "--experimental-features",
-            "nix-command",
-            "--read-only",
-            "--impure",
-            "--json",
-            "--show-trace",
-            "--expr",
-            &escape_string(nixpkgs_path),
-            // Workaround: `--argstr` is broken currently.
-            // https://github.com/NixOS/nix/issues/2678
-            "--apply",
-            include_str!("./nixos_options.nix"),
-        ])
-        .stdin(Stdio::null())
-        // Configures stdout/stderr automatically.
-        .output()
-        .await
-        .context("Failed to spawn `nix`")?;
-
-    ensure!(
-        output.status.success(),
-        "Nix eval failed with {}. Stderr:\n{}",
-        output.status,
-        String::from_utf8_lossy(&output.stderr),
-    );
 
-    let val = serde_json::from_slice(&output.stdout)?;
-    Ok(val)
+use crate::eval::Evaluator;
+
+/// Module systems whose options can be evaluated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OptionSource {
+    /// NixOS modules from nixpkgs.
+    Nixos,
+    /// Home Manager modules.
+    HomeManager,
+    /// nix-darwin modules.
+    NixDarwin,
+}
+
+impl OptionSource {
+    pub const ALL: [Self; 3] = [Self::Nixos, Self::HomeManager, Self::NixDarwin];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Nixos => "nixos",
+            Self::HomeManager => "home-manager",
+            Self::NixDarwin => "nix-darwin",
+        }
+    }
+
+    /// The human readable name of the module system.
+    pub fn title(self) -> &'static str {
+        match self {
+            Self::Nixos => "NixOS",
+            Self::HomeManager => "Home Manager",
+            Self::NixDarwin => "nix-darwin",
+        }
+    }
+
+    /// The Nix expression evaluating options of this source, accepting
+    /// `{ src, nixpkgs, normalize }`.
+    fn eval_expr(self) -> &'static str {
+        match self {
+            Self::Nixos => include_str!("./options/nixos.nix"),
+            Self::HomeManager => include_str!("./options/home_manager.nix"),
+            Self::NixDarwin => include_str!("./options/nix_darwin.nix"),
+        }
+    }
+}
+
+impl fmt::Display for OptionSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.as_str().fmt(f)
+    }
+}
+
+impl FromStr for OptionSource {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|src| src.as_str() == s)
+            .ok_or(())
+    }
+}
+
+pub async fn eval_all_options(
+    evaluator: Evaluator<'_>,
+    nixpkgs_path: &Path,
+) -> Result<NixosOptions> {
+    eval_options(evaluator, OptionSource::Nixos, nixpkgs_path, nixpkgs_path).await
+}
+
+/// Evaluate options of `source` from its source tree at `src_path`.
+/// Sources other than NixOS also need `nixpkgs_path` for `lib` and `pkgs`.
+pub async fn eval_options(
+    evaluator: Evaluator<'_>,
+    source: OptionSource,
+    src_path: &Path,
+    nixpkgs_path: &Path,
+) -> Result<NixosOptions> {
+    let to_nix_string = |path: &Path| -> Result<String> {
+        let s = path
+            .to_str()
+            .filter(|path| path.starts_with('/'))
+            .with_context(|| format!("Invalid path to {source} source: {}", path.display()))?;
+        Ok(escape_string(s))
+    };
+    let expr = format!(
+        "({}) {{ src = {}; nixpkgs = {}; normalize = {}; }}",
+        source.eval_expr(),
+        to_nix_string(src_path)?,
+        to_nix_string(nixpkgs_path)?,
+        include_str!("./options/normalize.nix"),
+    );
+    evaluator.eval_json(&expr).await
 }
 
 pub type NixosOptions = HashMap<String, NixosOption>;
 
-#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+/// Get the option at `path`, descending into submodules and `attrsOf` elements.
+/// Option sets grouping other options are also returned, without declarations.
+pub fn get_option<'a>(opts: &'a NixosOptions, path: &[impl AsRef<str>]) -> Option<&'a NixosOption> {
+    let (last, init) = path.split_last()?;
+    let (mut fields, mut rest) = (opts, None);
+    for key in init {
+        let ty = match fields.get(key.as_ref()) {
+            Some(opt) => &opt.ty,
+            None => rest?,
+        };
+        let Ty::Attrset {
+            fields: sub_fields,
+            rest: sub_rest,
+        } = ty
+        else {
+            return None;
+        };
+        fields = sub_fields;
+        rest = sub_rest.as_deref();
+    }
+    fields.get(last.as_ref())
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct NixosOption {
     pub description: Option<Doc>,
@@ -65,28 +142,33 @@ pub struct NixosOption {
     pub related_packages: Vec<RelatedPackage>,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
 #[serde(tag = "_type")]
 pub enum Doc {
     #[serde(rename = "mdDoc")]
     Markdown { text: String },
+    #[serde(rename = "literalDocBook")]
+    DocBook { text: String },
     #[serde(other)]
     Other,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
 #[serde(tag = "_type")]
 pub enum Value {
     #[serde(rename = "literalExpression")]
     Expression { text: String },
     #[serde(rename = "literalMD")]
     Markdown { text: String },
+    #[serde(rename = "literalDocBook")]
+    DocBook { text: String },
     #[serde(other)]
     Other,
 }
 
 // https://github.com/NixOS/nixpkgs/blob/28c1aac72e3aef70b8c898ea9c16d5907f9eae22/nixos/lib/make-options-doc/default.nix#L61
-#[derive(Debug, Clone, PartialEq, Eq)]
+// Serialized in the full form, which round-trips through the deserializer.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
 pub struct RelatedPackage {
     pub path: Vec<String>,
     pub comment: Option<String>,
@@ -130,7 +212,7 @@ impl<'de> de::Deserialize<'de> for RelatedPackage {
     }
 }
 
-#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize)]
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase", tag = "name")]
 pub enum Ty {
     #[default]
@@ -157,6 +239,9 @@ pub enum Ty {
 
 #[cfg(test)]
 mod tests {
+    use std::process::Stdio;
+
+    use tokio::process::Command;
     use tokio::sync::OnceCell;
 
     use crate::FlakeUrl;
@@ -192,7 +277,7 @@ mod tests {
             .unwrap()
             .to_owned();
 
-        let opts = eval_all_options("nix".as_ref(), nixpkgs_path.as_ref())
+        let opts = eval_all_options(Evaluator::Command("nix".as_ref()), nixpkgs_path.as_ref())
             .await
             .unwrap();
 
@@ -0,0 +1,34 @@
+# References:
+# - home-manager/docs/default.nix
+# - home-manager/modules/default.nix
+{ src, nixpkgs, normalize }:
+let
+  lib = import (nixpkgs + "/lib");
+  # Home Manager modules use its extended `lib.hm`.
+  hmLib = import (src + "/modules/lib/stdlib-extended.nix") lib;
+
+  # Packages are only forced by option defaults without `defaultText`.
+  pkgs = import nixpkgs {
+    system = builtins.currentSystem;
+    config = { };
+    overlays = [ ];
+  };
+
+  modules = import (src + "/modules/modules.nix") {
+    inherit pkgs;
+    lib = hmLib;
+    check = false;
+  };
+
+  eval = hmLib.evalModules {
+    modules = modules ++ [{
+      _module.check = false;
+      home.username = "nil";
+      home.homeDirectory = "/homeless-shelter";
+      home.stateVersion = lib.trivial.release;
+    }];
+    specialArgs.lib = hmLib;
+  };
+
+in
+  normalize hmLib eval.options
@@ -0,0 +1,29 @@
+# References:
+# - nix-darwin/eval-config.nix
+# - nix-darwin/release.nix
+{ src, nixpkgs, normalize }:
+let
+  lib = import (nixpkgs + "/lib");
+
+  # Packages are only forced by option defaults without `defaultText`.
+  pkgs = import nixpkgs {
+    system = builtins.currentSystem;
+    config = { };
+    overlays = [ ];
+  };
+
+  modules = import (src + "/modules/module-list.nix");
+
+  eval = lib.evalModules {
+    modules = modules ++ [{
+      _module.check = false;
+      _module.args = {
+        inherit pkgs;
+        baseModules = modules;
+        modules = [ ];
+      };
+    }];
+  };
+
+in
+  normalize lib eval.options
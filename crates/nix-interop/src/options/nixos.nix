@@ -0,0 +1,53 @@
+# References:
+# - nixos/lib/eval-cacheable-options.nix
+{ src, normalize, ... }:
+let
+  nixpkgs = src;
+  libPath = nixpkgs + "/lib";
+  lib = import libPath;
+  modulePath = nixpkgs + "/nixos/modules";
+  moduleListPath = modulePath + "/module-list.nix";
+
+  inherit (builtins) filter mapAttrs isPath isFunction functionArgs pathExists;
+  inherit (lib) evalModules trivial;
+
+  # Dummy `pkgs`.
+  pkgs = import (nixpkgs + "/pkgs/pkgs-lib") {
+    inherit lib;
+    pkgs = null;
+  };
+  utils = import (nixpkgs + "/nixos/lib/utils.nix") {
+    inherit config lib;
+    pkgs = null;
+  };
+
+  modules = filter canCacheDocs (import moduleListPath);
+
+  # From `nixos/modules/misc/documentation.nix`.
+  canCacheDocs = m:
+    let
+      f = import m;
+      instance = f (mapAttrs (n: _: abort "evaluating ${n} for `meta` failed") (functionArgs f));
+    in
+      isPath m
+        && isFunction f
+        && instance ? options
+        && instance.meta.buildDocsInSandbox or true;
+
+  config = {
+    _module.check = false;
+    _module.args = {};
+    system.stateVersion = trivial.release;
+  };
+  eval = evalModules {
+    modules = modules ++ [ config ];
+    specialArgs = {
+      inherit config pkgs utils;
+    };
+  };
+
+in
+  if pathExists libPath && pathExists moduleListPath
+    && builtins.compareVersions trivial.release "22.11" >= 0
+  then normalize lib eval.options
+  else { }
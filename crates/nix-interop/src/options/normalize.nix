@@ -1,15 +1,11 @@
+# Normalize evaluated options into the JSON structure of `NixosOptions`, shared by all
+# option sources.
 # References:
-# - nixos/lib/eval-cacheable-options.nix
 # - nixos/lib/make-options-doc/default.nix
-nixpkgs:
+lib:
 let
-  libPath = nixpkgs + "/lib";
-  lib = import libPath;
-  modulePath = nixpkgs + "/nixos/modules";
-  moduleListPath = modulePath + "/module-list.nix";
-
-  inherit (builtins) isString filter mapAttrs isPath isFunction functionArgs pathExists;
-  inherit (lib) evalModules trivial optionals filterAttrs;
+  inherit (builtins) isString filter mapAttrs;
+  inherit (lib) optionals filterAttrs;
   inherit (lib.options) unknownModule literalExpression;
 
   # Polyfill for < 23.05
@@ -26,41 +22,6 @@ let
     then { _type = "mdDoc"; text = v; }
     else v;
 
-  # Dummy `pkgs`.
-  pkgs = import (nixpkgs + "/pkgs/pkgs-lib") {
-    inherit lib;
-    pkgs = null;
-  };
-  utils = import (nixpkgs + "/nixos/lib/utils.nix") {
-    inherit config lib;
-    pkgs = null;
-  };
-
-  modules = filter canCacheDocs (import moduleListPath);
-
-  # From `nixos/modules/misc/documentation.nix`.
-  canCacheDocs = m:
-    let
-      f = import m;
-      instance = f (mapAttrs (n: _: abort "evaluating ${n} for `meta` failed") (functionArgs f));
-    in
-      isPath m
-        && isFunction f
-        && instance ? options
-        && instance.meta.buildDocsInSandbox or true;
-
-  config = {
-    _module.check = false;
-    _module.args = {};
-    system.stateVersion = trivial.release;
-  };
-  eval = evalModules {
-    modules = modules ++ [ config ];
-    specialArgs = {
-      inherit config pkgs utils;
-    };
-  };
-
   # https://github.com/NixOS/nixpkgs/blob/28c1aac72e3aef70b8c898ea9c16d5907f9eae22/lib/types.nix#L212
   normalizeType = submoduleVisible: ty: let
     elem = normalizeType submoduleVisible ty.nestedTypes.elemType;
@@ -177,7 +138,4 @@ let
       (mapAttrs (_: normalizeOptions) opts);
 
 in
-  if pathExists libPath && pathExists moduleListPath
-    && builtins.compareVersions trivial.release "22.11" >= 0
-  then normalizeOptionSet eval.options
-  else { }
+  normalizeOptionSet
@@ -0,0 +1,35 @@
+# Convert a value into a tree for pretty-printing, at most `depth` levels deep.
+#
+# `throw`s and failed assertions are caught and reported as `error` nodes, so
+# that a broken attribute does not hide its siblings.
+{ depth }:
+let
+  inherit (builtins) isAttrs map mapAttrs toString tryEval typeOf;
+
+  tryString = v: let r = tryEval v; in if r.success then r.value else null;
+
+  go = depth: value:
+    let
+      forced = tryEval value;
+      v = forced.value;
+      type = typeOf v;
+      isDerivation = isAttrs v && (tryEval (v.type or null)).value == "derivation";
+    in
+    if !forced.success then
+      { type = "error"; }
+    else if isDerivation then
+      { type = "derivation"; drvPath = tryString v.drvPath; }
+    # Children are `null` if truncated.
+    else if type == "set" then
+      { inherit type; attrs = if depth == 0 then null else mapAttrs (_: go (depth - 1)) v; }
+    else if type == "list" then
+      { inherit type; items = if depth == 0 then null else map (go (depth - 1)) v; }
+    # `toJSON` copies paths into the store.
+    else if type == "path" then
+      { inherit type; value = toString v; }
+    else if type == "lambda" then
+      { inherit type; }
+    else
+      { inherit type; value = v; };
+in
+go depth
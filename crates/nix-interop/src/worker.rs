@@ -0,0 +1,288 @@
+//! A pool of persistent `nix repl` processes, to evaluate expressions without starting Nix and
+//! re-evaluating common dependencies like flakes for every request.
+//!
+//! Each request is sent to the REPL as below, with a unique `id` per worker. Its JSON result and
+//! the end of the request are reported back by traces on stderr. Any other output on stderr
+//! before the end, like evaluation errors, is collected as the error message, without colors.
+//! ```text
+//! builtins.trace ("nil-worker-result:<id>:" + builtins.toJSON (
+//! <expr>
+//! )) null
+//! builtins.trace "nil-worker-end:<id>" null
+//! ```
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use serde::de::DeserializeOwned;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::process::{Child, ChildStderr, ChildStdin, Command};
+use tokio::sync::Semaphore;
+
+use crate::{is_out_of_memory_error, set_memory_limit, strip_ansi_escapes, NixOutOfMemory};
+
+const RESULT_TRACE_PREFIX: &str = "trace: nil-worker-result:";
+const END_TRACE_PREFIX: &str = "trace: nil-worker-end:";
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("Nix evaluation timed out after {0:?}")]
+pub struct NixTimeout(pub Duration);
+
+/// A pool of lazily spawned Nix workers.
+#[derive(Debug)]
+pub struct WorkerPool {
+    nix_command: PathBuf,
+    memory_limit: Option<u64>,
+    /// Limits the number of concurrent requests, thus live workers.
+    permits: Semaphore,
+    idle: Mutex<Vec<Worker>>,
+    /// Bumped by `recycle`. Workers spawned in older generations are not reused.
+    generation: AtomicU64,
+}
+
+impl WorkerPool {
+    /// Create a pool of at most `max_workers` workers, with the memory limit for each.
+    pub fn new(nix_command: PathBuf, max_workers: usize, memory_limit: Option<u64>) -> Self {
+        Self {
+            nix_command,
+            memory_limit,
+            permits: Semaphore::new(max_workers),
+            idle: Mutex::new(Vec::new()),
+            generation: AtomicU64::new(0),
+        }
+    }
+
+    /// Stop reusing current workers, including busy ones after their requests.
+    ///
+    /// Nix caches parsed and evaluated files, so this should be called when files may change.
+    pub fn recycle(&self) {
+        let mut idle = self.idle.lock().unwrap();
+        self.generation.fetch_add(1, Ordering::Relaxed);
+        idle.clear();
+    }
+
+    fn put_idle(&self, worker: Worker) {
+        let mut idle = self.idle.lock().unwrap();
+        if worker.generation == self.generation.load(Ordering::Relaxed) {
+            idle.push(worker);
+        }
+    }
+
+    pub fn nix_command(&self) -> &Path {
+        &self.nix_command
+    }
+
+    /// Evaluate `expr` into JSON and deserialize it, in impure mode.
+    ///
+    /// Workers are reused across requests until `recycle`. A worker is killed if the request times out or runs
+    /// out of memory. If a worker crashes, the request is retried once in a new worker.
+    pub async fn eval_json<T: DeserializeOwned>(&self, expr: &str, timeout: Duration) -> Result<T> {
+        let _permit = self.permits.acquire().await.expect("Never closed");
+        let mut retried = false;
+        loop {
+            let idle_worker = self.idle.lock().unwrap().pop();
+            let mut worker = match idle_worker {
+                Some(worker) => worker,
+                None => Worker::spawn(
+                    &self.nix_command,
+                    self.memory_limit,
+                    self.generation.load(Ordering::Relaxed),
+                )?,
+            };
+            // The worker is dropped and killed on timeout, since it's in an unknown state.
+            let ret = tokio::time::timeout(timeout, worker.eval(expr))
+                .await
+                .map_err(|_| NixTimeout(timeout))?;
+            match ret {
+                Ok(Ok(json)) => {
+                    self.put_idle(worker);
+                    return Ok(serde_json::from_str(&json)?);
+                }
+                Ok(Err(error_msg)) => {
+                    if error_msg.lines().any(is_out_of_memory_error) {
+                        return Err(NixOutOfMemory.into());
+                    }
+                    self.put_idle(worker);
+                    bail!("Nix evaluation failed. Stderr:\n{error_msg}");
+                }
+                Err(_) if !retried => retried = true,
+                Err(err) => return Err(err.context("Nix worker crashed")),
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Worker {
+    _child: Child,
+    stdin: ChildStdin,
+    stderr: Lines<BufReader<ChildStderr>>,
+    next_id: u64,
+    generation: u64,
+}
+
+impl Worker {
+    fn spawn(nix_command: &Path, memory_limit: Option<u64>, generation: u64) -> Result<Self> {
+        let mut command = Command::new(nix_command);
+        command
+            .kill_on_drop(true)
+            .args(["repl", "--experimental-features", "nix-command flakes"])
+            .stdin(Stdio::piped())
+            // Results are printed to stdout, which we don't need.
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped());
+        set_memory_limit(&mut command, memory_limit);
+        let mut child = command.spawn().context("Failed to spawn `nix repl`")?;
+        let stdin = child.stdin.take().expect("Piped");
+        let stderr = BufReader::new(child.stderr.take().expect("Piped")).lines();
+        Ok(Self {
+            _child: child,
+            stdin,
+            stderr,
+            next_id: 0,
+            generation,
+        })
+    }
+
+    /// Evaluate `expr` into a JSON string, or an error message.
+    /// The outer `Err` is returned when the worker process is dead.
+    async fn eval(&mut self, expr: &str) -> Result<Result<String, String>> {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        // The parentheses keep the REPL waiting for more lines, until the whole `expr` is read.
+        let request = format!(
+            "builtins.trace (\"nil-worker-result:{id}:\" + builtins.toJSON (\n\
+             {expr}\n\
+             )) null\n\
+             builtins.trace \"nil-worker-end:{id}\" null\n",
+        );
+        self.stdin.write_all(request.as_bytes()).await?;
+        self.stdin.flush().await?;
+
+        let result_prefix = format!("{RESULT_TRACE_PREFIX}{id}:");
+        let end_line = format!("{END_TRACE_PREFIX}{id}");
+        let mut result = None;
+        let mut error_msg = String::new();
+        loop {
+            let line = self
+                .stderr
+                .next_line()
+                .await?
+                .context("Nix worker exited unexpectedly")?;
+            let line = strip_ansi_escapes(&line);
+            if line == end_line {
+                break;
+            } else if let Some(json) = line.strip_prefix(&result_prefix) {
+                result = Some(json.to_owned());
+            } else {
+                error_msg.push_str(&line);
+                error_msg.push('\n');
+            }
+        }
+        Ok(result.ok_or(error_msg))
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    fn fake_pool(max_workers: usize) -> WorkerPool {
+        let nix = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fake_nix_repl/nix");
+        WorkerPool::new(nix, max_workers, None)
+    }
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    #[tokio::test]
+    async fn reuse() {
+        let pool = fake_pool(1);
+        assert_eq!(pool.eval_json::<i64>("1 + 1", TIMEOUT).await.unwrap(), 2);
+        let pid1 = pool.eval_json::<u32>("pid", TIMEOUT).await.unwrap();
+        let pid2 = pool.eval_json::<u32>("pid", TIMEOUT).await.unwrap();
+        assert_eq!(pid1, pid2);
+    }
+
+    #[tokio::test]
+    async fn error() {
+        let pool = fake_pool(1);
+        let pid1 = pool.eval_json::<u32>("pid", TIMEOUT).await.unwrap();
+        let err = pool.eval_json::<u32>("throw", TIMEOUT).await.unwrap_err();
+        assert!(
+            err.to_string().contains("error: expected failure"),
+            "{err:#}"
+        );
+        // Evaluation errors don't kill the worker.
+        let pid2 = pool.eval_json::<u32>("pid", TIMEOUT).await.unwrap();
+        assert_eq!(pid1, pid2);
+    }
+
+    #[tokio::test]
+    async fn timeout() {
+        let pool = fake_pool(1);
+        let pid1 = pool.eval_json::<u32>("pid", TIMEOUT).await.unwrap();
+        let err = pool
+            .eval_json::<u32>("sleep", Duration::from_millis(100))
+            .await
+            .unwrap_err();
+        assert!(err.is::<NixTimeout>(), "{err:#}");
+        let pid2 = pool.eval_json::<u32>("pid", TIMEOUT).await.unwrap();
+        assert_ne!(pid1, pid2);
+    }
+
+    #[tokio::test]
+    async fn out_of_memory() {
+        let pool = fake_pool(1);
+        let pid1 = pool.eval_json::<u32>("pid", TIMEOUT).await.unwrap();
+        let err = pool.eval_json::<u32>("oom", TIMEOUT).await.unwrap_err();
+        assert!(err.is::<NixOutOfMemory>(), "{err:#}");
+        let pid2 = pool.eval_json::<u32>("pid", TIMEOUT).await.unwrap();
+        assert_ne!(pid1, pid2);
+    }
+
+    #[tokio::test]
+    async fn restart_on_crash() {
+        let pool = fake_pool(1);
+        let pid1 = pool.eval_json::<u32>("pid", TIMEOUT).await.unwrap();
+        // Crashes again after the retry.
+        let err = pool.eval_json::<u32>("crash", TIMEOUT).await.unwrap_err();
+        assert!(err.to_string().contains("crashed"), "{err:#}");
+        let pid2 = pool.eval_json::<u32>("pid", TIMEOUT).await.unwrap();
+        assert_ne!(pid1, pid2);
+
+        // Workers killed while idle are replaced transparently.
+        let pid3 = pool.eval_json::<u32>("exit-after", TIMEOUT).await.unwrap();
+        assert_eq!(pid2, pid3);
+        let pid4 = pool.eval_json::<u32>("pid", TIMEOUT).await.unwrap();
+        assert_ne!(pid3, pid4);
+    }
+
+    #[tokio::test]
+    async fn recycle() {
+        let pool = fake_pool(1);
+        let path = std::env::temp_dir().join(format!("nil-worker-test-{}", std::process::id()));
+        let import = format!("import {}", path.display());
+        std::fs::write(&path, "1").unwrap();
+        assert_eq!(pool.eval_json::<i64>(&import, TIMEOUT).await.unwrap(), 1);
+        // Workers cache imported files.
+        std::fs::write(&path, "2").unwrap();
+        assert_eq!(pool.eval_json::<i64>(&import, TIMEOUT).await.unwrap(), 1);
+        pool.recycle();
+        assert_eq!(pool.eval_json::<i64>(&import, TIMEOUT).await.unwrap(), 2);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn concurrent() {
+        let pool = fake_pool(2);
+        let (a, b) = tokio::join!(
+            pool.eval_json::<u32>("pid", TIMEOUT),
+            pool.eval_json::<u32>("pid", TIMEOUT),
+        );
+        assert_ne!(a.unwrap(), b.unwrap());
+    }
+}
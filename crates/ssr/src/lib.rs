@@ -1,59 +1,238 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::str::FromStr;
 
 use anyhow::{bail, ensure, Context, Result};
-use indexmap::{IndexMap, IndexSet};
+use indexmap::IndexMap;
+use regex::Regex;
 use smol_str::SmolStr;
-use syntax::ast::{self, AstNode};
-use syntax::rowan::WalkEvent;
-use syntax::{NodeOrToken, SyntaxKind, SyntaxNode, SyntaxToken, TextRange};
+use syntax::ast::{self, AstNode, HasStringParts};
+use syntax::{NodeOrToken, SyntaxElement, SyntaxKind, SyntaxNode, TextRange, TextSize, T};
 
 #[cfg(test)]
 mod tests;
 
+/// A structural pattern, which is either an expression, or a sequence of bindings.
+///
+/// Placeholders `$name` match:
+/// - Any expression, in expression positions.
+/// - Any attribute, if it is one of attributes in an attrpath like `$name.foo`.
+/// - The whole attrpath, if it is the only attribute of it like `$name = 1;`.
+/// - All attributes of an `inherit`, if it is the only attribute like `inherit (a) $name;`.
+///
+/// Rest placeholders `$...name` match zero or more bindings in attribute sets and `let`.
+///
+/// Placeholders can be constrained by `${name:constraint}`, with constraints:
+/// - `literal`: Number, path or URI literals.
+/// - `string` or `string(regex)`: Strings, optionally with unescaped content matching
+///   the regex. Strings with interpolations never match a regex.
+/// - `ident` or `ident(regex)`: Identifiers, optionally matching the regex.
+/// - `kind(name)`: Nodes of the syntax kind, like `kind(list)` or `kind(attr_set)`.
+///
+/// Regexes must match the whole text, as if surrounded by `^` and `$`.
+///
+/// A placeholder can occur multiple times in a pattern, and all occurrences must match
+/// structurally equal nodes, ignoring whitespace and comments.
+///
+/// In semantic mode with a [`Resolver`], references and selections like `lib.mkIf` in the
+/// pattern match expressions referring to the same thing, rather than the same spelling.
 #[derive(Debug)]
 pub struct Pattern {
     raw: RawPattern,
-    names: IndexSet<SmolStr>,
+    /// The index of the first placeholder of each name.
+    names: HashMap<SmolStr, usize>,
+}
+
+/// A match of a pattern.
+#[derive(Debug, Clone)]
+pub struct Match {
+    /// The range of the matched expression, or the matched bindings.
+    pub range: TextRange,
+    /// The matched expression, or the node containing the matched bindings.
+    node: SyntaxNode,
+    /// Captures of placeholders, in the order of placeholders of the pattern.
+    captures: Vec<Capture>,
+}
+
+/// Consecutive sibling nodes captured by a placeholder.
+/// It contains exactly one node for placeholders matching a single node.
+type Capture = Vec<SyntaxNode>;
+
+/// Name resolution of the input, for semantic matching.
+pub trait Resolver {
+    /// Resolve a reference or a selection expression in the input.
+    fn resolve_expr(&self, node: &SyntaxNode) -> Option<RefPath>;
+
+    /// Resolve `name` as if it is written at the expression `scope` in the input,
+    /// or in the global scope if it is `None`.
+    ///
+    /// `scope` can also be a `let` or an attribute set, which means the scope of its bindings.
+    fn resolve_name(&self, name: &str, scope: Option<&SyntaxNode>) -> Option<RefPath>;
+}
+
+/// What a reference expression refers to, like `lib.mkIf` or `builtins.map`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct RefPath {
+    pub root: RefRoot,
+    pub attrs: Vec<SmolStr>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum RefRoot {
+    /// The `builtins` attribute set. Global builtins like `map` are `builtins.map`.
+    Builtins,
+    /// An undefined name, or an argument of the top-level function, like `lib` in
+    /// `{ lib, ... }: ...`.
+    Global(SmolStr),
+    /// An opaque local definition.
+    Local(u32),
+}
+
+impl RefPath {
+    pub fn join(mut self, attrs: impl IntoIterator<Item = SmolStr>) -> Self {
+        self.attrs.extend(attrs);
+        self
+    }
 }
 
 impl Pattern {
     pub fn parse(pattern: &str) -> Result<Self> {
-        let raw = pattern.parse::<RawPattern>()?;
-        let names = raw.placeholders.values().cloned().collect::<IndexSet<_>>();
-        ensure!(
-            raw.placeholders.len() == names.len(),
-            "duplicated placeholders"
-        );
+        // Try to parse as an expression first, then bindings.
+        let raw = RawPattern::parse(pattern, PatternRoot::Expr)
+            .or_else(|err| RawPattern::parse(pattern, PatternRoot::Bindings).map_err(|_| err))?;
+        let mut names = HashMap::new();
+        for (i, ph) in raw.placeholders.values().enumerate() {
+            let first = *names.entry(ph.name.clone()).or_insert(i);
+            ensure!(
+                raw.placeholders[first].is_rest == ph.is_rest,
+                "placeholder ${} must be consistently a rest placeholder or not",
+                ph.name,
+            );
+        }
+        if raw.root == PatternRoot::Bindings {
+            let bindings = raw.top_bindings();
+            ensure!(!bindings.is_empty(), "empty pattern");
+            ensure!(
+                bindings.iter().all(|n| raw.rest_placeholder(n).is_none()),
+                "rest placeholders cannot be at the top level",
+            );
+        }
         Ok(Self { raw, names })
     }
 
-    pub fn find_iter(&self, input: &SyntaxNode) -> impl Iterator<Item = SyntaxNode> + '_ {
-        input.descendants().filter(|n| self.matches(n))
+    /// Find all matches, including nested ones, in preorder.
+    pub fn find_iter<'a>(&'a self, input: &SyntaxNode) -> impl Iterator<Item = Match> + 'a {
+        self.find_iter_with(input, None)
+    }
+
+    /// Find all matches semantically, see [`Pattern`].
+    pub fn find_iter_semantic<'a>(
+        &'a self,
+        input: &SyntaxNode,
+        resolver: &'a dyn Resolver,
+    ) -> impl Iterator<Item = Match> + 'a {
+        self.find_iter_with(input, Some(resolver))
+    }
+
+    fn find_iter_with<'a>(
+        &'a self,
+        input: &SyntaxNode,
+        resolver: Option<&'a dyn Resolver>,
+    ) -> impl Iterator<Item = Match> + 'a {
+        input
+            .descendants()
+            .flat_map(move |n| self.matches_at(&n, resolver))
     }
 
     pub fn replace_edits(
         &self,
         template: &Template,
         input: &SyntaxNode,
+        opts: ReplaceOptions,
+    ) -> Vec<(TextRange, String)> {
+        self.replace_edits_with(template, input, None, opts)
+    }
+
+    /// Replace semantic matches.
+    ///
+    /// Matches are skipped if a free name in the template would refer to a different thing at
+    /// the location of the match, eg. being shadowed by a local definition.
+    /// As for all replacements, matches are also skipped if names bound by the template would
+    /// capture free names in the substituted captures.
+    pub fn replace_edits_semantic(
+        &self,
+        template: &Template,
+        input: &SyntaxNode,
+        resolver: &dyn Resolver,
+        opts: ReplaceOptions,
+    ) -> Vec<(TextRange, String)> {
+        self.replace_edits_with(template, input, Some(resolver), opts)
+    }
+
+    fn replace_edits_with(
+        &self,
+        template: &Template,
+        input: &SyntaxNode,
+        resolver: Option<&dyn Resolver>,
+        opts: ReplaceOptions,
     ) -> Vec<(TextRange, String)> {
-        let mut edits = Vec::new();
-        let mut captures = vec![None; self.raw.placeholders.len()];
-        let mut iter = input.preorder();
-        while let Some(event) = iter.next() {
-            let WalkEvent::Enter(n) = event else { continue };
-            if self.captures(&n, &mut captures) {
-                iter.skip_subtree();
+        let mut matches = self.find_iter_with(input, resolver).collect::<Vec<_>>();
+        // Bindings matches of a node are found before nested matches inside the node.
+        // Stable sorting keeps outer matches before inner ones with the same start.
+        matches.sort_by_key(|m| m.range.start());
+        // Names bound by the template must not capture free names of the substituted captures.
+        matches.retain(|m| {
+            template
+                .raw
+                .placeholders
+                .values()
+                .zip(&template.scopes)
+                .all(|(ph, scope)| {
+                    let free = free_names(&m.captures[self.names[&ph.name]]);
+                    match scope {
+                        Some(scope) => free.is_disjoint(scope),
+                        None => free.is_empty(),
+                    }
+                })
+        });
+        if let Some(resolver) = resolver {
+            matches.retain(|m| {
+                let mut roots = template.raw.paths.values().map(|path| &path.root);
+                roots.all(|root| {
+                    resolver.resolve_name(root, Some(&m.node)) == resolver.resolve_name(root, None)
+                })
+            });
+        }
 
-                let mut replacee = String::new();
-                self.substitute_node(&template.raw.node, template, &captures, &mut replacee);
-                edits.push((n.text_range(), replacee));
+        let replacer = Replacer {
+            pattern: self,
+            template,
+            matches: &matches,
+            nested: opts.nested,
+        };
+        let mut edits = Vec::<(TextRange, String)>::new();
+        for m in &matches {
+            // Only replace the outermost. Inner ones are replaced during substitution if
+            // `nested` is set.
+            if edits
+                .last()
+                .is_some_and(|(range, _)| m.range.start() < range.end())
+            {
+                continue;
             }
+            edits.push((m.range, replacer.substitute(m, &m.node)));
         }
         edits
     }
 
-    pub fn replace(&self, src: &str, template: &Template, input: &SyntaxNode) -> String {
-        let edits = self.replace_edits(template, input);
+    pub fn replace(
+        &self,
+        src: &str,
+        template: &Template,
+        input: &SyntaxNode,
+        opts: ReplaceOptions,
+    ) -> String {
+        let edits = self.replace_edits(template, input, opts);
         debug_assert!(
             edits.windows(2).all(|w| w[0].0.end() <= w[1].0.start()),
             "no overlapping",
@@ -68,150 +247,1016 @@ impl Pattern {
             ret.push_str(&src[TextRange::new(prev_end, range.start())]);
             ret.push_str(ins);
         }
-        if let Some((range, _)) = edits.last() {
-            ret.push_str(&src[range.end().into()..]);
-        }
+        let last_end = edits.last().map_or(0.into(), |(range, _)| range.end());
+        ret.push_str(&src[last_end.into()..]);
         ret
     }
 
-    fn substitute_node(
+    /// Parse and replace repeatedly until there is nothing to replace, for rules which
+    /// create new matches like `{ $...a } // { $...b }` to `{ $...a $...b }`.
+    /// It fails if there is still something to replace after [`MAX_FIXPOINT_ROUNDS`] rounds,
+    /// in case of non-terminating rules.
+    pub fn replace_fixpoint(
         &self,
-        template_node: &SyntaxNode,
+        src: &str,
         template: &Template,
-        captures: &[Option<SyntaxNode>],
-        ret: &mut String,
-    ) {
-        for nt in template_node.children_with_tokens() {
-            let range = nt.text_range();
-            if let Some(templ_idx) = template.raw.placeholders.get_index_of(&range) {
-                // NB. `templ_idx` is the placeholder index of the template, while
-                // `captures` expects the index of the pattern.
-                let pat_idx = self
-                    .names
-                    .get_index_of(&template.raw.placeholders[templ_idx])
-                    .unwrap();
-
-                let replacee = captures[pat_idx].clone().unwrap();
-                let replacee_text = replacee.text();
-
-                // If the old parent cannot safely contain the replacee, wrap it in parentheses.
-                let parent_expr = nt.parent().and_then(ast::Expr::cast);
-                let replacee_expr = ast::Expr::cast(replacee.clone());
-                let need_paren = matches!((parent_expr, replacee_expr), (Some(outer), Some(inner)) if !outer.contains_without_paren(&inner));
-
-                if need_paren {
-                    ret.push('(');
-                }
-                replacee_text.for_each_chunk(|s| ret.push_str(s));
-                if need_paren {
-                    ret.push(')');
-                }
-
-                continue;
-            }
-            match nt {
-                NodeOrToken::Token(t) => ret.push_str(t.text()),
-                NodeOrToken::Node(n) => self.substitute_node(&n, template, captures, ret),
+        opts: ReplaceOptions,
+    ) -> Result<String, FixpointLimitReached> {
+        let mut src = src.to_owned();
+        for _ in 0..MAX_FIXPOINT_ROUNDS {
+            let parse = syntax::parse_file(&src);
+            let ret = self.replace(&src, template, &parse.syntax_node(), opts);
+            if ret == src {
+                return Ok(src);
             }
+            src = ret;
         }
+        Err(FixpointLimitReached)
     }
 
-    fn matches(&self, input: &SyntaxNode) -> bool {
-        self.matches_node(&self.raw.node, input, &mut |_, _| {})
-    }
+    /// Get matches rooted at `input`. There can be multiple non-overlapping matches
+    /// of bindings patterns inside one attribute set.
+    fn matches_at(&self, input: &SyntaxNode, resolver: Option<&dyn Resolver>) -> Vec<Match> {
+        let mut captures = vec![Vec::new(); self.raw.placeholders.len()];
+        match self.raw.root {
+            PatternRoot::Expr => {
+                // Go through `matches_seq` to handle a top-level placeholder.
+                let (pat, input_elem) = (self.raw.node.clone().into(), input.clone().into());
+                if self.matches_seq(&[pat], &[input_elem], &mut captures, resolver) {
+                    return vec![Match {
+                        range: trimmed_range(input),
+                        node: input.clone(),
+                        captures,
+                    }];
+                }
+                Vec::new()
+            }
+            PatternRoot::Bindings => {
+                if !matches!(input.kind(), SyntaxKind::ATTR_SET | SyntaxKind::LET_IN) {
+                    return Vec::new();
+                }
+                let pats = self.raw.top_bindings();
+                let inputs = input
+                    .children()
+                    .filter(|n| ast::Binding::can_cast(n.kind()))
+                    .collect::<Vec<_>>();
 
-    fn captures(&self, input: &SyntaxNode, captures: &mut [Option<SyntaxNode>]) -> bool {
-        self.matches_node(&self.raw.node, input, &mut |i, n| {
-            captures[i] = Some(n);
-        })
+                let mut ret = Vec::new();
+                let mut i = 0;
+                while i + pats.len() <= inputs.len() {
+                    let window = &inputs[i..i + pats.len()];
+                    if pats
+                        .iter()
+                        .zip(window)
+                        .all(|(pat, input)| self.matches_node(pat, input, &mut captures, resolver))
+                    {
+                        ret.push(Match {
+                            range: trimmed_range(&window[0])
+                                .cover(trimmed_range(&window[window.len() - 1])),
+                            node: input.clone(),
+                            captures: captures.clone(),
+                        });
+                        i += pats.len();
+                    } else {
+                        i += 1;
+                    }
+                }
+                ret
+            }
+        }
     }
 
     fn matches_node(
         &self,
         pat: &SyntaxNode,
         input: &SyntaxNode,
-        on_placeholder: &mut impl FnMut(usize, SyntaxNode),
+        captures: &mut [Capture],
+        resolver: Option<&dyn Resolver>,
     ) -> bool {
+        if let (Some(resolver), Some(path)) = (resolver, self.raw.paths.get(&trimmed_range(pat))) {
+            return resolver
+                .resolve_name(&path.root, None)
+                .map(|root| root.join(path.attrs.iter().cloned()))
+                .is_some_and(|path| resolver.resolve_expr(input) == Some(path));
+        }
+
         if pat.kind() != input.kind() {
             return false;
         }
 
-        fn no_trivia(nt: &NodeOrToken<SyntaxNode, SyntaxToken>) -> bool {
+        fn no_trivia(nt: &SyntaxElement) -> bool {
             !matches!(nt.as_token(), Some(tok) if tok.kind().is_trivia())
         }
 
-        let mut pat_iter = pat.children_with_tokens().filter(no_trivia);
-        let mut input_iter = input.children_with_tokens().filter(no_trivia);
+        let pats = pat
+            .children_with_tokens()
+            .filter(no_trivia)
+            .collect::<Vec<_>>();
+        let inputs = input
+            .children_with_tokens()
+            .filter(no_trivia)
+            .collect::<Vec<_>>();
+        self.matches_seq(&pats, &inputs, captures, resolver)
+    }
+
+    /// Match a sequence of sibling elements. Placeholders matching multiple nodes are
+    /// matched by backtracking.
+    fn matches_seq(
+        &self,
+        mut pats: &[SyntaxElement],
+        mut inputs: &[SyntaxElement],
+        captures: &mut [Capture],
+        resolver: Option<&dyn Resolver>,
+    ) -> bool {
         loop {
-            match (pat_iter.next(), input_iter.next()) {
-                (None, None) => break true,
-                (Some(NodeOrToken::Node(pat_node)), Some(NodeOrToken::Node(input_node))) => {
-                    // Placeholders match any expressions.
-                    if let Some(i) = self.raw.placeholders.get_index_of(&pat_node.text_range()) {
-                        if ast::Expr::can_cast(input_node.kind()) {
-                            on_placeholder(i, input_node);
-                            continue;
+            let Some((pat, pats_rest)) = pats.split_first() else {
+                return inputs.is_empty();
+            };
+
+            if let Some(MultiPlaceholder {
+                idx,
+                min_len,
+                accepts,
+            }) = pat.as_node().and_then(|n| self.multi_placeholder(n))
+            {
+                let max_len = inputs
+                    .iter()
+                    .take_while(|nt| nt.as_node().is_some_and(|n| accepts(n.kind())))
+                    .count();
+                return (min_len..=max_len).any(|len| {
+                    let (taken, inputs_rest) = inputs.split_at(len);
+                    captures[idx] = taken
+                        .iter()
+                        .filter_map(|nt| nt.as_node().cloned())
+                        .collect();
+                    self.check_capture(idx, captures)
+                        && self.matches_seq(pats_rest, inputs_rest, captures, resolver)
+                });
+            }
+
+            let Some((input, inputs_rest)) = inputs.split_first() else {
+                return false;
+            };
+            match (pat, input) {
+                (NodeOrToken::Node(pat_node), NodeOrToken::Node(input_node)) => {
+                    if let Some(i) = self.raw.placeholder_of(pat_node) {
+                        if !placeholder_accepts(pat_node.kind(), input_node.kind()) {
+                            return false;
                         }
-                    } else if self.matches_node(&pat_node, &input_node, on_placeholder) {
-                        continue;
+                        captures[i] = vec![input_node.clone()];
+                        if !self.check_capture(i, captures) {
+                            return false;
+                        }
+                    } else if !self.matches_node(pat_node, input_node, captures, resolver) {
+                        return false;
                     }
-                    break false;
                 }
-                (Some(NodeOrToken::Token(lhs)), Some(NodeOrToken::Token(rhs)))
-                    if lhs.text() == rhs.text() => {}
-                _ => break false,
+                (NodeOrToken::Token(lhs), NodeOrToken::Token(rhs)) if lhs.text() == rhs.text() => {}
+                _ => return false,
+            }
+            (pats, inputs) = (pats_rest, inputs_rest);
+        }
+    }
+
+    /// Check the constraint of a newly captured placeholder, and if it is equal to the first
+    /// occurrence of the same name. Placeholders are always captured in order.
+    fn check_capture(&self, idx: usize, captures: &[Capture]) -> bool {
+        let ph = &self.raw.placeholders[idx];
+        if let Some(c) = &ph.constraint {
+            if !captures[idx].iter().all(|n| c.accepts(n)) {
+                return false;
             }
         }
+        let first = self.names[&ph.name];
+        first == idx || nodes_eq(&captures[first], &captures[idx])
+    }
+
+    /// Check if `pat` is a placeholder matching multiple sibling nodes.
+    fn multi_placeholder(&self, pat: &SyntaxNode) -> Option<MultiPlaceholder> {
+        if let Some(idx) = self.raw.rest_placeholder(pat) {
+            return Some(MultiPlaceholder {
+                idx,
+                min_len: 0,
+                accepts: ast::Binding::can_cast,
+            });
+        }
+        // `inherit (a) $names;`
+        let idx = self.raw.placeholder_of(pat)?;
+        let parent = ast::Inherit::cast(pat.parent()?)?;
+        if !ast::Attr::can_cast(pat.kind()) || parent.attrs().count() != 1 {
+            return None;
+        }
+        Some(MultiPlaceholder {
+            idx,
+            min_len: 1,
+            accepts: ast::Attr::can_cast,
+        })
+    }
+}
+
+struct MultiPlaceholder {
+    idx: usize,
+    /// The minimal number of nodes to match.
+    min_len: usize,
+    /// If a node of the kind can be matched.
+    accepts: fn(SyntaxKind) -> bool,
+}
+
+/// Parse a placeholder with a constraint, like `${name:ident}`, at the start of `src`.
+/// Returns `None` if it is not a placeholder but an interpolation like `${name}`.
+fn parse_braced_placeholder(src: &str) -> Result<Option<(TextSize, &str, Constraint)>> {
+    let inner = &src["${".len()..];
+    let name_len = inner
+        .find(|c: char| !(c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '\'')))
+        .unwrap_or(inner.len());
+    let (name, rest) = inner.split_at(name_len);
+    let Some(rest) = rest.strip_prefix(':').filter(|_| !name.is_empty()) else {
+        return Ok(None);
+    };
+    // Regex arguments may contain `}`.
+    let end = if rest.starts_with(|c: char| c.is_ascii_alphabetic())
+        && rest[..rest.find('}').unwrap_or(rest.len())].contains('(')
+    {
+        rest.find(")}").map(|i| i + 1)
+    } else {
+        rest.find('}')
+    }
+    .with_context(|| format!("unterminated placeholder `${{{name}:`"))?;
+    let constraint = rest[..end]
+        .parse::<Constraint>()
+        .with_context(|| format!("invalid constraint of placeholder ${name}"))?;
+    let len = "${".len() + name_len + ":".len() + end + "}".len();
+    Ok(Some((TextSize::try_from(len).unwrap(), name, constraint)))
+}
+
+/// Shared states for substituting the template for matches.
+struct Replacer<'a> {
+    pattern: &'a Pattern,
+    template: &'a Template,
+    /// All matches sorted by their start, outer ones first.
+    matches: &'a [Match],
+    nested: bool,
+}
+
+impl Replacer<'_> {
+    /// Substitute the template for a match. The replacement is put in the position of
+    /// `context`, which decides if parentheses are needed.
+    fn substitute(&self, m: &Match, context: &SyntaxNode) -> String {
+        let first_tok = m.node.token_at_offset(m.range.start()).right_biased();
+        let mut indent = first_tok.as_ref().map(line_indent).unwrap_or_default();
+
+        // Keep comments in the matched region which are neither from captures nor the
+        // template, by moving them before the replacement.
+        let captured = m
+            .captures
+            .iter()
+            .filter_map(|cap| Some(trimmed_range(cap.first()?).cover(trimmed_range(cap.last()?))))
+            .collect::<Vec<_>>();
+        let comments = m
+            .node
+            .descendants_with_tokens()
+            .filter_map(|elem| elem.into_token())
+            .filter(|tok| {
+                let range = tok.text_range();
+                tok.kind() == SyntaxKind::COMMENT
+                    && m.range.contains_range(range)
+                    && !captured.iter().any(|cap| cap.contains_range(range))
+            })
+            .collect::<Vec<_>>();
+
+        // A line comment in the middle of a line makes the replacement a continuation line,
+        // like `a = # Comment.`, which is indented one more level.
+        let starts_line = first_tok
+            .and_then(|tok| tok.prev_token())
+            .map_or(true, |tok| {
+                tok.kind() == SyntaxKind::SPACE && tok.text().contains('\n')
+            });
+        if !starts_line && comments.iter().any(|tok| tok.text().starts_with('#')) {
+            indent += "  ";
+        }
+
+        let mut ret = String::new();
+        for tok in &comments {
+            ret.push_str(tok.text());
+            if tok.text().starts_with('#') {
+                ret.push('\n');
+                ret.push_str(&indent);
+            } else {
+                ret.push(' ');
+            }
+        }
+        let mut subst = Substitution {
+            replacer: self,
+            m,
+            indent: &indent,
+            ret,
+        };
+
+        let root = &self.template.raw.node;
+        if self.template.raw.root == PatternRoot::Bindings {
+            let start = subst.ret.len();
+            subst.push_node(root);
+            let wrapped = subst.ret.split_off(start);
+            subst.ret += RawPattern::unwrap_bindings(&wrapped);
+            return subst.ret;
+        }
+
+        // If the parent cannot safely contain the replacement, wrap it in parentheses.
+        let need_paren =
+            ast::Expr::cast(root.clone()).is_some_and(|inner| needs_paren(context, &inner));
+        if need_paren {
+            subst.ret.push('(');
+        }
+        subst.push_node(root);
+        if need_paren {
+            subst.ret.push(')');
+        }
+        subst.ret
+    }
+
+    /// The outermost matches inside `range` of the match `outer`, if `nested` is set.
+    fn inner_matches(&self, outer: &Match, range: TextRange) -> Vec<&Match> {
+        if !self.nested {
+            return Vec::new();
+        }
+        let start = self
+            .matches
+            .partition_point(|m| m.range.start() < range.start());
+        let mut ret = Vec::<&Match>::new();
+        for m in &self.matches[start..] {
+            if m.range.start() >= range.end() {
+                break;
+            }
+            let is_outer = m.node == outer.node && m.range == outer.range;
+            if is_outer
+                || !range.contains_range(m.range)
+                || ret
+                    .last()
+                    .is_some_and(|prev| m.range.start() < prev.range.end())
+            {
+                continue;
+            }
+            ret.push(m);
+        }
+        ret
+    }
+
+    /// If `capture` is exactly a match to be replaced, returns it.
+    fn capture_as_match(&self, outer: &Match, capture: &[SyntaxNode]) -> Option<&Match> {
+        let [node] = capture else {
+            return None;
+        };
+        self.inner_matches(outer, trimmed_range(node))
+            .into_iter()
+            .find(|m| m.node == *node && m.range == trimmed_range(node))
+    }
+}
+
+/// States for substituting the template for a match.
+struct Substitution<'a> {
+    replacer: &'a Replacer<'a>,
+    m: &'a Match,
+    /// The indentation of the line where the match starts.
+    indent: &'a str,
+    ret: String,
+}
+
+impl<'a> Substitution<'a> {
+    fn push_node(&mut self, template_node: &SyntaxNode) {
+        for nt in template_node.children_with_tokens() {
+            match nt {
+                NodeOrToken::Token(tok) => self.push_template_token(&tok),
+                NodeOrToken::Node(n) => self.push_child(&n),
+            }
+        }
+    }
+
+    fn push_child(&mut self, n: &SyntaxNode) {
+        let Some(templ_idx) = self.replacer.template.raw.placeholder_of(n) else {
+            // Parentheses around a placeholder are redundant if the capture does not need
+            // them, eg. `f ($x)` with `$x` being `a.b`.
+            if let Some(inner) = self.redundant_paren_inner(n) {
+                self.push_child(&inner);
+                // Keep the trivia attached to the template node.
+                for tok in trailing_trivia(n) {
+                    self.push_template_token(&tok);
+                }
+                return;
+            }
+            self.push_node(n);
+            return;
+        };
+
+        let capture = self.capture_of(templ_idx);
+        if let Some(m) = self.replacer.capture_as_match(self.m, capture) {
+            // The capture is replaced as a whole, and the replacement is put in the position
+            // of the placeholder.
+            let text = self.replacer.substitute(m, n);
+            let (from, to) = self.reindent_for(&capture[0]);
+            push_reindented(&mut self.ret, &text, &from, &to);
+            for tok in trailing_trivia(n) {
+                self.push_template_token(&tok);
+            }
+            return;
+        }
+        let need_paren = match (ast::Expr::cast(n.clone()), capture) {
+            (Some(_), [node]) => {
+                ast::Expr::cast(node.clone()).is_some_and(|inner| needs_paren(n, &inner))
+            }
+            _ => false,
+        };
+        if need_paren {
+            self.ret.push('(');
+        }
+        self.push_capture(capture);
+        if need_paren {
+            self.ret.push(')');
+        }
+        // Keep the trivia attached to the template node.
+        for tok in trailing_trivia(n) {
+            self.push_template_token(&tok);
+        }
+    }
+
+    /// The capture of a placeholder of the template.
+    fn capture_of(&self, templ_idx: usize) -> &'a [SyntaxNode] {
+        // NB. `templ_idx` is the placeholder index of the template, while `captures` expects
+        // the index of the pattern.
+        let raw = &self.replacer.template.raw;
+        let pat_idx = self.replacer.pattern.names[&raw.placeholders[templ_idx].name];
+        &self.m.captures[pat_idx]
+    }
+
+    /// If `n` is a parenthesized placeholder whose capture can be put there without
+    /// parentheses, returns the placeholder node.
+    fn redundant_paren_inner(&self, n: &SyntaxNode) -> Option<SyntaxNode> {
+        let inner = ast::Paren::cast(n.clone())?.expr()?;
+        let templ_idx = self.replacer.template.raw.placeholder_of(inner.syntax())?;
+        // Comments inside parentheses would be lost.
+        if n.children_with_tokens()
+            .any(|elem| elem.kind() == SyntaxKind::COMMENT)
+        {
+            return None;
+        }
+        let capture = self.capture_of(templ_idx);
+        if self.replacer.capture_as_match(self.m, capture).is_some() {
+            return None;
+        }
+        let [capture] = capture else {
+            return None;
+        };
+        let capture = ast::Expr::cast(capture.clone())?;
+        (!needs_paren(n, &capture)).then(|| inner.syntax().clone())
+    }
+
+    /// Push a token from the template, indenting new lines to the match.
+    fn push_template_token(&mut self, tok: &syntax::SyntaxToken) {
+        if tok.kind().is_trivia() {
+            push_reindented(&mut self.ret, tok.text(), "", self.indent);
+        } else {
+            self.ret.push_str(tok.text());
+        }
+    }
+
+    /// The indentation of the line of `node`, and of the current line to put it.
+    fn reindent_for(&self, node: &SyntaxNode) -> (String, String) {
+        let from = node
+            .token_at_offset(trimmed_range(node).start())
+            .right_biased()
+            .map(|tok| line_indent(&tok))
+            .unwrap_or_default();
+        let to = match self.ret.rfind('\n') {
+            Some(i) => {
+                let line = &self.ret[i + 1..];
+                line[..line.len() - line.trim_start_matches([' ', '\t']).len()].to_owned()
+            }
+            None => self.indent.to_owned(),
+        };
+        (from, to)
+    }
+
+    /// Push consecutive captured siblings, including trivia between them.
+    /// Multi-line captures are re-indented to the current line, except for string contents.
+    fn push_capture(&mut self, capture: &[SyntaxNode]) {
+        let (Some(first), Some(last)) = (capture.first(), capture.last()) else {
+            return;
+        };
+        let range = trimmed_range(first).cover(trimmed_range(last));
+        let (from, to) = self.reindent_for(first);
+        let parent = first.parent().unwrap_or_else(|| first.clone());
+        let tokens = parent
+            .descendants_with_tokens()
+            .filter_map(|elem| elem.into_token())
+            .filter(|tok| range.contains_range(tok.text_range()));
+        // Replace inner matches, with offsets relative to the original text.
+        let mut inner = self
+            .replacer
+            .inner_matches(self.m, range)
+            .into_iter()
+            .peekable();
+        let mut skip_until = None;
+        for tok in tokens {
+            let tok_start = tok.text_range().start();
+            if skip_until.is_some_and(|end| tok_start < end) {
+                continue;
+            }
+            if let Some(m) = inner.next_if(|m| m.range.start() == tok_start) {
+                let text = self.replacer.substitute(m, &m.node);
+                push_reindented(&mut self.ret, &text, &from, &to);
+                skip_until = Some(m.range.end());
+                continue;
+            }
+            if tok.kind().is_trivia() {
+                push_reindented(&mut self.ret, tok.text(), &from, &to);
+            } else {
+                self.ret.push_str(tok.text());
+            }
+        }
+    }
+}
+
+/// Check if `inner` needs parentheses to replace `node`, considering the position of `node` in
+/// its parent.
+fn needs_paren(node: &SyntaxNode, inner: &ast::Expr) -> bool {
+    let Some(parent) = node.parent() else {
+        return false;
+    };
+    let Some(outer) = ast::Expr::cast(parent.clone()) else {
+        return false;
+    };
+    if outer.contains_without_paren(inner) {
+        return false;
+    }
+    // Left (or right) associative operators can contain the same operator on the left (or
+    // right) side, eg. `f a b` and `a + b + c`.
+    let is_lhs = parent.children().next().as_ref() == Some(node);
+    match (&outer, inner) {
+        (ast::Expr::Apply(_), ast::Expr::Apply(_)) => !is_lhs,
+        (ast::Expr::BinaryOp(outer), ast::Expr::BinaryOp(inner)) => {
+            use syntax::ast::BinaryOpKind::*;
+            match (outer.op_kind(), inner.op_kind()) {
+                (Some(op), Some(inner_op)) if op == inner_op => match op {
+                    Add | Sub | Mul | Div | And | Or | PipeRight => !is_lhs,
+                    Update | Concat | Imply | PipeLeft => is_lhs,
+                    _ => true,
+                },
+                _ => true,
+            }
+        }
+        _ => true,
+    }
+}
+
+/// Trivia tokens at the end of `node`, which are attached to it by the parser.
+fn trailing_trivia(node: &SyntaxNode) -> Vec<syntax::SyntaxToken> {
+    let end = trimmed_range(node).end();
+    node.descendants_with_tokens()
+        .filter_map(|elem| elem.into_token())
+        .filter(|tok| tok.text_range().start() >= end && !tok.text_range().is_empty())
+        .collect()
+}
+
+/// The indentation of the line containing `tok`.
+fn line_indent(tok: &syntax::SyntaxToken) -> String {
+    let mut cur = tok.prev_token();
+    while let Some(tok) = cur {
+        if let Some(i) = tok.text().rfind('\n') {
+            let line = &tok.text()[i + 1..];
+            return line[..line.len() - line.trim_start_matches([' ', '\t']).len()].to_owned();
+        }
+        cur = tok.prev_token();
+    }
+    String::new()
+}
+
+/// Push `text`, replacing the indentation `from` with `to` for each line after the first.
+/// Lines not starting with `from` are kept as is.
+fn push_reindented(ret: &mut String, text: &str, from: &str, to: &str) {
+    let mut lines = text.split('\n').peekable();
+    ret.push_str(lines.next().unwrap_or_default());
+    while let Some(line) = lines.next() {
+        ret.push('\n');
+        match line.strip_prefix(from) {
+            // Don't indent empty lines. The last line is continued by the next token.
+            Some(rest) if !line.is_empty() || lines.peek().is_none() => {
+                ret.push_str(to);
+                ret.push_str(rest);
+            }
+            _ => ret.push_str(line),
+        }
+    }
+}
+
+/// Options for replacement.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ReplaceOptions {
+    /// Also replace matches inside captures of outer matches, bottom-up.
+    /// Eg. `1 + 2 + 3` with `$a + $b` to `$b + $a` gives `3 + (2 + 1)` rather than
+    /// `3 + (1 + 2)`.
+    pub nested: bool,
+}
+
+/// The limit of rounds of [`Pattern::replace_fixpoint`].
+pub const MAX_FIXPOINT_ROUNDS: usize = 16;
+
+/// The error of replacing repeatedly when it is still changing after [`MAX_FIXPOINT_ROUNDS`]
+/// rounds, which usually means the rule does not terminate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FixpointLimitReached;
+
+impl fmt::Display for FixpointLimitReached {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "replacement is still changing after {MAX_FIXPOINT_ROUNDS} rounds, \
+             the rule may not terminate",
+        )
+    }
+}
+
+impl std::error::Error for FixpointLimitReached {}
+
+/// Check if two sequences of nodes are structurally equal, ignoring trivia.
+fn nodes_eq(lhs: &[SyntaxNode], rhs: &[SyntaxNode]) -> bool {
+    let tokens = |nodes: &[SyntaxNode]| {
+        nodes
+            .iter()
+            .flat_map(|n| n.descendants_with_tokens())
+            .filter_map(|elem| elem.into_token())
+            .filter(|tok| !tok.kind().is_trivia())
+            .collect::<Vec<_>>()
+    };
+    let (lhs, rhs) = (tokens(lhs), tokens(rhs));
+    lhs.len() == rhs.len()
+        && lhs
+            .iter()
+            .zip(&rhs)
+            .all(|(a, b)| a.kind() == b.kind() && a.text() == b.text())
+}
+
+/// Names bound by `let`, `rec` attribute sets and lambdas inside a pattern.
+fn bound_names(node: &SyntaxNode) -> HashSet<SmolStr> {
+    let mut names = HashSet::new();
+    for n in node.descendants() {
+        add_binders(&n, &mut names);
+    }
+    names
+}
+
+/// Add names bound by `node` itself into `names`, if it is a `let`, `rec` attribute set or
+/// lambda.
+fn add_binders(node: &SyntaxNode, names: &mut HashSet<SmolStr>) {
+    let mut add_name = |attr: Option<ast::Attr>| {
+        if let Some(ast::Attr::Name(name)) = attr {
+            if let Some(tok) = name.token() {
+                names.insert(tok.text().into());
+            }
+        }
+    };
+    if let Some(lam) = ast::Lambda::cast(node.clone()) {
+        let Some(param) = lam.param() else { return };
+        add_name(param.name().map(ast::Attr::Name));
+        for field in param.pat().into_iter().flat_map(|pat| pat.fields()) {
+            add_name(field.name().map(ast::Attr::Name));
+        }
+    } else if ast::LetIn::can_cast(node.kind())
+        || ast::AttrSet::cast(node.clone()).is_some_and(|set| set.rec_token().is_some())
+    {
+        for binding in node.children().filter_map(ast::Binding::cast) {
+            match binding {
+                ast::Binding::AttrpathValue(b) => {
+                    add_name(b.attrpath().and_then(|path| path.attrs().next()));
+                }
+                ast::Binding::Inherit(i) => i.attrs().for_each(|attr| add_name(Some(attr))),
+            }
+        }
+    }
+}
+
+/// Names bound around `node` by its ancestors up to `root`, or `None` if it is inside the body
+/// of a `with`, which may bind any name.
+fn names_in_scope(node: &SyntaxNode, root: &SyntaxNode) -> Option<HashSet<SmolStr>> {
+    let mut names = HashSet::new();
+    if node == root {
+        return Some(names);
+    }
+    let range = node.text_range();
+    for n in node.ancestors().skip(1) {
+        if ast::With::cast(n.clone())
+            .and_then(|with| with.body())
+            .is_some_and(|body| body.syntax().text_range().contains_range(range))
+        {
+            return None;
+        }
+        add_binders(&n, &mut names);
+        if n == *root {
+            break;
+        }
+    }
+    Some(names)
+}
+
+/// Names referenced in `nodes` but not bound inside them.
+fn free_names(nodes: &[SyntaxNode]) -> HashSet<SmolStr> {
+    let mut names = HashSet::new();
+    for node in nodes {
+        for r in node.descendants().filter_map(ast::Ref::cast) {
+            let Some(tok) = r.token() else { continue };
+            let mut bound = HashSet::new();
+            for n in r.syntax().ancestors() {
+                add_binders(&n, &mut bound);
+                if n == *node {
+                    break;
+                }
+            }
+            if !bound.contains(tok.text()) {
+                names.insert(tok.text().into());
+            }
+        }
+    }
+    names
+}
+
+/// The range of a node excluding leading and trailing trivia.
+fn trimmed_range(node: &SyntaxNode) -> TextRange {
+    let mut tokens = node
+        .descendants_with_tokens()
+        .filter_map(|elem| elem.into_token())
+        .filter(|tok| !tok.kind().is_trivia());
+    match (tokens.next(), tokens.last()) {
+        (Some(first), last) => first
+            .text_range()
+            .cover(last.map_or(first.text_range(), |tok| tok.text_range())),
+        (None, _) => TextRange::empty(node.text_range().start()),
+    }
+}
+
+/// Check if a single-node placeholder parsed as `pat_kind` can match a node of `input_kind`.
+fn placeholder_accepts(pat_kind: SyntaxKind, input_kind: SyntaxKind) -> bool {
+    if ast::Expr::can_cast(pat_kind) {
+        ast::Expr::can_cast(input_kind)
+    } else if ast::Attr::can_cast(pat_kind) {
+        ast::Attr::can_cast(input_kind)
+    } else {
+        pat_kind == input_kind
     }
 }
 
 #[derive(Debug)]
 pub struct Template {
     raw: RawPattern,
+    /// Names bound by the template around each placeholder. See [`names_in_scope`].
+    scopes: Vec<Option<HashSet<SmolStr>>>,
 }
 
 impl Template {
     pub fn parse(templ: &str, pat: &Pattern) -> Result<Self> {
-        let raw = templ.parse::<RawPattern>()?;
-        if let Some(name) = raw
+        let raw = RawPattern::parse(templ, pat.raw.root)?;
+        for ph in raw.placeholders.values() {
+            let Some(&idx) = pat.names.get(&ph.name) else {
+                bail!("missing placeholder ${} from source pattern", ph.name);
+            };
+            ensure!(
+                ph.constraint.is_none(),
+                "constraints are not allowed in templates"
+            );
+            ensure!(
+                pat.raw.placeholders[idx].is_rest == ph.is_rest,
+                "placeholder ${} must be consistently a rest placeholder or not",
+                ph.name,
+            );
+        }
+        let scopes = raw
             .placeholders
-            .values()
-            .find(|&name| !pat.names.contains(name))
-        {
-            bail!("missing placeholder ${name} from source pattern");
+            .keys()
+            .map(|&range| {
+                let node = match raw.node.covering_element(range) {
+                    NodeOrToken::Node(n) => n,
+                    NodeOrToken::Token(tok) => tok.parent().expect("Not root"),
+                };
+                names_in_scope(&node, &raw.node)
+            })
+            .collect();
+        Ok(Self { raw, scopes })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PatternRoot {
+    Expr,
+    Bindings,
+}
+
+#[derive(Debug)]
+struct Placeholder {
+    name: SmolStr,
+    /// If this is a rest placeholder `$...name`.
+    is_rest: bool,
+    constraint: Option<Constraint>,
+}
+
+#[derive(Debug)]
+enum Constraint {
+    Literal,
+    String(Option<Regex>),
+    Ident(Option<Regex>),
+    Kind(SyntaxKind),
+}
+
+/// Names for `kind(name)` constraints.
+const KIND_NAMES: &[(&str, SyntaxKind)] = &[
+    ("apply", SyntaxKind::APPLY),
+    ("assert", SyntaxKind::ASSERT),
+    ("attr_path", SyntaxKind::ATTR_PATH),
+    ("attr_set", SyntaxKind::ATTR_SET),
+    ("binary_op", SyntaxKind::BINARY_OP),
+    ("dynamic", SyntaxKind::DYNAMIC),
+    ("has_attr", SyntaxKind::HAS_ATTR),
+    ("if_then_else", SyntaxKind::IF_THEN_ELSE),
+    ("indent_string", SyntaxKind::INDENT_STRING),
+    ("lambda", SyntaxKind::LAMBDA),
+    ("let_in", SyntaxKind::LET_IN),
+    ("list", SyntaxKind::LIST),
+    ("literal", SyntaxKind::LITERAL),
+    ("name", SyntaxKind::NAME),
+    ("paren", SyntaxKind::PAREN),
+    ("path_interpolation", SyntaxKind::PATH_INTERPOLATION),
+    ("ref", SyntaxKind::REF),
+    ("select", SyntaxKind::SELECT),
+    ("string", SyntaxKind::STRING),
+    ("unary_op", SyntaxKind::UNARY_OP),
+    ("with", SyntaxKind::WITH),
+];
+
+impl FromStr for Constraint {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, arg) = match s.strip_suffix(')').and_then(|s| s.split_once('(')) {
+            Some((name, arg)) => (name, Some(arg)),
+            None => (s, None),
+        };
+        let regex = || {
+            arg.map(|re| {
+                Regex::new(&format!("^(?:{re})$")).with_context(|| format!("invalid regex `{re}`"))
+            })
+            .transpose()
+        };
+        Ok(match name {
+            "literal" if arg.is_none() => Self::Literal,
+            "string" => Self::String(regex()?),
+            "ident" => Self::Ident(regex()?),
+            "kind" => {
+                let arg = arg.unwrap_or_default();
+                let (_, kind) = KIND_NAMES
+                    .iter()
+                    .find(|(name, _)| *name == arg)
+                    .with_context(|| format!("unknown syntax kind `{arg}`"))?;
+                Self::Kind(*kind)
+            }
+            _ => bail!("unknown constraint `{s}`"),
+        })
+    }
+}
+
+impl Constraint {
+    fn accepts(&self, node: &SyntaxNode) -> bool {
+        match self {
+            Self::Literal => node.kind() == SyntaxKind::LITERAL,
+            Self::String(re) => {
+                let text = if let Some(s) = ast::String::cast(node.clone()) {
+                    syntax::semantic::unescape_string_literal(&s)
+                } else if let Some(s) = ast::IndentString::cast(node.clone()) {
+                    s.string_parts()
+                        .map(|part| match part {
+                            ast::StringPart::Fragment(tok) | ast::StringPart::Escape(tok) => {
+                                Some(tok.text().to_owned())
+                            }
+                            ast::StringPart::Dynamic(_) => None,
+                        })
+                        .collect::<Option<String>>()
+                } else {
+                    return false;
+                };
+                match re {
+                    None => true,
+                    Some(re) => text.is_some_and(|text: String| re.is_match(&text)),
+                }
+            }
+            Self::Ident(re) => {
+                let name = match node.kind() {
+                    SyntaxKind::REF | SyntaxKind::NAME => node.clone(),
+                    // `$name = 1;`
+                    SyntaxKind::ATTR_PATH => match &node.children().collect::<Vec<_>>()[..] {
+                        [attr] if attr.kind() == SyntaxKind::NAME => attr.clone(),
+                        _ => return false,
+                    },
+                    _ => return false,
+                };
+                let Some(tok) = name.first_token() else {
+                    return false;
+                };
+                re.as_ref().map_or(true, |re| re.is_match(tok.text()))
+            }
+            Self::Kind(kind) => node.kind() == *kind,
         }
-        Ok(Self { raw })
     }
 }
 
+/// A syntactic reference path in patterns, like `lib.mkIf`, without placeholders.
+#[derive(Debug)]
+struct NamePath {
+    root: SmolStr,
+    attrs: Vec<SmolStr>,
+}
+
 #[derive(Debug)]
 struct RawPattern {
-    placeholders: IndexMap<TextRange, SmolStr>,
+    root: PatternRoot,
+    placeholders: IndexMap<TextRange, Placeholder>,
+    /// Reference paths of `REF` and `SELECT` nodes by their trimmed ranges,
+    /// excluding names bound inside the pattern.
+    paths: HashMap<TextRange, NamePath>,
+    /// The expression for `PatternRoot::Expr`, or the wrapping `ATTR_SET` for
+    /// `PatternRoot::Bindings`.
     node: SyntaxNode,
 }
 
-impl FromStr for RawPattern {
-    type Err = anyhow::Error;
+impl RawPattern {
+    const BINDINGS_PREFIX: &'static str = "{";
+    // Newline is required in case of trailing line comments.
+    const BINDINGS_SUFFIX: &'static str = "\n}";
 
-    fn from_str(src: &str) -> Result<Self, Self::Err> {
-        let mut placeholders = IndexMap::new();
+    fn parse(src: &str, root: PatternRoot) -> Result<Self> {
+        let wrapped;
+        let src = match root {
+            PatternRoot::Expr => src,
+            PatternRoot::Bindings => {
+                wrapped = format!("{}{}{}", Self::BINDINGS_PREFIX, src, Self::BINDINGS_SUFFIX);
+                &wrapped
+            }
+        };
 
-        let mut iter = syntax::lexer::lex(src.as_bytes()).into_iter();
+        let mut placeholders = IndexMap::new();
+        let mut iter = syntax::lexer::lex(src.as_bytes()).into_iter().peekable();
         let mut tokens = Vec::with_capacity(iter.size_hint().0);
         while let Some((kind, range)) = iter.next() {
+            if kind == T!["${"] {
+                if let Some((len, name, constraint)) =
+                    parse_braced_placeholder(&src[usize::from(range.start())..])?
+                {
+                    let range = TextRange::at(range.start(), len);
+                    // Skip tokens inside.
+                    while let Some((_, tok_range)) =
+                        iter.next_if(|(_, tok_range)| tok_range.start() < range.end())
+                    {
+                        ensure!(
+                            tok_range.end() <= range.end(),
+                            "invalid placeholder `{}`",
+                            &src[range],
+                        );
+                    }
+                    let placeholder = Placeholder {
+                        name: name.into(),
+                        is_rest: false,
+                        constraint: Some(constraint),
+                    };
+                    assert!(placeholders.insert(range, placeholder).is_none());
+                    tokens.push((SyntaxKind::IDENT, range));
+                    continue;
+                }
+            }
+
             if kind == SyntaxKind::ERROR && &src[range] == "$" {
+                let is_rest = iter.next_if(|(kind, _)| *kind == T![...]).is_some();
                 let (_, ident_range) = iter
                     .next()
                     .filter(|(kind, _)| *kind == SyntaxKind::IDENT)
                     .context("missing placeholder name")?;
                 let name = &src[ident_range];
 
-                // Union two ranges and create a identifier token covering both of them.
                 // Since `rowan` parser maintains sizes itself using string length, we cannot
-                // create virtual tokens with mismatched length.
+                // create virtual tokens with mismatched length. Instead, we create tokens
+                // covering the whole placeholder.
                 let range = range.cover(ident_range);
-                assert!(placeholders.insert(range, name.into()).is_none());
-                tokens.push((SyntaxKind::IDENT, range));
+                let placeholder = Placeholder {
+                    name: name.into(),
+                    is_rest,
+                    constraint: None,
+                };
+                assert!(placeholders.insert(range, placeholder).is_none());
+                if is_rest {
+                    // Parsed as an empty `inherit;` binding.
+                    let semicolon = TextRange::at(range.end() - TextSize::from(1), 1.into());
+                    tokens.push((
+                        T![inherit],
+                        TextRange::new(range.start(), semicolon.start()),
+                    ));
+                    tokens.push((T![;], semicolon));
+                } else {
+                    // An identifier token covering both of them.
+                    tokens.push((SyntaxKind::IDENT, range));
+                }
             } else {
                 tokens.push((kind, range));
             }
@@ -221,9 +1266,77 @@ impl FromStr for RawPattern {
         if let Some(err) = parse.errors().first() {
             bail!("syntax error: {}", err);
         }
-        Ok(Self {
+        let node = parse.root().expr().unwrap().syntax().clone();
+        ensure!(
+            root == PatternRoot::Expr || node.kind() == SyntaxKind::ATTR_SET,
+            "invalid bindings",
+        );
+        let mut this = Self {
+            root,
             placeholders,
-            node: parse.root().expr().unwrap().syntax().clone(),
-        })
+            paths: HashMap::new(),
+            node,
+        };
+        let bound_names = bound_names(&this.node);
+        for n in this.node.descendants() {
+            if let Some(path) = this.name_path(&n) {
+                if !bound_names.contains(&path.root) {
+                    this.paths.insert(trimmed_range(&n), path);
+                }
+            }
+        }
+        Ok(this)
+    }
+
+    fn name_path(&self, node: &SyntaxNode) -> Option<NamePath> {
+        if self.placeholder_of(node).is_some() {
+            return None;
+        }
+        match ast::Expr::cast(node.clone())? {
+            ast::Expr::Ref(r) => Some(NamePath {
+                root: r.token()?.text().into(),
+                attrs: Vec::new(),
+            }),
+            ast::Expr::Select(sel) if sel.or_token().is_none() => {
+                let mut path = self.name_path(sel.set()?.syntax())?;
+                for attr in sel.attrpath()?.attrs() {
+                    if self.placeholder_of(attr.syntax()).is_some() {
+                        return None;
+                    }
+                    let ast::Attr::Name(name) = attr else {
+                        return None;
+                    };
+                    path.attrs.push(name.token()?.text().into());
+                }
+                Some(path)
+            }
+            _ => None,
+        }
+    }
+
+    /// Strip the wrapper of a substituted bindings template.
+    /// The suffix may be indented after substitution.
+    fn unwrap_bindings(s: &str) -> &str {
+        let end = s.rfind('\n').expect("Has suffix");
+        &s[Self::BINDINGS_PREFIX.len()..end]
+    }
+
+    /// Top level bindings of a `PatternRoot::Bindings` pattern.
+    fn top_bindings(&self) -> Vec<SyntaxNode> {
+        self.node
+            .children()
+            .filter(|n| ast::Binding::can_cast(n.kind()))
+            .collect()
+    }
+
+    /// If `node` is a placeholder, returns its index.
+    fn placeholder_of(&self, node: &SyntaxNode) -> Option<usize> {
+        self.placeholders.get_index_of(&trimmed_range(node))
+    }
+
+    /// If `node` is a rest placeholder, returns its index.
+    fn rest_placeholder(&self, node: &SyntaxNode) -> Option<usize> {
+        let (idx, _, ph) = self.placeholders.get_full(&trimmed_range(node))?;
+        (ph.is_rest && node.kind() == SyntaxKind::INHERIT).then_some(idx)
     }
 }
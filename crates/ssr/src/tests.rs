@@ -1,7 +1,7 @@
 use expect_test::{expect, Expect};
 use syntax::parse_file;
 
-use crate::Template;
+use crate::{FixpointLimitReached, ReplaceOptions, Template};
 
 use super::Pattern;
 
@@ -10,10 +10,10 @@ fn check_find(src: &str, pattern: &str, expect: Expect) {
     let parse = parse_file(src);
     assert!(parse.errors().is_empty(), "syntax error");
     let pat = Pattern::parse(pattern).expect("invalid pattern");
-    let got_nodes = pat.find_iter(&parse.syntax_node()).collect::<Vec<_>>();
-    let mut markers = got_nodes
+    let got_matches = pat.find_iter(&parse.syntax_node()).collect::<Vec<_>>();
+    let mut markers = got_matches
         .iter()
-        .flat_map(|n| [(n.text_range().start(), '<'), (n.text_range().end(), '>')])
+        .flat_map(|m| [(m.range.start(), '<'), (m.range.end(), '>')])
         .collect::<Vec<_>>();
     markers.sort_by_key(|(pos, _)| *pos);
 
@@ -26,17 +26,28 @@ fn check_find(src: &str, pattern: &str, expect: Expect) {
 
 #[track_caller]
 fn check_replace(src: &str, pattern: &str, template: &str, expect: Expect) {
+    check_replace_with(src, pattern, template, ReplaceOptions::default(), expect);
+}
+
+#[track_caller]
+fn check_replace_with(
+    src: &str,
+    pattern: &str,
+    template: &str,
+    opts: ReplaceOptions,
+    expect: Expect,
+) {
     let parse = parse_file(src);
     assert!(parse.errors().is_empty(), "syntax error");
     let pat = Pattern::parse(pattern).expect("invalid pattern");
     let templ = Template::parse(template, &pat).expect("invalid template");
-    let got = pat.replace(src, &templ, &parse.syntax_node());
+    let got = pat.replace(src, &templ, &parse.syntax_node(), opts);
     expect.assert_eq(&got);
 }
 
 #[test]
 fn ident() {
-    // FIXME: Let bindings are not matched because they are `Attr`s.
+    // Names of bindings are `Attr`s, not expressions. See `binding_name`.
     check_find(
         "let foo = \"foo${foo}\"; /* foo */ in foo.foo",
         "foo",
@@ -68,9 +79,78 @@ fn placeholder() {
 
 #[test]
 fn recursive_match() {
-    check_find("1 + 2 + 3", "$a + $b", expect!["<<1 + 2 >+ 3>"]);
+    check_find("1 + 2 + 3", "$a + $b", expect!["<<1 + 2> + 3>"]);
     // Only replace the outermost.
-    check_replace("1 + 2 + 3", "$a + $b", "$b + $a", expect!["3 + (1 + 2 )"]);
+    check_replace("1 + 2 + 3", "$a + $b", "$b + $a", expect!["3 + (1 + 2)"]);
+}
+
+#[test]
+fn nested() {
+    let opts = ReplaceOptions { nested: true };
+    check_replace_with(
+        "1 + 2 + 3",
+        "$a + $b",
+        "$b + $a",
+        opts,
+        expect!["3 + (2 + 1)"],
+    );
+    check_replace_with("f (f x)", "f $x", "g $x", opts, expect!["g (g x)"]);
+    check_replace_with(
+        "map f (map g (map h xs))",
+        "map $f (map $g $xs)",
+        "map (x: $f ($g x)) $xs",
+        opts,
+        expect!["map (x: f (g x)) (map h xs)"],
+    );
+    check_replace_with(
+        "{\n  a = f {\n    b = f 1;\n  };\n}",
+        "f $x",
+        "g $x",
+        opts,
+        expect![[r#"
+            {
+              a = g {
+                b = g 1;
+              };
+            }"#]],
+    );
+}
+
+#[test]
+fn fixpoint() {
+    let src = "{ a = 1; } // { b = 2; } // { c = 3; }";
+    let pat = Pattern::parse("{ $...a } // { $...b }").unwrap();
+    let templ = Template::parse("{ $...a $...b }", &pat).unwrap();
+    let got = pat
+        .replace_fixpoint(src, &templ, ReplaceOptions::default())
+        .unwrap();
+    expect!["{ a = 1; b = 2; c = 3; }"].assert_eq(&got);
+
+    // Non-terminating rules.
+    let pat = Pattern::parse("$a").unwrap();
+    let templ = Template::parse("[ $a ]", &pat).unwrap();
+    let err = pat
+        .replace_fixpoint("1", &templ, ReplaceOptions::default())
+        .unwrap_err();
+    assert_eq!(err, FixpointLimitReached);
+}
+
+#[test]
+fn replace_capture() {
+    // Names bound by the template would capture free names of captures.
+    check_replace(
+        "[ (f a) (f y) (f (y: y)) ]",
+        "f $x",
+        "let y = 1; in g $x",
+        expect!["[ (let y = 1; in g a) (f y) (let y = 1; in g (y: y)) ]"],
+    );
+    // A `with` may capture any free name.
+    check_replace(
+        "[ (f a) (f 1) ]",
+        "f $x",
+        "with lib; g $x",
+        expect!["[ (f a) (with lib; g 1) ]"],
+    );
 }
 
 #[test]
@@ -85,3 +165,352 @@ fn replace_paren() {
         expect!["let a = assert 42; 42; in assert a; a"],
     );
 }
+
+#[test]
+fn paren_context() {
+    // The parent of the match.
+    check_replace("f a", "a", "x + 1", expect!["f (x + 1)"]);
+    check_replace("[ a ]", "a", "f x", expect!["[ (f x) ]"]);
+    check_replace("{ b = a; }", "a", "f x", expect!["{ b = f x; }"]);
+    // Associativity.
+    check_replace("f a b", "a", "g x", expect!["f (g x) b"]);
+    check_replace("f b", "a", "g x", expect!["f b"]);
+    check_replace("a b", "a", "g x", expect!["g x b"]);
+    check_replace("a + 1", "a", "x + y", expect!["x + y + 1"]);
+    check_replace("1 + a", "a", "x + y", expect!["1 + (x + y)"]);
+    check_replace("a ++ [ ]", "a", "x ++ y", expect!["(x ++ y) ++ [ ]"]);
+    check_replace("[ ] ++ a", "a", "x ++ y", expect!["[ ] ++ x ++ y"]);
+    // Redundant parentheses in the template.
+    check_replace(
+        "[ (f a.b) (f (g c)) ]",
+        "f $x",
+        "h ($x) 1",
+        expect!["[ (h a.b 1) (h (g c) 1) ]"],
+    );
+    check_replace(
+        "f (a /* c */ )",
+        "f $x",
+        "g ($x)",
+        expect!["g (a /* c */ )"],
+    );
+}
+
+#[test]
+fn keep_comments() {
+    check_replace(
+        "lib.mkIf /* cond */ a /* value */ b",
+        "lib.mkIf $c $v",
+        "lib.optionalAttrs $c $v",
+        expect!["/* cond */ /* value */ lib.optionalAttrs a b"],
+    );
+    check_replace(
+        "{\n  a = f # Comment.\n    x;\n}",
+        "f $x",
+        "g $x",
+        expect![[r#"
+            {
+              a = # Comment.
+                g x;
+            }"#]],
+    );
+    check_replace(
+        "{\n  a = f # Comment.\n    {\n      b = 1;\n    };\n}",
+        "f $x",
+        "g $x // {\n  c = 2;\n}",
+        expect![[r#"
+            {
+              a = # Comment.
+                g {
+                  b = 1;
+                } // {
+                  c = 2;
+                };
+            }"#]],
+    );
+    check_replace(
+        "let\n  v =\n    f # Comment.\n      x;\nin v",
+        "f $x",
+        "g $x",
+        expect![[r#"
+            let
+              v =
+                # Comment.
+                g x;
+            in v"#]],
+    );
+    // Comments inside captures are kept in place.
+    check_replace(
+        "f (a /* c */ + b)",
+        "f $x",
+        "g $x",
+        expect!["g (a /* c */ + b)"],
+    );
+}
+
+#[test]
+fn reindent() {
+    // Captures follow the indentation of the template.
+    check_replace(
+        "{\n  a = f {\n    b = 1;\n  };\n}",
+        "f $x",
+        "g {\n  x = $x;\n}",
+        expect![[r#"
+            {
+              a = g {
+                x = {
+                  b = 1;
+                };
+              };
+            }"#]],
+    );
+    // String contents are kept.
+    check_replace(
+        "{\n  a = f [\n    \"x\n    y\"\n    ''\n      z\n    ''\n  ];\n}",
+        "f $x",
+        "g\n  $x",
+        expect![[r#"
+            {
+              a = g
+                [
+                  "x
+                y"
+                  ''
+                  z
+                ''
+                ];
+            }"#]],
+    );
+    // Bindings templates.
+    check_replace(
+        "{\n  a = {\n    b = 1;\n  };\n}",
+        "b = $v;",
+        "c = $v;\nd = $v;",
+        expect![[r#"
+            {
+              a = {
+                c = 1;
+                d = 1;
+              };
+            }"#]],
+    );
+}
+
+#[test]
+fn binding_name() {
+    check_find(
+        "let foo = 1; bar = 2; in { foo = foo; }",
+        "foo = $v;",
+        expect!["let <foo = 1;> bar = 2; in { <foo = foo;> }"],
+    );
+    check_replace(
+        "let foo = 1; in { foo.bar = foo; }",
+        "$a.bar = $v;",
+        "$a.baz = $v;",
+        expect!["let foo = 1; in { foo.baz = foo; }"],
+    );
+    check_replace(
+        "{ a.b = 1; a.c = x.a.b; }",
+        "$x.$a.b",
+        "$x.$a.c",
+        expect!["{ a.b = 1; a.c = x.a.c; }"],
+    );
+}
+
+#[test]
+fn attrpath() {
+    check_find(
+        "{ a = 1; b.c = 2; \"d\".${e} = 3; inherit f; }",
+        "$path = $v;",
+        expect![[r#"{ <a = 1;> <b.c = 2;> <"d".${e} = 3;> inherit f; }"#]],
+    );
+    check_replace(
+        "{ a.b = 1; c = x.y.z; }",
+        "$path = $v;",
+        "$path = lib.mkDefault $v;",
+        expect!["{ a.b = lib.mkDefault 1; c = lib.mkDefault x.y.z; }"],
+    );
+    check_replace("x.a.b", "x.$path", "y.$path", expect!["y.a.b"]);
+}
+
+#[test]
+fn multiple_bindings() {
+    check_find(
+        "{ foo.bar = 1; foo.baz = 2; foo.bar = 3; x = { foo.bar = 4; foo.baz = 5; }; }",
+        "foo.bar = $x; foo.baz = $y;",
+        expect![
+            "{ <foo.bar = 1; foo.baz = 2;> foo.bar = 3; x = { <foo.bar = 4; foo.baz = 5;> }; }"
+        ],
+    );
+    check_replace(
+        "let foo.bar = 1; foo.baz = 2; in foo",
+        "foo.bar = $x; foo.baz = $y;",
+        "foo = { bar = $x; baz = $y; };",
+        expect!["let foo = { bar = 1; baz = 2; }; in foo"],
+    );
+    // Remove bindings.
+    check_replace(
+        "{ a = 1; b = { a = 2; }; }",
+        "a = $v;",
+        "",
+        expect!["{  b = {  }; }"],
+    );
+}
+
+#[test]
+fn inherit() {
+    check_find(
+        "{ inherit (pkgs) a b; inherit (pkgs.lib) c; inherit (pkgs) d; inherit e; }",
+        "inherit (pkgs) $names;",
+        expect!["{ <inherit (pkgs) a b;> inherit (pkgs.lib) c; <inherit (pkgs) d;> inherit e; }"],
+    );
+    check_find(
+        "{ inherit (pkgs) a b; inherit (pkgs) c; }",
+        "inherit (pkgs) $a $b;",
+        expect!["{ <inherit (pkgs) a b;> inherit (pkgs) c; }"],
+    );
+    check_replace(
+        "{ inherit (pkgs) a b; }",
+        "inherit (pkgs) $names;",
+        "inherit (pkgs.lib) $names;",
+        expect!["{ inherit (pkgs.lib) a b; }"],
+    );
+}
+
+#[test]
+fn rest_bindings() {
+    check_find(
+        "[ { type = 1; } { a = 1; type = 2; b = 2; } { b = 1; } ]",
+        "{ $...before type = $t; $...after }",
+        expect!["[ <{ type = 1; }> <{ a = 1; type = 2; b = 2; }> { b = 1; } ]"],
+    );
+    check_replace(
+        "{ a = 1; type = 2; b = 2; }",
+        "{ $...before type = $t; $...after }",
+        "{ $...before $...after }",
+        expect!["{ a = 1; b = 2; }"],
+    );
+    check_replace(
+        "let a = 1; in let b = 1; in b",
+        "let $...bindings in $body",
+        "with { $...bindings }; $body",
+        expect!["with { a = 1; }; let b = 1; in b"],
+    );
+}
+
+#[test]
+fn constraints() {
+    check_find(
+        "[ (f 1) (f ./a) (f \"s\") (f ''s'') (f x) (f [ ]) ]",
+        "f ${a:literal}",
+        expect![[r#"[ (<f 1>) (<f ./a>) (f "s") (f ''s'') (f x) (f [ ]) ]"#]],
+    );
+    check_find(
+        "[ (f 1) (f ./a) (f \"s\") (f ''s'') (f x) (f [ ]) ]",
+        "f ${a:string}",
+        expect![[r#"[ (f 1) (f ./a) (<f "s">) (<f ''s''>) (f x) (f [ ]) ]"#]],
+    );
+    check_find(
+        "[ (f 1) (f ./a) (f \"s\") (f ''s'') (f x) (f [ ]) ]",
+        "f ${a:kind(list)}",
+        expect![[r#"[ (f 1) (f ./a) (f "s") (f ''s'') (f x) (<f [ ]>) ]"#]],
+    );
+    check_replace(
+        "[ stdenv.lib.mkIf stdenv.lib.${x} (stdenv.lib.a.b) stdenv.lib.\"c\" ]",
+        "stdenv.lib.${x:ident}",
+        "lib.$x",
+        expect![[r#"[ lib.mkIf stdenv.lib.${x} (stdenv.lib.a.b) stdenv.lib."c" ]"#]],
+    );
+    // `${name}` without a constraint is still an interpolation.
+    check_find(
+        "{ ${x} = 1; a = 1; }",
+        "${x} = $v;",
+        expect!["{ <${x} = 1;> a = 1; }"],
+    );
+}
+
+#[test]
+fn regex_constraints() {
+    check_find(
+        "[ fooBar foo_bar \"foo-bar\" \"x${y}\" ''foo}'' ]",
+        "${a:ident(.*[A-Z].*)}",
+        expect![[r#"[ <fooBar> foo_bar "foo-bar" "x${y}" ''foo}'' ]"#]],
+    );
+    check_find(
+        "[ fooBar foo_bar \"foo-bar\" \"x${y}\" ''foo}'' ]",
+        "${a:string(foo.+)}",
+        expect![[r#"[ fooBar foo_bar <"foo-bar"> "x${y}" <''foo}''> ]"#]],
+    );
+    // Regexes are anchored.
+    check_find(
+        "[ foo fooBar \"foo\" \"foo-bar\" ]",
+        "${a:ident(foo)}",
+        expect![[r#"[ <foo> fooBar "foo" "foo-bar" ]"#]],
+    );
+    check_find(
+        "[ foo fooBar \"foo\" \"foo-bar\" ]",
+        "${a:string(foo|bar)}",
+        expect![[r#"[ foo fooBar <"foo"> "foo-bar" ]"#]],
+    );
+    check_replace(
+        "{ foo_bar = 1; baz = 2; }",
+        "${n:ident(.*_.*)} = $v;",
+        "",
+        expect!["{  baz = 2; }"],
+    );
+}
+
+#[test]
+fn repeated_placeholders() {
+    check_find(
+        "[ (a == a) (a == b) (f x /* c */ == f  x) (f x == (f x)) ]",
+        "$a == $a",
+        expect!["[ (<a == a>) (a == b) (<f x /* c */ == f  x>) (f x == (f x)) ]"],
+    );
+    check_replace(
+        "if x != null then x else y",
+        "if $a != null then $a else $b",
+        "if $a == null then $b else $a",
+        expect!["if x == null then y else x"],
+    );
+    check_find(
+        "[ { a = 1; } { a = 1; b = 2; } ]",
+        "[ { $...x } { $...x b = $v; } ]",
+        expect!["<[ { a = 1; } { a = 1; b = 2; } ]>"],
+    );
+}
+
+#[test]
+fn invalid_pattern() {
+    let err = |pat: &str| Pattern::parse(pat).unwrap_err().to_string();
+    assert_eq!(err(""), "empty pattern");
+    assert_eq!(
+        err("$...rest"),
+        "rest placeholders cannot be at the top level"
+    );
+    assert_eq!(
+        err("{ $...a a = $a; }"),
+        "placeholder $a must be consistently a rest placeholder or not"
+    );
+    let err_chain = |pat: &str| format!("{:#}", Pattern::parse(pat).unwrap_err());
+    assert_eq!(
+        err_chain("${a:foo}"),
+        "invalid constraint of placeholder $a: unknown constraint `foo`"
+    );
+    assert_eq!(
+        err_chain("${a:kind(foo)}"),
+        "invalid constraint of placeholder $a: unknown syntax kind `foo`"
+    );
+    assert!(err_chain("${a:ident(()}")
+        .starts_with("invalid constraint of placeholder $a: invalid regex `(`"));
+
+    let pat = Pattern::parse("${a:ident}").unwrap();
+    let err = Template::parse("${a:ident}", &pat).unwrap_err().to_string();
+    assert_eq!(err, "constraints are not allowed in templates");
+
+    let pat = Pattern::parse("{ $...a }").unwrap();
+    let err = Template::parse("$a", &pat).unwrap_err().to_string();
+    assert_eq!(
+        err,
+        "placeholder $a must be consistently a rest placeholder or not"
+    );
+}
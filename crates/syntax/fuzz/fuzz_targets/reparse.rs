@@ -0,0 +1,21 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use syntax::TextRange;
+
+fuzz_target!(|input: (&str, u16, u8, &str)| {
+    let (src, start, len, insert) = input;
+    let mut start = start as usize % (src.len() + 1);
+    while !src.is_char_boundary(start) {
+        start -= 1;
+    }
+    let mut end = (start + len as usize).min(src.len());
+    while !src.is_char_boundary(end) {
+        end += 1;
+    }
+
+    let mut new_src = src.to_owned();
+    new_src.replace_range(start..end, insert);
+    let delete = TextRange::new((start as u32).into(), (end as u32).into());
+    let reparsed = syntax::parse_file(src).reparse(delete, &new_src);
+    assert_eq!(reparsed, syntax::parse_file(&new_src));
+});
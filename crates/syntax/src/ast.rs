@@ -7,6 +7,9 @@ pub use rowan::ast::{AstChildren, AstNode};
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum BinaryOpKind {
+    PipeRight,
+    PipeLeft,
+
     Imply,
     Or,
     And,
@@ -45,6 +48,103 @@ pub trait HasStringParts: AstNode<Language = NixLanguage> {
     }
 }
 
+/// Nodes which can be documented by a doc comment right before them.
+pub trait HasDocComment: AstNode<Language = NixLanguage> {
+    /// Get the Markdown content of the doc comment of this node.
+    ///
+    /// It is either a `/** ... */` comment as in [RFC 145], or a block of consecutive `#`
+    /// comments each on their own line. A blank line between the comment and the node detaches
+    /// the comment.
+    ///
+    /// [RFC 145]: https://github.com/NixOS/rfcs/blob/master/rfcs/0145-doc-strings.md
+    fn doc_comment(&self) -> Option<std::string::String> {
+        doc_comment_before(&self.syntax().first_token()?)
+    }
+}
+
+fn doc_comment_before(tok: &SyntaxToken) -> Option<std::string::String> {
+    let newlines = |tok: &SyntaxToken| tok.text().matches('\n').count();
+    let is_line_start = |tok: &SyntaxToken| match tok.prev_token() {
+        None => true,
+        Some(prev) => prev.kind() == SPACE && (newlines(&prev) > 0 || prev.prev_token().is_none()),
+    };
+
+    let mut tok = tok.prev_token()?;
+    if tok.kind() == SPACE {
+        if newlines(&tok) > 1 {
+            return None;
+        }
+        tok = tok.prev_token()?;
+    }
+    if tok.kind() != COMMENT {
+        return None;
+    }
+
+    let text = tok.text();
+    if let Some(content) = text.strip_prefix("/**") {
+        if content.starts_with(['*', '/']) {
+            return None;
+        }
+        return Some(dedent_block_doc(content.strip_suffix("*/")?));
+    }
+    if !text.starts_with('#') {
+        return None;
+    }
+
+    let mut lines = Vec::new();
+    loop {
+        if !is_line_start(&tok) {
+            break;
+        }
+        let line = &tok.text()[1..];
+        lines.push(line.strip_prefix(' ').unwrap_or(line).trim_end().to_owned());
+        match tok
+            .prev_token()
+            .and_then(|space| Some((space.prev_token()?, space)))
+        {
+            Some((prev, space))
+                if prev.kind() == COMMENT
+                    && prev.text().starts_with('#')
+                    && newlines(&space) == 1 =>
+            {
+                tok = prev;
+            }
+            _ => break,
+        }
+    }
+    if lines.is_empty() {
+        return None;
+    }
+    lines.reverse();
+    Some(lines.join("\n"))
+}
+
+/// Strip the common indentation of a `/** ... */` doc comment, like indented strings.
+fn dedent_block_doc(content: &str) -> std::string::String {
+    let mut lines = content.lines();
+    let first = lines.next().unwrap_or_default().trim();
+    let rest = lines.collect::<Vec<_>>();
+    let indent = rest
+        .iter()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| line.len() - line.trim_start_matches([' ', '\t']).len())
+        .min()
+        .unwrap_or(0);
+    let rest = rest.iter().map(|line| {
+        if line.trim().is_empty() {
+            ""
+        } else {
+            line[indent..].trim_end()
+        }
+    });
+    let mut ret = std::iter::once(first)
+        .chain(rest)
+        .collect::<Vec<_>>()
+        .join("\n");
+    ret.truncate(ret.trim_end().len());
+    ret.trim_start_matches('\n').to_owned()
+}
+
 #[derive(Clone, Debug)]
 pub struct StringPartIter(SyntaxElementChildren);
 
@@ -273,38 +373,39 @@ impl Expr {
 
                 // Binary and unary ops. They follow `infix_bp` in parser.
                 Expr::BinaryOp(e) => match e.op_kind()? {
-                    BinaryOpKind::Imply => 1,
-                    BinaryOpKind::Or => 3,
-                    BinaryOpKind::And => 5,
-                    BinaryOpKind::Equal | BinaryOpKind::NotEqual => 7,
+                    BinaryOpKind::PipeRight | BinaryOpKind::PipeLeft => 1,
+                    BinaryOpKind::Imply => 3,
+                    BinaryOpKind::Or => 5,
+                    BinaryOpKind::And => 7,
+                    BinaryOpKind::Equal | BinaryOpKind::NotEqual => 9,
                     BinaryOpKind::Less
                     | BinaryOpKind::Greater
                     | BinaryOpKind::LessEqual
-                    | BinaryOpKind::GreaterEqual => 9,
-                    BinaryOpKind::Update => 11,
-                    BinaryOpKind::Add | BinaryOpKind::Sub => 15,
-                    BinaryOpKind::Mul | BinaryOpKind::Div => 17,
-                    BinaryOpKind::Concat => 19,
+                    | BinaryOpKind::GreaterEqual => 11,
+                    BinaryOpKind::Update => 13,
+                    BinaryOpKind::Add | BinaryOpKind::Sub => 17,
+                    BinaryOpKind::Mul | BinaryOpKind::Div => 19,
+                    BinaryOpKind::Concat => 21,
                 },
                 Expr::UnaryOp(e) => match e.op_kind()? {
-                    UnaryOpKind::Not => 13,
-                    UnaryOpKind::Negate => 23,
+                    UnaryOpKind::Not => 15,
+                    UnaryOpKind::Negate => 25,
                 },
-                Expr::HasAttr(_) => 21,
-                Expr::Apply(_) => 25,
+                Expr::HasAttr(_) => 23,
+                Expr::Apply(_) => 27,
 
                 // Lists can contain Select.
-                Expr::List(_) => 27,
+                Expr::List(_) => 29,
 
-                Expr::Select(_) => 29,
+                Expr::Select(_) => 31,
 
-                // Atoms.
+                // Atoms. Select can contain them.
                 Expr::AttrSet(_)
                 | Expr::String(_)
                 | Expr::IndentString(_)
                 | Expr::Literal(_)
                 | Expr::PathInterpolation(_)
-                | Expr::Ref(_) => 29,
+                | Expr::Ref(_) => 32,
 
                 // Special. See below.
                 Expr::Paren(_) => PAREN,
@@ -312,7 +413,7 @@ impl Expr {
         }
 
         const TOPLEVEL: u8 = 0;
-        const PAREN: u8 = 31;
+        const PAREN: u8 = 33;
 
         match (bp(self), bp(inner)) {
             // Special case 1: `Paren`s can safely contain or be contained by anything.
@@ -345,7 +446,7 @@ asts! {
     ATTR_PATH = Attrpath {
         attrs: [Attr],
     },
-    ATTR_PATH_VALUE = AttrpathValue {
+    ATTR_PATH_VALUE = AttrpathValue [HasDocComment] {
         attrpath: Attrpath,
         equal_token: T![=],
         value: Expr,
@@ -380,6 +481,8 @@ asts! {
             self.syntax().children_with_tokens().find_map(|n| {
                 let tok = n.into_token()?;
                 let op = match tok.kind() {
+                    T![|>] => BinaryOpKind::PipeRight,
+                    T![<|] => BinaryOpKind::PipeLeft,
                     T![->] => BinaryOpKind::Imply,
                     T![&&] => BinaryOpKind::And,
                     T![||] => BinaryOpKind::Or,
@@ -435,7 +538,7 @@ asts! {
         attrs: [Attr],
         semicolon_token: T![;],
     },
-    LAMBDA = Lambda {
+    LAMBDA = Lambda [HasDocComment] {
         param: Param,
         colon_token: T![:],
         body: Expr,
@@ -492,7 +595,7 @@ asts! {
         ellipsis_token: T![...],
         r_curly_token: T!['}'],
     },
-    PAT_FIELD = PatField {
+    PAT_FIELD = PatField [HasDocComment] {
         name: Name,
         question_token: T![?],
         default_expr: Expr,
@@ -547,10 +650,6 @@ mod tests {
     use super::*;
     use crate::tests::parse;
 
-    trait HasSyntaxNode {
-        fn has_syntax_node(&self) -> &SyntaxNode;
-    }
-
     trait AstTest {
         fn should_eq(&self, expect: &str);
     }
@@ -725,6 +824,32 @@ mod tests {
         e.body().unwrap().syntax().should_eq("b");
     }
 
+    #[test]
+    fn doc_comment() {
+        let doc = |src: &str| parse::<AttrpathValue>(src).doc_comment();
+        assert_eq!(doc("{ /** foo */ a = 1; }").as_deref(), Some("foo"));
+        assert_eq!(
+            doc("{\n  /**\n    foo\n\n      bar\n  */\n  a = 1;\n}").as_deref(),
+            Some("foo\n\n  bar"),
+        );
+        assert_eq!(
+            doc("{\n  # foo\n  #  bar\n  a = 1;\n}").as_deref(),
+            Some("foo\n bar"),
+        );
+        assert_eq!(
+            doc("{\n  # unrelated\n\n  # foo\n  a = 1;\n}").as_deref(),
+            Some("foo"),
+        );
+        let lambda = parse::<Lambda>("{ f = /** foo */ x: x; }");
+        assert_eq!(lambda.doc_comment().as_deref(), Some("foo"));
+
+        // Not doc comments.
+        assert_eq!(doc("{ /* foo */ a = 1; }"), None);
+        assert_eq!(doc("{ /***/ a = 1; }"), None);
+        assert_eq!(doc("{ /** foo */\n\n a = 1; }"), None);
+        assert_eq!(doc("{ b = 1; # foo\n  a = 1; }"), None);
+    }
+
     #[test]
     fn let_in() {
         let e = parse::<LetIn>("let a = 1; in b");
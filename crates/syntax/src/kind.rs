@@ -52,6 +52,13 @@ macro_rules! to_str {
     ($variant:tt, -) => {
         r#""-""#
     };
+    // These are multiple Rust tokens, which are stringified with spaces.
+    ($variant:tt, < |) => {
+        r#""<|""#
+    };
+    ($variant:tt, | >) => {
+        r#""|>""#
+    };
     // '['
     ($variant:tt, $s:literal) => {
         concat!('"', $s, '"')
@@ -111,9 +118,11 @@ def! {
     EQ2 = [==],
     GT_EQ = [>=],
     LT_EQ = [<=],
+    LT_PIPE = [<|],
     MINUS_GT = [->],
     NOT_EQ = [!=],
     OR2 = [||],
+    PIPE_GT = [|>],
     PLUS2 = [++],
     QUOTE2 = ["''"],
     SLASH2 = ["//"],
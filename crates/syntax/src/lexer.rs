@@ -111,6 +111,8 @@ regex_dfa! {
         DOT3 = r"\.\.\.",
         MINUS_GT = r"->",
         OR2 = r"\|\|",
+        PIPE_GT = r"\|>",
+        LT_PIPE = r"<\|",
         AND2 = r"&&",
         EQ2 = r"==",
         NOT_EQ = r"!=",
@@ -185,6 +187,28 @@ pub type LexTokens = Vec<(SyntaxKind, TextRange)>;
 /// # Panics
 /// Panic if the source is longer than `u32::MAX`.
 pub fn lex(src: &[u8]) -> LexTokens {
+    lex_impl(src).0
+}
+
+/// Tokenize a piece of source starting in the default context, like the content of a `{ }`.
+/// Return `None` if lexer contexts are unbalanced, that is, it closes contexts it does not open,
+/// or leaves some of its own unclosed.
+///
+/// # Panics
+/// Panic if the source is longer than `u32::MAX`.
+pub(crate) fn lex_balanced(src: &[u8]) -> Option<LexTokens> {
+    let (tokens, balanced) = lex_impl(src);
+    balanced.then_some(tokens)
+}
+
+/// Match the longest token in the default context at the start of `src`, without keyword
+/// recognition.
+pub(crate) fn match_default_token(src: &[u8]) -> Option<(SyntaxKind, TextSize)> {
+    let (kind, len) = DEFAULT_TOKEN_DFA.match_first(src)?;
+    Some((kind, TextSize::try_from(len).expect("Length overflow")))
+}
+
+fn lex_impl(src: &[u8]) -> (LexTokens, bool) {
     assert!(u32::try_from(src.len()).is_ok());
 
     let total_len = TextSize::try_from(src.len()).expect("Length overflow");
@@ -196,6 +220,7 @@ pub fn lex(src: &[u8]) -> LexTokens {
 
     let mut out = Vec::new();
     let mut ctxs = Vec::new();
+    let mut balanced = true;
 
     let mut offset = TextSize::from(0);
     while offset != total_len {
@@ -231,7 +256,7 @@ pub fn lex(src: &[u8]) -> LexTokens {
             T!["''"] => ctxs.push(indent_string_ctx),
             T!['{'] | T!["${"] => ctxs.push(default_ctx),
             T!['}'] => {
-                ctxs.pop();
+                balanced &= ctxs.pop().is_some();
             }
             IDENT => {
                 let ident = &rest[..usize::from(len)];
@@ -265,7 +290,8 @@ pub fn lex(src: &[u8]) -> LexTokens {
         out.push((PATH_END, TextRange::empty(total_len)));
     }
 
-    out
+    balanced &= ctxs.is_empty();
+    (out, balanced)
 }
 
 #[cfg(test)]
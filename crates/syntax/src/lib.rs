@@ -4,6 +4,7 @@ mod kind;
 pub mod ast;
 pub mod lexer;
 pub mod parser;
+mod reparse;
 pub mod semantic;
 
 #[cfg(test)]
@@ -2,7 +2,7 @@ use crate::ast::{AstNode, SourceFile};
 use crate::lexer::LexTokens;
 use crate::SyntaxKind::{self, *};
 use crate::{lexer, Error, ErrorKind, SyntaxNode};
-use rowan::{Checkpoint, GreenNode, GreenNodeBuilder, TextRange, TextSize};
+use rowan::{Checkpoint, GreenNode, GreenNodeBuilder, NodeOrToken, TextRange, TextSize};
 
 const MAX_STEPS: usize = 100_000_000;
 const MAX_DEPTHS: usize = 500;
@@ -14,6 +14,10 @@ pub struct Parse {
 }
 
 impl Parse {
+    pub(crate) fn new(green: GreenNode, errors: Vec<Error>) -> Self {
+        Self { green, errors }
+    }
+
     pub fn green_node(&self) -> GreenNode {
         self.green.clone()
     }
@@ -58,6 +62,72 @@ pub fn parse_file_tokens(src: &str, mut tokens: LexTokens) -> Parse {
     .parse()
 }
 
+/// Parse a single atom expression from tokens, which must be exactly consumed by it.
+/// This is used for incremental reparsing of balanced nodes like `ATTR_SET` and `LIST`.
+///
+/// `depth` is the nesting depth of the atom, which is used to limit the recursion as the
+/// full parsing does. The returned errors are relative to the start of `src`.
+pub(crate) fn parse_atom_tokens(
+    src: &str,
+    mut tokens: LexTokens,
+    depth: usize,
+) -> Option<(GreenNode, Vec<Error>)> {
+    assert!(u32::try_from(src.len()).is_ok());
+    tokens.reverse();
+    let mut p = Parser {
+        tokens,
+        builder: GreenNodeBuilder::default(),
+        errors: Vec::new(),
+        src,
+        steps: 0,
+        depth,
+    };
+    // Wrap it with a dummy node, since the builder requires a single root node.
+    p.start_node(SOURCE_FILE);
+    p.expr_atom_opt();
+    if !p.tokens.is_empty() {
+        return None;
+    }
+    p.finish_node();
+
+    let wrapper = p.builder.finish();
+    let mut children = wrapper.children();
+    match (children.next(), children.next()) {
+        (Some(NodeOrToken::Node(node)), None) => Some((node.to_owned(), p.errors)),
+        _ => None,
+    }
+}
+
+/// Recognize patterns of `LAMBDA` starting from the following non-whitespace tokens `tok_iter`.
+/// Otherwise, it's an `ATTR_SET`.
+/// - '{ ...'
+/// - '{ } :'
+/// - '{ } @'
+/// - '{ x ,'
+/// - '{ x ?'
+/// - '{ x ...'
+///   This is invalid but may occur when typing.
+///
+/// - '{ x } @'
+/// - '{ x } :'
+///   We reject `{ x }` following tokens other than `@` and `:` as lambda.
+///   This can occur for incomplete attrsets.
+pub(crate) fn is_lambda_pat_start(mut tok_iter: impl Iterator<Item = SyntaxKind>) -> bool {
+    if tok_iter.next() != Some(T!['{']) {
+        return false;
+    }
+    match tok_iter.next() {
+        Some(T![...]) => true,
+        Some(T!['}']) => matches!(tok_iter.next(), Some(T![:] | T![@])),
+        Some(IDENT) => match tok_iter.next() {
+            Some(T![,] | T![?] | T![...]) => true,
+            Some(T!['}']) => matches!(tok_iter.next(), Some(T![@] | T![:])),
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
 struct Parser<'i> {
     tokens: lexer::LexTokens,
     builder: GreenNodeBuilder<'static>,
@@ -279,35 +349,7 @@ impl<'i> Parser<'i> {
                 self.finish_node();
             }
             Some(T!['{']) => {
-                // Recognise patterns of `LAMBDA` starting. Otherwise, it's an `ATTR_SET`.
-                // - '{ ...'
-                // - '{ } :'
-                // - '{ } @'
-                // - '{ x ,'
-                // - '{ x ?'
-                // - '{ x ...'
-                //   This is invalid but may occur when typing.
-                //
-                // - '{ x } @'
-                // - '{ x } :'
-                //   We reject `{ x }` following tokens other than `@` and `:` as lambda.
-                //   This can occur for incomplete attrsets.
-                let is_lambda = {
-                    let mut tok_iter = self.peek_iter_non_ws();
-                    tok_iter.next(); // '{'
-                    match tok_iter.next() {
-                        Some(T![...]) => true,
-                        Some(T!['}']) => matches!(tok_iter.next(), Some(T![:] | T![@])),
-                        Some(IDENT) => match tok_iter.next() {
-                            Some(T![,] | T![?] | T![...]) => true,
-                            Some(T!['}']) => matches!(tok_iter.next(), Some(T![@] | T![:])),
-                            _ => false,
-                        },
-                        _ => false,
-                    }
-                };
-
-                if is_lambda {
+                if is_lambda_pat_start(self.peek_iter_non_ws()) {
                     self.start_node(LAMBDA);
 
                     self.start_node(PARAM);
@@ -391,9 +433,7 @@ impl<'i> Parser<'i> {
             _ => self.expr_select_opt(),
         }
 
-        loop {
-            let Some(tok) = self.peek_non_ws() else { break };
-
+        while let Some(tok) = self.peek_non_ws() {
             if let Some(lbp) = tok.postfix_bp() {
                 if lbp < min_bp {
                     break;
@@ -669,7 +709,7 @@ impl<'i> Parser<'i> {
                         self.finish_node();
                     }
                     // Use lookahead for ending condition, since `;` might not be typed yet.
-                    while self.peek_non_ws().map_or(false, SyntaxKind::can_start_attr) {
+                    while self.peek_non_ws().is_some_and(SyntaxKind::can_start_attr) {
                         self.attr_opt(false);
                     }
                     self.want(T![;]);
@@ -850,8 +890,8 @@ impl SyntaxKind {
     fn prefix_bp(self) -> Option<u8> {
         // See `infix_bp`.
         Some(match self {
-            T![!] => 13,
-            T![-] => 23,
+            T![!] => 15,
+            T![-] => 25,
             _ => return None,
         })
     }
@@ -860,7 +900,7 @@ impl SyntaxKind {
     fn postfix_bp(self) -> Option<u8> {
         // See `infix_bp`.
         Some(match self {
-            T![?] => 21,
+            T![?] => 23,
             _ => return None,
         })
     }
@@ -868,28 +908,31 @@ impl SyntaxKind {
     #[rustfmt::skip]
     fn infix_bp(self) -> Option<(u8, u8)> {
         Some(match self {
-            T![->] => (2, 1),
-            T![||] => (3, 4),
-            T![&&] => (5, 6),
+            // Pipe operators cannot be mixed without parentheses.
+            T![|>] => (1, 2),
+            T![<|] => (2, 1),
+            T![->] => (4, 3),
+            T![||] => (5, 6),
+            T![&&] => (7, 8),
             T![==] |
-            T![!=] => (7, 7),
+            T![!=] => (9, 9),
             T![<] |
             T![<=] |
             T![>] |
-            T![>=] => (9, 9),
-            T!["//"] => (12, 11),
-            // Prefix `!` => 13
+            T![>=] => (11, 11),
+            T!["//"] => (14, 13),
+            // Prefix `!` => 15
             T![+] |
-            T![-] => (15, 16),
+            T![-] => (17, 18),
             T![*] |
-            T![/] => (17, 18),
-            T![++] => (20, 19),
-            // Postfix `?` => 21
-            // Prefix `-` => 23
-            _ if self.can_start_atom_expr() => (25, 26), // APPLY
+            T![/] => (19, 20),
+            T![++] => (22, 21),
+            // Postfix `?` => 23
+            // Prefix `-` => 25
+            _ if self.can_start_atom_expr() => (27, 28), // APPLY
             _ => return None,
         })
     }
 }
 
-const APPLY_RBP: u8 = 26;
+const APPLY_RBP: u8 = 28;
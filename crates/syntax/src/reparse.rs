@@ -0,0 +1,353 @@
+//! Incremental reparsing.
+//!
+//! After an edit, we try to relex the single token containing it, or reparse the smallest
+//! balanced node containing it, and splice the result into the old tree. If neither is possible,
+//! we fall back to parsing the whole file.
+//!
+//! The result must always be identical to a full parse of the new source, which is verified by
+//! the fuzz target `reparse`.
+use crate::lexer::{self, LexTokens};
+use crate::parser::{self, Parse};
+use crate::SyntaxKind::{self, *};
+use crate::{Error, ErrorKind, NodeOrToken, SyntaxNode, SyntaxToken, TextRange, TextSize};
+use rowan::GreenToken;
+
+impl Parse {
+    /// Reparse the source after an edit, reusing the unchanged parts of the current tree.
+    ///
+    /// `delete` is the range in the current source being replaced, and `new_src` is the whole
+    /// source after the edit.
+    ///
+    /// # Panics
+    /// Panic if `delete` is out of bound, or the source is longer than `u32::MAX`.
+    pub fn reparse(&self, delete: TextRange, new_src: &str) -> Parse {
+        let edit = Edit::new(self, delete, new_src);
+        reparse_token(self, &edit, new_src)
+            .or_else(|| reparse_block(self, &edit, new_src))
+            .unwrap_or_else(|| parser::parse_file(new_src))
+    }
+}
+
+#[derive(Debug)]
+struct Edit {
+    /// The replaced range in the old source.
+    delete: TextRange,
+    /// The inserted range in the new source.
+    insert: TextRange,
+}
+
+impl Edit {
+    fn new(old: &Parse, delete: TextRange, new_src: &str) -> Self {
+        let old_len = old.green_node().text_len();
+        assert!(delete.end() <= old_len, "Delete range out of bound");
+        let new_len = TextSize::try_from(new_src.len()).expect("Length overflow");
+        let insert_len = new_len + delete.len() - old_len;
+        Self {
+            delete,
+            insert: TextRange::at(delete.start(), insert_len),
+        }
+    }
+
+    /// Apply the edit on the text of a range in the old source, which covers the deleted range.
+    fn apply_within(&self, range: TextRange, old_text: &str, new_src: &str) -> String {
+        let rel_delete = self.delete - range.start();
+        let mut buf = old_text.to_owned();
+        buf.replace_range(
+            std::ops::Range::<usize>::from(rel_delete),
+            &new_src[self.insert],
+        );
+        buf
+    }
+
+    /// Map a range in the old source, which does not overlap the edit, to the new source.
+    fn shift(&self, range: TextRange) -> TextRange {
+        if range.start() < self.delete.end() {
+            range
+        } else {
+            range - self.delete.len() + self.insert.len()
+        }
+    }
+}
+
+/// Relex a single token containing the edit, if it remains a single token of the same kind.
+/// The structure of the tree is unchanged in this case, since the parser only looks at token
+/// kinds.
+fn reparse_token(old: &Parse, edit: &Edit, new_src: &str) -> Option<Parse> {
+    let root = old.syntax_node();
+    // An insertion between two tokens may extend either of them.
+    if edit.delete.is_empty() {
+        return root
+            .token_at_offset(edit.delete.start())
+            .find_map(|tok| relex_token(old, edit, new_src, tok));
+    }
+    match root.covering_element(edit.delete) {
+        NodeOrToken::Token(tok) => relex_token(old, edit, new_src, tok),
+        NodeOrToken::Node(_) => None,
+    }
+}
+
+fn relex_token(old: &Parse, edit: &Edit, new_src: &str, tok: SyntaxToken) -> Option<Parse> {
+    let kind = tok.kind();
+    if !matches!(kind, IDENT | INT | FLOAT | SPACE | COMMENT) {
+        return None;
+    }
+
+    let old_range = tok.text_range();
+    let old_text = tok.text();
+    let new_text = edit.apply_within(old_range, old_text, new_src);
+    let new_range = TextRange::at(old_range.start(), TextSize::of(&*new_text));
+
+    // Both texts must be lexed into a single token of the same kind. This also rejects `or`
+    // used as an identifier, whose lexed kind differs from the kind in the tree.
+    let is_single = |text: &str| {
+        matches!(
+            &lexer::lex_balanced(text.as_bytes())?[..],
+            &[(k, range)] if k == kind && range.len() == TextSize::of(text)
+        )
+        .then_some(())
+    };
+    is_single(old_text)?;
+    is_single(&new_text)?;
+    if kind == COMMENT && old_text.starts_with('#') != new_text.starts_with('#') {
+        return None;
+    }
+    if introduces_comment_end(old_text, &new_text) {
+        return None;
+    }
+
+    // The new token must not swallow its following text.
+    let new_tail = &new_src.as_bytes()[usize::from(new_range.start())..];
+    if lexer::match_default_token(new_tail) != Some((kind, new_range.len())) {
+        return None;
+    }
+    // The previous token must not swallow the new token.
+    if let Some(prev) = tok.prev_token() {
+        let prev_range = prev.text_range();
+        let prev_tail = &new_src.as_bytes()[usize::from(prev_range.start())..];
+        if prev_range.is_empty()
+            || lexer::match_default_token(prev_tail).map(|(_, len)| len) != Some(prev_range.len())
+        {
+            return None;
+        }
+    }
+
+    let green = tok.replace_with(GreenToken::new(kind.into(), &new_text));
+    let errors = old
+        .errors()
+        .iter()
+        .map(|&err| {
+            let range = if err.range == old_range {
+                new_range
+            } else {
+                edit.shift(err.range)
+            };
+            Error { range, ..err }
+        })
+        .collect();
+    Some(Parse::new(green, errors))
+}
+
+/// Reparse the smallest balanced node containing the edit, that is, an `ATTR_SET` or `LIST`
+/// whose content is edited, or a string.
+fn reparse_block(old: &Parse, edit: &Edit, new_src: &str) -> Option<Parse> {
+    // The parsing depth may differ after a nesting-too-deep error, which is hard to track.
+    if old
+        .errors()
+        .iter()
+        .any(|err| err.kind == ErrorKind::NestTooDeep)
+    {
+        return None;
+    }
+
+    let root = old.syntax_node();
+    let (node, open) = root
+        .covering_element(edit.delete)
+        .ancestors()
+        .find_map(|node| {
+            let (open, close) = block_delimiters(&node)?;
+            (open.text_range().end() <= edit.delete.start()
+                && edit.delete.end() <= close.text_range().start())
+            .then_some((node, open))
+        })?;
+
+    let old_range = node.text_range();
+    let old_text = node.text().to_string();
+    let new_text = edit.apply_within(old_range, &old_text, new_src);
+    if introduces_comment_end(&old_text, &new_text) {
+        return None;
+    }
+    // The parser may close a node before the lexer leaves a string or interpolation inside it,
+    // in which case the lexing of the text after the node depends on the node content.
+    lexer::lex_balanced(old_text.as_bytes())?;
+    let tokens = lexer::lex_balanced(new_text.as_bytes())?;
+    // Similarly, an unterminated `/*` inside may be closed by some `*/` after the node.
+    if tokens
+        .windows(2)
+        .any(|w| matches!(w, [(T![/], lhs), (T![*], rhs)] if lhs.end() == rhs.start()))
+    {
+        return None;
+    }
+
+    // The parent chooses between `LAMBDA` and `ATTR_SET` by looking into the content of `{ }`.
+    if node.kind() == ATTR_SET && open.index() == 0 {
+        let next_tok = next_non_trivia_token(&node).map(|tok| tok.kind());
+        let toks = tokens.iter().map(|&(k, _)| k).filter(|k| !k.is_trivia());
+        if parser::is_lambda_pat_start(toks.chain(next_tok)) {
+            return None;
+        }
+    }
+
+    // Each level of parsing recursion is inside an ancestor node, so this is an upper bound.
+    let depth = node.ancestors().count() - 1;
+    let (green, new_errors) = reparse_node_tokens(&new_text, tokens, depth, node.kind())?;
+
+    let open_end = open.text_range().end();
+    let node_start = old_range.start();
+    let errors = old
+        .errors()
+        .iter()
+        .copied()
+        .filter(|err| err.range.start() < open_end)
+        .chain(new_errors.into_iter().map(|err| Error {
+            range: err.range + node_start,
+            ..err
+        }))
+        .chain(
+            old.errors()
+                .iter()
+                .filter(|err| old_range.end() <= err.range.start())
+                .map(|&err| Error {
+                    range: edit.shift(err.range),
+                    ..err
+                }),
+        )
+        .collect();
+    Some(Parse::new(node.replace_with(green), errors))
+}
+
+fn reparse_node_tokens(
+    text: &str,
+    tokens: LexTokens,
+    depth: usize,
+    kind: SyntaxKind,
+) -> Option<(rowan::GreenNode, Vec<Error>)> {
+    let (green, errors) = parser::parse_atom_tokens(text, tokens, depth)?;
+    let node = SyntaxNode::new_root(green.clone());
+    let (_, close) = block_delimiters(&node)?;
+    let ok = node.kind() == kind
+        && close.text_range().end() == TextSize::of(text)
+        && errors.iter().all(|err| err.kind != ErrorKind::NestTooDeep);
+    ok.then_some((green, errors))
+}
+
+/// Get the opening and closing delimiter tokens of a balanced node.
+fn block_delimiters(node: &SyntaxNode) -> Option<(SyntaxToken, SyntaxToken)> {
+    let (open, close) = match node.kind() {
+        ATTR_SET => (T!['{'], T!['}']),
+        LIST => (T!['['], T![']']),
+        STRING => (T!['"'], T!['"']),
+        INDENT_STRING => (T!["''"], T!["''"]),
+        _ => return None,
+    };
+    let open = node
+        .children_with_tokens()
+        .filter_map(|elem| elem.into_token())
+        .find(|tok| tok.kind() == open)?;
+    let close = node
+        .last_child_or_token()?
+        .into_token()
+        .filter(|tok| tok.kind() == close)?;
+    // A single `"` is both the opening and the closing delimiter.
+    (open.text_range().end() <= close.text_range().start()).then_some((open, close))
+}
+
+fn next_non_trivia_token(node: &SyntaxNode) -> Option<SyntaxToken> {
+    std::iter::successors(node.last_token()?.next_token(), |tok| tok.next_token())
+        .find(|tok| !tok.kind().is_trivia())
+}
+
+/// An unterminated `/*` before the edited range may be closed by a newly introduced `*/`,
+/// turning everything between into a comment.
+fn introduces_comment_end(old_text: &str, new_text: &str) -> bool {
+    new_text.contains("*/") && !old_text.contains("*/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_file;
+
+    /// Apply the edit `delete` -> `insert` on `src`, and check the result of incremental
+    /// reparsing against the full parsing. Return whether `f` succeeded.
+    #[track_caller]
+    fn check(
+        src: &str,
+        delete: (u32, u32),
+        insert: &str,
+        f: fn(&Parse, &Edit, &str) -> Option<Parse>,
+    ) -> bool {
+        let delete = TextRange::new(delete.0.into(), delete.1.into());
+        let mut new_src = src.to_owned();
+        new_src.replace_range(std::ops::Range::<usize>::from(delete), insert);
+
+        let old = parse_file(src);
+        let full = parse_file(&new_src);
+        let edit = Edit::new(&old, delete, &new_src);
+        let ret = f(&old, &edit, &new_src);
+        if let Some(parse) = &ret {
+            assert_eq!(parse, &full, "Reparse mismatch for {new_src:?}");
+        }
+        assert_eq!(old.reparse(delete, &new_src), full);
+        ret.is_some()
+    }
+
+    #[test]
+    fn token() {
+        assert!(check("foo bar", (1, 2), "x", reparse_token));
+        assert!(check("foo bar", (3, 3), "z", reparse_token));
+        assert!(check("{ a = 12; }", (6, 7), "345", reparse_token));
+        assert!(check("{ a = 1;  }", (8, 8), "\n", reparse_token));
+        assert!(check("a # foo\n", (4, 4), "bar", reparse_token));
+        assert!(check("{ a = 1 }", (6, 7), "42", reparse_token));
+
+        // Kind changed.
+        assert!(!check("foo bar", (4, 7), "42", reparse_token));
+        // Keywords.
+        assert!(!check("foo bar", (4, 7), "let", reparse_token));
+        assert!(!check("{ or = 1; }", (4, 4), "x", reparse_token));
+        // Merge with neighbors.
+        assert!(!check("1 .5", (1, 2), "", reparse_token));
+        assert!(!check("a :b", (1, 2), "", reparse_token));
+        assert!(!check("_x:a", (0, 1), "", reparse_token));
+        // Unterminated block comment.
+        assert!(!check("/* a # b", (8, 8), "*/", reparse_token));
+    }
+
+    #[test]
+    fn block() {
+        assert!(check("[ 1 2 ]", (4, 4), " 3", reparse_block));
+        assert!(check("{ a = 1; }", (8, 8), " b = 2;", reparse_block));
+        assert!(check("f { a = 1; } { b = 2; }", (4, 8), "", reparse_block));
+        assert!(check("rec { a = 1; }", (6, 12), "b = a;", reparse_block));
+        assert!(check(r#"{ a = "foo"; }"#, (7, 10), "${bar}", reparse_block));
+        assert!(check("''\n  foo\n''", (5, 5), "bar", reparse_block));
+        assert!(check("[ [ 1 ] ]", (4, 5), "[ ]", reparse_block));
+        assert!(check("{ a = 1; } // { }", (2, 8), "", reparse_block));
+        // Errors before and after are kept.
+        assert!(check("[ ( [ 1 ] ) ( ]", (6, 7), "2", reparse_block));
+
+        // Unbalanced.
+        assert!(!check("[ 1 ] ++ [ 2 ]", (2, 3), "]", reparse_block));
+        assert!(!check("[ 1 ]", (2, 3), "{", reparse_block));
+        assert!(!check(r#"[ "a" ]"#, (3, 4), r#"""#, reparse_block));
+        // Becoming a lambda.
+        assert!(!check("{ a = 1; }: 1", (3, 8), ",", reparse_block));
+        assert!(!check("{ a = 1; }: 1", (2, 9), "", reparse_block));
+        // The lexer is still inside the interpolation after `]`.
+        assert!(!check(r#"["${""]}#"#, (1, 4), "a", reparse_block));
+        // Delimiters are edited.
+        assert!(!check("[ 1 ]", (0, 1), "(", reparse_block));
+        // Unterminated block comment.
+        assert!(!check("/* [ 1 ]", (6, 6), "*/", reparse_block));
+    }
+}
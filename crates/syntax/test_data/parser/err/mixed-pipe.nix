@@ -0,0 +1,4 @@
+[
+  (a |> f <| b)
+  (f <| a |> g)
+]
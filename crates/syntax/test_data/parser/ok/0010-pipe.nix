@@ -0,0 +1 @@
+x |> f a -> b |> g (h <| y <| z)
@@ -0,0 +1,39 @@
+#!/usr/bin/env bash
+# Generate a builtins snapshot from the `nix` in PATH, which should be at least 2.17 for
+# `nix __dump-language`.
+# Usage: ./dev/dump-builtins.sh
+# It writes `crates/builtin/snapshots/nix-<major>.<minor>.json`. Then add it to `SNAPSHOTS` in
+# `crates/builtin/src/lib.rs`, where tests check that each snapshot is selected by its version.
+set -euo pipefail
+
+nix_eval() {
+    nix eval --experimental-features nix-command --read-only --json --expr "$1"
+}
+
+version="$(nix_eval 'builtins.nixVersion')"
+major_minor="$(jq --raw-output 'split(".")[:2] | join(".")' <<<"$version")"
+out="$(dirname "$0")/../crates/builtin/snapshots/nix-$major_minor.json"
+attr_names="$(nix_eval 'builtins.attrNames builtins')"
+# Probe each name to filter global ones.
+globals="$(
+    jq --raw-output '.[]' <<<"$attr_names" | while read -r name; do
+        if nix eval --experimental-features nix-command --store dummy:// --impure \
+            --expr "$name" &>/dev/null; then
+            echo "$name"
+        fi
+    done | jq --raw-input --slurp 'split("\n") | map(select(. != ""))'
+)"
+language="$(nix __dump-language)"
+
+jq --null-input --sort-keys \
+    --argjson version "$version" \
+    --argjson attrNames "$attr_names" \
+    --argjson globals "$globals" \
+    --argjson language "$language" \
+    '{
+        "nix-version": $version,
+        "attr-names": $attrNames,
+        "globals": $globals,
+        "language": $language,
+    }' >"$out"
+echo "Written to $out" >&2
@@ -18,7 +18,7 @@ rec {
       date = "${substring 0 4 mtime}-${substring 4 2 mtime}-${substring 6 2 mtime}";
       rev = self.rev or (throw "Git changes are not committed");
 
-      mkNil = { rustPlatform, nixUnstable, ... }:
+      mkNil = { rustPlatform, ... }:
         rustPlatform.buildRustPackage {
           pname = "nil";
           version = "unstable-${date}";
@@ -29,8 +29,6 @@ rec {
             allowBuiltinFetchGit = false;
           };
 
-          nativeBuildInputs = [ nixUnstable.out ];
-
           CFG_RELEASE = "git-${rev}";
 
           meta = {